@@ -0,0 +1,299 @@
+use std::{fs::read, path::PathBuf, time::Duration};
+
+use ::inat::{
+    Api as InatApi, Comment, Error as InatError, Identification, Model, Observation, Photo,
+    Selection, SyncOptions, Taxon, User,
+};
+use pyo3::{
+    create_exception,
+    exceptions::{PyAttributeError, PyException},
+    prelude::*,
+    types::{PyDict, PyList},
+    IntoPyObjectExt,
+};
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use tokio::{
+    runtime::{Builder, Runtime},
+    select,
+    time::sleep,
+};
+use tokio_util::sync::CancellationToken;
+
+// How often a running sync looks for Ctrl-C in Python.
+const SIGNAL_INTERVAL: Duration = Duration::from_millis(100);
+
+create_exception!(inat, Error, PyException);
+
+// The same Api as in Rust, with its own runtime: syncs block the calling thread, but not other
+// Python threads.
+#[pyclass(frozen, module = "inat")]
+struct Api {
+    api: InatApi,
+    runtime: Runtime,
+}
+
+#[pymethods]
+impl Api {
+    #[new]
+    #[pyo3(signature = (data_dir, endpoint=None, token=None, ca_cert=None, rate_limit=None))]
+    fn new(
+        data_dir: PathBuf,
+        endpoint: Option<&str>,
+        token: Option<&str>,
+        ca_cert: Option<PathBuf>,
+        rate_limit: Option<u32>,
+    ) -> PyResult<Self> {
+        let mut builder = InatApi::builder().data_dir(data_dir);
+        if let Some(endpoint) = endpoint {
+            builder = builder.base_url(endpoint);
+        }
+        if let Some(token) = token {
+            builder = builder.token(token);
+        }
+        let mut api = builder.build().map_err(error)?;
+        if let Some(path) = ca_cert {
+            api = api.with_root_certificates(&read(path)?).map_err(error)?;
+        }
+        // Requests per minute.
+        if let Some(per_minute) = rate_limit {
+            api = api.with_rate_limit(per_minute);
+        }
+
+        Ok(Self {
+            api,
+            runtime: Builder::new_current_thread().enable_all().build()?,
+        })
+    }
+
+    // Returns the summary, as a dict. Ctrl-C stops it after the current chunk, the next one
+    // resumes from there.
+    #[pyo3(signature = (username, only=vec![], exclude=vec![], full=false))]
+    fn sync(
+        &self,
+        py: Python<'_>,
+        username: &str,
+        only: Vec<String>,
+        exclude: Vec<String>,
+        full: bool,
+    ) -> PyResult<PyObject> {
+        let cancel = CancellationToken::new();
+        let opts = SyncOptions::new(Selection::new(only, exclude).map_err(error)?)
+            .full(full)
+            .cancel_on(cancel.clone());
+
+        let mut interrupt = None;
+        let summary = py.allow_threads(|| {
+            self.runtime.block_on(async {
+                let sync = self.api.sync(username, &opts);
+                tokio::pin!(sync);
+                loop {
+                    select! {
+                        res = &mut sync => return res,
+                        _ = sleep(SIGNAL_INTERVAL), if interrupt.is_none() => {
+                            if let Err(err) = Python::with_gil(|py| py.check_signals()) {
+                                interrupt = Some(err);
+                                cancel.cancel();
+                            }
+                        }
+                    }
+                }
+            })
+        });
+        if let Some(err) = interrupt {
+            return Err(err);
+        }
+
+        to_py(py, &summary.map_err(error)?)
+    }
+
+    fn load_observation(&self, py: Python<'_>, id: u64) -> PyResult<Option<PyObject>> {
+        self.load::<Observation>(py, id)
+    }
+
+    fn load_taxon(&self, py: Python<'_>, id: u64) -> PyResult<Option<PyObject>> {
+        self.load::<Taxon>(py, id)
+    }
+
+    // The synced user by login, or any other user the observations brought along.
+    fn load_user(&self, py: Python<'_>, login: &str) -> PyResult<Option<PyObject>> {
+        self.api
+            .load_user(login)
+            .map_err(error)?
+            .map(|user| Record::wrap(py, user))
+            .transpose()
+    }
+
+    fn observations(&self, py: Python<'_>) -> PyResult<Py<PyList>> {
+        self.table::<Observation>(py)
+    }
+
+    fn taxa(&self, py: Python<'_>) -> PyResult<Py<PyList>> {
+        self.table::<Taxon>(py)
+    }
+
+    fn users(&self, py: Python<'_>) -> PyResult<Py<PyList>> {
+        self.table::<User>(py)
+    }
+
+    fn identifications(&self, py: Python<'_>) -> PyResult<Py<PyList>> {
+        self.table::<Identification>(py)
+    }
+
+    fn comments(&self, py: Python<'_>) -> PyResult<Py<PyList>> {
+        self.table::<Comment>(py)
+    }
+
+    fn photos(&self, py: Python<'_>) -> PyResult<Py<PyList>> {
+        self.table::<Photo>(py)
+    }
+}
+
+impl Api {
+    fn load<T: PyModel>(&self, py: Python<'_>, id: u64) -> PyResult<Option<PyObject>> {
+        self.api
+            .load::<T>(id)
+            .map_err(error)?
+            .map(|record| Record::wrap(py, record))
+            .transpose()
+    }
+
+    fn table<T: PyModel>(&self, py: Python<'_>) -> PyResult<Py<PyList>> {
+        let list = PyList::empty(py);
+        for record in self.api.iter_table::<T>().map_err(error)? {
+            list.append(Record::wrap(py, record.map_err(error)?)?)?;
+        }
+
+        Ok(list.unbind())
+    }
+}
+
+// A cached record, e.g. an Observation. Fields read as attributes, the extra ones included;
+// nested records are either their ID, or a dict when the API sent them along.
+#[pyclass(frozen, subclass, module = "inat")]
+struct Record {
+    id: u64,
+    fields: Py<PyDict>,
+}
+
+#[pymethods]
+impl Record {
+    #[getter]
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn __getattr__(&self, py: Python<'_>, name: &str) -> PyResult<PyObject> {
+        match self.fields.bind(py).get_item(name)? {
+            Some(value) => Ok(value.unbind()),
+            _ => Err(PyAttributeError::new_err(name.to_string())),
+        }
+    }
+
+    fn to_dict(&self, py: Python<'_>) -> PyResult<Py<PyDict>> {
+        Ok(self.fields.bind(py).copy()?.unbind())
+    }
+
+    fn __repr__(slf: &Bound<'_, Self>) -> PyResult<String> {
+        Ok(format!(
+            "{}(id={})",
+            slf.get_type().qualname()?,
+            slf.get().id
+        ))
+    }
+}
+
+// The Python class of each model, all of them records.
+trait PyModel: Model {
+    fn class(py: Python<'_>, record: Record) -> PyResult<PyObject>;
+}
+
+impl Record {
+    fn wrap<T: PyModel>(py: Python<'_>, record: T) -> PyResult<PyObject> {
+        let fields = to_py(py, &record)?.downcast_bound::<PyDict>(py)?.clone();
+        T::class(
+            py,
+            Record {
+                id: record.id(),
+                fields: fields.unbind(),
+            },
+        )
+    }
+}
+
+macro_rules! models {
+    ($($model:ident => $class:ident as $name:literal),*) => {
+        $(
+            #[pyclass(frozen, extends = Record, module = "inat", name = $name)]
+            struct $class;
+
+            impl PyModel for $model {
+                fn class(py: Python<'_>, record: Record) -> PyResult<PyObject> {
+                    Ok(Py::new(py, PyClassInitializer::from(record).add_subclass($class))?.into_any())
+                }
+            }
+        )*
+    };
+}
+
+models!(
+    Comment => PyComment as "Comment",
+    Identification => PyIdentification as "Identification",
+    Observation => PyObservation as "Observation",
+    Photo => PyPhoto as "Photo",
+    Taxon => PyTaxon as "Taxon",
+    User => PyUser as "User"
+);
+
+fn to_py<T: Serialize>(py: Python<'_>, value: &T) -> PyResult<PyObject> {
+    let value = serde_json::to_value(value).map_err(|err| Error::new_err(err.to_string()))?;
+    Ok(json_to_py(py, &value)?.unbind())
+}
+
+fn json_to_py<'py>(py: Python<'py>, value: &JsonValue) -> PyResult<Bound<'py, PyAny>> {
+    Ok(match value {
+        JsonValue::Null => py.None().into_bound(py),
+        JsonValue::Bool(val) => val.into_bound_py_any(py)?,
+        JsonValue::Number(val) => match (val.as_u64(), val.as_i64()) {
+            (Some(val), _) => val.into_bound_py_any(py)?,
+            (_, Some(val)) => val.into_bound_py_any(py)?,
+            _ => val.as_f64().into_bound_py_any(py)?,
+        },
+        JsonValue::String(val) => val.into_bound_py_any(py)?,
+        JsonValue::Array(vals) => {
+            let list = PyList::empty(py);
+            for val in vals {
+                list.append(json_to_py(py, val)?)?;
+            }
+            list.into_any()
+        }
+        JsonValue::Object(map) => {
+            let dict = PyDict::new(py);
+            for (key, val) in map {
+                dict.set_item(key, json_to_py(py, val)?)?;
+            }
+            dict.into_any()
+        }
+    })
+}
+
+// inat.Error, with the message of the Rust one.
+fn error(err: InatError) -> PyErr {
+    Error::new_err(err.to_string())
+}
+
+#[pymodule]
+#[pyo3(name = "inat")]
+fn inat_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("Error", m.py().get_type::<Error>())?;
+    m.add_class::<Api>()?;
+    m.add_class::<Record>()?;
+    m.add_class::<PyComment>()?;
+    m.add_class::<PyIdentification>()?;
+    m.add_class::<PyObservation>()?;
+    m.add_class::<PyPhoto>()?;
+    m.add_class::<PyTaxon>()?;
+    m.add_class::<PyUser>()?;
+
+    Ok(())
+}
@@ -1,37 +1,214 @@
 use std::{
-    fs::{create_dir_all, File},
-    io::{BufReader, Write},
+    cmp::Ordering,
+    collections::HashMap,
+    ffi::OsString,
+    fs::File,
+    hash::{BuildHasher, RandomState},
+    io::{BufReader, Error as IoError, Read, Write},
     path::{Path, PathBuf},
-    thread::sleep,
-    time::Duration,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
 
+use bytes::Bytes;
 use chrono::{DateTime, Utc};
-use httpdate::parse_http_date;
+use httpdate::{fmt_http_date, parse_http_date};
+use itertools::Itertools;
 use reqwest::{
-    header::{HeaderMap, HeaderValue, ACCEPT, AGE, CONTENT_TYPE, DATE, ETAG, RETRY_AFTER},
-    Client, RequestBuilder, Response, StatusCode, Url,
+    header::{
+        HeaderMap, HeaderValue, ACCEPT, AGE, AUTHORIZATION, CONTENT_TYPE, DATE, ETAG,
+        IF_MODIFIED_SINCE, IF_NONE_MATCH, RETRY_AFTER,
+    },
+    Client, Method, RequestBuilder, Response, StatusCode, Url,
 };
-use serde::{Deserialize, Serialize};
+#[cfg(not(target_arch = "wasm32"))]
+use reqwest::{Certificate, ClientBuilder, Proxy};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{Map as JsonMap, Value as JsonValue};
 use serde_yaml::{
     Deserializer as YamlDeserializer, Mapping as YamlMapping, Sequence as YamlSequence,
     Value as YamlValue,
 };
+use tokio::{task::spawn_blocking, time::sleep};
+#[cfg(feature = "http-cache")]
 use tracing::debug;
+use tracing::{info, warn};
 
-use crate::error::{bad_status, corrupt_cache, internal, Error};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::cassette::Recorder;
+#[cfg(feature = "http-cache")]
+use crate::http_cache::HttpCache;
+#[cfg(feature = "raw-archive")]
+use crate::raw_archive::RawArchive;
+#[cfg(feature = "replica")]
+use crate::storage::Storage;
+use crate::{
+    archive::Archive,
+    cache_control::CacheControl,
+    chunks::Validator,
+    circuit_breaker::{CircuitBreaker, DEFAULT_COOL_DOWN, DEFAULT_THRESHOLD},
+    clock::{Clock, SystemClock},
+    durable,
+    error::{
+        bad_record, bad_status, corrupt_cache, internal, is_transient, unexpected_response, Error,
+    },
+    extractor::TableExtractor,
+    fields::{self, Fields},
+    in_flight::InFlight,
+    middleware::Middleware,
+    progress::SyncProgress,
+    quota::{DailyQuota, DEFAULT_DAILY_QUOTA},
+    rate_limit::{RateLimiter, DEFAULT_REQUESTS_PER_MINUTE},
+    store::{Settings, Store},
+    summary::Metrics,
+};
 
 pub(crate) const ID: &str = "id";
 
+const DEFAULT_BASE_URL: &str = "https://api.inaturalist.org/v1";
+const DEFAULT_DATA_DIR: &str = "data";
+
+// So that the API admins know who to contact about misbehaving clients.
+const DEFAULT_USER_AGENT: &str = concat!("inat/", env!("CARGO_PKG_VERSION"));
+
+// Cache header key of listings, recording when the last complete sync started.
+pub(crate) const UPDATED_SINCE: &str = "updated_since";
+
+// Cache header key of listings, recording when they were last listed in full.
+pub(crate) const LISTED_AT: &str = "listed_at";
+
 // In case no Retry-After header is returned, default to 1m as documented.
 // TODO(https://github.com/rust-lang/rust/issues/120301): Use from_mins().
 const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(60);
 
+// Waits between retries of failed requests.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// Tries per request, unless configured otherwise.
+const DEFAULT_ATTEMPTS: u32 = 5;
+
+// Without these, a hung connection would stall the sync forever.
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Up to this much is added to waits, so that concurrent requests don't all retry at once.
+const MAX_JITTER: f64 = 0.1;
+
+// Waits longer than this mean the quota is used up; better to stop and resume later.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(15 * 60);
+
 pub struct Api {
     pub(crate) client: Client,
+    client_config: ClientConfig,
     pub(crate) data_dir: PathBuf,
+    pub(crate) store: Arc<Store>,
     base_url: Url,
+    api_version: ApiVersion,
+    // Asked for on top of those the crate uses.
+    observation_fields: Fields,
+    limiter: RateLimiter,
+    pub(crate) quota: DailyQuota,
+    attempts: u32,
+    breaker: CircuitBreaker,
+    in_flight: InFlight<Option<(YamlMapping, Bytes)>>,
+    // None when disabled.
+    #[cfg(feature = "http-cache")]
+    pub(crate) http_cache: Option<HttpCache>,
+    cache_policy: CachePolicy,
+    #[cfg(feature = "raw-archive")]
+    raw_archive: Option<RawArchive>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    recorder: Option<Recorder>,
+    offline: bool,
+    progress: Option<Arc<dyn SyncProgress>>,
+    pub(crate) extractors: Vec<Arc<dyn TableExtractor>>,
+    pub(crate) metrics: Metrics,
+}
+
+// What the client is built from, kept so that it can be rebuilt with each change. Only the token
+// and the user agent matter on wasm32, where the browser's fetch does the connecting.
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+#[derive(Clone)]
+struct ClientConfig {
+    token: Option<String>,
+    user_agent: String,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    #[cfg(not(target_arch = "wasm32"))]
+    proxy: Option<Proxy>,
+    #[cfg(not(target_arch = "wasm32"))]
+    root_certificates: Vec<Certificate>,
+    // Left to reqwest unless set.
+    pool_max_idle: Option<usize>,
+    pool_idle_timeout: Option<Duration>,
+    tcp_keepalive: Option<Duration>,
+    http_version: HttpVersion,
+    response_compression: bool,
+}
+
+// Which HTTP version to talk to the API with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HttpVersion {
+    // HTTP/2 if the server offers it when connecting, HTTP/1.1 otherwise.
+    #[default]
+    Auto,
+    Http1,
+    // Without asking first; fails with servers that don't speak it.
+    Http2,
+}
+
+// Which version of the API to talk to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ApiVersion {
+    #[default]
+    V1,
+    // Asked for only the fields the crate uses, see Fields; responses shrink to a fraction of v1's.
+    V2,
+}
+
+// What the HTTP cache is used for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CachePolicy {
+    // Answers while fresh, revalidates after.
+    #[default]
+    Enabled,
+    // Revalidates every time, even while fresh; unchanged responses still cost a request, but not
+    // the bandwidth.
+    Revalidate,
+    Disabled,
+}
+
+// Everything Api::new leaves at the defaults; the other settings follow with Api::with_*.
+#[derive(Clone, Debug)]
+pub struct ApiBuilder {
+    base_url: String,
+    data_dir: PathBuf,
+    token: Option<String>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    requests_per_minute: u32,
+    daily_quota: u32,
+    user_agent: String,
+    cache_policy: CachePolicy,
+    #[cfg(feature = "replica")]
+    storage: Option<Arc<dyn Storage>>,
+    store: Option<Arc<Store>>,
+    clock: Arc<dyn Clock>,
+    deterministic: bool,
+    durable: bool,
+}
+
+// Only the HTTP cache, when built with it, reads the cache control and unmodified headers.
+#[cfg_attr(not(feature = "http-cache"), allow(dead_code))]
+enum Fetched {
+    Modified(YamlMapping, Bytes, CacheControl),
+    // A cache hit; the header is missing if the API didn't send a Date.
+    NotModified(Option<YamlMapping>, CacheControl),
 }
 
 pub(crate) struct ApiResults {
@@ -50,6 +227,10 @@ pub(crate) struct ApiResponse {
     // Error case:
     status: Option<u16>,
     error: Option<String>,
+
+    // Where from, for errors; None when read back from the cache.
+    #[serde(skip)]
+    url: Option<Url>,
 }
 
 #[derive(Debug)]
@@ -68,29 +249,327 @@ pub(crate) struct IDsCache {
 pub(crate) struct CacheHeader {
     pub(crate) date: DateTime<Utc>,
     pub(crate) etag: Option<String>,
+    pub(crate) updated_since: Option<DateTime<Utc>>,
+    pub(crate) listed_at: Option<DateTime<Utc>>,
 }
 
 impl Api {
+    pub fn builder() -> ApiBuilder {
+        ApiBuilder::default()
+    }
+
     pub fn new(base_url: &str, data_dir: &str) -> Result<Self, Error> {
-        let mut headers = HeaderMap::new();
-        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
-        Ok(Self {
-            client: Client::builder()
-                .default_headers(headers)
-                .https_only(true)
-                .build()?,
-            base_url: base_url.parse()?,
-            data_dir: PathBuf::from(data_dir),
+        Self::builder()
+            .base_url(base_url)
+            .data_dir(data_dir)
+            .build()
+    }
+
+    // Sends the API token with every request, e.g. to get one's own private coordinates.
+    pub fn with_token(mut self, token: &str) -> Result<Self, Error> {
+        self.client_config.token = Some(token.to_string());
+        self.rebuild_client()
+    }
+
+    // Gives up on connecting after connect, and on responses after read without any data; 30s and
+    // 60s by default. Timeouts are retried like connection problems.
+    pub fn with_timeouts(mut self, connect: Duration, read: Duration) -> Result<Self, Error> {
+        self.client_config.connect_timeout = connect;
+        self.client_config.read_timeout = read;
+        self.rebuild_client()
+    }
+
+    // Sends all requests through an http://, https:// or, with the socks feature, socks5:// proxy,
+    // instead of the one from the environment (HTTPS_PROXY, ALL_PROXY or their lowercase
+    // versions), if any.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn with_proxy(mut self, url: &str) -> Result<Self, Error> {
+        self.client_config.proxy = Some(Proxy::all(url)?);
+        self.rebuild_client()
+    }
+
+    // Also trusts the PEM encoded root certificate(s), e.g. those of a corporate proxy.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn with_root_certificates(mut self, pem: &[u8]) -> Result<Self, Error> {
+        self.client_config
+            .root_certificates
+            .extend(Certificate::from_pem_bundle(pem)?);
+        self.rebuild_client()
+    }
+
+    // Keeps up to this many idle connections to the API open, for as long as the timeout; unlimited
+    // and 90s by default.
+    pub fn with_connection_pool(
+        mut self,
+        max_idle: usize,
+        idle_timeout: Duration,
+    ) -> Result<Self, Error> {
+        self.client_config.pool_max_idle = Some(max_idle);
+        self.client_config.pool_idle_timeout = Some(idle_timeout);
+        self.rebuild_client()
+    }
+
+    // Sends TCP keep-alive probes on idle connections this often, so that NATs and proxies don't
+    // drop them; off by default.
+    pub fn with_tcp_keepalive(mut self, interval: Duration) -> Result<Self, Error> {
+        self.client_config.tcp_keepalive = Some(interval);
+        self.rebuild_client()
+    }
+
+    // HttpVersion::Auto by default.
+    pub fn with_http_version(mut self, version: HttpVersion) -> Result<Self, Error> {
+        self.client_config.http_version = version;
+        self.rebuild_client()
+    }
+
+    // Asks for gzip or deflate compressed responses, and brotli or zstd with the
+    // response-compression feature; on by default. Turning it off trades bandwidth for CPU time.
+    pub fn with_response_compression(mut self, enabled: bool) -> Result<Self, Error> {
+        self.client_config.response_compression = enabled;
+        self.rebuild_client()
+    }
+
+    // Paces API requests to at most this many per minute, 60 by default.
+    pub fn with_rate_limit(mut self, per_minute: u32) -> Self {
+        self.limiter = RateLimiter::new(per_minute);
+        self
+    }
+
+    // Stops with QuotaExhausted before making more requests than this per UTC day, counting
+    // those of earlier runs too; 10,000 by default, zero for no limit.
+    pub fn with_daily_quota(mut self, requests: u32) -> Self {
+        self.quota = DailyQuota::new(requests);
+        self
+    }
+
+    // Tries requests failing with a server error or a connection problem this many times, five by
+    // default, waiting longer and longer in between.
+    pub fn with_attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    // Pauses all requests for the cool-down after this many failed attempts in a row, three and
+    // five minutes by default, then probes with a single one; zero failures to never pause.
+    pub fn with_circuit_breaker(mut self, failures: u32, cool_down: Duration) -> Self {
+        self.breaker = CircuitBreaker::new(failures, cool_down);
+        self
+    }
+
+    // Keeps API responses in .http-cache in the data directory, to answer repeated requests while
+    // fresh and revalidate them after; on by default, when built with the http-cache feature.
+    pub fn with_http_cache(self, enabled: bool) -> Self {
+        self.with_cache_policy(match enabled {
+            true => CachePolicy::Enabled,
+            _ => CachePolicy::Disabled,
         })
     }
 
-    pub async fn sync_all(&self, username: &str) -> Result<(), Error> {
-        create_dir_all(self.path("users"))?;
+    pub fn with_cache_policy(mut self, policy: CachePolicy) -> Self {
+        #[cfg(feature = "http-cache")]
+        {
+            self.http_cache =
+                (policy != CachePolicy::Disabled).then(|| HttpCache::new(&self.data_dir));
+        }
+        self.cache_policy = policy;
+        self
+    }
 
-        let user_id = self.sync_user(username).await?;
-        self.sync_user_observations(user_id).await?;
+    // Sent with every request instead of inat/{version}.
+    pub fn with_user_agent(mut self, user_agent: &str) -> Result<Self, Error> {
+        self.client_config.user_agent = user_agent.to_string();
+        self.rebuild_client()
+    }
 
-        Ok(())
+    // Also keeps every response as received in .raw in the data directory, compressed, to replay
+    // with Archive::replay_raw; off by default.
+    #[cfg(feature = "raw-archive")]
+    pub fn with_raw_archive(mut self, enabled: bool) -> Self {
+        self.raw_archive = enabled.then(|| RawArchive::new(&self.data_dir));
+        self
+    }
+
+    // Installed after any others, see Middleware.
+    pub fn with_middleware<M: Middleware + 'static>(mut self, middleware: M) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    // Also writes every response of the API to a cassette, to replay with Cassette instead of
+    // asking the API again; responses answered by middleware aren't recorded.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn with_recording<P: AsRef<Path>>(mut self, path: P) -> Result<Self, Error> {
+        self.recorder = Some(Recorder::create(path.as_ref())?);
+        Ok(self)
+    }
+
+    // Fails requests right away instead of sending them, leaving the HTTP cache and middleware
+    // (e.g. a Cassette) to answer what they can; off by default.
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    pub(crate) fn ensure_online(&self, url: &Url) -> Result<(), Error> {
+        match self.offline {
+            true => Err(Error::Offline(url.to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    // ApiVersion::V1 by default. A base URL ending in the other version's /v1 or /v2 is switched
+    // over; others are kept as they are.
+    pub fn with_api_version(mut self, version: ApiVersion) -> Self {
+        let (from, to) = match version {
+            ApiVersion::V1 => ("/v2", "/v1"),
+            ApiVersion::V2 => ("/v1", "/v2"),
+        };
+        if let Some(base) = self.base_url.path().strip_suffix(from) {
+            let path = format!("{}{}", base, to);
+            self.base_url.set_path(&path);
+        }
+        self.api_version = version;
+        self
+    }
+
+    // Also asks v2 for these fields of observations, e.g. those an extractor of one's own reads.
+    pub fn with_observation_fields(mut self, fields: Fields) -> Self {
+        self.observation_fields = self.observation_fields.merge(fields);
+        self
+    }
+
+    pub(crate) fn api_version(&self) -> ApiVersion {
+        self.api_version
+    }
+
+    // Asks v2 for only these fields; v1 sends all of them anyway.
+    pub(crate) fn select_fields(&self, url: &mut Url, fields: impl FnOnce() -> Fields) {
+        if self.api_version != ApiVersion::V2 {
+            return;
+        }
+        // Not form encoded: the syntax only uses characters allowed in queries, and escaping them
+        // would triple the length of URLs that are long already.
+        let fields = format!("fields={}", fields());
+        let query = match url.query() {
+            Some(query) if !query.is_empty() => format!("{}&{}", query, fields),
+            _ => fields,
+        };
+        url.set_query(Some(&query));
+    }
+
+    // Reports what the syncs are up to, instead of only logging it.
+    pub fn with_progress<P: SyncProgress + 'static>(mut self, progress: P) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    // Run after any others, see TableExtractor.
+    pub fn with_extractor<E: TableExtractor + 'static>(mut self, extractor: E) -> Self {
+        self.extractors.push(Arc::new(extractor));
+        self
+    }
+
+    // A client for files off the API, e.g. photos: the same proxy, certificates and timeouts,
+    // but without the token, which is none of their hosts' business.
+    #[cfg(feature = "export")]
+    pub(crate) fn download_client(&self) -> Result<Client, Error> {
+        client(&ClientConfig {
+            token: None,
+            ..self.client_config.clone()
+        })
+    }
+
+    // Downloads a file with such a client, rate limited, counted and retried like API requests.
+    #[cfg(feature = "export")]
+    pub(crate) async fn download(&self, client: &Client, url: &str) -> Result<Bytes, Error> {
+        let req = client.get(url).header(ACCEPT, "*/*");
+        match self.fetch_with_retries(req, false).await? {
+            Fetched::Modified(_, body, _) => Ok(body),
+            _ => Err(unexpected_response(
+                Url::parse(url).ok().as_ref(),
+                "not modified",
+            )),
+        }
+    }
+
+    // Writes to the store upload to the storage too, if there is one, which blocks: they run off
+    // the async runtime.
+    pub(crate) async fn blocking<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Store, &[Arc<dyn TableExtractor>]) -> Result<T, Error> + Send + 'static,
+    {
+        let store = self.store.clone();
+        let extractors = self.extractors.clone();
+        spawn_blocking(move || f(&store, &extractors)).await?
+    }
+
+    pub(crate) fn report(&self, f: impl FnOnce(&dyn SyncProgress)) {
+        if let Some(progress) = &self.progress {
+            f(progress.as_ref());
+        }
+    }
+
+    fn rebuild_client(mut self) -> Result<Self, Error> {
+        self.client = client(&self.client_config)?;
+
+        Ok(self)
+    }
+
+    pub fn archive(&self) -> Archive {
+        Archive {
+            data_dir: self.data_dir.clone(),
+            store: self.store.clone(),
+            extractors: self.extractors.clone(),
+        }
+    }
+
+    // Fetches records by ID from endpoints like /observations/{id,id,...}.
+    pub(crate) async fn fetch_ids(
+        &self,
+        path: &str,
+        ids: &[u64],
+    ) -> Result<(YamlMapping, HashMap<u64, JsonMap<String, JsonValue>>), Error> {
+        let (mut header, records) = self
+            .fetch_ids_if_changed(path, ids, None)
+            .await?
+            .ok_or_else(|| {
+                unexpected_response(Some(&self.ids_endpoint(path, ids)), "no response")
+            })?;
+
+        // The header can be used for each individual item.
+        // But the etag doesn't match single items, so remove it.
+        header.remove(YamlValue::String(ETAG.to_string()));
+
+        Ok((header, records))
+    }
+
+    // Like fetch_ids, but None if nothing changed since the validator was taken. The header keeps
+    // the etag of the whole chunk.
+    pub(crate) async fn fetch_ids_if_changed(
+        &self,
+        path: &str,
+        ids: &[u64],
+        validator: Option<&Validator>,
+    ) -> Result<Option<(YamlMapping, HashMap<u64, JsonMap<String, JsonValue>>)>, Error> {
+        let mut req = self.client.get(self.ids_endpoint(path, ids));
+        if let Some(validator) = validator {
+            req = req.header(IF_MODIFIED_SINCE, fmt_http_date(validator.date.into()));
+            if let Some(etag) = &validator.etag {
+                req = req.header(IF_NONE_MATCH, etag);
+            }
+        }
+
+        let (header, res) = match self.fetch(req).await? {
+            Some(val) => val,
+            _ => return Ok(None),
+        };
+        let records = expect_results(res)?
+            .into_iter()
+            .map(|obj| extract_id(&obj).map(|id| (id, obj)))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Some((header, records)))
     }
 
     pub(crate) fn path(&self, sub: &str) -> PathBuf {
@@ -102,46 +581,588 @@ impl Api {
         url.set_path(&format!("{}{}", url.path(), path));
         url
     }
-}
 
-pub(crate) async fn fetch(
-    req: RequestBuilder,
-) -> Result<Option<(YamlMapping, ApiResponse)>, Error> {
-    let res = loop {
-        let res = req
+    pub(crate) fn ids_endpoint(&self, path: &str, ids: &[u64]) -> Url {
+        let mut url = self.endpoint(&format!(
+            "{}/{}",
+            path,
+            ids.iter().map(|id| id.to_string()).join(",")
+        ));
+        if let Some(fields) = fields::by_path(path) {
+            self.select_fields(&mut url, || match path {
+                "/observations" => fields().merge(self.observation_fields.clone()),
+                _ => fields(),
+            });
+        }
+        url
+    }
+
+    pub(crate) async fn fetch(
+        &self,
+        req: RequestBuilder,
+    ) -> Result<Option<(YamlMapping, ApiResponse)>, Error> {
+        let url = req
             .try_clone()
             .ok_or(internal("request not cloneable"))?
-            .send()
-            .await?;
-        if res.status().is_success() {
-            break res;
+            .build()?
+            .url()
+            .clone();
+        Ok(match self.fetch_raw(req).await? {
+            Some((header, body)) => Some((
+                header,
+                ApiResponse {
+                    url: Some(url),
+                    ..parse_response(&body)?
+                },
+            )),
+            _ => None,
+        })
+    }
+
+    // The same request made again while in flight, e.g. concurrently, waits for the first one.
+    pub(crate) async fn fetch_raw(
+        &self,
+        req: RequestBuilder,
+    ) -> Result<Option<(YamlMapping, Bytes)>, Error> {
+        let built = req
+            .try_clone()
+            .ok_or(internal("request not cloneable"))?
+            .build()?;
+        let res = match built.method() {
+            &Method::GET => {
+                let key = format!("{} {:?}", built.url(), built.headers());
+                // Requests with their own validators are answered by the API.
+                let conditional = built.headers().contains_key(IF_MODIFIED_SINCE)
+                    || built.headers().contains_key(IF_NONE_MATCH);
+                self.in_flight
+                    .run(key, self.fetch_cached(req, built.url(), conditional))
+                    .await?
+            }
+            _ => match self.fetch_with_retries(req, true).await? {
+                Fetched::Modified(header, body, _) => Some((header, body)),
+                _ => None,
+            },
+        };
+        if res.is_some() {
+            self.report(|progress| progress.page_fetched(built.url()));
         }
 
-        match res.status() {
-            StatusCode::NOT_MODIFIED => return Ok(None), // cache hit
-            StatusCode::TOO_MANY_REQUESTS => {
-                let retry_after = match res.headers().get(RETRY_AFTER) {
-                    Some(val) => Duration::from_secs(
-                        val.to_str()
-                            .map_err(|err| Error::BadHeaderCoding(RETRY_AFTER, err))?
-                            .parse()
-                            .map_err(|err| Error::BadIntFormat(RETRY_AFTER, err))?,
-                    ),
-                    _ => DEFAULT_RETRY_AFTER,
-                };
-                debug!("TOO MANY REQUESTS: sleeping for {}s", retry_after.as_secs());
-                sleep(retry_after);
+        Ok(res)
+    }
+
+    #[cfg(feature = "http-cache")]
+    async fn fetch_cached(
+        &self,
+        mut req: RequestBuilder,
+        url: &Url,
+        conditional: bool,
+    ) -> Result<Option<(YamlMapping, Bytes)>, Error> {
+        let token = self.client_config.token.as_deref();
+        let cached = match &self.http_cache {
+            Some(cache) if !conditional => cache.get(url, token)?,
+            _ => None,
+        };
+        if let Some(entry) = &cached {
+            if entry.is_fresh(self.store.now()) && self.cache_policy != CachePolicy::Revalidate {
+                debug!("fresh in the HTTP cache: {}", url);
+                self.metrics.cache_hit();
+                self.report(|progress| progress.cache_hit(url));
+                return Ok(Some((entry.header.clone(), entry.body.clone())));
+            }
+            if let Some(date) = entry.date() {
+                req = req.header(IF_MODIFIED_SINCE, fmt_http_date(date.into()));
+            }
+            if let Some(etag) = entry.etag() {
+                req = req.header(IF_NONE_MATCH, etag);
+            }
+        }
+
+        match (
+            self.fetch_with_retries(req, true).await?,
+            cached,
+            &self.http_cache,
+        ) {
+            (Fetched::Modified(header, body, cc), _, cache) => {
+                self.archive_raw(url, &header, &body)?;
+                if let Some(cache) = cache {
+                    cache.put(url, token, &header, &cc, &body)?;
+                }
+                Ok(Some((header, body)))
+            }
+            (Fetched::NotModified(header, cc), Some(entry), Some(cache)) => {
+                self.metrics.cache_hit();
+                self.report(|progress| progress.cache_hit(url));
+                let entry = cache.refresh(url, token, entry, header, &cc)?;
+                Ok(Some((entry.header, entry.body)))
+            }
+            (Fetched::NotModified(..), ..) => {
+                self.metrics.cache_hit();
+                self.report(|progress| progress.cache_hit(url));
+                Ok(None)
+            }
+        }
+    }
+
+    // Without the HTTP cache, only requests made conditional by the caller come back unmodified.
+    #[cfg(not(feature = "http-cache"))]
+    async fn fetch_cached(
+        &self,
+        req: RequestBuilder,
+        url: &Url,
+        _conditional: bool,
+    ) -> Result<Option<(YamlMapping, Bytes)>, Error> {
+        match self.fetch_with_retries(req, true).await? {
+            Fetched::Modified(header, body, _) => {
+                self.archive_raw(url, &header, &body)?;
+                Ok(Some((header, body)))
+            }
+            Fetched::NotModified(..) => {
+                self.metrics.cache_hit();
+                self.report(|progress| progress.cache_hit(url));
+                Ok(None)
+            }
+        }
+    }
+
+    fn archive_raw(&self, url: &Url, header: &YamlMapping, body: &Bytes) -> Result<(), Error> {
+        #[cfg(feature = "raw-archive")]
+        if let Some(raw) = &self.raw_archive {
+            raw.put(url, header, body, self.store.now())?;
+        }
+        #[cfg(not(feature = "raw-archive"))]
+        let _ = (url, header, body);
+
+        Ok(())
+    }
+
+    // Files off the API, e.g. photos, come in whatever type they are, possibly without a date.
+    async fn fetch_with_retries(&self, req: RequestBuilder, api: bool) -> Result<Fetched, Error> {
+        // The breaker is there for the API, not for the hosts of the files.
+        let breaker = |f: fn(&CircuitBreaker)| {
+            if api {
+                f(&self.breaker)
+            }
+        };
+        let mut attempt = 0;
+        loop {
+            self.quota.count(&self.store)?;
+            self.limiter.acquire().await;
+            attempt += 1;
+            let retry = attempt < self.attempts;
+            // Ends the probe, if it was one, should this attempt end without an outcome.
+            let _entered = match api {
+                true => Some(self.breaker.enter().await),
+                _ => None,
+            };
+            self.metrics.request();
+            let res = match self
+                .send(req.try_clone().ok_or(internal("request not cloneable"))?)
+                .await?
+            {
+                Ok(res) => res,
+                Err(err) if is_transient(&err) => {
+                    breaker(CircuitBreaker::failure);
+                    if !retry {
+                        return Err(err.into());
+                    }
+                    backoff(attempt, &err.to_string(), self.store.is_deterministic()).await;
+                    continue;
+                }
+                Err(err) => {
+                    // Not the API's fault.
+                    breaker(CircuitBreaker::success);
+                    return Err(err.into());
+                }
+            };
+            // Rate limited still means the API is up, the 429 is handled below.
+            match res.status().is_server_error() {
+                true => breaker(CircuitBreaker::failure),
+                _ => breaker(CircuitBreaker::success),
+            }
+
+            match res.status() {
+                status if status.is_success() => {
+                    let header = match api {
+                        true => {
+                            ensure_json(&res)?;
+                            extract_header(&res, self.store.settings())?
+                        }
+                        _ => extract_header(&res, self.store.settings()).unwrap_or_default(),
+                    };
+                    let cc = CacheControl::parse(res.headers());
+                    match res.bytes().await {
+                        Ok(body) => {
+                            self.metrics.received(body.len());
+                            return Ok(Fetched::Modified(header, body, cc));
+                        }
+                        Err(err) if is_transient(&err) => {
+                            breaker(CircuitBreaker::failure);
+                            if !retry {
+                                return Err(err.into());
+                            }
+                            backoff(attempt, &err.to_string(), self.store.is_deterministic()).await
+                        }
+                        Err(err) => return Err(err.into()),
+                    }
+                }
+                StatusCode::NOT_MODIFIED => {
+                    return Ok(Fetched::NotModified(
+                        extract_header(&res, self.store.settings()).ok(),
+                        CacheControl::parse(res.headers()),
+                    ))
+                }
+                StatusCode::TOO_MANY_REQUESTS => {
+                    let retry_after = match res.headers().get(RETRY_AFTER) {
+                        Some(val) => parse_retry_after(val)?,
+                        _ => DEFAULT_RETRY_AFTER,
+                    };
+                    if retry_after > MAX_RETRY_AFTER {
+                        return Err(Error::QuotaExhausted(self.store.now() + retry_after));
+                    }
+                    let wait = jitter(retry_after, self.store.is_deterministic());
+                    info!("rate limited, retrying in {:.1}s", wait.as_secs_f64());
+                    sleep(wait).await;
+                }
+                status if retry && status.is_server_error() => {
+                    backoff(attempt, &status.to_string(), self.store.is_deterministic()).await
+                }
+                _ => return Err(bad_status(res).await),
+            }
+        }
+    }
+
+    // Through the middleware, if any; the outer error is theirs, the inner one the client's.
+    async fn send(&self, req: RequestBuilder) -> Result<Result<Response, reqwest::Error>, Error> {
+        #[cfg(not(target_arch = "wasm32"))]
+        let recording = self.recorder.is_some();
+        #[cfg(target_arch = "wasm32")]
+        let recording = false;
+        if self.middleware.is_empty() && !recording && !self.offline {
+            return Ok(req.send().await);
+        }
+
+        // Executed by the client that built it, e.g. that of Api::download.
+        let (client, req) = req.build_split();
+        let mut req = req?;
+        let method = req.method().clone();
+        let mut answered = None;
+        for (i, middleware) in self.middleware.iter().enumerate() {
+            if let Some(res) = middleware.request(&mut req)? {
+                answered = Some((i, res));
+                break;
             }
-            _ => return Err(bad_status(res).await),
         }
+        let (seen, res) = match answered {
+            Some((i, res)) => (i + 1, res),
+            _ => {
+                self.ensure_online(req.url())?;
+                match self.record(&method, client.execute(req).await).await? {
+                    Ok(res) => (self.middleware.len(), res),
+                    Err(err) => return Ok(Err(err)),
+                }
+            }
+        };
+        for middleware in self.middleware[..seen].iter().rev() {
+            middleware.response(&res)?;
+        }
+
+        Ok(Ok(res))
+    }
+
+    // Into the cassette, if recording; errors like in send.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn record(
+        &self,
+        method: &Method,
+        res: Result<Response, reqwest::Error>,
+    ) -> Result<Result<Response, reqwest::Error>, Error> {
+        match (res, &self.recorder) {
+            (Ok(res), Some(recorder)) => recorder.record(method, res).await,
+            (res, _) => Ok(res),
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    async fn record(
+        &self,
+        _: &Method,
+        res: Result<Response, reqwest::Error>,
+    ) -> Result<Result<Response, reqwest::Error>, Error> {
+        Ok(res)
+    }
+}
+
+impl Default for ApiBuilder {
+    fn default() -> Self {
+        Self {
+            base_url: DEFAULT_BASE_URL.to_string(),
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+            token: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            requests_per_minute: DEFAULT_REQUESTS_PER_MINUTE,
+            daily_quota: DEFAULT_DAILY_QUOTA,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            cache_policy: CachePolicy::default(),
+            #[cfg(feature = "replica")]
+            storage: None,
+            store: None,
+            clock: Arc::new(SystemClock),
+            deterministic: false,
+            durable: false,
+        }
+    }
+}
+
+impl ApiBuilder {
+    // https://api.inaturalist.org/v1 by default.
+    pub fn base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.to_string();
+        self
+    }
+
+    // ./data by default.
+    pub fn data_dir<P: AsRef<Path>>(mut self, data_dir: P) -> Self {
+        self.data_dir = data_dir.as_ref().to_path_buf();
+        self
+    }
+
+    // Like Api::with_token.
+    pub fn token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    // Like Api::with_timeouts.
+    pub fn timeouts(mut self, connect: Duration, read: Duration) -> Self {
+        self.connect_timeout = connect;
+        self.read_timeout = read;
+        self
+    }
+
+    // Like Api::with_rate_limit and Api::with_daily_quota.
+    pub fn rate_limits(mut self, per_minute: u32, per_day: u32) -> Self {
+        self.requests_per_minute = per_minute;
+        self.daily_quota = per_day;
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = user_agent.to_string();
+        self
+    }
+
+    pub fn cache_policy(mut self, policy: CachePolicy) -> Self {
+        self.cache_policy = policy;
+        self
+    }
+
+    // Keeps the data directory in sync with the storage: restored from there when built, and
+    // written through to it from then on. The data directory keeps a full copy, reads never go to
+    // the storage.
+    #[cfg(feature = "replica")]
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    // What time it is for everything written to the data directory.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    // Makes runs against the same responses write the same data directory: cache headers get the
+    // clock's time instead of the API's date, retries wait without jitter, sync stages run one
+    // after another, and summaries take no time. Best with a FixedClock. Off by default: jitter
+    // keeps concurrent clients from retrying all at once, and stages running concurrently sync
+    // faster.
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    // Makes all files written to the data directory hit the disk before they count as written, so
+    // that a power loss leaves either the old version or the new one, never a truncated file. Off
+    // by default: everything written can be fetched again, and fsyncs are slow on spinning disks.
+    pub fn durable(mut self, durable: bool) -> Self {
+        self.durable = durable;
+        self
+    }
+
+    // Shares the archive's store instead of opening the data directory again: its data directory,
+    // storage and settings replace the ones set here, and nothing is restored twice.
+    pub fn archive(mut self, archive: &Archive) -> Self {
+        self.data_dir = archive.data_dir.clone();
+        self.store = Some(archive.store.clone());
+        self
+    }
+
+    // Opens the store in the data directory, unless shared with an archive, but doesn't talk to
+    // the API yet. Restoring from the storage, if any, blocks.
+    pub fn build(self) -> Result<Api, Error> {
+        let client_config = ClientConfig {
+            token: self.token,
+            user_agent: self.user_agent,
+            connect_timeout: self.connect_timeout,
+            read_timeout: self.read_timeout,
+            #[cfg(not(target_arch = "wasm32"))]
+            proxy: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            root_certificates: Vec::new(),
+            pool_max_idle: None,
+            pool_idle_timeout: None,
+            tcp_keepalive: None,
+            http_version: HttpVersion::default(),
+            response_compression: true,
+        };
+        let data_dir = self.data_dir;
+        let settings = Settings {
+            clock: self.clock,
+            deterministic: self.deterministic,
+            durable: self.durable,
+        };
+        #[cfg(feature = "replica")]
+        let store = match (self.store, self.storage) {
+            (Some(store), _) => store,
+            (_, Some(storage)) => Arc::new(Store::open_with_storage(&data_dir, storage, settings)?),
+            _ => Arc::new(Store::open(&data_dir)?.with_settings(settings)),
+        };
+        #[cfg(not(feature = "replica"))]
+        let store = match self.store {
+            Some(store) => store,
+            _ => Arc::new(Store::open(&data_dir)?.with_settings(settings)),
+        };
+        Ok(Api {
+            client: client(&client_config)?,
+            client_config,
+            base_url: self.base_url.parse()?,
+            api_version: ApiVersion::default(),
+            observation_fields: Fields::default(),
+            store,
+            limiter: RateLimiter::new(self.requests_per_minute),
+            quota: DailyQuota::new(self.daily_quota),
+            attempts: DEFAULT_ATTEMPTS,
+            breaker: CircuitBreaker::new(DEFAULT_THRESHOLD, DEFAULT_COOL_DOWN),
+            in_flight: InFlight::new(),
+            #[cfg(feature = "http-cache")]
+            http_cache: (self.cache_policy != CachePolicy::Disabled)
+                .then(|| HttpCache::new(&data_dir)),
+            cache_policy: self.cache_policy,
+            #[cfg(feature = "raw-archive")]
+            raw_archive: None,
+            middleware: Vec::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            recorder: None,
+            offline: false,
+            progress: None,
+            extractors: Vec::new(),
+            metrics: Metrics::default(),
+            data_dir,
+        })
+    }
+}
+
+fn client(config: &ClientConfig) -> Result<Client, Error> {
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+    if let Some(token) = &config.token {
+        let mut val = HeaderValue::from_str(token).map_err(|_| Error::BadToken)?;
+        val.set_sensitive(true);
+        headers.insert(AUTHORIZATION, val);
+    }
+
+    let builder = Client::builder()
+        .default_headers(headers)
+        .user_agent(&config.user_agent);
+    #[cfg(not(target_arch = "wasm32"))]
+    let builder = connection(builder, config);
+
+    Ok(builder.build()?)
+}
+
+// How to connect, which is up to the browser's fetch on wasm32.
+#[cfg(not(target_arch = "wasm32"))]
+fn connection(builder: ClientBuilder, config: &ClientConfig) -> ClientBuilder {
+    let mut builder = builder
+        .https_only(true)
+        .connect_timeout(config.connect_timeout)
+        .read_timeout(config.read_timeout);
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(proxy.clone());
+    }
+    for cert in &config.root_certificates {
+        builder = builder.add_root_certificate(cert.clone());
+    }
+    if let Some(max_idle) = config.pool_max_idle {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(timeout) = config.pool_idle_timeout {
+        builder = builder.pool_idle_timeout(timeout);
+    }
+    builder = match config.http_version {
+        HttpVersion::Auto => builder,
+        HttpVersion::Http1 => builder.http1_only(),
+        HttpVersion::Http2 => builder.http2_prior_knowledge(),
     };
+    let compress = config.response_compression;
+    builder = builder
+        .tcp_keepalive(config.tcp_keepalive)
+        .gzip(compress)
+        .deflate(compress);
+    #[cfg(feature = "response-compression")]
+    {
+        builder = builder.brotli(compress).zstd(compress);
+    }
+
+    builder
+}
 
-    ensure_json(&res)?;
-    let header = extract_header(&res)?;
-    let api_res: ApiResponse = serde_json::from_slice(&res.bytes().await?)?;
+pub(crate) fn parse_response(body: &[u8]) -> Result<ApiResponse, Error> {
+    let api_res: ApiResponse = serde_json::from_slice(body)?;
     ensure_ok(&api_res)?;
 
-    Ok(Some((header, api_res)))
+    Ok(api_res)
+}
+
+// Waits twice as long after each failed attempt, up to MAX_BACKOFF.
+async fn backoff(attempt: u32, reason: &str, deterministic: bool) {
+    let wait = jitter(
+        MIN_BACKOFF
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(MAX_BACKOFF),
+        deterministic,
+    );
+    warn!("{}; retrying in {:.1}s", reason, wait.as_secs_f64());
+    sleep(wait).await;
+}
+
+fn jitter(wait: Duration, deterministic: bool) -> Duration {
+    match deterministic {
+        true => wait,
+        _ => wait + wait.mul_f64(random() * MAX_JITTER),
+    }
+}
+
+// Between 0 and 1, from the randomly keyed std hasher: good enough for jitter.
+fn random() -> f64 {
+    (RandomState::new().hash_one(()) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Retry-After is either a number of seconds or an HTTP date.
+fn parse_retry_after(val: &HeaderValue) -> Result<Duration, Error> {
+    let val = val
+        .to_str()
+        .map_err(|err| Error::BadHeaderCoding(RETRY_AFTER, err))?;
+    match val.parse() {
+        Ok(secs) => Ok(Duration::from_secs(secs)),
+        Err(err) => match parse_http_date(val) {
+            Ok(date) => Ok(date
+                .duration_since(SystemTime::now())
+                .unwrap_or(Duration::ZERO)),
+            _ => Err(Error::BadIntFormat(RETRY_AFTER, err)),
+        },
+    }
 }
 
 pub(crate) fn ensure_json(res: &Response) -> Result<(), Error> {
@@ -154,7 +1175,7 @@ pub(crate) fn ensure_json(res: &Response) -> Result<(), Error> {
         .trim()
         .to_lowercase();
     let parts: Vec<&str> = ct.split(';').map(|part| part.trim()).collect();
-    if (parts.len() > 0 && parts[0] != "application/json")
+    if (!parts.is_empty() && parts[0] != "application/json")
         || (parts.len() > 1 && parts[1] != "charset=utf-8")
     {
         return Err(Error::BadContentType(ct.to_string()));
@@ -182,7 +1203,7 @@ pub(crate) fn ensure_ok(res: &ApiResponse) -> Result<(), Error> {
     Ok(())
 }
 
-pub(crate) fn extract_header(res: &Response) -> Result<YamlMapping, Error> {
+pub(crate) fn extract_header(res: &Response, settings: &Settings) -> Result<YamlMapping, Error> {
     let mut header = YamlMapping::new();
 
     let mut ts: DateTime<Utc> = parse_http_date(
@@ -204,6 +1225,9 @@ pub(crate) fn extract_header(res: &Response) -> Result<YamlMapping, Error> {
         ts -= duration;
     }
 
+    if settings.deterministic {
+        ts = settings.clock.now();
+    }
     header.insert(
         YamlValue::String(DATE.to_string()),
         YamlValue::String(ts.to_rfc3339()),
@@ -253,12 +1277,33 @@ pub(crate) fn lookup_cache_ids(path: &Path) -> Result<Option<IDsCache>, Error> {
     })
 }
 
-fn lookup_cache(path: &Path) -> Result<Option<(CacheHeader, YamlDeserializer)>, Error> {
-    match File::open(path) {
+pub(crate) fn lookup_cache_data<T: DeserializeOwned>(
+    path: &Path,
+) -> Result<Option<(CacheHeader, T)>, Error> {
+    Ok(match lookup_cache(path)? {
+        Some((header, data)) => Some((header, T::deserialize(data)?)),
+        _ => None,
+    })
+}
+
+// Like lookup_cache_data, but keeps the header as-is for writing it back.
+pub(crate) fn lookup_cache_raw<T: DeserializeOwned>(
+    path: &Path,
+) -> Result<Option<(YamlMapping, T)>, Error> {
+    Ok(match lookup_cache(path)? {
+        Some((header, data)) => Some((header, T::deserialize(data)?)),
+        _ => None,
+    })
+}
+
+fn lookup_cache<H: DeserializeOwned>(
+    path: &Path,
+) -> Result<Option<(H, YamlDeserializer<'_>)>, Error> {
+    match open_cache(path) {
         Ok(f) => {
-            let mut des = serde_yaml::Deserializer::from_reader(BufReader::new(f));
+            let mut des = serde_yaml::Deserializer::from_reader(f);
             if let Some(chunk) = des.next() {
-                let header = CacheHeader::deserialize(chunk)?;
+                let header = H::deserialize(chunk)?;
                 match des.next() {
                     Some(data) => Ok(Some((header, data))),
                     _ => Err(corrupt_cache(path, "contains only one document")),
@@ -271,10 +1316,45 @@ fn lookup_cache(path: &Path) -> Result<Option<(CacheHeader, YamlDeserializer)>,
     }
 }
 
+// Cache files are read the same whether compressed or not, the plain one first.
+pub(crate) fn open_cache(path: &Path) -> Result<Box<dyn Read>, IoError> {
+    match File::open(path) {
+        Ok(f) => Ok(Box::new(BufReader::new(f))),
+        Err(err) => match File::open(compressed_path(path)) {
+            Ok(f) => decompress(f),
+            // Neither is there, report the plain one missing.
+            _ => Err(err),
+        },
+    }
+}
+
+#[cfg(feature = "compression")]
+pub(crate) fn decompress(f: File) -> Result<Box<dyn Read>, IoError> {
+    Ok(Box::new(zstd::Decoder::new(f)?))
+}
+
+#[cfg(not(feature = "compression"))]
+pub(crate) fn decompress(_: File) -> Result<Box<dyn Read>, IoError> {
+    Err(IoError::new(
+        std::io::ErrorKind::Unsupported,
+        "compressed, built without the compression feature",
+    ))
+}
+
+pub(crate) fn compressed_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".zst");
+    PathBuf::from(name)
+}
+
 macro_rules! expect_prop {
     ($res:expr, $field:ident) => {
-        $res.$field
-            .ok_or(internal(&format!("missing: {}", stringify!($field))))?
+        $res.$field.ok_or_else(|| {
+            unexpected_response(
+                $res.url.as_ref(),
+                &format!("missing {}", stringify!($field)),
+            )
+        })?
     };
 }
 
@@ -283,19 +1363,26 @@ pub(crate) fn is_last_page(res: &ApiResponse) -> Result<bool, Error> {
     let per_page = expect_prop!(res, per_page);
     let total_results = expect_prop!(res, total_results);
 
-    Ok((total_results + per_page - 1u64) / per_page <= page)
+    Ok(total_results.div_ceil(per_page) <= page)
+}
+
+pub(crate) fn total_results(res: &ApiResponse) -> Result<u64, Error> {
+    Ok(expect_prop!(res, total_results))
 }
 
 macro_rules! check_prop {
     ($res:expr, $field:ident, $expected:expr) => {
         if let Some(value) = $res.$field {
             if value != $expected {
-                return Err(internal(&format!(
-                    "expected {}: {}; got: {}",
-                    stringify!($field),
-                    $expected,
-                    value
-                )));
+                return Err(unexpected_response(
+                    $res.url.as_ref(),
+                    &format!(
+                        "expected {}: {}; got: {}",
+                        stringify!($field),
+                        $expected,
+                        value
+                    ),
+                ));
             }
         }
     };
@@ -306,9 +1393,10 @@ pub(crate) fn extract_single_value(res: ApiResponse) -> Result<JsonMap<String, J
     check_prop!(res, per_page, 1);
     check_prop!(res, total_results, 1);
 
+    let url = res.url.clone();
     Ok(expect_results(res)?
-        .get(0)
-        .ok_or(internal("empty results array"))?
+        .first()
+        .ok_or_else(|| unexpected_response(url.as_ref(), "empty results"))?
         .clone())
 }
 
@@ -317,24 +1405,118 @@ pub(crate) fn extract_ids(res: ApiResponse) -> Result<Vec<u64>, Error> {
 }
 
 pub(crate) fn extract_id(obj: &JsonMap<String, JsonValue>) -> Result<u64, Error> {
-    obj.get(ID)
-        .ok_or(internal("missing id"))
-        .and_then(|val| val.as_u64().ok_or(internal("id is not u64")))
+    match obj.get(ID) {
+        Some(val) => val.as_u64().ok_or_else(|| bad_record(None, ID, "not u64")),
+        _ => Err(bad_record(None, ID, "missing")),
+    }
 }
 
 pub(crate) fn expect_results(res: ApiResponse) -> Result<Vec<JsonMap<String, JsonValue>>, Error> {
-    res.results.ok_or(internal("no results"))
+    res.results
+        .ok_or_else(|| unexpected_response(res.url.as_ref(), "no results"))
 }
 
 pub(crate) fn write_cache<H: Serialize, D: Serialize>(
     path: &Path,
     header: &H,
     data: &D,
+    durable: bool,
+) -> Result<(), Error> {
+    durable::write_file(path, durable, |out| write_documents(out, header, data))
+}
+
+fn write_documents<W: Write, H: Serialize, D: Serialize>(
+    mut out: W,
+    header: &H,
+    data: &D,
 ) -> Result<(), Error> {
-    let file = File::create(path)?;
-    serde_yaml::to_writer(&file, header)?;
-    writeln!(&file, "---")?;
-    serde_yaml::to_writer(&file, &data)?;
+    serde_yaml::to_writer(&mut out, &canonical(header)?)?;
+    writeln!(out, "---")?;
+    serde_yaml::to_writer(&mut out, &canonical(data)?)?;
 
     Ok(())
 }
+
+// Same content, same bytes: mappings are written with their keys sorted, whatever order they
+// were built or received in, to keep diffs of the data dir down to actual changes.
+pub(crate) fn canonical<T: Serialize>(data: &T) -> Result<YamlValue, Error> {
+    Ok(sort_keys(serde_yaml::to_value(data)?))
+}
+
+fn sort_keys(val: YamlValue) -> YamlValue {
+    match val {
+        YamlValue::Mapping(map) => {
+            let mut entries: Vec<_> = map.into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| match (a.as_str(), b.as_str()) {
+                (Some(a), Some(b)) => a.cmp(b),
+                _ => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+            });
+            YamlValue::Mapping(
+                entries
+                    .into_iter()
+                    .map(|(key, val)| (key, sort_keys(val)))
+                    .collect(),
+            )
+        }
+        YamlValue::Sequence(seq) => YamlValue::Sequence(seq.into_iter().map(sort_keys).collect()),
+        val => val,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::cassette::{Cassette, Interaction};
+
+    const URL: &str = "https://api.inaturalist.org/v1/users/42";
+
+    fn answer(status: u16, headers: &[(&str, &str)], body: &str) -> Interaction {
+        Interaction {
+            method: "GET".to_string(),
+            url: URL.to_string(),
+            status,
+            headers: headers
+                .iter()
+                .map(|(name, val)| (name.to_string(), val.to_string()))
+                .collect::<BTreeMap<_, _>>(),
+            body: body.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limited_probes_close_the_breaker() {
+        let dir = tempdir().expect("tempdir");
+        // The failure opens the breaker, the probe is rate limited, the retry goes through.
+        let api = Api::builder()
+            .data_dir(dir.path())
+            .build()
+            .expect("api")
+            .with_http_cache(false)
+            .with_circuit_breaker(1, Duration::from_millis(10))
+            .with_middleware(Cassette::new([
+                answer(500, &[], ""),
+                answer(429, &[("retry-after", "0")], ""),
+                answer(
+                    200,
+                    &[
+                        ("content-type", "application/json; charset=utf-8"),
+                        ("date", "Wed, 14 Oct 2026 07:09:22 GMT"),
+                    ],
+                    r#"{"total_results": 1, "page": 1, "per_page": 1, "results": [{"id": 42}]}"#,
+                ),
+            ]));
+
+        let url = Url::parse(URL).expect("url");
+        let fetched = tokio::time::timeout(
+            Duration::from_secs(10),
+            api.fetch(api.client.get(url.clone())),
+        )
+        .await
+        .expect("stuck probing");
+        assert!(fetched.expect("fetch").is_some());
+    }
+}
@@ -0,0 +1,91 @@
+use reqwest::{header::AUTHORIZATION, Url};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+use crate::{
+    api::{extract_single_value, Api},
+    error::{bad_status, unexpected_response, Error},
+    fields::Fields,
+};
+
+#[derive(Debug, Deserialize)]
+struct OauthToken {
+    access_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiToken {
+    api_token: String,
+}
+
+impl Api {
+    // Login of the user the API token belongs to, failing if it's rejected.
+    pub async fn me(&self) -> Result<String, Error> {
+        let mut url = self.endpoint("/users/me");
+        self.select_fields(&mut url, || Fields::new(["id", "login"]));
+        let (_, res) = self
+            .fetch(self.client.get(url.clone()))
+            .await?
+            .ok_or_else(|| unexpected_response(Some(&url), "no response"))?;
+
+        match extract_single_value(res)?.get("login") {
+            Some(JsonValue::String(login)) => Ok(login.clone()),
+            _ => Err(unexpected_response(Some(&url), "no login")),
+        }
+    }
+
+    // Resource owner password flow of an OAuth application registered on the site, e.g.
+    // https://www.inaturalist.org; the access token does not expire like API tokens do.
+    pub async fn oauth_token(
+        &self,
+        site: &str,
+        client_id: &str,
+        client_secret: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<String, Error> {
+        let url = site_url(site, "/oauth/token")?;
+        self.ensure_online(&url)?;
+        let res = self
+            .client
+            .post(url)
+            .form(&[
+                ("grant_type", "password"),
+                ("client_id", client_id),
+                ("client_secret", client_secret),
+                ("username", username),
+                ("password", password),
+            ])
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(bad_status(res).await);
+        }
+
+        Ok(serde_json::from_slice::<OauthToken>(&res.bytes().await?)?.access_token)
+    }
+
+    // Exchanges an OAuth access token for an API token, valid for 24 hours.
+    pub async fn api_token(&self, site: &str, access_token: &str) -> Result<String, Error> {
+        let url = site_url(site, "/users/api_token")?;
+        self.ensure_online(&url)?;
+        let res = self
+            .client
+            .get(url)
+            .header(AUTHORIZATION, format!("Bearer {}", access_token))
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(bad_status(res).await);
+        }
+
+        Ok(serde_json::from_slice::<ApiToken>(&res.bytes().await?)?.api_token)
+    }
+}
+
+fn site_url(site: &str, path: &str) -> Result<Url, Error> {
+    let mut url: Url = site.parse()?;
+    url.set_path(&format!("{}{}", url.path().trim_end_matches('/'), path));
+
+    Ok(url)
+}
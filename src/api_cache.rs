@@ -0,0 +1,45 @@
+use crate::{
+    api::{lookup_cache_raw, Api},
+    api_users::is_login,
+    archive::{str_field, Record},
+    error::Error,
+    models::{from_record, Model, Observation, User},
+};
+
+// Reading back what the syncs cached, typed, without talking to the API.
+impl Api {
+    pub fn load<T: Model>(&self, id: u64) -> Result<Option<T>, Error> {
+        self.store
+            .get(T::TABLE, id)?
+            .map(|(_, record)| from_record(record))
+            .transpose()
+    }
+
+    pub fn load_observation(&self, id: u64) -> Result<Option<Observation>, Error> {
+        self.load(id)
+    }
+
+    // The synced user by login, or any other user the observations brought along.
+    pub fn load_user(&self, login: &str) -> Result<Option<User>, Error> {
+        if !is_login(login) {
+            return Ok(None);
+        }
+        let path = self.path("users").join(format!("{}.yaml", login));
+        if let Some((_, user)) = lookup_cache_raw::<Record>(&path)? {
+            return from_record(user).map(Some);
+        }
+
+        self.store
+            .all(User::TABLE)?
+            .into_values()
+            .find(|user| str_field(user, "login") == Some(login))
+            .map(from_record)
+            .transpose()
+    }
+
+    // All cached records of the model's table, in ID order, e.g. api.iter_table::<Taxon>().
+    // The table is read into memory up front, only the typing happens as it goes.
+    pub fn iter_table<T: Model>(&self) -> Result<impl Iterator<Item = Result<T, Error>>, Error> {
+        Ok(self.store.all(T::TABLE)?.into_values().map(from_record))
+    }
+}
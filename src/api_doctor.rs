@@ -0,0 +1,363 @@
+use std::{
+    fs::{metadata, read_dir, remove_file, File},
+    io::{self, ErrorKind, Write},
+    path::Path,
+    time::{Instant, SystemTime},
+};
+
+use chrono::{DateTime, Utc};
+#[cfg(feature = "locking")]
+use fs2::available_space;
+use httpdate::parse_http_date;
+use reqwest::{
+    header::{AUTHORIZATION, DATE},
+    StatusCode,
+};
+use serde::Serialize;
+
+use crate::{
+    api::{Api, ApiVersion},
+    error::Error,
+    query::write_rows,
+};
+
+// NOTE: Cache headers store the server's date, but retries, checkpoints and updated_since use
+// the local clock.
+const MAX_CLOCK_SKEW: i64 = 60;
+
+#[cfg(feature = "locking")]
+const MIN_FREE_SPACE: u64 = 1 << 30;
+
+const TOKEN_URL: &str = "https://www.inaturalist.org/users/api_token";
+
+#[derive(Debug, Default, Serialize)]
+#[non_exhaustive]
+pub struct DoctorReport {
+    pub checks: Vec<Check>,
+}
+
+#[derive(Debug, Serialize)]
+#[non_exhaustive]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub message: String,
+    // What to do about it, unless everything is fine.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fix: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum CheckStatus {
+    Ok,
+    Skipped,
+    Warning,
+    Failed,
+}
+
+impl Api {
+    // Checks everything a sync depends on; problems end up in the report rather than as errors.
+    pub async fn doctor(&self, token: Option<&str>) -> DoctorReport {
+        let mut report = DoctorReport::default();
+        let server_date = self.check_api(&mut report).await;
+        report.checks.push(check_clock(server_date));
+        report.checks.push(self.check_token(token).await);
+        report.checks.push(self.check_data_dir());
+        report.checks.push(self.check_symlinks());
+        report.checks.push(self.check_disk_space());
+
+        report
+    }
+
+    async fn check_api(&self, report: &mut DoctorReport) -> Option<DateTime<Utc>> {
+        let base = self.endpoint("");
+        if self.ensure_online(&base).is_err() {
+            report
+                .checks
+                .push(check("api", CheckStatus::Skipped, "offline", None));
+            return None;
+        }
+        let mut url = self.endpoint("/observations");
+        url.query_pairs_mut().append_pair("per_page", "0");
+        let start = Instant::now();
+        let (status, message, fix, date) = match self.client.get(url).send().await {
+            Ok(res) => {
+                let date = res
+                    .headers()
+                    .get(DATE)
+                    .and_then(|date| date.to_str().ok())
+                    .and_then(|date| parse_http_date(date).ok())
+                    .map(DateTime::<Utc>::from);
+                let elapsed = start.elapsed().as_millis();
+                match res.status() {
+                    status if status.is_success() => (
+                        CheckStatus::Ok,
+                        format!("{} reachable ({} ms)", base, elapsed),
+                        None,
+                        date,
+                    ),
+                    StatusCode::TOO_MANY_REQUESTS => (
+                        CheckStatus::Warning,
+                        format!("{} is rate limiting requests", base),
+                        Some("wait a few minutes before syncing again".to_string()),
+                        date,
+                    ),
+                    status => (
+                        CheckStatus::Failed,
+                        format!("{} returned {}", base, status),
+                        Some(format!(
+                            "check the --endpoint, it should end in /{}",
+                            match self.api_version() {
+                                ApiVersion::V1 => "v1",
+                                _ => "v2",
+                            }
+                        )),
+                        date,
+                    ),
+                }
+            }
+            Err(err) => (
+                CheckStatus::Failed,
+                format!("{} unreachable: {}", base, err),
+                Some("check the network connection and any proxy settings".to_string()),
+                None,
+            ),
+        };
+        report.checks.push(Check {
+            name: "api",
+            status,
+            message,
+            fix,
+        });
+
+        date
+    }
+
+    async fn check_token(&self, token: Option<&str>) -> Check {
+        let token = match token {
+            Some(token) => token,
+            _ => return check("token", CheckStatus::Skipped, "no API token given", None),
+        };
+        if self.ensure_online(&self.endpoint("/users/me")).is_err() {
+            return check("token", CheckStatus::Skipped, "offline", None);
+        }
+
+        let req = self
+            .client
+            .get(self.endpoint("/users/me"))
+            .header(AUTHORIZATION, token);
+        match req.send().await.map(|res| res.status()) {
+            Ok(status) if status.is_success() => {
+                check("token", CheckStatus::Ok, "API token accepted", None)
+            }
+            Ok(StatusCode::UNAUTHORIZED) => check(
+                "token",
+                CheckStatus::Failed,
+                "API token rejected, it expires after 24 hours",
+                Some(format!("get a new one from {}", TOKEN_URL)),
+            ),
+            Ok(status) => check(
+                "token",
+                CheckStatus::Warning,
+                &format!("could not check the API token: {}", status),
+                None,
+            ),
+            Err(err) => check(
+                "token",
+                CheckStatus::Warning,
+                &format!("could not check the API token: {}", err),
+                None,
+            ),
+        }
+    }
+
+    fn check_data_dir(&self) -> Check {
+        let dir = self.data_dir.display();
+        match metadata(&self.data_dir) {
+            Ok(md) if !md.is_dir() => check(
+                "data",
+                CheckStatus::Failed,
+                &format!("{} is not a directory", dir),
+                Some("pass another --data directory".to_string()),
+            ),
+            Ok(_) => match writable(&self.data_dir) {
+                Ok(_) => check(
+                    "data",
+                    CheckStatus::Ok,
+                    &format!("{} is writable", dir),
+                    None,
+                ),
+                Err(err) => check(
+                    "data",
+                    CheckStatus::Failed,
+                    &format!("{} is not writable: {}", dir, err),
+                    Some(format!("fix the permissions of {}", dir)),
+                ),
+            },
+            Err(err) if err.kind() == ErrorKind::NotFound => check(
+                "data",
+                CheckStatus::Warning,
+                &format!("{} does not exist yet", dir),
+                Some("it gets created by the first sync".to_string()),
+            ),
+            Err(err) => check(
+                "data",
+                CheckStatus::Failed,
+                &format!("{}: {}", dir, err),
+                Some(format!("fix the permissions of {}", dir)),
+            ),
+        }
+    }
+
+    // Logins are symlinked to user IDs; a dangling link makes the next sync refetch the user.
+    fn check_symlinks(&self) -> Check {
+        let files = match read_dir(self.path("users")) {
+            Ok(files) => files,
+            _ => return check("symlinks", CheckStatus::Skipped, "no users synced", None),
+        };
+
+        let mut links = 0;
+        let mut dangling = vec![];
+        for path in files.filter_map(|file| file.ok()).map(|file| file.path()) {
+            if path.is_symlink() {
+                links += 1;
+                if !path.exists() {
+                    dangling.push(path.display().to_string());
+                }
+            }
+        }
+        dangling.sort();
+
+        match dangling.is_empty() {
+            true => check(
+                "symlinks",
+                CheckStatus::Ok,
+                &format!("login symlinks: {}", links),
+                None,
+            ),
+            _ => check(
+                "symlinks",
+                CheckStatus::Failed,
+                &format!("dangling: {}", dangling.join(", ")),
+                Some("remove them and sync the users again".to_string()),
+            ),
+        }
+    }
+
+    #[cfg(feature = "locking")]
+    fn check_disk_space(&self) -> Check {
+        let dir = self
+            .data_dir
+            .ancestors()
+            .find(|dir| dir.exists())
+            .unwrap_or(&self.data_dir);
+        match available_space(dir) {
+            Ok(free) if free < MIN_FREE_SPACE => check(
+                "disk",
+                CheckStatus::Warning,
+                &format!("{} MiB free", free >> 20),
+                Some("free up some space, photos alone can take gigabytes".to_string()),
+            ),
+            Ok(free) => check(
+                "disk",
+                CheckStatus::Ok,
+                &format!("{} MiB free", free >> 20),
+                None,
+            ),
+            Err(err) => check(
+                "disk",
+                CheckStatus::Warning,
+                &format!("could not check free space: {}", err),
+                None,
+            ),
+        }
+    }
+
+    #[cfg(not(feature = "locking"))]
+    fn check_disk_space(&self) -> Check {
+        check(
+            "disk",
+            CheckStatus::Skipped,
+            "built without the locking feature",
+            None,
+        )
+    }
+}
+
+impl DoctorReport {
+    pub fn is_ok(&self) -> bool {
+        self.checks
+            .iter()
+            .all(|check| check.status != CheckStatus::Failed)
+    }
+
+    pub fn write_table<W: Write>(&self, out: &mut W) -> Result<(), Error> {
+        let mut rows = vec![];
+        for check in &self.checks {
+            let status = match check.status {
+                CheckStatus::Ok => "ok",
+                CheckStatus::Skipped => "skipped",
+                CheckStatus::Warning => "warning",
+                CheckStatus::Failed => "failed",
+            };
+            rows.push(vec![
+                status.to_string(),
+                check.name.to_string(),
+                check.message.to_string(),
+            ]);
+            if let Some(fix) = &check.fix {
+                rows.push(vec![String::new(), String::new(), format!("fix: {}", fix)]);
+            }
+        }
+
+        write_rows(out, &rows)
+    }
+}
+
+fn check_clock(server_date: Option<DateTime<Utc>>) -> Check {
+    let server_date = match server_date {
+        Some(date) => date,
+        _ => {
+            return check(
+                "clock",
+                CheckStatus::Skipped,
+                "no server date to compare with",
+                None,
+            )
+        }
+    };
+
+    let skew = (DateTime::<Utc>::from(SystemTime::now()) - server_date).num_seconds();
+    match skew.abs() > MAX_CLOCK_SKEW {
+        true => check(
+            "clock",
+            CheckStatus::Warning,
+            &format!("local clock is {}s off the server's", skew),
+            Some("enable time synchronisation, e.g. NTP".to_string()),
+        ),
+        _ => check(
+            "clock",
+            CheckStatus::Ok,
+            &format!("within {}s of the server", MAX_CLOCK_SKEW),
+            None,
+        ),
+    }
+}
+
+fn check(name: &'static str, status: CheckStatus, message: &str, fix: Option<String>) -> Check {
+    Check {
+        name,
+        status,
+        message: message.to_string(),
+        fix,
+    }
+}
+
+// Writes and removes a file in the directory.
+fn writable(dir: &Path) -> io::Result<()> {
+    let path = dir.join(".doctor");
+    File::create(&path)?.write_all(b"ok")?;
+    remove_file(path)
+}
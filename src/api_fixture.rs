@@ -0,0 +1,106 @@
+use std::{
+    collections::HashMap,
+    fs::{read_dir, File},
+    io::{copy, Seek, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use serde_json::{Map as JsonMap, Value as JsonValue};
+use tempfile::tempdir;
+use zip::{write::SimpleFileOptions, ZipWriter};
+
+use crate::{
+    api::{expect_results, extract_id, parse_response, Api},
+    error::{unexpected_response, Error},
+    normalise::Writer,
+    store::{Layout, Store},
+};
+
+const REDACTED: &str = "REDACTED";
+
+// Personal details on user objects, anything else is kept so the normaliser sees the same shape.
+const USER_FIELDS: [&str; 6] = ["description", "email", "icon", "icon_url", "login", "name"];
+
+// Exact locations and other owner-only fields on observations.
+const PRIVATE_FIELDS: [&str; 4] = [
+    "private_geojson",
+    "private_location",
+    "private_place_guess",
+    "private_place_ids",
+];
+
+impl Api {
+    pub async fn dump_fixture<W: Write + Seek>(&self, id: u64, out: W) -> Result<(), Error> {
+        let url = self.ids_endpoint("/observations", &[id]);
+        let (header, body) = self
+            .fetch_raw(self.client.get(url.clone()))
+            .await?
+            .ok_or_else(|| unexpected_response(Some(&url), "no response"))?;
+
+        let mut raw: JsonValue = serde_json::from_slice(&body)?;
+        redact(&mut raw, false);
+        let raw = serde_json::to_vec_pretty(&raw)?;
+
+        let observations = expect_results(parse_response(&raw)?)?
+            .into_iter()
+            .map(|obs| extract_id(&obs).map(|id| (id, obs)))
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        let dir = tempdir()?;
+        // Fixtures always use the directory layout, one file per record.
+        let store = Arc::new(Store::with_layout(dir.path(), Layout::Directory));
+        Writer::new(store).observations(header, observations)?;
+
+        let mut zip = ZipWriter::new(out);
+        let options = SimpleFileOptions::default();
+        zip.start_file("response.json", options)?;
+        zip.write_all(&raw)?;
+        add_dir(&mut zip, dir.path(), "normalised", options)?;
+        zip.finish()?;
+
+        Ok(())
+    }
+}
+
+fn redact(val: &mut JsonValue, is_user: bool) {
+    match val {
+        JsonValue::Object(obj) => redact_object(obj, is_user),
+        JsonValue::Array(arr) => arr.iter_mut().for_each(|item| redact(item, is_user)),
+        _ => {}
+    }
+}
+
+fn redact_object(obj: &mut JsonMap<String, JsonValue>, is_user: bool) {
+    for (key, val) in obj.iter_mut() {
+        let redacted = PRIVATE_FIELDS.contains(&key.as_str())
+            || (is_user && USER_FIELDS.contains(&key.as_str()))
+            || key == "user_login";
+        if redacted && !val.is_null() {
+            *val = REDACTED.into();
+        } else {
+            redact(val, key == "user" || key.ends_with("_user"));
+        }
+    }
+}
+
+fn add_dir<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    dir: &Path,
+    prefix: &str,
+    options: SimpleFileOptions,
+) -> Result<(), Error> {
+    let mut entries = read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = format!("{}/{}", prefix, entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            add_dir(zip, &entry.path(), &name, options)?;
+        } else {
+            zip.start_file(name, options)?;
+            copy(&mut File::open(entry.path())?, zip)?;
+        }
+    }
+
+    Ok(())
+}
@@ -1,57 +1,405 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::{Arc, Mutex},
+};
 
+use chrono::{DateTime, SubsecRound, Utc};
+use futures::{future::join, stream, Stream, StreamExt, TryStreamExt};
 use httpdate::fmt_http_date;
 use itertools::Itertools;
 use reqwest::header::{DATE, ETAG, IF_MODIFIED_SINCE};
+use serde_json::{Map as JsonMap, Value as JsonValue};
 use serde_yaml::{Mapping as YamlMapping, Value as YamlValue};
+use tokio::{select, sync::mpsc::channel};
+use tracing::debug;
+use url::Url;
 
 use crate::{
     api::{
-        expect_results, extract_id, extract_ids, fetch, is_last_page, lookup_cache_ids,
-        write_cache, Api, ID,
+        extract_ids, is_last_page, lookup_cache_ids, total_results, Api, ApiVersion, ID, LISTED_AT,
+        UPDATED_SINCE,
     },
-    error::{internal, Error},
-    normalise::Normaliser,
+    api_sync::SyncOptions,
+    checkpoint::Checkpoint,
+    chunks::{chunk_validator, id_chunks, Validator, Validators},
+    error::{unexpected_response, Error},
+    fields,
+    models::{from_record, Observation},
+    normalise::Writer,
 };
 
 // NOTE: Sometimes incorrectly documented as 500.
-const MAX_IDS_PER_PAGE: usize = 200;
+pub(crate) const MAX_IDS_PER_PAGE: usize = 200;
+
+// Documented as 200, but whole observations make for large responses.
+pub(crate) const MAX_ITEMS_PER_PAGE: usize = 200;
 
-// NOTE: This is an educated guess; documented as 200;
-const MAX_ITEMS_PER_PAGE: usize = 20;
+// NOTE: This is an educated guess.
+pub(crate) const DEFAULT_ITEMS_PER_PAGE: usize = 20;
 
 impl Api {
-    pub(crate) async fn sync_user_observations(&self, user_id: u64) -> Result<(), Error> {
+    // All of the user's observations, oldest first, fetched page by page as the stream is polled.
+    // Requests go through the rate limit and the HTTP cache like the sync's; nothing is written to
+    // the cache tables.
+    pub fn observations(
+        &self,
+        user_id: u64,
+    ) -> impl Stream<Item = Result<Observation, Error>> + '_ {
+        let url = self.user_observations_url(user_id, MAX_IDS_PER_PAGE);
+        // None once the last page is done; otherwise where the next one starts.
+        stream::try_unfold(Some(None), move |id_above: Option<Option<u64>>| {
+            let url = url.clone();
+            async move {
+                let id_above = match id_above {
+                    Some(id_above) => id_above,
+                    _ => return Ok::<_, Error>(None),
+                };
+                let (ids, is_last) = self.fetch_id_page(&url, id_above).await?;
+
+                let mut observations = Vec::with_capacity(ids.len());
+                for chunk in ids.chunks(DEFAULT_ITEMS_PER_PAGE) {
+                    let (_, mut records) = self.fetch_ids("/observations", chunk).await?;
+                    // Deleted since listed, if missing.
+                    for id in chunk {
+                        if let Some(obs) = records.remove(id) {
+                            observations.push(from_record(obs));
+                        }
+                    }
+                }
+
+                let next = match (is_last, ids.last()) {
+                    (false, Some(id)) => Some(Some(*id)),
+                    _ => None,
+                };
+                Ok(Some((stream::iter(observations), next)))
+            }
+        })
+        .try_flatten()
+    }
+
+    pub(crate) async fn sync_user_observations(
+        &self,
+        user_id: u64,
+        opts: &SyncOptions,
+        writer: &Arc<Mutex<Writer>>,
+    ) -> Result<(), Error> {
         let mut ids: Vec<u64> = vec![];
         let mut last_header = YamlMapping::new();
         let cache_path = self
             .path("users")
             .join(format!("{}.observations.yaml", user_id));
 
+        let url = self.user_observations_url(user_id, opts.page_size);
+        let started = self.store.now().trunc_subsecs(0);
+        let mut updated_since = None;
+        let mut listed_at = None;
+        // Even when syncing in full, to tell which observations were deleted since.
+        let listing = lookup_cache_ids(&cache_path)?;
+        let previous: HashSet<u64> = listing
+            .iter()
+            .flat_map(|listing| listing.ids.iter().copied())
+            .collect();
+        let cached = match opts.full {
+            true => None,
+            _ => listing,
+        };
+        let last_modified = cached.map(|cached| {
+            ids = cached.ids;
+            last_header.insert(
+                YamlValue::String(DATE.to_string()),
+                YamlValue::String(cached.header.date.to_rfc3339()),
+            );
+            if let Some(since) = cached.header.updated_since {
+                last_header.insert(
+                    YamlValue::String(UPDATED_SINCE.to_string()),
+                    YamlValue::String(since.to_rfc3339()),
+                );
+                updated_since = Some(since);
+            }
+            listed_at = cached.header.listed_at;
+            cached.header.date
+        });
+        let cached: HashSet<u64> = ids.iter().copied().collect();
+
+        // Listed from the start, unless there was a listing to go on from.
+        let mut complete = false;
+        if let Some((mut header, listed)) = self
+            .fetch_id_pages(&url, ids.last().copied(), last_modified)
+            .await?
+        {
+            ids.extend_from_slice(&listed);
+            // No need to store the etag since it won't be used.
+            header.remove(YamlValue::String(ETAG.to_string()));
+            if let Some(since) = updated_since {
+                header.insert(
+                    YamlValue::String(UPDATED_SINCE.to_string()),
+                    YamlValue::String(since.to_rfc3339()),
+                );
+            }
+            last_header = header;
+            complete = last_modified.is_none();
+        }
+
+        // Going on from the last ID listed only finds new observations; deleted ones are noticed
+        // once all of them are listed again, if the listing has more than iNat or it's time to.
+        let due = opts.relist_interval.is_some_and(|interval| {
+            listed_at.is_none_or(|at| (started - at).to_std().is_ok_and(|ago| ago >= interval))
+        });
+        let relist = !complete && (due || self.listed_total(user_id).await? != ids.len() as u64);
+        if relist {
+            if let Some((_, listed)) = self.fetch_id_pages(&url, None, None).await? {
+                ids = listed;
+                complete = true;
+            }
+        }
+        if complete {
+            listed_at = Some(started);
+            let listed: HashSet<u64> = ids.iter().copied().collect();
+            let deleted: Vec<u64> = previous
+                .into_iter()
+                .filter(|id| !listed.contains(id))
+                .sorted()
+                .collect();
+            if !deleted.is_empty() {
+                debug!("observations deleted: {}", deleted.len());
+                let archive = self.archive();
+                self.blocking(move |_, _| archive.move_deleted(&deleted))
+                    .await?;
+            }
+        }
+        if let Some(at) = listed_at {
+            last_header.insert(
+                YamlValue::String(LISTED_AT.to_string()),
+                YamlValue::String(at.to_rfc3339()),
+            );
+        }
+
+        self.store.write_cache(&cache_path, &last_header, &ids)?;
+        self.mirror(&cache_path).await?;
+
+        // Without a previous run to go by, everything is due.
+        // Otherwise only what changed since then, plus whatever got listed just now.
+        let mut url = url;
+        let since = self.store.now().trunc_subsecs(0);
+        let mut due: Vec<u64> = match updated_since {
+            Some(updated_since) => {
+                url.query_pairs_mut()
+                    .append_pair(UPDATED_SINCE, &updated_since.to_rfc3339());
+                let updated = match self.fetch_id_pages(&url, None, None).await? {
+                    Some((_, updated)) => updated,
+                    _ => vec![],
+                };
+                debug!(
+                    "observations updated since {}: {}",
+                    updated_since,
+                    updated.len()
+                );
+                updated
+                    .into_iter()
+                    .chain(ids.iter().copied().filter(|id| !cached.contains(id)))
+                    .collect()
+            }
+            _ => ids.clone(),
+        };
+
+        // When resuming, the leftovers from the last run are due too.
+        if let Some(checkpoint) = self.load_checkpoint(user_id)? {
+            debug!(
+                "resuming from checkpoint: {} observations left",
+                checkpoint.remaining.len()
+            );
+            due.extend_from_slice(&checkpoint.remaining);
+        }
+        let queue: Vec<u64> = due.into_iter().sorted().dedup().collect();
+
+        // Chunks of observations that didn't change since they were last fetched come back as
+        // 304s, unless some of them went missing from the store since.
+        let validators = match opts.full {
+            true => Validators::new(),
+            _ => self.load_validators()?,
+        };
+        let chunks = id_chunks(&queue, opts.chunk_size, MAX_ITEMS_PER_PAGE);
+        let mut conditional = Vec::with_capacity(chunks.len());
+        for chunk in &chunks {
+            let validator = match self.stored_observations(chunk)? {
+                true => chunk_validator(&validators, chunk),
+                _ => None,
+            };
+            conditional.push(validator);
+        }
+
+        // Chunks are fetched while earlier ones are being written, through a bounded channel so
+        // that the fetching doesn't run too far ahead. Later chunks may be in flight when one
+        // fails, or the sync is cancelled; the checkpoint starts at the first one not written.
+        let mut fresh = Validators::new();
+        let mut unchanged = 0;
+        let (tx, mut rx) = channel(opts.concurrency.max(1));
+        let fetch = async {
+            // Dropped when done, which ends the writing.
+            let tx = tx;
+            let mut results = stream::iter(chunks.iter().zip(&conditional))
+                .map(|(chunk, validator)| {
+                    self.fetch_ids_if_changed("/observations", chunk, validator.as_ref())
+                })
+                .buffered(opts.concurrency.max(1))
+                .enumerate();
+            while let Some(res) = results.next().await {
+                // Writing stopped at an error.
+                if tx.send(res).await.is_err() {
+                    break;
+                }
+            }
+        };
+        let write = async {
+            let mut next = 0;
+            loop {
+                let (i, res) = select! {
+                    biased;
+                    _ = opts.cancel.cancelled() => return Err((next, Error::Cancelled)),
+                    msg = rx.recv() => match msg {
+                        Some(msg) => msg,
+                        _ => break,
+                    },
+                };
+                next = i + 1;
+                let res = match res {
+                    Ok(Some((mut header, observations))) => {
+                        let validator = Validator::from_header(&header);
+                        // The etag doesn't match single items, so remove it.
+                        header.remove(YamlValue::String(ETAG.to_string()));
+                        self.normalise_observations(header, observations, writer)
+                            .await
+                            .map(|()| {
+                                self.report(|progress| progress.written(&self.store.changes()));
+                                validator
+                            })
+                    }
+                    Ok(None) => {
+                        unchanged += 1;
+                        Ok(None)
+                    }
+                    Err(err) => Err(err),
+                };
+                match res {
+                    // Partial syncs leave the other tables behind, so they can't be skipped later.
+                    Ok(Some(validator)) if opts.tables.is_all() => {
+                        for id in chunks[i] {
+                            fresh.insert(*id, validator.clone());
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => return Err((i, err)),
+                }
+            }
+            Ok(())
+        };
+        let ((), res) = join(fetch, write).await;
+        self.update_validators(validators, fresh, &ids)?;
+        if let Err((i, err)) = res {
+            let retry_at = match err {
+                Error::QuotaExhausted(retry_at) => Some(retry_at),
+                Error::Cancelled => Some(self.store.now()),
+                _ => None,
+            };
+            if let Some(retry_at) = retry_at {
+                let written: usize = chunks[..i].iter().map(|chunk| chunk.len()).sum();
+                let done = self
+                    .load_checkpoint(user_id)?
+                    .map(|checkpoint| checkpoint.done)
+                    .unwrap_or_default();
+                self.save_checkpoint(&Checkpoint {
+                    user_id,
+                    retry_at,
+                    remaining: queue[written..].to_vec(),
+                    done,
+                })?;
+            }
+            return Err(err);
+        }
+        debug!("observation chunks unchanged: {}", unchanged);
+
+        // Partial syncs leave the other tables behind, so they don't count as a previous run.
+        if opts.tables.is_all() {
+            last_header.insert(
+                YamlValue::String(UPDATED_SINCE.to_string()),
+                YamlValue::String(since.to_rfc3339()),
+            );
+            self.store.write_cache(&cache_path, &last_header, &ids)?;
+            self.mirror(&cache_path).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn mirror(&self, path: &Path) -> Result<(), Error> {
+        let path = path.to_path_buf();
+        self.blocking(move |store, _| store.mirror(&path)).await
+    }
+
+    // How many observations iNat lists for the user now, from a single ID.
+    async fn listed_total(&self, user_id: u64) -> Result<u64, Error> {
+        let url = self.user_observations_url(user_id, 1);
+        let (_, res) = self
+            .fetch(self.client.get(url.clone()))
+            .await?
+            .ok_or_else(|| unexpected_response(Some(&url), "no response"))?;
+
+        total_results(&res)
+    }
+
+    fn stored_observations(&self, ids: &[u64]) -> Result<bool, Error> {
+        for id in ids {
+            if !self.store.contains("observations", *id)? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Keeps the validators of the observations fetched just now, and drops those of deleted ones.
+    fn update_validators(
+        &self,
+        mut validators: Validators,
+        fresh: Validators,
+        ids: &[u64],
+    ) -> Result<(), Error> {
+        validators.extend(fresh);
+        let ids: HashSet<u64> = ids.iter().copied().collect();
+        validators.retain(|id, _| ids.contains(id));
+        self.save_validators(&validators)
+    }
+
+    fn user_observations_url(&self, user_id: u64, per_page: usize) -> Url {
         let mut url = self.endpoint("/observations");
-        for (key, val) in [
+        // Only v1 has only_id, v2 has fields= instead.
+        self.select_fields(&mut url, fields::ids);
+        let only_id = (self.api_version() == ApiVersion::V1).then_some(("only_id", "true"));
+        for (key, val) in only_id.into_iter().chain([
             // keep sorted
-            ("only_id", "true"),
             ("order", "asc"),
             ("order_by", ID),
-            ("per_page", &MAX_IDS_PER_PAGE.to_string()),
+            ("per_page", &per_page.to_string()),
             ("user_id", &user_id.to_string()),
-        ] {
+        ]) {
             url.query_pairs_mut().append_pair(key, val);
         }
 
-        let last_modified = lookup_cache_ids(&cache_path)?.map(|cached| {
-            ids = cached.ids;
-            last_header.insert(
-                YamlValue::String(DATE.to_string()),
-                YamlValue::String(cached.header.date.to_rfc3339()),
-            );
-            cached.header.date
-        });
+        url
+    }
 
+    // Lists IDs page by page, returning the header of the last page, or None on a cache hit.
+    async fn fetch_id_pages(
+        &self,
+        url: &Url,
+        mut id_above: Option<u64>,
+        last_modified: Option<DateTime<Utc>>,
+    ) -> Result<Option<(YamlMapping, Vec<u64>)>, Error> {
+        let mut ids = vec![];
         loop {
             let mut url = url.clone();
-            if let Some(id) = ids.last() {
+            if let Some(id) = id_above {
                 url.query_pairs_mut()
                     .append_pair("id_above", &id.to_string());
             }
@@ -61,56 +409,55 @@ impl Api {
                 req = req.header(IF_MODIFIED_SINCE, fmt_http_date(date.into()));
             }
 
-            let (mut header, res) = match fetch(req).await? {
+            let (header, res) = match self.fetch(req).await? {
                 Some(val) => val,
-                _ => break, // cache hit
+                _ => return Ok(None),
             };
 
             let is_last = is_last_page(&res)?;
             ids.extend_from_slice(&extract_ids(res)?);
+            id_above = ids.last().copied().or(id_above);
 
             if is_last {
-                // No need to store the etag since it won't be used.
-                header.remove(YamlValue::String(ETAG.to_string()));
-                last_header = header;
-                break;
+                return Ok(Some((header, ids)));
             }
         }
+    }
 
-        // TODO: handle deleted observations!
-        // If we receive no updates, but we end up having more IDs than listed in the users object,
-        // we need to re-fetch all IDs to make sure we get rid of the deleted ones.
-
-        write_cache(&cache_path, &last_header, &ids)?;
-
-        for chunk in &ids.into_iter().chunks(MAX_ITEMS_PER_PAGE) {
-            let ids: Vec<u64> = chunk.collect();
-            self.sync_observations(&ids).await?;
+    async fn fetch_id_page(
+        &self,
+        url: &Url,
+        id_above: Option<u64>,
+    ) -> Result<(Vec<u64>, bool), Error> {
+        let mut url = url.clone();
+        if let Some(id) = id_above {
+            url.query_pairs_mut()
+                .append_pair("id_above", &id.to_string());
         }
 
-        Ok(())
+        let (_, res) = self
+            .fetch(self.client.get(url.clone()))
+            .await?
+            .ok_or_else(|| unexpected_response(Some(&url), "no response"))?;
+        let is_last = is_last_page(&res)?;
+
+        Ok((extract_ids(res)?, is_last))
     }
 
-    async fn sync_observations(&self, ids: &[u64]) -> Result<(), Error> {
-        let (mut header, res) = fetch(self.client.get(self.endpoint(&format!(
-            "/observations/{}",
-            ids.iter().map(|id| id.to_string()).join(",")
-        ))))
-        .await?
-        .ok_or(internal(&format!(
-            "observations ({}): no response",
-            ids.len()
-        )))?;
-
-        // The header can be used for each individual item.
-        // But the etag doesn't match single items, so remove it.
-        header.remove(YamlValue::String(ETAG.to_string()));
-
-        let observations = expect_results(res)?
-            .into_iter()
-            .map(|obs| extract_id(&obs).and_then(|id| Ok((id, obs))))
-            .collect::<Result<HashMap<_, _>, _>>()?;
-
-        Normaliser::new(header, observations, &self.data_dir).write()
+    // Off the runtime, so that fetching goes on meanwhile.
+    async fn normalise_observations(
+        &self,
+        header: YamlMapping,
+        observations: HashMap<u64, JsonMap<String, JsonValue>>,
+        writer: &Arc<Mutex<Writer>>,
+    ) -> Result<(), Error> {
+        let writer = writer.clone();
+        self.blocking(move |_, _| {
+            writer
+                .lock()
+                .expect("writer poisoned")
+                .observations(header, observations)
+        })
+        .await
     }
 }
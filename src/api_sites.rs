@@ -0,0 +1,62 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tracing::warn;
+
+use crate::{
+    api::{expect_results, extract_id, is_last_page, Api},
+    error::Error,
+    fields,
+    normalise::Writer,
+};
+
+const MAX_SITES_PER_PAGE: usize = 200;
+
+impl Api {
+    // The iNat network sites observations and users are from, by their site_id. There are only a
+    // few dozen, so all of them are listed every time; the HTTP cache answers while they're fresh.
+    pub(crate) async fn sync_sites(&self, writer: &Arc<Mutex<Writer>>) -> Result<(), Error> {
+        for page in 1.. {
+            let mut url = self.endpoint("/sites");
+            self.select_fields(&mut url, fields::sites);
+            for (key, val) in [
+                // keep sorted
+                ("page", &page.to_string()),
+                ("per_page", &MAX_SITES_PER_PAGE.to_string()),
+            ] {
+                url.query_pairs_mut().append_pair(key, val);
+            }
+
+            // Not worth failing the sync over, e.g. with a mirror of the API that has no sites.
+            let (header, res) = match self.fetch(self.client.get(url)).await {
+                Ok(Some(val)) => val,
+                Ok(None) => break,
+                Err(err @ (Error::QuotaExhausted(_) | Error::Cancelled | Error::Offline(_))) => {
+                    return Err(err)
+                }
+                Err(err) => {
+                    warn!("sites: {}", err);
+                    break;
+                }
+            };
+            let is_last = is_last_page(&res)?;
+            let sites = expect_results(res)?
+                .into_iter()
+                .map(|site| extract_id(&site).map(|id| (id, site)))
+                .collect::<Result<HashMap<_, _>, _>>()
+                .map_err(|err| err.in_table("sites"))?;
+
+            let writer = writer.clone();
+            self.blocking(move |_, _| writer.lock().expect("writer poisoned").sites(header, sites))
+                .await?;
+            self.report(|progress| progress.written(&self.store.changes()));
+            if is_last {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,56 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    api::{expect_results, extract_id, is_last_page, lookup_cache_id, Api},
+    error::{unexpected_response, Error},
+    fields,
+};
+
+const MAX_COUNTS_PER_PAGE: usize = 500;
+
+impl Api {
+    // Observation counts per leaf taxon, according to the server; nothing is written to the cache.
+    pub async fn species_counts(&self, username: &str) -> Result<BTreeMap<u64, u64>, Error> {
+        let user_id = lookup_cache_id(&self.path("users").join(format!("{}.yaml", username)))?
+            .ok_or_else(|| Error::NotFound(format!("user {}", username)))?
+            .id;
+
+        let mut counts = BTreeMap::new();
+        for page in 1.. {
+            let mut url = self.endpoint("/observations/species_counts");
+            self.select_fields(&mut url, fields::species_counts);
+            for (key, val) in [
+                // keep sorted
+                ("page", &page.to_string()),
+                ("per_page", &MAX_COUNTS_PER_PAGE.to_string()),
+                ("user_id", &user_id.to_string()),
+            ] {
+                url.query_pairs_mut().append_pair(key, val);
+            }
+
+            let res = match self.fetch(self.client.get(url.clone())).await? {
+                Some((_, res)) => res,
+                _ => break,
+            };
+            let is_last = is_last_page(&res)?;
+            for result in expect_results(res)? {
+                let taxon = result
+                    .get("taxon")
+                    .and_then(|taxon| taxon.as_object())
+                    .ok_or_else(|| {
+                        unexpected_response(Some(&url), "species count without taxon")
+                    })?;
+                let count = result
+                    .get("count")
+                    .and_then(|count| count.as_u64())
+                    .ok_or_else(|| unexpected_response(Some(&url), "species count is not u64"))?;
+                counts.insert(extract_id(taxon)?, count);
+            }
+            if is_last {
+                break;
+            }
+        }
+
+        Ok(counts)
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    collections::HashSet,
+    fs::create_dir_all,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{stream::FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+use crate::{
+    api::Api,
+    api_observations::{DEFAULT_ITEMS_PER_PAGE, MAX_IDS_PER_PAGE, MAX_ITEMS_PER_PAGE},
+    error::Error,
+    normalise::{Writer, TABLES},
+    summary::SyncSummary,
+};
+
+// Observation chunks in flight at once.
+const DEFAULT_CONCURRENCY: usize = 2;
+
+// Tables fetched in full by the taxa and sites stages; all the others come with the observations.
+const TAXA_TABLES: [&str; 6] = [
+    "conservation_statuses",
+    "listed_taxa",
+    "lists",
+    "places",
+    "taxa",
+    "taxon_photos",
+];
+const SITES_TABLES: [&str; 1] = ["sites"];
+
+// Which tables to sync, all of them by default.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct Selection {
+    pub only: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct SyncOptions {
+    pub tables: Selection,
+    // Ignore the cache state (conditional headers, listings) and fetch everything again; the HTTP
+    // cache still answers while fresh, or when the API confirms that nothing changed.
+    pub full: bool,
+    // Chunks are fetched concurrently, but still normalised in order.
+    pub concurrency: usize,
+    // Observation IDs listed per request.
+    pub page_size: usize,
+    // Observations fetched per request, and taxa as far as their endpoint allows; larger chunks
+    // mean fewer requests, but larger responses.
+    pub chunk_size: usize,
+    // List all of the observation IDs again this long after the last time, to notice those deleted
+    // on iNat; they're listed again anyway when there are more than the user's count.
+    pub relist_interval: Option<Duration>,
+    // Also write the summary to .sync/last_run.yaml in the data directory.
+    pub save_summary: bool,
+    // Commit the data directory to git afterwards, with the summary as the message.
+    pub git_commit: bool,
+    // Once cancelled, the sync stops after the chunk being written, leaving a checkpoint.
+    pub cancel: CancellationToken,
+}
+
+// Stages read what the ones they come after wrote, e.g. taxa are enriched once observations are
+// in; the others run concurrently, sharing the rate limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Stage {
+    Observations,
+    Sites,
+    Taxa,
+}
+
+const STAGES: [Stage; 3] = [Stage::Observations, Stage::Sites, Stage::Taxa];
+
+impl Selection {
+    pub fn new(only: Vec<String>, exclude: Vec<String>) -> Result<Self, Error> {
+        for table in only.iter().chain(&exclude) {
+            if !TABLES.contains(&table.as_str()) {
+                return Err(Error::NotFound(format!("table {}", table)));
+            }
+        }
+
+        Ok(Self { only, exclude })
+    }
+
+    pub fn is_all(&self) -> bool {
+        self.only.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn includes(&self, table: &str) -> bool {
+        (self.only.is_empty() || self.only.iter().any(|t| t == table))
+            && !self.exclude.iter().any(|t| t == table)
+    }
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        Self::new(Selection::default())
+    }
+}
+
+impl SyncOptions {
+    pub fn new(tables: Selection) -> Self {
+        Self {
+            tables,
+            full: false,
+            concurrency: DEFAULT_CONCURRENCY,
+            page_size: MAX_IDS_PER_PAGE,
+            chunk_size: DEFAULT_ITEMS_PER_PAGE,
+            relist_interval: None,
+            save_summary: false,
+            git_commit: false,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    pub fn full(mut self, full: bool) -> Self {
+        self.full = full;
+        self
+    }
+
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    // Clamped to the API's maximum of 200.
+    pub fn page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size.clamp(1, MAX_IDS_PER_PAGE);
+        self
+    }
+
+    // Clamped like the page size.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.clamp(1, MAX_ITEMS_PER_PAGE);
+        self
+    }
+
+    pub fn relist_interval(mut self, interval: Option<Duration>) -> Self {
+        self.relist_interval = interval;
+        self
+    }
+
+    pub fn save_summary(mut self, save_summary: bool) -> Self {
+        self.save_summary = save_summary;
+        self
+    }
+
+    pub fn git_commit(mut self, git_commit: bool) -> Self {
+        self.git_commit = git_commit;
+        self
+    }
+
+    pub fn cancel_on(mut self, token: CancellationToken) -> Self {
+        self.cancel = token;
+        self
+    }
+}
+
+impl Stage {
+    fn after(self) -> &'static [Stage] {
+        match self {
+            Stage::Observations | Stage::Sites => &[],
+            Stage::Taxa => &[Stage::Observations],
+        }
+    }
+
+    fn tables(self) -> Vec<&'static str> {
+        TABLES
+            .iter()
+            .filter(|table| match self {
+                Stage::Observations => {
+                    !TAXA_TABLES.contains(table) && !SITES_TABLES.contains(table)
+                }
+                Stage::Sites => SITES_TABLES.contains(table),
+                Stage::Taxa => TAXA_TABLES.contains(table),
+            })
+            .copied()
+            .collect()
+    }
+}
+
+impl Api {
+    pub async fn sync_all(&self, username: &str) -> Result<SyncSummary, Error> {
+        self.sync(username, &SyncOptions::default()).await
+    }
+
+    // What the sync did; the changes are those written to the cache tables, the user's own record
+    // and listings don't count.
+    pub async fn sync(&self, username: &str, opts: &SyncOptions) -> Result<SyncSummary, Error> {
+        create_dir_all(self.path("users"))?;
+        // Going on before then would only run into the quota again.
+        if let Some(retry_at) = self.retry_at()? {
+            return Err(Error::QuotaExhausted(retry_at));
+        }
+
+        let started = self.store.now();
+        let start = Instant::now();
+        self.store.take_changes();
+        self.metrics.take();
+        let writer = Arc::new(Mutex::new(
+            Writer::new(self.store.clone())
+                .select(opts.tables.clone())
+                .extract_with(self.extractors.clone()),
+        ));
+        let res = self.sync_stages(username, opts, &writer).await;
+        self.blocking(|store, _| store.compact()).await?;
+        self.quota.flush()?;
+        #[cfg(feature = "http-cache")]
+        if let Some(cache) = &self.http_cache {
+            let evicted = cache.evict()?;
+            if evicted > 0 {
+                debug!("evicted {} cached responses", evicted);
+            }
+        }
+        res?;
+
+        let (requests, bytes, cache_hits) = self.metrics.take();
+        let summary = SyncSummary {
+            started,
+            duration: match self.store.is_deterministic() {
+                true => Duration::ZERO,
+                _ => start.elapsed(),
+            },
+            requests,
+            bytes,
+            cache_hits,
+            changes: self.store.take_changes(),
+        };
+        if opts.save_summary {
+            self.save_summary(&summary)?;
+        }
+        if opts.git_commit {
+            self.commit_sync(username, &summary)?;
+        }
+
+        Ok(summary)
+    }
+
+    // The user is always looked up first, since all the stages need the ID. Then each stage starts
+    // as soon as the ones it comes after are done, or skipped; the first error stops them all.
+    async fn sync_stages(
+        &self,
+        username: &str,
+        opts: &SyncOptions,
+        writer: &Arc<Mutex<Writer>>,
+    ) -> Result<(), Error> {
+        let user_id = self.sync_user(username, opts.full).await?;
+        // Those a sync that stopped halfway got through are skipped, unless syncing in full.
+        let mut resumed = match opts.full {
+            true => vec![],
+            _ => self
+                .load_checkpoint(user_id)?
+                .map(|checkpoint| checkpoint.done)
+                .unwrap_or_default(),
+        };
+        let (mut pending, skipped): (Vec<_>, Vec<_>) = STAGES.into_iter().partition(|stage| {
+            !resumed.contains(stage)
+                && stage
+                    .tables()
+                    .iter()
+                    .any(|table| opts.tables.includes(table))
+        });
+        if !resumed.is_empty() {
+            debug!("resuming after stages: {:?}", resumed);
+        }
+        let mut done: HashSet<Stage> = skipped.into_iter().collect();
+        let mut running = FuturesUnordered::new();
+        loop {
+            let (mut ready, mut waiting): (Vec<_>, Vec<_>) = pending
+                .into_iter()
+                .partition(|stage| stage.after().iter().all(|after| done.contains(after)));
+            // One at a time, in the order of STAGES.
+            if self.store.is_deterministic() && ready.len() > 1 {
+                waiting.splice(0..0, ready.drain(1..));
+            }
+            pending = waiting;
+            if !ready.is_empty() && opts.cancel.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+            for stage in ready {
+                running.push(async move { self.sync_stage(stage, user_id, opts, writer).await });
+            }
+            match running.next().await {
+                Some(Ok(stage)) => {
+                    done.insert(stage);
+                    resumed.push(stage);
+                    self.save_stages(user_id, &resumed, self.store.now())?;
+                }
+                Some(Err(err)) => {
+                    let retry_at = match err {
+                        Error::QuotaExhausted(retry_at) => Some(retry_at),
+                        Error::Cancelled => Some(self.store.now()),
+                        _ => None,
+                    };
+                    if let Some(retry_at) = retry_at {
+                        self.save_stages(user_id, &resumed, retry_at)?;
+                    }
+                    return Err(err);
+                }
+                _ => break,
+            };
+        }
+
+        self.clear_checkpoint()
+    }
+
+    async fn sync_stage(
+        &self,
+        stage: Stage,
+        user_id: u64,
+        opts: &SyncOptions,
+        writer: &Arc<Mutex<Writer>>,
+    ) -> Result<Stage, Error> {
+        debug!("sync stage: {:?}", stage);
+        let tables: Vec<&str> = stage
+            .tables()
+            .into_iter()
+            .filter(|table| opts.tables.includes(table))
+            .collect();
+        self.report(|progress| progress.tables_started(&tables));
+        match stage {
+            Stage::Observations => self.sync_user_observations(user_id, opts, writer).await?,
+            Stage::Sites => self.sync_sites(writer).await?,
+            Stage::Taxa => self.sync_taxa(opts, writer).await?,
+        }
+        self.report(|progress| progress.tables_finished(&tables));
+
+        Ok(stage)
+    }
+}
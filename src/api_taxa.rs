@@ -0,0 +1,62 @@
+use std::sync::{Arc, Mutex};
+
+use tracing::{debug, warn};
+
+use crate::{api::Api, api_sync::SyncOptions, chunks::id_chunks, error::Error, normalise::Writer};
+
+// NOTE: The /taxa/{id} endpoint accepts at most 30 IDs.
+const MAX_TAXA_PER_PAGE: usize = 30;
+
+impl Api {
+    // Taxa embedded in observations are abbreviated; fetch the full records once.
+    pub(crate) async fn sync_taxa(
+        &self,
+        opts: &SyncOptions,
+        writer: &Arc<Mutex<Writer>>,
+    ) -> Result<(), Error> {
+        // Only full taxon records come with taxon photos.
+        let ids: Vec<u64> = self
+            .archive()
+            .table("taxa")?
+            .into_iter()
+            .filter(|(_, taxon)| opts.full || !taxon.contains_key("taxon_photos"))
+            .map(|(id, _)| id)
+            .collect();
+        debug!("taxa to enrich: {}", ids.len());
+
+        for chunk in id_chunks(&ids, opts.chunk_size, MAX_TAXA_PER_PAGE) {
+            // The rest are enriched next time.
+            if opts.cancel.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+            match self.sync_taxa_chunk(chunk, writer).await {
+                Err(err @ Error::QuotaExhausted(_)) => return Err(err),
+                Err(err) if chunk.len() > 1 => {
+                    warn!("taxa ({}): {}; retrying one by one", chunk.len(), err);
+                    for id in chunk {
+                        match self.sync_taxa_chunk(&[*id], writer).await {
+                            Err(err @ Error::QuotaExhausted(_)) => return Err(err),
+                            Err(err) => warn!("taxon {}: {}", id, err),
+                            _ => {}
+                        }
+                    }
+                }
+                Err(err) => warn!("taxon {}: {}", chunk[0], err),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn sync_taxa_chunk(&self, ids: &[u64], writer: &Arc<Mutex<Writer>>) -> Result<(), Error> {
+        let (header, taxa) = self.fetch_ids("/taxa", ids).await?;
+
+        let writer = writer.clone();
+        self.blocking(move |_, _| writer.lock().expect("writer poisoned").taxa(header, taxa))
+            .await?;
+        self.report(|progress| progress.written(&self.store.changes()));
+
+        Ok(())
+    }
+}
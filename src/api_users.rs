@@ -1,47 +1,61 @@
 use std::{
+    ffi::OsStr,
     fs::{read_dir, read_link, remove_file, symlink_metadata},
     io::Error as IoError,
-    os::unix::fs::symlink,
     path::Path,
 };
 
 use httpdate::fmt_http_date;
 use reqwest::header::{IF_MODIFIED_SINCE, IF_NONE_MATCH};
+use serde_json::Value as JsonValue;
 
-use crate::api::{
-    extract_id, extract_single_value, fetch, lookup_cache_id, write_cache, Api, ApiResults,
-    CacheHeader,
-};
-use crate::error::{internal, Error};
+use crate::api::{extract_id, extract_single_value, lookup_cache_id, Api, ApiResults, CacheHeader};
+use crate::error::{bad_record, internal, unexpected_response, Error};
+use crate::fields;
+use crate::normalise::extract_preferences;
+use crate::store::symlink;
 
 impl Api {
-    pub(crate) async fn sync_user(&self, username: &str) -> Result<u64, Error> {
-        let cached = lookup_cache_id(&self.path("users").join(format!("{}.yaml", username)))?;
-        let cached_id = cached.as_ref().and_then(|c| Some(c.id));
-        let user = match self
-            .fetch_user(cached.and_then(|c| Some(c.header)), username)
-            .await?
-        {
+    pub(crate) async fn sync_user(&self, username: &str, full: bool) -> Result<u64, Error> {
+        if !is_login(username) {
+            return Err(Error::NotFound(format!("user {}", username)));
+        }
+        let cached = match full {
+            true => None,
+            _ => lookup_cache_id(&self.path("users").join(format!("{}.yaml", username)))?,
+        };
+        let cached_id = cached.as_ref().map(|c| c.id);
+        let user = match self.fetch_user(cached.map(|c| c.header), username).await? {
             Some(user) => user,
             // If nothing was returned, it was a cache hit, no need to update.
-            _ => return Ok(cached_id.ok_or(internal("user cache missing id"))?),
+            _ => return cached_id.ok_or(internal("user cache missing id")),
         };
 
-        let body = user.body.get(0).ok_or(internal("no user returned"))?;
-        let id = extract_id(body)?;
-        let login = body
-            .get("login")
-            .ok_or(internal("user login not found"))?
-            .as_str()
-            .ok_or(internal("user login was not a string"))?
-            .to_string();
+        let body = user.body.first().ok_or_else(|| {
+            unexpected_response(
+                Some(&self.endpoint(&format!("/users/{}", username))),
+                "no user returned",
+            )
+        })?;
+        let id = extract_id(body).map_err(|err| err.in_table("users"))?;
+        let login = match body.get("login") {
+            Some(JsonValue::String(login)) if is_login(login) => login.clone(),
+            Some(JsonValue::String(_)) => {
+                return Err(bad_record(Some(id), "login", "not a login").in_table("users"))
+            }
+            Some(_) => return Err(bad_record(Some(id), "login", "not a string").in_table("users")),
+            _ => return Err(bad_record(Some(id), "login", "missing").in_table("users")),
+        };
 
+        let mut body = body.clone();
         let cache_path = self.path("users").join(format!("{}.yaml", id));
-        write_cache(
-            &cache_path,
-            &user.header,
-            &user.body.first().ok_or(internal("user has no body"))?,
-        )?;
+        self.blocking(move |store, _| {
+            if let Some((pid, preferences)) = extract_preferences("users", "User", id, &mut body)? {
+                store.put("preferences", &user.header, [(pid, &preferences)])?;
+            }
+            store.write_file(&cache_path, &user.header, &body)
+        })
+        .await?;
 
         self.symlink_user(&login, &id)?;
 
@@ -53,7 +67,8 @@ impl Api {
         cache: Option<CacheHeader>,
         username: &str,
     ) -> Result<Option<ApiResults>, Error> {
-        let url = self.endpoint(&format!("/users/{}", username));
+        let mut url = self.endpoint(&format!("/users/{}", username));
+        self.select_fields(&mut url, fields::users);
         let mut req = self.client.get(url);
         if let Some(cache) = cache {
             req = req.header(IF_MODIFIED_SINCE, fmt_http_date(cache.date.into()));
@@ -62,7 +77,7 @@ impl Api {
             }
         }
 
-        match fetch(req).await? {
+        match self.fetch(req).await? {
             Some((header, res)) => Ok(Some(ApiResults {
                 header,
                 body: vec![extract_single_value(res)?],
@@ -73,8 +88,11 @@ impl Api {
 
     fn symlink_user(&self, username: &str, id: &u64) -> Result<(), IoError> {
         let dir = self.path("users");
-        let link = format!("{}.yaml", username).to_owned();
-        let target = &format!("{}.yaml", id);
+        let extension = self.store.extension();
+        let link = format!("{}.{}", username, extension);
+        let target = &format!("{}.{}", id, extension);
+        // Links made before the compression changed point at the other file name.
+        let targets = [format!("{}.yaml", id), format!("{}.yaml.zst", id)];
         let mut exists = false;
 
         for entry in read_dir(&dir)? {
@@ -82,9 +100,12 @@ impl Api {
             if let Ok(md) = symlink_metadata(&path) {
                 if md.file_type().is_symlink() {
                     if let Ok(target_path) = read_link(&path) {
-                        let target = Path::new(target);
-                        let ok = path.file_name() == Some(target.as_os_str());
-                        if target_path == target && !ok {
+                        if !targets.iter().any(|t| target_path == Path::new(t)) {
+                            continue;
+                        }
+                        let ok = path.file_name() == Some(OsStr::new(&link))
+                            && target_path == Path::new(target);
+                        if !ok {
                             remove_file(&path)?;
                         }
                         exists |= ok;
@@ -100,3 +121,26 @@ impl Api {
         Ok(())
     }
 }
+
+// Logins are letters, digits, dashes and underscores; they name files, so nothing else goes.
+pub(crate) fn is_login(login: &str) -> bool {
+    !login.is_empty()
+        && login
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logins_name_no_other_files() {
+        assert!(is_login("alice"));
+        assert!(is_login("bob_42-x"));
+        assert!(!is_login(""));
+        assert!(!is_login("../alice"));
+        assert!(!is_login("alice/observations"));
+        assert!(!is_login("alice.observations"));
+    }
+}
@@ -0,0 +1,212 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+#[cfg(feature = "export")]
+use chrono::{DateTime, Utc};
+use serde_json::{Map as JsonMap, Value as JsonValue};
+
+#[cfg(feature = "replica")]
+use crate::storage::Storage;
+use crate::{
+    clock::Clock,
+    error::Error,
+    extractor::TableExtractor,
+    models::{from_record, Model},
+    store::{Layout, Settings, Store},
+};
+
+pub(crate) type Record = JsonMap<String, JsonValue>;
+
+// View of the normalised data directory.
+pub struct Archive {
+    pub(crate) data_dir: PathBuf,
+    pub(crate) store: Arc<Store>,
+    pub(crate) extractors: Vec<Arc<dyn TableExtractor>>,
+}
+
+impl Archive {
+    pub fn new<P: AsRef<Path>>(data_dir: P) -> Result<Self, Error> {
+        let data_dir = data_dir.as_ref().to_path_buf();
+        Ok(Self {
+            store: Arc::new(Store::open(&data_dir)?),
+            data_dir,
+            extractors: Vec::new(),
+        })
+    }
+
+    // Like ApiBuilder::storage.
+    #[cfg(feature = "replica")]
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Result<Self, Error> {
+        let settings = self.store.settings().clone();
+        self.store = Arc::new(Store::open_with_storage(&self.data_dir, storage, settings)?);
+        Ok(self)
+    }
+
+    // Like ApiBuilder::clock.
+    pub fn with_clock<C: Clock + 'static>(self, clock: C) -> Self {
+        let settings = Settings {
+            clock: Arc::new(clock),
+            ..self.store.settings().clone()
+        };
+        self.with_settings(settings)
+    }
+
+    // Like ApiBuilder::deterministic.
+    pub fn with_deterministic(self, deterministic: bool) -> Self {
+        let settings = Settings {
+            deterministic,
+            ..self.store.settings().clone()
+        };
+        self.with_settings(settings)
+    }
+
+    // Like ApiBuilder::durable.
+    pub fn with_durable(self, durable: bool) -> Self {
+        let settings = Settings {
+            durable,
+            ..self.store.settings().clone()
+        };
+        self.with_settings(settings)
+    }
+
+    fn with_settings(mut self, settings: Settings) -> Self {
+        self.store = Arc::new(self.store.reopen(settings));
+        self
+    }
+
+    // Like Api::with_extractor, for normalising the cache again.
+    pub fn with_extractor<E: TableExtractor + 'static>(mut self, extractor: E) -> Self {
+        self.extractors.push(Arc::new(extractor));
+        self
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.store.layout()
+    }
+
+    // The zstd level the cached tables are compressed at, if they are.
+    pub fn compression(&self) -> Option<i32> {
+        self.store.compression()
+    }
+
+    // Moves the cached tables over to the given layout and compression.
+    pub fn convert(&mut self, layout: Layout, compression: Option<i32>) -> Result<(), Error> {
+        self.store = Arc::new(self.store.convert(layout, compression)?);
+
+        Ok(())
+    }
+
+    pub(crate) fn path(&self, sub: &str) -> PathBuf {
+        self.data_dir.join(sub)
+    }
+
+    pub(crate) fn record(&self, table: &str, id: u64) -> Result<Option<Record>, Error> {
+        Ok(self.store.get(table, id)?.map(|(_, record)| record))
+    }
+
+    // Common name with the scientific one in parens, or whatever the observer guessed.
+    pub(crate) fn observation_title(&self, obs: &Record) -> Result<String, Error> {
+        let taxon = match id_field(obs, "taxon") {
+            Some(id) => self.record("taxa", id)?,
+            _ => None,
+        };
+        Ok(match taxon.as_ref().map(taxon_name) {
+            Some(name) => name,
+            _ => str_field(obs, "species_guess")
+                .unwrap_or("Unknown")
+                .to_string(),
+        })
+    }
+
+    // All cached records of a table, by ID; empty for unknown tables.
+    pub fn table(&self, name: &str) -> Result<BTreeMap<u64, Record>, Error> {
+        self.store.all(name)
+    }
+
+    // Like table, typed, e.g. archive.all::<Taxon>().
+    pub fn all<T: Model>(&self) -> Result<BTreeMap<u64, T>, Error> {
+        self.table(T::TABLE)?
+            .into_iter()
+            .map(|(id, record)| from_record(record).map(|record| (id, record)))
+            .collect()
+    }
+
+    pub fn get<T: Model>(&self, id: u64) -> Result<Option<T>, Error> {
+        self.record(T::TABLE, id)?.map(from_record).transpose()
+    }
+}
+
+// Fixtures for the tests of the modules reading the archive.
+#[cfg(test)]
+impl Archive {
+    pub(crate) fn insert(&self, table: &str, records: JsonValue) {
+        let records: Vec<_> = records
+            .as_array()
+            .expect("array")
+            .iter()
+            .map(|record| record.as_object().expect("object").clone())
+            .collect();
+        self.store
+            .put(
+                table,
+                &serde_yaml::Mapping::new(),
+                records
+                    .iter()
+                    .map(|record| (id_field(record, "id").expect("id"), record)),
+            )
+            .expect("put");
+    }
+}
+
+pub(crate) fn ids(record: &Record, key: &str) -> Vec<u64> {
+    match record.get(key) {
+        Some(JsonValue::Array(vals)) => vals.iter().filter_map(JsonValue::as_u64).collect(),
+        _ => vec![],
+    }
+}
+
+pub(crate) fn str_field<'a>(record: &'a Record, key: &str) -> Option<&'a str> {
+    record.get(key).and_then(JsonValue::as_str)
+}
+
+pub(crate) fn id_field(record: &Record, key: &str) -> Option<u64> {
+    record.get(key).and_then(JsonValue::as_u64)
+}
+
+#[cfg(feature = "export")]
+pub(crate) fn coordinates(obs: &Record) -> Option<(f64, f64)> {
+    if let Some(JsonValue::Array(coords)) = obs.get("geojson").and_then(|g| g.get("coordinates")) {
+        if let [lng, lat] = &coords[..] {
+            return Some((lat.as_f64()?, lng.as_f64()?));
+        }
+    }
+
+    let (lat, lng) = str_field(obs, "location")?.split_once(',')?;
+    Some((lat.trim().parse().ok()?, lng.trim().parse().ok()?))
+}
+
+#[cfg(feature = "export")]
+pub(crate) fn timestamp(record: &Record, key: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(str_field(record, key)?)
+        .ok()
+        .map(|ts| ts.with_timezone(&Utc))
+}
+
+pub(crate) fn taxon_name(taxon: &Record) -> String {
+    let name = str_field(taxon, "name").unwrap_or("Unknown");
+    match str_field(taxon, "preferred_common_name") {
+        Some(common) => format!("{} ({})", common, name),
+        _ => name.to_string(),
+    }
+}
+
+pub(crate) fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
@@ -0,0 +1,373 @@
+use std::{
+    collections::BTreeMap,
+    env::{args_os, var_os},
+    fs::read_to_string,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use clap::{Arg, Command};
+use inat::Error;
+use serde::Deserialize;
+
+// Used when no profile is given, if the config file has one.
+const DEFAULT_PROFILE: &str = "default";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    profiles: BTreeMap<String, Profile>,
+}
+
+// Defaults for the command line options, which still win. Keys are named like the options.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Profile {
+    user: Option<String>,
+    endpoint: Option<String>,
+    api_version: Option<String>,
+    data: Option<String>,
+    token: Option<String>,
+    rate_limit: Option<u32>,
+    daily_quota: Option<u32>,
+    attempts: Option<u32>,
+    breaker_failures: Option<u32>,
+    breaker_cool_down: Option<String>,
+    connect_timeout: Option<String>,
+    read_timeout: Option<String>,
+    proxy: Option<String>,
+    ca_cert: Option<String>,
+    pool_size: Option<usize>,
+    pool_idle_timeout: Option<String>,
+    tcp_keepalive: Option<String>,
+    http: Option<String>,
+    no_response_compression: Option<bool>,
+    user_agent: Option<String>,
+    no_http_cache: Option<bool>,
+    archive_raw: Option<bool>,
+    offline: Option<bool>,
+    record: Option<String>,
+    replay: Option<String>,
+    extraction_rules: Option<String>,
+    #[cfg(feature = "scripting")]
+    script: Option<String>,
+    deterministic: Option<String>,
+    durable: Option<bool>,
+    lock_timeout: Option<String>,
+    output_format: Option<String>,
+    #[cfg(any(feature = "s3", feature = "webdav"))]
+    storage: Option<String>,
+    #[cfg(feature = "s3")]
+    s3_endpoint: Option<String>,
+    #[cfg(feature = "s3")]
+    s3_region: Option<String>,
+    #[cfg(feature = "webdav")]
+    storage_password: Option<String>,
+    sync: SyncProfile,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SyncProfile {
+    only: Vec<String>,
+    exclude: Vec<String>,
+    full: Option<bool>,
+    concurrency: Option<usize>,
+    page_size: Option<u16>,
+    chunk_size: Option<u16>,
+    relist_interval: Option<String>,
+    save_summary: Option<bool>,
+    git_commit: Option<bool>,
+    daemon: Option<bool>,
+    interval: Option<String>,
+    digest: Option<String>,
+    digest_format: Option<String>,
+    digest_output: Option<String>,
+    digest_command: Option<String>,
+    hooks: Vec<String>,
+}
+
+// The CLI with the defaults of the selected profile, from --config (or ~/.config/inat/config.toml).
+pub(crate) fn configure(cmd: Command) -> Result<Command, Error> {
+    let path = match option("config", "INAT_CONFIG") {
+        Some(path) => PathBuf::from(path),
+        _ => match config_path() {
+            Some(path) => path,
+            _ => return Ok(cmd),
+        },
+    };
+    let name = option("profile", "INAT_PROFILE");
+    let config = match read_to_string(&path) {
+        Ok(text) => toml::from_str::<Config>(&text)
+            .map_err(|err| Error::BadFile(path.clone(), err.to_string()))?,
+        Err(err) if err.kind() == ErrorKind::NotFound && name.is_none() => return Ok(cmd),
+        Err(err) => return Err(err.into()),
+    };
+
+    let profile = match name {
+        Some(name) => config
+            .profiles
+            .get(&name)
+            .ok_or_else(|| Error::NotFound(format!("profile {} in {}", name, path.display())))?,
+        _ => match config.profiles.get(DEFAULT_PROFILE) {
+            Some(profile) => profile,
+            _ => return Ok(cmd),
+        },
+    };
+
+    Ok(profile.apply(cmd))
+}
+
+impl Profile {
+    fn apply(&self, cmd: Command) -> Command {
+        let sync = &self.sync;
+        let cmd = defaults(
+            cmd,
+            [
+                ("endpoint", one(self.endpoint.clone())),
+                ("api_version", one(self.api_version.clone())),
+                ("data", one(self.data.as_deref().map(expand_home))),
+                ("token", one(self.token.clone())),
+                (
+                    "rate_limit",
+                    one(self.rate_limit.map(|limit| limit.to_string())),
+                ),
+                (
+                    "daily_quota",
+                    one(self.daily_quota.map(|quota| quota.to_string())),
+                ),
+                (
+                    "attempts",
+                    one(self.attempts.map(|attempts| attempts.to_string())),
+                ),
+                (
+                    "breaker_failures",
+                    one(self.breaker_failures.map(|failures| failures.to_string())),
+                ),
+                ("breaker_cool_down", one(self.breaker_cool_down.clone())),
+                ("connect_timeout", one(self.connect_timeout.clone())),
+                ("read_timeout", one(self.read_timeout.clone())),
+                ("proxy", one(self.proxy.clone())),
+                ("ca_cert", one(self.ca_cert.as_deref().map(expand_home))),
+                (
+                    "pool_size",
+                    one(self.pool_size.map(|size| size.to_string())),
+                ),
+                ("pool_idle_timeout", one(self.pool_idle_timeout.clone())),
+                ("tcp_keepalive", one(self.tcp_keepalive.clone())),
+                ("http", one(self.http.clone())),
+                (
+                    "no_response_compression",
+                    one(self.no_response_compression.map(|off| off.to_string())),
+                ),
+                ("user_agent", one(self.user_agent.clone())),
+                (
+                    "no_http_cache",
+                    one(self.no_http_cache.map(|off| off.to_string())),
+                ),
+                (
+                    "archive_raw",
+                    one(self.archive_raw.map(|on| on.to_string())),
+                ),
+                ("offline", one(self.offline.map(|on| on.to_string()))),
+                ("record", one(self.record.as_deref().map(expand_home))),
+                ("replay", one(self.replay.as_deref().map(expand_home))),
+                (
+                    "extraction_rules",
+                    one(self.extraction_rules.as_deref().map(expand_home)),
+                ),
+                ("deterministic", one(self.deterministic.clone())),
+                ("durable", one(self.durable.map(|on| on.to_string()))),
+                ("lock_timeout", one(self.lock_timeout.clone())),
+                ("output_format", one(self.output_format.clone())),
+            ],
+        );
+        #[cfg(any(feature = "s3", feature = "webdav"))]
+        let cmd = defaults(cmd, [("storage", one(self.storage.clone()))]);
+        #[cfg(feature = "s3")]
+        let cmd = defaults(
+            cmd,
+            [
+                ("s3_endpoint", one(self.s3_endpoint.clone())),
+                ("s3_region", one(self.s3_region.clone())),
+            ],
+        );
+        #[cfg(feature = "webdav")]
+        let cmd = defaults(
+            cmd,
+            [("storage_password", one(self.storage_password.clone()))],
+        );
+        #[cfg(feature = "scripting")]
+        let cmd = defaults(
+            cmd,
+            [("script", one(self.script.as_deref().map(expand_home)))],
+        );
+        cmd.mut_subcommand("sync", |sub| {
+            defaults(
+                sub,
+                [
+                    ("user", one(self.user.clone())),
+                    ("only", sync.only.clone()),
+                    ("exclude", sync.exclude.clone()),
+                    ("full", one(sync.full.map(|full| full.to_string()))),
+                    (
+                        "concurrency",
+                        one(sync.concurrency.map(|concurrency| concurrency.to_string())),
+                    ),
+                    (
+                        "page_size",
+                        one(sync.page_size.map(|size| size.to_string())),
+                    ),
+                    (
+                        "chunk_size",
+                        one(sync.chunk_size.map(|size| size.to_string())),
+                    ),
+                    ("relist_interval", one(sync.relist_interval.clone())),
+                    (
+                        "save_summary",
+                        one(sync.save_summary.map(|save| save.to_string())),
+                    ),
+                    (
+                        "git_commit",
+                        one(sync.git_commit.map(|commit| commit.to_string())),
+                    ),
+                    ("daemon", one(sync.daemon.map(|daemon| daemon.to_string()))),
+                    ("interval", one(sync.interval.clone())),
+                    ("digest", one(sync.digest.clone())),
+                    ("digest_format", one(sync.digest_format.clone())),
+                    (
+                        "digest_output",
+                        one(sync.digest_output.as_deref().map(expand_home)),
+                    ),
+                    ("digest_command", one(sync.digest_command.clone())),
+                    ("hooks", sync.hooks.clone()),
+                ],
+            )
+        })
+    }
+}
+
+// Options without a value in the profile are left alone.
+fn defaults<const N: usize>(cmd: Command, vals: [(&'static str, Vec<String>); N]) -> Command {
+    vals.into_iter()
+        .fold(cmd, |cmd, (id, vals)| match vals.is_empty() {
+            true => cmd,
+            // Keep secrets out of --help.
+            _ => cmd.mut_arg(id, |arg: Arg| {
+                arg.default_values(vals)
+                    .hide_default_value(id == "token" || id == "storage_password")
+                    .required(false)
+            }),
+        })
+}
+
+fn one(val: Option<String>) -> Vec<String> {
+    val.into_iter().collect()
+}
+
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), var_os("HOME")) {
+        (Some(rest), Some(home)) => Path::new(&home).join(rest).display().to_string(),
+        _ => path.to_string(),
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    match var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => var_os("HOME").map(|home| Path::new(&home).join(".config")),
+    }
+    .map(|dir| dir.join("inat").join("config.toml"))
+}
+
+// Needed before the command line can be parsed for real, since the profile changes how.
+fn option(long: &str, env: &str) -> Option<String> {
+    let flag = format!("--{}", long);
+    let mut args = args_os()
+        .skip(1)
+        .map(|arg| arg.to_string_lossy().into_owned());
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == flag {
+            return args.next();
+        }
+        if let Some(val) = arg.strip_prefix(&format!("{}=", flag)) {
+            return Some(val.to_string());
+        }
+    }
+
+    var_os(env).map(|val| val.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{ArgMatches, CommandFactory};
+
+    use super::*;
+    use crate::Args;
+
+    const CONFIG: &str = r#"
+[profiles.default]
+rate_limit = 30
+token = "secret"
+
+[profiles.default.sync]
+only = ["observations", "taxa"]
+
+[profiles.work]
+user = "alice"
+data = "~/inat"
+"#;
+
+    fn matches(profile: &str, args: &[&str]) -> ArgMatches {
+        let config: Config = toml::from_str(CONFIG).expect("config");
+        config.profiles[profile]
+            .apply(Args::command())
+            .try_get_matches_from(["inat"].iter().chain(args))
+            .expect("matches")
+    }
+
+    #[test]
+    fn fills_in_the_options_of_the_profile() {
+        let args = matches("default", &["sync", "--user", "bob"]);
+        assert_eq!(args.get_one::<u32>("rate_limit"), Some(&30));
+        assert_eq!(
+            args.get_one::<String>("token").map(String::as_str),
+            Some("secret")
+        );
+        let sync = args.subcommand_matches("sync").expect("sync");
+        let only: Vec<_> = sync.get_many::<String>("only").expect("only").collect();
+        assert_eq!(only, ["observations", "taxa"]);
+    }
+
+    #[test]
+    fn leaves_the_command_line_the_last_word() {
+        let args = matches("work", &["--rate-limit", "10", "sync", "--user", "bob"]);
+        assert_eq!(args.get_one::<u32>("rate_limit"), Some(&10));
+        let sync = args.subcommand_matches("sync").expect("sync");
+        assert_eq!(
+            sync.get_one::<String>("user").map(String::as_str),
+            Some("bob")
+        );
+    }
+
+    #[test]
+    fn makes_required_options_optional() {
+        let args = matches("work", &["sync"]);
+        let sync = args.subcommand_matches("sync").expect("sync");
+        assert_eq!(
+            sync.get_one::<String>("user").map(String::as_str),
+            Some("alice")
+        );
+        let data = args.get_one::<String>("data").expect("data");
+        assert!(data.ends_with("inat") && !data.starts_with('~'), "{}", data);
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(toml::from_str::<Config>("[profiles.default]\nrate = 30\n").is_err());
+        assert!(toml::from_str::<Config>("[profiles.default.sync]\nusr = \"alice\"\n").is_err());
+    }
+}
@@ -0,0 +1,231 @@
+use std::{fs::File, io::BufWriter, path::PathBuf};
+
+use chrono::NaiveDate;
+use clap::{Subcommand, ValueEnum};
+use inat::{Api, Archive, AttributionFormat, Error, Filter};
+use serde::Deserialize;
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct ExportArgs {
+    #[command(flatten)]
+    filter: FilterArgs,
+
+    #[command(subcommand)]
+    format: Export,
+}
+
+// Also read from query strings by the serve command.
+#[derive(clap::Args, Debug, Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct FilterArgs {
+    /// Only observations of this quality grade, e.g. research.
+    #[arg(long, global = true)]
+    quality_grade: Option<String>,
+
+    /// Only observations of this taxon (ID or name) or any below it.
+    #[arg(long, global = true)]
+    taxon: Option<String>,
+
+    /// Only observations made on or after this day, e.g. 2023-05-01.
+    #[arg(long, global = true)]
+    after: Option<NaiveDate>,
+
+    /// Only observations made on or before this day.
+    #[arg(long, global = true)]
+    before: Option<NaiveDate>,
+
+    /// Only observations in this place (ID, or part of the place name).
+    #[arg(long, global = true)]
+    place: Option<String>,
+
+    /// Only observations in this project (ID, slug or title).
+    #[arg(long, global = true)]
+    project: Option<String>,
+
+    /// Only observations with photos.
+    #[arg(long, global = true)]
+    with_photos: bool,
+}
+
+impl FilterArgs {
+    pub(crate) fn filter(&self) -> Filter {
+        let mut filter = Filter::default();
+        filter.quality_grade = self.quality_grade.clone();
+        filter.taxon = self.taxon.clone();
+        filter.after = self.after;
+        filter.before = self.before;
+        filter.place = self.place.clone();
+        filter.project = self.project.clone();
+        filter.with_photos = self.with_photos;
+        filter
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Export {
+    /// License and attribution manifest of photos and observations.
+    Licenses {
+        /// Output file.
+        #[arg(short, long, default_value = "licenses.md")]
+        output: PathBuf,
+
+        /// Output format.
+        #[arg(short, long, default_value = "markdown")]
+        format: AttributionFormatArg,
+    },
+
+    /// HTML map of all observations.
+    Map {
+        /// Output file.
+        #[arg(short, long, default_value = "map.html")]
+        output: PathBuf,
+    },
+
+    /// Atom feed of the most recent observations.
+    Atom {
+        /// Output file.
+        #[arg(short, long, default_value = "feed.xml")]
+        output: PathBuf,
+
+        /// Number of entries.
+        #[arg(short, long, default_value_t = 50)]
+        limit: usize,
+    },
+
+    /// Anki flashcards (deck.csv plus media) of observed taxa.
+    Anki {
+        /// Output directory.
+        #[arg(short, long, default_value = "anki")]
+        output: PathBuf,
+
+        /// Only include taxa of this rank, e.g. species.
+        #[arg(long)]
+        rank: Option<String>,
+
+        /// Only include taxa observed at least this many times.
+        #[arg(long, default_value_t = 1)]
+        min_observations: usize,
+
+        /// Skip downloading the photos.
+        #[arg(long)]
+        no_media: bool,
+    },
+
+    /// iCalendar file with an all-day event per observation.
+    Ics {
+        /// Output file.
+        #[arg(short, long, default_value = "observations.ics")]
+        output: PathBuf,
+
+        /// One event per field day instead of per observation.
+        #[arg(long)]
+        per_day: bool,
+    },
+
+    /// CSV of observation field values, one row per observation.
+    Ofv {
+        /// Observation field ID or name.
+        #[arg(short, long)]
+        field: String,
+
+        /// Output file.
+        #[arg(short, long, default_value = "ofv.csv")]
+        output: PathBuf,
+    },
+
+    /// Render a Tera template with the records of a table.
+    Template {
+        /// Template file, e.g. observation.md.tera.
+        #[arg(short, long)]
+        template: PathBuf,
+
+        /// Table to render.
+        #[arg(long, default_value = "observations")]
+        table: String,
+
+        /// Render once per record, into the output directory.
+        #[arg(long)]
+        per_record: bool,
+
+        /// Output file, or directory with --per-record.
+        #[arg(short, long, default_value = "export")]
+        output: PathBuf,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum AttributionFormatArg {
+    Csv,
+    Markdown,
+}
+
+impl ExportArgs {
+    // Whether photos get downloaded, through an Api.
+    pub(crate) fn downloads(&self) -> bool {
+        matches!(
+            self.format,
+            Export::Anki {
+                no_media: false,
+                ..
+            }
+        )
+    }
+}
+
+// Without an Api, e.g. offline, Anki decks go without the photos not downloaded yet.
+pub(crate) async fn export(
+    archive: &Archive,
+    args: &ExportArgs,
+    api: Option<&Api>,
+) -> Result<(), Error> {
+    let filter = &args.filter.filter();
+    match &args.format {
+        Export::Licenses { output, format } => {
+            let format = match format {
+                AttributionFormatArg::Csv => AttributionFormat::Csv,
+                AttributionFormatArg::Markdown => AttributionFormat::Markdown,
+            };
+            archive.export_licenses(&mut BufWriter::new(File::create(output)?), format, filter)
+        }
+        Export::Map { output } => {
+            archive.export_map(&mut BufWriter::new(File::create(output)?), filter)
+        }
+        Export::Atom { output, limit } => {
+            archive.export_atom(&mut BufWriter::new(File::create(output)?), *limit, filter)
+        }
+        Export::Anki {
+            output,
+            rank,
+            min_observations,
+            no_media,
+        } => {
+            archive
+                .export_anki(
+                    output,
+                    rank.as_deref(),
+                    *min_observations,
+                    api.filter(|_| !no_media),
+                    filter,
+                )
+                .await
+        }
+        Export::Ics { output, per_day } => {
+            archive.export_ics(&mut BufWriter::new(File::create(output)?), *per_day, filter)
+        }
+        Export::Ofv { field, output } => {
+            archive.export_ofv(&mut BufWriter::new(File::create(output)?), field, filter)
+        }
+        Export::Template {
+            template,
+            table,
+            per_record,
+            output,
+        } => {
+            if *per_record {
+                archive.export_template_records(template, table, output, filter)
+            } else {
+                archive.export_template_table(template, table, output, filter)
+            }
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use inat::{Api, Error};
+use keyring::Entry;
+use rpassword::prompt_password;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+const SERVICE: &str = "inat";
+
+// What gets saved in the keyring, per API endpoint.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Credentials {
+    // Pasted from the website, expires after 24 hours.
+    ApiToken(String),
+    // From logging in with a password, exchanged for a fresh API token on every run.
+    AccessToken { site: String, token: String },
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct LoginArgs {
+    /// iNat username, to log in with the password instead of pasting an API token.
+    #[arg(short, long, env, requires = "client_id")]
+    user: Option<String>,
+
+    /// OAuth application ID, see https://www.inaturalist.org/oauth/applications.
+    #[arg(long, env = "INAT_CLIENT_ID")]
+    client_id: Option<String>,
+
+    /// OAuth application secret.
+    #[arg(long, env = "INAT_CLIENT_SECRET", hide_env_values = true)]
+    client_secret: Option<String>,
+
+    /// The iNaturalist website, for logging in and getting API tokens.
+    #[arg(long, env, default_value = "https://www.inaturalist.org")]
+    site: String,
+}
+
+pub(crate) async fn login(args: &LoginArgs, api: Api, endpoint: &str) -> Result<(), Error> {
+    let (credentials, token) = match (&args.user, &args.client_id) {
+        (Some(user), Some(client_id)) => {
+            let password = prompt_password(format!("Password for {}: ", user))?;
+            let secret = args.client_secret.as_deref().unwrap_or_default();
+            let access_token = api
+                .oauth_token(&args.site, client_id, secret, user, &password)
+                .await?;
+            let token = api.api_token(&args.site, &access_token).await?;
+            let credentials = Credentials::AccessToken {
+                site: args.site.clone(),
+                token: access_token,
+            };
+            (credentials, token)
+        }
+        _ => {
+            eprintln!("Get an API token from {}/users/api_token", args.site);
+            let token = prompt_password("API token: ")?.trim().to_string();
+            (Credentials::ApiToken(token.clone()), token)
+        }
+    };
+
+    let login = api.with_token(&token)?.me().await?;
+    entry(endpoint)?
+        .set_password(&serde_json::to_string(&credentials)?)
+        .map_err(keyring_error)?;
+    info!("logged in as {}", login);
+
+    Ok(())
+}
+
+pub(crate) fn logout(endpoint: &str) -> Result<(), Error> {
+    match entry(endpoint)?.delete_credential() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(err) => Err(keyring_error(err)),
+    }
+}
+
+// The API token saved by the last login, if any. Failing to get one is not fatal: commands that
+// need it will fail with a more useful error.
+pub(crate) async fn saved_token(api: &Api, endpoint: &str) -> Option<String> {
+    let saved = match entry(endpoint).and_then(|entry| entry.get_password().map_err(keyring_error))
+    {
+        Ok(saved) => saved,
+        // Also the case on machines without a keyring.
+        Err(err) => {
+            debug!("no saved login: {}", err);
+            return None;
+        }
+    };
+
+    let res = match serde_json::from_str(&saved) {
+        Ok(Credentials::ApiToken(token)) => return Some(token),
+        Ok(Credentials::AccessToken { site, token }) => api.api_token(&site, &token).await,
+        Err(err) => Err(err.into()),
+    };
+    match res {
+        Ok(token) => Some(token),
+        Err(err) => {
+            warn!("saved login: {}; try inat login again", err);
+            None
+        }
+    }
+}
+
+fn entry(endpoint: &str) -> Result<Entry, Error> {
+    Entry::new(SERVICE, endpoint).map_err(keyring_error)
+}
+
+fn keyring_error(err: keyring::Error) -> Error {
+    Error::Keyring(err.to_string())
+}
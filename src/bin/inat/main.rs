@@ -0,0 +1,958 @@
+mod config;
+mod export;
+mod login;
+mod serve;
+mod sync;
+mod tui;
+
+use std::{
+    fs::{create_dir_all, read, write},
+    io::{stderr, stdout, Cursor},
+    path::{Path, PathBuf},
+    process::exit,
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use clap_mangen::Man;
+#[cfg(feature = "s3")]
+use inat::S3Storage;
+#[cfg(feature = "scripting")]
+use inat::Script;
+#[cfg(feature = "webdav")]
+use inat::WebDavStorage;
+use inat::{
+    Api, ApiVersion, Archive, CachePolicy, Cassette, DataLock, Error, ErrorKind, ExtractionRules,
+    FixedClock, HttpVersion, Layout, QueryFormat, Storage, SyncSummary,
+};
+use serde::Serialize;
+use tokio::task::spawn_blocking;
+use tracing::{error, info, subscriber::set_global_default, warn, Level};
+use tracing_subscriber::FmtSubscriber;
+
+use config::configure;
+use export::{export, ExportArgs, FilterArgs};
+use login::{login, logout, saved_token, LoginArgs};
+use serve::{serve, ServeArgs};
+use sync::{sync, SyncArgs};
+use tui::tui;
+
+/// CLI iNaturalist sync utility.
+/// Stores a copy of one's personal inaturalist data.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None, after_help = EXIT_STATUS)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+
+    /// iNat API endpoint.
+    #[arg(
+        short,
+        long,
+        env,
+        default_value = "https://api.inaturalist.org/v1",
+        global = true
+    )]
+    endpoint: String,
+
+    /// API version; 2 asks for only the fields the syncs use, and switches an --endpoint ending in
+    /// /v1 over to /v2.
+    #[arg(long, env, default_value = "1", global = true)]
+    api_version: ApiVersionArg,
+
+    /// Data directory for saving the results.
+    #[arg(short, long, env, default_value = "data", global = true)]
+    data: String,
+
+    /// API token, from https://www.inaturalist.org/users/api_token; defaults to the saved login.
+    #[arg(long, env = "INAT_API_TOKEN", hide_env_values = true, global = true)]
+    token: Option<String>,
+
+    /// Maximum number of API requests per minute.
+    #[arg(long, env, default_value_t = 60, global = true)]
+    rate_limit: u32,
+
+    /// Maximum number of API requests per UTC day, over all runs; 0 for no limit.
+    #[arg(long, env, default_value_t = 10_000, global = true)]
+    daily_quota: u32,
+
+    /// Tries per API request, retrying server errors and connection problems.
+    #[arg(long, env, default_value_t = 5, global = true)]
+    attempts: u32,
+
+    /// Consecutive failed API requests after which to pause all of them; 0 to never pause.
+    #[arg(long, env, default_value_t = 3, global = true)]
+    breaker_failures: u32,
+
+    /// How long to pause API requests for after too many failures, before trying again.
+    #[arg(long, env, default_value = "5m", value_parser = humantime::parse_duration, global = true)]
+    breaker_cool_down: Duration,
+
+    /// How long to wait for connecting to the API.
+    #[arg(long, env, default_value = "30s", value_parser = humantime::parse_duration, global = true)]
+    connect_timeout: Duration,
+
+    /// How long to wait for data from the API, before retrying the request.
+    #[arg(long, env, default_value = "60s", value_parser = humantime::parse_duration, global = true)]
+    read_timeout: Duration,
+
+    /// HTTP(S) or SOCKS5 proxy URL, e.g. socks5://localhost:1080; defaults to HTTPS_PROXY.
+    #[arg(long, env = "INAT_PROXY", global = true)]
+    proxy: Option<String>,
+
+    /// PEM file with extra root certificates to trust, e.g. for a corporate proxy.
+    #[arg(long, env, global = true)]
+    ca_cert: Option<PathBuf>,
+
+    /// Idle connections to keep open to the API; unlimited by default.
+    #[arg(long, env, global = true)]
+    pool_size: Option<usize>,
+
+    /// How long to keep idle connections to the API open.
+    #[arg(long, env, default_value = "90s", value_parser = humantime::parse_duration, global = true)]
+    pool_idle_timeout: Duration,
+
+    /// Interval of TCP keep-alive probes on idle connections; off by default.
+    #[arg(long, env, value_parser = humantime::parse_duration, global = true)]
+    tcp_keepalive: Option<Duration>,
+
+    /// HTTP version to use; auto prefers HTTP/2 when the server offers it.
+    #[arg(long, env, default_value = "auto", global = true)]
+    http: HttpVersionArg,
+
+    /// Don't ask for compressed API responses.
+    #[arg(long, env, global = true)]
+    no_response_compression: bool,
+
+    /// User-Agent header to send instead of inat/{version}.
+    #[arg(long, env, global = true)]
+    user_agent: Option<String>,
+
+    /// Don't keep API responses in the data directory's HTTP cache.
+    #[arg(long, env, global = true)]
+    no_http_cache: bool,
+
+    /// Also keep every API response as received, compressed, to replay with normalise --raw.
+    #[arg(long, env, global = true)]
+    archive_raw: bool,
+
+    /// Don't connect to the API or the storage: commands reading the data directory work as
+    /// usual, syncs fail unless the HTTP cache or a --replay cassette has the answers.
+    #[arg(long, env, global = true)]
+    offline: bool,
+
+    /// Write every API response to this cassette, e.g. to attach to a bug report.
+    #[arg(long, env, global = true)]
+    record: Option<PathBuf>,
+
+    /// Answer API requests from this cassette, recorded with --record, instead of the API.
+    #[arg(long, env, global = true)]
+    replay: Option<PathBuf>,
+
+    /// YAML file with extraction rules, for nested records the built-in tables don't cover.
+    #[arg(long, env, global = true)]
+    extraction_rules: Option<PathBuf>,
+
+    /// Rhai script to run on every record before it's written, e.g. to drop or rename fields.
+    #[cfg(feature = "scripting")]
+    #[arg(long, env, global = true)]
+    script: Option<PathBuf>,
+
+    /// Stop the clock at this time, e.g. 2024-01-01T00:00:00Z, and turn off retry jitter and
+    /// concurrent sync stages: syncs of the same responses (see --replay) write the same files.
+    #[arg(long, env = "INAT_DETERMINISTIC", global = true)]
+    deterministic: Option<DateTime<Utc>>,
+
+    /// Fsync what gets written to the data directory, so that a power loss can't truncate it.
+    #[arg(long, env, global = true)]
+    durable: bool,
+
+    /// Where to keep the data directory in sync with: s3://bucket/prefix, with the credentials
+    /// from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY; or a WebDAV folder, e.g. on Nextcloud:
+    /// https://{user}@cloud.example.com/remote.php/dav/files/{user}/inat.
+    /// The data directory still holds a full copy, restored from the storage where missing, so it
+    /// needs the space for all of it; nothing is read through from the storage.
+    #[cfg(any(feature = "s3", feature = "webdav"))]
+    #[arg(long, env, global = true)]
+    storage: Option<String>,
+
+    /// S3 endpoint of the storage bucket, e.g. http://localhost:9000 for MinIO.
+    #[cfg(feature = "s3")]
+    #[arg(long, env, default_value = "https://s3.amazonaws.com", global = true)]
+    s3_endpoint: String,
+
+    /// Region of the storage bucket.
+    #[cfg(feature = "s3")]
+    #[arg(long, env, default_value = "us-east-1", global = true)]
+    s3_region: String,
+
+    /// Password of the WebDAV storage user, e.g. a Nextcloud app password.
+    #[cfg(feature = "webdav")]
+    #[arg(long, env, hide_env_values = true, global = true)]
+    storage_password: Option<String>,
+
+    /// How long to wait for another inat process writing to the data directory; 0 to not wait.
+    #[arg(long, env, default_value = "0s", value_parser = humantime::parse_duration, global = true)]
+    lock_timeout: Duration,
+
+    /// What to print when done: just the log, or also a JSON result as the last line of stdout,
+    /// the log going to stderr then.
+    #[arg(
+        long,
+        env = "INAT_OUTPUT_FORMAT",
+        default_value = "text",
+        global = true
+    )]
+    output_format: OutputArg,
+
+    /// Config file, defaults to ~/.config/inat/config.toml.
+    #[arg(long, env = "INAT_CONFIG", global = true)]
+    config: Option<PathBuf>,
+
+    /// Config file profile with defaults for the options, "default" unless given.
+    #[arg(long, env = "INAT_PROFILE", global = true)]
+    profile: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Save an API token in the keyring, used by all commands talking to the API.
+    Login(LoginArgs),
+
+    /// Remove the saved API token.
+    Logout,
+
+    /// Sync the user's observations (and everything they reference) into the data directory.
+    Sync(SyncArgs),
+
+    /// List the cached observations matching the filters.
+    Query {
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        /// Output format.
+        #[arg(short, long, default_value = "table")]
+        format: QueryFormatArg,
+
+        /// Print at most this many observations.
+        #[arg(short, long)]
+        limit: Option<usize>,
+    },
+
+    /// Browse the cached observations, taxa and identifications in the terminal.
+    Tui,
+
+    /// Serve a read-only JSON API over the cache, e.g. /api/observations?taxon=anas.
+    Serve(ServeArgs),
+
+    /// Open an observation on iNaturalist in the browser.
+    Open {
+        /// Observation ID, or words matching a single cached observation, e.g. "mallard 2023-05".
+        #[arg(required = true)]
+        query: Vec<String>,
+
+        /// Only print the URL.
+        #[arg(short, long)]
+        print: bool,
+    },
+
+    /// Summary statistics of the cached observations.
+    Stats {
+        /// Output format.
+        #[arg(short, long, default_value = "table")]
+        format: QueryFormatArg,
+
+        /// Number of most observed taxa to list.
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+
+    /// When each cached table was last synced, and how much of it is stale.
+    Status {
+        /// Records synced longer ago than this count as stale.
+        #[arg(long, default_value = "7days", value_parser = humantime::parse_duration)]
+        max_age: Duration,
+
+        /// Output format.
+        #[arg(short, long, default_value = "table")]
+        format: QueryFormatArg,
+    },
+
+    /// The life list: the first observation of each taxon, plus distinct taxa per rank.
+    Lifelist {
+        /// Output format.
+        #[arg(short, long, default_value = "table")]
+        format: QueryFormatArg,
+
+        /// Compare with the server's species counts of this (synced) user instead.
+        #[arg(long, value_name = "USER")]
+        compare: Option<String>,
+    },
+
+    /// Run an SQL (SQLite) query over the cached tables, e.g.
+    /// "SELECT taxon, count(*) FROM observations GROUP BY 1".
+    Sql {
+        /// The query; tables are loaded as needed, one column per top level key.
+        query: String,
+
+        /// Output format.
+        #[arg(short, long, default_value = "table")]
+        format: QueryFormatArg,
+    },
+
+    /// Build the full-text search index of the cached observations.
+    Index,
+
+    /// Search the observations, e.g. "mallard", "taxon:anas AND place:budapest".
+    Search {
+        /// Query, searching descriptions, comments, places and taxon names.
+        query: String,
+
+        /// Print at most this many observations.
+        #[arg(short, long, default_value_t = 20)]
+        limit: usize,
+    },
+
+    /// Export the archive to other formats.
+    Export(ExportArgs),
+
+    /// Import data from other sources into the archive.
+    #[command(subcommand)]
+    Import(Import),
+
+    /// Re-run the normaliser without network access, applying extraction changes to synced data.
+    Normalise {
+        /// Saved /observations API responses (JSON) to normalise, instead of the cached ones.
+        responses: Vec<PathBuf>,
+
+        /// Replay the responses kept with --archive-raw instead, observations and taxa.
+        #[arg(long, conflicts_with = "responses")]
+        raw: bool,
+    },
+
+    /// Check the API, the token, the clock and the data directory, suggesting fixes.
+    Doctor {
+        /// Output format.
+        #[arg(short, long, default_value = "table")]
+        format: QueryFormatArg,
+    },
+
+    /// Check that the cached files parse and that all references between them resolve.
+    Verify {
+        /// Only check the references, in any layout.
+        #[arg(long)]
+        refs: bool,
+    },
+
+    /// Remove cached records no longer referenced by any observation.
+    Gc {
+        /// Only list what would be removed.
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+
+    /// Print the cache layout, or convert the cached tables to another one.
+    Layout {
+        /// The layout to convert to.
+        layout: Option<LayoutArg>,
+
+        /// Compress the cached tables with zstd at this level, as .yaml.zst files.
+        #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(i32).range(1..=22))]
+        compress: Option<i32>,
+
+        /// Write the cached tables uncompressed again.
+        #[arg(long, conflicts_with = "compress")]
+        no_compress: bool,
+    },
+
+    /// JSON Schema of the cached tables.
+    Schema {
+        /// Only print the schema of this table.
+        table: Option<String>,
+
+        /// Output directory for the schemas of all tables.
+        #[arg(short, long, default_value = "schema")]
+        output: PathBuf,
+    },
+
+    /// Print the shell completion script, e.g. `inat completions bash > /etc/bash_completion.d/inat`.
+    Completions {
+        /// The shell to complete in.
+        shell: Shell,
+    },
+
+    /// Write man pages of inat and all of its subcommands.
+    Man {
+        /// Output directory.
+        #[arg(short, long, default_value = "man")]
+        output: PathBuf,
+    },
+
+    /// Debugging helpers.
+    #[command(subcommand)]
+    Debug(Debug),
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum QueryFormatArg {
+    Table,
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputArg {
+    Text,
+    Json,
+}
+
+// Printed when done with --output-format json; the sync summary for syncs.
+#[derive(Serialize)]
+struct Outcome {
+    ok: bool,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    summary: Option<SyncSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorReport>,
+}
+
+#[derive(Serialize)]
+struct ErrorReport {
+    kind: ErrorKind,
+    exit_code: i32,
+    message: String,
+    retryable: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ApiVersionArg {
+    #[value(name = "1")]
+    V1,
+    #[value(name = "2")]
+    V2,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum HttpVersionArg {
+    Auto,
+    #[value(name = "1.1")]
+    Http1,
+    #[value(name = "2")]
+    Http2,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum LayoutArg {
+    /// One file per record, {table}/{id}.yaml.
+    Directory,
+    /// One file per table, {table}.yaml, kept in memory whole; for small archives.
+    File,
+    /// One file per record, in subdirectories by ID, {table}/{aa}/{bb}/{id}.yaml.
+    Sharded,
+}
+
+#[derive(Serialize)]
+struct LayoutInfo {
+    layout: Layout,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression: Option<i32>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Import {
+    /// Seed the cache from the CSV export, so that the next sync can skip listing observations.
+    Csv {
+        /// The exported CSV file.
+        export: PathBuf,
+    },
+
+    /// Link GBIF occurrences from a Darwin Core Archive download and report mismatches.
+    Gbif {
+        /// The downloaded DwC-A zip.
+        archive: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum Debug {
+    /// Zip a redacted API response and its normalised output, for attaching to bug reports.
+    DumpFixture {
+        /// Observation ID.
+        id: u64,
+
+        /// Output file, defaults to fixture-<id>.zip.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+const EXIT_STATUS: &str = "\
+Exit status:
+  0    done
+  1    any other error, e.g. a bad response from the API or a file that can't be written
+  2    bad command line options
+  3    the API rejected the token, or wants one
+  4    out of API requests, either the daily quota or the API's rate limit
+  5    network error, with the API or the storage
+  6    corrupt cache in the data directory
+  7    internal error
+  130  cancelled with ctrl-c, the next sync resumes where it stopped";
+
+#[tokio::main]
+async fn main() {
+    let args = configure(Args::command())
+        .map(|cmd| Args::from_arg_matches(&cmd.get_matches()).unwrap_or_else(|err| err.exit()));
+    let output = args
+        .as_ref()
+        .map_or(OutputArg::Text, |args| args.output_format);
+    let subscriber = FmtSubscriber::builder().with_max_level(Level::INFO);
+    match output {
+        OutputArg::Json => set_global_default(subscriber.with_writer(stderr).finish()),
+        _ => set_global_default(subscriber.finish()),
+    }
+    .expect("failed to set global default subscriber");
+
+    let res = match args {
+        Ok(args) => app(&args).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = &res {
+        error!("{}", err);
+    }
+    let code = res.as_ref().err().map_or(0, exit_code);
+    if output == OutputArg::Json {
+        let outcome = match res {
+            Ok(summary) => Outcome {
+                ok: true,
+                summary,
+                error: None,
+            },
+            Err(err) => Outcome {
+                ok: false,
+                summary: None,
+                error: Some(ErrorReport {
+                    kind: err.kind(),
+                    exit_code: code,
+                    message: err.to_string(),
+                    retryable: err.is_retryable(),
+                }),
+            },
+        };
+        println!(
+            "{}",
+            serde_json::to_string(&outcome).expect("outcome not serialisable")
+        );
+    }
+    if code != 0 {
+        exit(code);
+    }
+}
+
+// As in EXIT_STATUS.
+fn exit_code(err: &Error) -> i32 {
+    match err.kind() {
+        ErrorKind::Auth => 3,
+        ErrorKind::Quota => 4,
+        ErrorKind::Network => 5,
+        ErrorKind::Cache => 6,
+        ErrorKind::Internal => 7,
+        ErrorKind::Cancelled => 130,
+        _ => 1,
+    }
+}
+
+// The sync summary for syncs, None for the other commands.
+async fn app(args: &Args) -> Result<Option<SyncSummary>, Error> {
+    // Held until done by the commands writing to the data directory.
+    let _lock = match writes_data(&args.command) {
+        true => Some(DataLock::acquire(&args.data, args.lock_timeout).await?),
+        _ => None,
+    };
+    let mut archive = Archive::new(&args.data)?.with_durable(args.durable);
+    if let Some(time) = args.deterministic {
+        archive = archive
+            .with_clock(FixedClock(time))
+            .with_deterministic(true);
+    }
+    // Restored from the storage once, here, which blocks; the Api shares the store.
+    if let Some(storage) = storage(args)? {
+        archive = spawn_blocking(move || archive.with_storage(storage)).await??;
+    }
+    if let Some(path) = &args.extraction_rules {
+        archive = archive.with_extractor(ExtractionRules::from_file(path)?);
+    }
+    #[cfg(feature = "scripting")]
+    if let Some(path) = &args.script {
+        archive = archive.with_extractor(Script::from_file(path)?);
+    }
+    match &args.command {
+        Command::Login(login_args) => {
+            login(login_args, client(args, &archive).await?, &args.endpoint).await
+        }
+        Command::Logout => logout(&args.endpoint),
+        Command::Sync(sync_args) => {
+            return sync(sync_args, api(args, &archive).await?, &args.data).await;
+        }
+        Command::Query {
+            filter,
+            format,
+            limit,
+        } => archive.query(
+            &mut stdout().lock(),
+            &filter.filter(),
+            query_format(*format),
+            *limit,
+        ),
+        Command::Tui => tui(&archive),
+        Command::Open { query, print } => {
+            let url = archive.observation_url(archive.resolve_observation(&query.join(" "))?)?;
+            match print {
+                true => println!("{}", url),
+                _ => open::that(&url)?,
+            }
+            Ok(())
+        }
+        Command::Serve(serve_args) => serve(archive, serve_args).await,
+        Command::Stats { format, top } => {
+            let stats = archive.stats(*top)?;
+            match format {
+                QueryFormatArg::Table => stats.write_table(&mut stdout().lock()),
+                QueryFormatArg::Json => Ok(serde_json::to_writer_pretty(stdout(), &stats)?),
+            }
+        }
+        Command::Status { max_age, format } => {
+            let status = archive.status(*max_age)?;
+            match format {
+                QueryFormatArg::Table => status.write_table(&mut stdout().lock()),
+                QueryFormatArg::Json => Ok(serde_json::to_writer_pretty(stdout(), &status)?),
+            }
+        }
+        Command::Lifelist { format, compare } => {
+            let lifelist = archive.lifelist()?;
+            let diff = match compare {
+                Some(user) => {
+                    Some(lifelist.compare(&api(args, &archive).await?.species_counts(user).await?))
+                }
+                _ => None,
+            };
+            match (diff, format) {
+                (Some(diff), QueryFormatArg::Table) => diff.write_table(&mut stdout().lock()),
+                (Some(diff), QueryFormatArg::Json) => {
+                    Ok(serde_json::to_writer_pretty(stdout(), &diff)?)
+                }
+                (_, QueryFormatArg::Table) => lifelist.write_table(&mut stdout().lock()),
+                (_, QueryFormatArg::Json) => Ok(serde_json::to_writer_pretty(stdout(), &lifelist)?),
+            }
+        }
+        Command::Sql { query, format } => {
+            archive.sql(&mut stdout().lock(), query, query_format(*format))
+        }
+        Command::Index => {
+            info!("indexed {} observations", archive.index()?);
+            Ok(())
+        }
+        Command::Search { query, limit } => archive.search(&mut stdout().lock(), query, *limit),
+        Command::Export(export_args) => {
+            let api = match export_args.downloads() && !args.offline {
+                true => Some(client(args, &archive).await?),
+                _ => None,
+            };
+            export(&archive, export_args, api.as_ref()).await
+        }
+        Command::Import(Import::Csv { export }) => {
+            let report = archive.import_csv(export)?;
+            Ok(serde_yaml::to_writer(stdout(), &report)?)
+        }
+        Command::Import(Import::Gbif { archive: path }) => {
+            let report = archive.import_gbif(path)?;
+            Ok(serde_yaml::to_writer(stdout(), &report)?)
+        }
+        Command::Normalise { raw: true, .. } => {
+            let count = archive.replay_raw()?;
+            info!("normalised {} records from the raw archive", count);
+            Ok(())
+        }
+        Command::Normalise { responses, .. } => {
+            let count = match responses.is_empty() {
+                true => archive.normalise()?,
+                _ => archive.normalise_responses(responses)?,
+            };
+            info!("normalised {} observations", count);
+            for (table, count) in archive.verify_refs()?.dangling {
+                warn!("{}: {} dangling references", table, count);
+            }
+            Ok(())
+        }
+        Command::Doctor { format } => {
+            let api = client(args, &archive).await?;
+            let report = api.doctor(token(args, &api).await.as_deref()).await;
+            if !report.is_ok() {
+                warn!("some checks failed");
+            }
+            match format {
+                QueryFormatArg::Table => report.write_table(&mut stdout().lock()),
+                QueryFormatArg::Json => Ok(serde_json::to_writer_pretty(stdout(), &report)?),
+            }
+        }
+        Command::Verify { refs } => {
+            let report = match refs {
+                true => archive.verify_refs()?,
+                _ => archive.verify()?,
+            };
+            if !report.problems.is_empty() {
+                warn!("found {} problems", report.problems.len());
+            }
+            Ok(serde_yaml::to_writer(stdout(), &report)?)
+        }
+        Command::Gc { dry_run } => Ok(serde_yaml::to_writer(stdout(), &archive.gc(*dry_run)?)?),
+        Command::Layout {
+            layout: None,
+            compress: None,
+            no_compress: false,
+        } => Ok(serde_yaml::to_writer(
+            stdout(),
+            &LayoutInfo {
+                layout: archive.layout(),
+                compression: archive.compression(),
+            },
+        )?),
+        Command::Layout {
+            layout,
+            compress,
+            no_compress,
+        } => {
+            let layout = match layout {
+                Some(LayoutArg::Directory) => Layout::Directory,
+                Some(LayoutArg::File) => Layout::File,
+                Some(LayoutArg::Sharded) => Layout::Sharded,
+                _ => archive.layout(),
+            };
+            let compression = match (compress, no_compress) {
+                (Some(level), _) => Some(*level),
+                (_, true) => None,
+                _ => archive.compression(),
+            };
+            archive.convert(layout, compression)
+        }
+        Command::Schema {
+            table: Some(table), ..
+        } => Ok(serde_json::to_writer_pretty(
+            stdout(),
+            &Archive::schema(table)?,
+        )?),
+        Command::Schema {
+            table: None,
+            output,
+        } => {
+            create_dir_all(output)?;
+            for table in Archive::tables() {
+                let schema = serde_json::to_string_pretty(&Archive::schema(table)?)?;
+                write(output.join(format!("{}.schema.json", table)), schema)?;
+            }
+            Ok(())
+        }
+        Command::Completions { shell } => {
+            let mut cmd = Args::command();
+            let name = cmd.get_name().to_string();
+            clap_complete::generate(*shell, &mut cmd, name, &mut stdout());
+            Ok(())
+        }
+        Command::Man { output } => {
+            create_dir_all(output)?;
+            let mut cmd = Args::command();
+            cmd.build();
+            write_man(&cmd, output)
+        }
+        Command::Debug(Debug::DumpFixture { id, output }) => {
+            let output = match output {
+                Some(output) => output.to_owned(),
+                _ => PathBuf::from(format!("fixture-{}.zip", id)),
+            };
+            let mut buf = Cursor::new(vec![]);
+            api(args, &archive)
+                .await?
+                .dump_fixture(*id, &mut buf)
+                .await?;
+            Ok(write(output, buf.into_inner())?)
+        }
+    }
+    .map(|()| None)
+}
+
+// Commands writing to the data directory, including those that build an Api: its HTTP cache and
+// request quota live there too.
+fn writes_data(command: &Command) -> bool {
+    match command {
+        Command::Sync(_)
+        | Command::Import(_)
+        | Command::Normalise { .. }
+        | Command::Index
+        | Command::Login(_)
+        | Command::Doctor { .. }
+        | Command::Export(_)
+        | Command::Debug(_) => true,
+        Command::Lifelist { compare, .. } => compare.is_some(),
+        Command::Gc { dry_run } => !dry_run,
+        Command::Layout {
+            layout,
+            compress,
+            no_compress,
+        } => layout.is_some() || compress.is_some() || *no_compress,
+        _ => false,
+    }
+}
+
+fn api_version(args: &Args) -> ApiVersion {
+    match args.api_version {
+        ApiVersionArg::V1 => ApiVersion::V1,
+        ApiVersionArg::V2 => ApiVersion::V2,
+    }
+}
+
+// Api sending the given token, or the saved one.
+async fn api(args: &Args, archive: &Archive) -> Result<Api, Error> {
+    let api = client(args, archive).await?;
+    match token(args, &api).await {
+        Some(token) => api.with_token(&token),
+        _ => Ok(api),
+    }
+}
+
+// Api without a token, e.g. to log in with. Shares the archive's store, restored from the storage
+// already if there is one.
+async fn client(args: &Args, archive: &Archive) -> Result<Api, Error> {
+    let mut builder = Api::builder()
+        .base_url(&args.endpoint)
+        .archive(archive)
+        .timeouts(args.connect_timeout, args.read_timeout)
+        .rate_limits(args.rate_limit, args.daily_quota)
+        .cache_policy(match args.no_http_cache {
+            true => CachePolicy::Disabled,
+            _ => CachePolicy::Enabled,
+        });
+    if let Some(user_agent) = &args.user_agent {
+        builder = builder.user_agent(user_agent);
+    }
+    let mut api = builder
+        .build()?
+        .with_api_version(api_version(args))
+        .with_connection_pool(args.pool_size.unwrap_or(usize::MAX), args.pool_idle_timeout)?
+        .with_http_version(match args.http {
+            HttpVersionArg::Auto => HttpVersion::Auto,
+            HttpVersionArg::Http1 => HttpVersion::Http1,
+            HttpVersionArg::Http2 => HttpVersion::Http2,
+        })?
+        .with_response_compression(!args.no_response_compression)?
+        .with_attempts(args.attempts)
+        .with_circuit_breaker(args.breaker_failures, args.breaker_cool_down)
+        .with_raw_archive(args.archive_raw)
+        .with_offline(args.offline);
+    if let Some(interval) = args.tcp_keepalive {
+        api = api.with_tcp_keepalive(interval)?;
+    }
+    if let Some(proxy) = &args.proxy {
+        api = api.with_proxy(proxy)?;
+    }
+    if let Some(path) = &args.ca_cert {
+        api = api.with_root_certificates(&read(path)?)?;
+    }
+    if let Some(path) = &args.record {
+        api = api.with_recording(path)?;
+    }
+    if let Some(path) = &args.replay {
+        api = api.with_middleware(Cassette::from_file(path)?);
+    }
+    if let Some(path) = &args.extraction_rules {
+        api = api.with_extractor(ExtractionRules::from_file(path)?);
+    }
+    #[cfg(feature = "scripting")]
+    if let Some(path) = &args.script {
+        api = api.with_extractor(Script::from_file(path)?);
+    }
+
+    Ok(api)
+}
+
+#[cfg(any(feature = "s3", feature = "webdav"))]
+fn storage(args: &Args) -> Result<Option<Arc<dyn Storage>>, Error> {
+    let url = match &args.storage {
+        Some(url) if args.offline => return Err(Error::Offline(url.clone())),
+        Some(url) => url,
+        _ => return Ok(None),
+    };
+
+    #[cfg(feature = "s3")]
+    if let Some(path) = url.strip_prefix("s3://") {
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        return Ok(Some(Arc::new(S3Storage::from_env(
+            &args.s3_endpoint,
+            &args.s3_region,
+            bucket,
+            prefix,
+        )?)));
+    }
+
+    #[cfg(feature = "webdav")]
+    if url.starts_with("https://") || url.starts_with("http://") {
+        return Ok(Some(Arc::new(webdav_storage(url, args)?)));
+    }
+
+    Err(Error::Storage(format!("{}: unsupported storage", url)))
+}
+
+// The user is taken from the URL, and so is the password unless given separately.
+#[cfg(feature = "webdav")]
+fn webdav_storage(url: &str, args: &Args) -> Result<WebDavStorage, Error> {
+    let mut url: url::Url = url.parse()?;
+    let user = url.username().to_string();
+    let password = args
+        .storage_password
+        .clone()
+        .or(url.password().map(str::to_string));
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+
+    let storage = WebDavStorage::new(url.as_str())?;
+    Ok(match (user.as_str(), password) {
+        ("", None) => storage,
+        (user, password) => storage.with_login(user, &password.unwrap_or_default()),
+    })
+}
+
+#[cfg(not(any(feature = "s3", feature = "webdav")))]
+fn storage(_: &Args) -> Result<Option<Arc<dyn Storage>>, Error> {
+    Ok(None)
+}
+
+async fn token(args: &Args, api: &Api) -> Option<String> {
+    match &args.token {
+        Some(token) => Some(token.clone()),
+        _ => saved_token(api, &args.endpoint).await,
+    }
+}
+
+// One page per command, named like git's: inat-export-map.1.
+fn write_man(cmd: &clap::Command, dir: &Path) -> Result<(), Error> {
+    let mut page = vec![];
+    Man::new(cmd.clone()).render(&mut page)?;
+    write(dir.join(format!("{}.1", cmd.get_name())), page)?;
+
+    for sub in cmd
+        .get_subcommands()
+        .filter(|sub| !sub.is_hide_set() && sub.get_name() != "help")
+    {
+        let name = format!("{}-{}", cmd.get_name(), sub.get_name());
+        write_man(&sub.clone().name(name), dir)?;
+    }
+
+    Ok(())
+}
+
+fn query_format(format: QueryFormatArg) -> QueryFormat {
+    match format {
+        QueryFormatArg::Table => QueryFormat::Table,
+        QueryFormatArg::Json => QueryFormat::Json,
+    }
+}
@@ -0,0 +1,150 @@
+use std::{collections::BTreeMap, net::SocketAddr, path::PathBuf, sync::Arc};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use inat::{Archive, Error, ErrorKind};
+use serde::Deserialize;
+use serde_json::{json, Map as JsonMap, Value as JsonValue};
+use tokio::net::TcpListener;
+use tower_http::services::ServeDir;
+use tracing::info;
+
+use crate::export::FilterArgs;
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct ServeArgs {
+    /// Address to listen on; keep it on localhost, there is no authentication.
+    #[arg(short, long, env, default_value = "127.0.0.1:8080")]
+    listen: SocketAddr,
+
+    /// Also serve the files in this directory, e.g. a template export.
+    #[arg(long = "static", env = "STATIC")]
+    static_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Page {
+    limit: Option<usize>,
+    offset: usize,
+}
+
+impl Page {
+    fn apply<T>(&self, records: impl IntoIterator<Item = T>) -> Vec<T> {
+        records
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+}
+
+struct ServeError(Error);
+
+impl From<Error> for ServeError {
+    fn from(err: Error) -> Self {
+        Self(err)
+    }
+}
+
+impl IntoResponse for ServeError {
+    fn into_response(self) -> Response {
+        let status = match self.0.kind() {
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::Input => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, Json(json!({ "error": self.0.to_string() }))).into_response()
+    }
+}
+
+type Archived = State<Arc<Archive>>;
+
+pub(crate) async fn serve(archive: Archive, args: &ServeArgs) -> Result<(), Error> {
+    let mut app = Router::new()
+        .route("/api/tables", get(tables))
+        .route("/api/stats", get(stats))
+        .route("/api/observations", get(observations))
+        .route("/api/:table", get(table))
+        .route("/api/:table/:id", get(record))
+        .route("/map", get(map))
+        .with_state(Arc::new(archive));
+    if let Some(dir) = &args.static_dir {
+        app = app.fallback_service(ServeDir::new(dir));
+    }
+
+    let listener = TcpListener::bind(args.listen).await?;
+    info!("serving on http://{}", listener.local_addr()?);
+    Ok(axum::serve(listener, app).await?)
+}
+
+async fn tables() -> Json<&'static [&'static str]> {
+    Json(Archive::tables())
+}
+
+async fn stats(State(archive): Archived) -> Result<Json<JsonValue>, ServeError> {
+    Ok(Json(
+        serde_json::to_value(archive.stats(10)?).map_err(Error::from)?,
+    ))
+}
+
+async fn observations(
+    State(archive): Archived,
+    Query(filter): Query<FilterArgs>,
+    Query(page): Query<Page>,
+) -> Result<Json<Vec<JsonValue>>, ServeError> {
+    let observations = archive.observations(&filter.filter())?;
+    Ok(Json(
+        page.apply(observations.into_values())
+            .into_iter()
+            .map(serde_json::to_value)
+            .collect::<Result<_, _>>()
+            .map_err(Error::from)?,
+    ))
+}
+
+async fn table(
+    State(archive): Archived,
+    Path(table): Path<String>,
+    Query(page): Query<Page>,
+) -> Result<Json<Vec<JsonValue>>, ServeError> {
+    let records = known_table(&archive, &table)?;
+    Ok(Json(
+        page.apply(records.into_values().map(JsonValue::Object)),
+    ))
+}
+
+async fn record(
+    State(archive): Archived,
+    Path((table, id)): Path<(String, u64)>,
+) -> Result<Json<JsonValue>, ServeError> {
+    match known_table(&archive, &table)?.remove(&id) {
+        Some(record) => Ok(Json(JsonValue::Object(record))),
+        _ => Err(Error::NotFound(format!("{} {}", table, id)).into()),
+    }
+}
+
+async fn map(
+    State(archive): Archived,
+    Query(filter): Query<FilterArgs>,
+) -> Result<Html<Vec<u8>>, ServeError> {
+    let mut html = vec![];
+    archive.export_map(&mut html, &filter.filter())?;
+    Ok(Html(html))
+}
+
+// Unlike Archive::table, unknown tables are an error.
+fn known_table(
+    archive: &Archive,
+    table: &str,
+) -> Result<BTreeMap<u64, JsonMap<String, JsonValue>>, Error> {
+    if !Archive::tables().contains(&table) {
+        return Err(Error::NotFound(format!("table {}", table)));
+    }
+    archive.table(table)
+}
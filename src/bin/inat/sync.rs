@@ -0,0 +1,289 @@
+use std::{
+    fs::write,
+    io::Write,
+    path::PathBuf,
+    process::{exit, Command as ShellCommand, Stdio},
+    time::Duration,
+};
+
+use chrono::{TimeDelta, Utc};
+use clap::ValueEnum;
+use inat::{
+    Api, Archive, Changes, DigestFormat, Error, Selection, SyncOptions, SyncSummary, TableChanges,
+};
+use tokio::{select, signal::ctrl_c, spawn, time::sleep};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct SyncArgs {
+    /// iNat username.
+    #[arg(short, long, env = "INAT_USER")]
+    user: String,
+
+    /// Only sync these tables, e.g. "observations,photos".
+    #[arg(long, env, value_delimiter = ',')]
+    only: Vec<String>,
+
+    /// Skip these tables.
+    #[arg(long, env, value_delimiter = ',')]
+    exclude: Vec<String>,
+
+    /// Ignore the cached state and download everything again, rewriting the cache.
+    #[arg(long, env)]
+    full: bool,
+
+    /// Number of observation requests in flight at once.
+    #[arg(long, env, default_value_t = 2)]
+    concurrency: usize,
+
+    /// Observation IDs listed per request, up to 200.
+    #[arg(long, env, default_value_t = 200, value_parser = clap::value_parser!(u16).range(1..=200))]
+    page_size: u16,
+
+    /// Observations fetched per request, up to 200, and taxa, up to 30; larger chunks make for
+    /// fewer, slower requests.
+    #[arg(long, env, default_value_t = 20, value_parser = clap::value_parser!(u16).range(1..=200))]
+    chunk_size: u16,
+
+    /// List all observation IDs again this long after the last time, e.g. "7d", to notice those
+    /// deleted on iNat; they're moved to deleted/ in the data directory.
+    #[arg(long, env, value_parser = humantime::parse_duration)]
+    relist_interval: Option<Duration>,
+
+    /// Also write the summary of each sync to .sync/last_run.yaml in the data directory.
+    #[arg(long, env)]
+    save_summary: bool,
+
+    /// Commit the data directory to git after each sync, with the record counts as the message;
+    /// it becomes a repository of its own unless it's in one already.
+    #[arg(long, env)]
+    git_commit: bool,
+
+    /// Shell command to run after each successful sync, e.g. "git commit -qam sync"; repeatable.
+    /// INAT_NEW, INAT_UPDATED and INAT_DELETED hold the record counts, per table too, e.g.
+    /// INAT_NEW_OBSERVATIONS.
+    #[arg(long = "hook", value_name = "COMMAND")]
+    hooks: Vec<String>,
+
+    /// Keep running, syncing periodically and resuming once the API quota resets.
+    #[arg(long, env)]
+    daemon: bool,
+
+    /// Time to wait between syncs in daemon mode.
+    #[arg(long, env, default_value = "1h", value_parser = humantime::parse_duration)]
+    interval: Duration,
+
+    /// Send a digest of new observations, identifications and comments (and replaced photos) in
+    /// daemon mode.
+    #[arg(long, env)]
+    digest: Option<DigestPeriod>,
+
+    /// Digest format.
+    #[arg(long, env, default_value = "markdown")]
+    digest_format: DigestFormatArg,
+
+    /// Write the digest to this file.
+    #[arg(long, env)]
+    digest_output: Option<PathBuf>,
+
+    /// Pipe the digest to this command, e.g. "sendmail me@example.com".
+    #[arg(long, env)]
+    digest_command: Option<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum DigestPeriod {
+    Daily,
+    Weekly,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum DigestFormatArg {
+    Markdown,
+    Html,
+}
+
+// The summary of the sync, or None in daemon mode.
+pub(crate) async fn sync(
+    args: &SyncArgs,
+    api: Api,
+    data: &str,
+) -> Result<Option<SyncSummary>, Error> {
+    let user = &args.user;
+    let mut opts = SyncOptions::new(Selection::new(args.only.clone(), args.exclude.clone())?)
+        .full(args.full)
+        .concurrency(args.concurrency)
+        .page_size(args.page_size.into())
+        .chunk_size(args.chunk_size.into())
+        .relist_interval(args.relist_interval)
+        .save_summary(args.save_summary)
+        .git_commit(args.git_commit)
+        .cancel_on(cancel_on_ctrl_c());
+
+    if !args.daemon {
+        let summary = api.sync(user, &opts).await?;
+        log_summary(&summary);
+        run_hooks(args, data, &summary.changes)?;
+        return Ok(Some(summary));
+    }
+
+    loop {
+        let wait = match api.sync(user, &opts).await {
+            Ok(summary) => {
+                opts = opts.full(false);
+                log_summary(&summary);
+                if let Err(err) = run_hooks(args, data, &summary.changes) {
+                    error!("hook: {}", err);
+                }
+                args.interval
+            }
+            Err(Error::Cancelled) => return Err(Error::Cancelled),
+            Err(Error::QuotaExhausted(retry_at)) => {
+                // The checkpoint has what's left of a full sync.
+                opts = opts.full(false);
+                info!("quota exhausted, resuming at {}", retry_at);
+                (retry_at - Utc::now()).to_std().unwrap_or_default()
+            }
+            Err(err) => {
+                error!("{}", err);
+                args.interval
+            }
+        };
+        if let Err(err) = send_digest(args, data) {
+            error!("digest: {}", err);
+        }
+        select! {
+            _ = sleep(wait) => {}
+            _ = opts.cancel.cancelled() => return Ok(None),
+        }
+    }
+}
+
+// The first ctrl-c lets the sync finish the chunk it's writing, the second one doesn't wait.
+fn cancel_on_ctrl_c() -> CancellationToken {
+    let cancel = CancellationToken::new();
+    let token = cancel.clone();
+    spawn(async move {
+        if ctrl_c().await.is_err() {
+            return;
+        }
+        warn!("stopping after the current chunk, ctrl-c again to quit right away");
+        token.cancel();
+        if ctrl_c().await.is_ok() {
+            exit(130);
+        }
+    });
+
+    cancel
+}
+
+fn log_summary(summary: &SyncSummary) {
+    let total = summary.changes.total();
+    info!(
+        "synced in {:.1}s: {} requests, {} KiB received, {} cache hits",
+        summary.duration.as_secs_f64(),
+        summary.requests,
+        summary.bytes.div_ceil(1024),
+        summary.cache_hits
+    );
+    for (table, changes) in &summary.changes.tables {
+        info!(
+            "{}: {} new, {} updated, {} deleted, {} unchanged",
+            table, changes.new, changes.updated, changes.deleted, changes.unchanged
+        );
+    }
+    info!(
+        "{} new, {} updated, {} deleted records",
+        total.new, total.updated, total.deleted
+    );
+}
+
+fn run_hooks(args: &SyncArgs, data: &str, changes: &Changes) -> Result<(), Error> {
+    let total = changes.total();
+    let mut vars = vec![
+        ("INAT_DATA".to_string(), data.to_string()),
+        ("INAT_USER".to_string(), args.user.clone()),
+    ];
+    vars.extend(count_vars("", &total));
+    for (table, table_changes) in &changes.tables {
+        vars.extend(count_vars(
+            &format!("_{}", table.to_uppercase()),
+            table_changes,
+        ));
+    }
+
+    for hook in &args.hooks {
+        let status = ShellCommand::new("sh")
+            .args(["-c", hook])
+            .envs(vars.iter().cloned())
+            .status()?;
+        if !status.success() {
+            return Err(Error::CommandFailed(hook.clone(), status));
+        }
+    }
+
+    Ok(())
+}
+
+fn count_vars(suffix: &str, changes: &TableChanges) -> [(String, String); 3] {
+    [
+        ("NEW", changes.new),
+        ("UPDATED", changes.updated),
+        ("DELETED", changes.deleted),
+    ]
+    .map(|(name, count)| (format!("INAT_{}{}", name, suffix), count.to_string()))
+}
+
+fn send_digest(args: &SyncArgs, data: &str) -> Result<(), Error> {
+    let period = match args.digest {
+        Some(DigestPeriod::Daily) => TimeDelta::days(1),
+        Some(DigestPeriod::Weekly) => TimeDelta::weeks(1),
+        _ => return Ok(()),
+    };
+    let archive = Archive::new(data)?;
+    let now = Utc::now();
+    match archive.last_digest()? {
+        Some(last) if now - last < period => return Ok(()),
+        // The initial import is not news, start digesting from here.
+        None => return archive.mark_digest(now),
+        _ => {}
+    }
+
+    let format = match args.digest_format {
+        DigestFormatArg::Markdown => DigestFormat::Markdown,
+        DigestFormatArg::Html => DigestFormat::Html,
+    };
+    if let Some(digest) = archive.digest(format)? {
+        if let Some(path) = &args.digest_output {
+            write(path, &digest)?;
+        }
+        if let Some(command) = &args.digest_command {
+            pipe(command, format, &digest)?;
+        }
+    }
+
+    archive.mark_digest(now)
+}
+
+fn pipe(command: &str, format: DigestFormat, digest: &str) -> Result<(), Error> {
+    let mut child = ShellCommand::new("sh")
+        .args(["-c", command])
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        write!(
+            stdin,
+            "Subject: iNaturalist digest\nContent-Type: {}; charset=utf-8\n\n{}",
+            format.content_type(),
+            digest
+        )?;
+    }
+
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(Error::CommandFailed(command.to_string(), status));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,349 @@
+use std::collections::BTreeMap;
+
+use inat::{Archive, Error};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    text::{Line, Text},
+    widgets::{Block, List, ListState, Paragraph, Tabs, Wrap},
+    DefaultTerminal, Frame,
+};
+use serde_json::{Map as JsonMap, Value as JsonValue};
+
+type Record = JsonMap<String, JsonValue>;
+
+const PANES: [&str; 3] = ["Observations", "Taxa", "Identifications"];
+
+const HELP: &str = "tab: pane  /: search  esc: clear  ↑↓: move  q: quit";
+
+struct App {
+    observations: BTreeMap<u64, Record>,
+    taxa: BTreeMap<u64, Record>,
+    identifications: BTreeMap<u64, Record>,
+    comments: BTreeMap<u64, Record>,
+    users: BTreeMap<u64, Record>,
+    pane: usize,
+    search: String,
+    searching: bool,
+    // Rows of the current pane matching the search: ID and title.
+    rows: Vec<(u64, String)>,
+    list: ListState,
+}
+
+pub(crate) fn tui(archive: &Archive) -> Result<(), Error> {
+    let mut app = App {
+        observations: archive.table("observations")?,
+        taxa: archive.table("taxa")?,
+        identifications: archive.table("identifications")?,
+        comments: archive.table("comments")?,
+        users: archive.table("users")?,
+        pane: 0,
+        search: String::new(),
+        searching: false,
+        rows: vec![],
+        list: ListState::default(),
+    };
+    app.filter();
+
+    let mut terminal = ratatui::init();
+    let res = app.run(&mut terminal);
+    ratatui::restore();
+
+    res
+}
+
+impl App {
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<(), Error> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !self.handle(key) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // False once it's time to quit.
+    fn handle(&mut self, key: KeyEvent) -> bool {
+        if self.searching {
+            match key.code {
+                KeyCode::Enter => self.searching = false,
+                KeyCode::Esc => {
+                    self.searching = false;
+                    self.search.clear();
+                    self.filter();
+                }
+                KeyCode::Backspace => {
+                    self.search.pop();
+                    self.filter();
+                }
+                KeyCode::Char(c) => {
+                    self.search.push(c);
+                    self.filter();
+                }
+                _ => {}
+            }
+            return true;
+        }
+
+        match key.code {
+            KeyCode::Char('q') => return false,
+            KeyCode::Char('/') => self.searching = true,
+            KeyCode::Esc => {
+                self.search.clear();
+                self.filter();
+            }
+            KeyCode::Tab | KeyCode::Right => self.switch(1),
+            KeyCode::BackTab | KeyCode::Left => self.switch(PANES.len() - 1),
+            KeyCode::Down | KeyCode::Char('j') => self.list.select_next(),
+            KeyCode::Up | KeyCode::Char('k') => self.list.select_previous(),
+            KeyCode::PageDown => self.list.scroll_down_by(20),
+            KeyCode::PageUp => self.list.scroll_up_by(20),
+            KeyCode::Home => self.list.select_first(),
+            KeyCode::End => self.list.select_last(),
+            _ => {}
+        }
+
+        true
+    }
+
+    fn switch(&mut self, by: usize) {
+        self.pane = (self.pane + by) % PANES.len();
+        self.filter();
+    }
+
+    fn filter(&mut self) {
+        let search = self.search.to_lowercase();
+        let rows: Vec<_> = match self.pane {
+            0 => self
+                .observations
+                .iter()
+                .map(|(id, obs)| (*id, self.observation_row(obs)))
+                .collect(),
+            1 => self
+                .taxa
+                .iter()
+                .map(|(id, taxon)| (*id, taxon_name(taxon)))
+                .collect(),
+            _ => self
+                .identifications
+                .iter()
+                .map(|(id, ident)| (*id, self.identification_row(ident)))
+                .collect(),
+        };
+        self.rows = rows
+            .into_iter()
+            .filter(|(_, title)| title.to_lowercase().contains(&search))
+            .collect();
+        self.list.select((!self.rows.is_empty()).then_some(0));
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [tabs, main, status] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)])
+                .areas(main);
+
+        frame.render_widget(
+            Tabs::new(PANES)
+                .select(self.pane)
+                .highlight_style(Style::new().add_modifier(Modifier::REVERSED)),
+            tabs,
+        );
+
+        let list = List::new(self.rows.iter().map(|(_, title)| title.as_str()))
+            .block(Block::bordered().title(format!("{} ({})", PANES[self.pane], self.rows.len())))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, left, &mut self.list);
+
+        let detail = match self.list.selected().and_then(|i| self.rows.get(i)) {
+            Some((id, _)) => self.detail(*id),
+            _ => Text::default(),
+        };
+        frame.render_widget(
+            Paragraph::new(detail)
+                .block(Block::bordered())
+                .wrap(Wrap { trim: false }),
+            right,
+        );
+
+        let status_line = match (self.searching, self.search.is_empty()) {
+            (true, _) => format!("/{}", self.search),
+            (_, false) => format!("search: {}  ({})", self.search, HELP),
+            _ => HELP.to_string(),
+        };
+        frame.render_widget(Paragraph::new(status_line), status);
+    }
+
+    fn detail(&self, id: u64) -> Text<'static> {
+        let mut lines = vec![];
+        match self.pane {
+            0 => {
+                let obs = match self.observations.get(&id) {
+                    Some(obs) => obs,
+                    _ => return Text::default(),
+                };
+                lines.push(heading(&self.observation_title(obs)));
+                for (label, key) in [
+                    ("Observed", "observed_on"),
+                    ("Place", "place_guess"),
+                    ("Quality", "quality_grade"),
+                ] {
+                    lines.push(field(label, str_field(obs, key).unwrap_or_default()));
+                }
+                lines.push(field("ID", &id.to_string()));
+                if let Some(description) = str_field(obs, "description") {
+                    lines.push(Line::default());
+                    lines.extend(description.lines().map(|line| Line::from(line.to_string())));
+                }
+
+                lines.push(Line::default());
+                lines.push(heading("Identifications"));
+                for ident in ids(obs, "identifications")
+                    .iter()
+                    .filter_map(|id| self.identifications.get(id))
+                {
+                    lines.push(Line::from(self.identification_row(ident)));
+                }
+
+                lines.push(Line::default());
+                lines.push(heading("Comments"));
+                for comment in ids(obs, "comments")
+                    .iter()
+                    .filter_map(|id| self.comments.get(id))
+                {
+                    lines.push(Line::from(format!(
+                        "{}: {}",
+                        self.login(comment),
+                        str_field(comment, "body").unwrap_or_default()
+                    )));
+                }
+            }
+            1 => {
+                let taxon = match self.taxa.get(&id) {
+                    Some(taxon) => taxon,
+                    _ => return Text::default(),
+                };
+                lines.push(heading(&taxon_name(taxon)));
+                lines.push(field("Rank", str_field(taxon, "rank").unwrap_or_default()));
+                lines.push(field("ID", &id.to_string()));
+                let lineage: Vec<_> = ids(taxon, "ancestor_ids")
+                    .iter()
+                    .filter(|ancestor| **ancestor != id)
+                    .filter_map(|id| self.taxa.get(id))
+                    .filter_map(|taxon| str_field(taxon, "name"))
+                    .map(str::to_string)
+                    .collect();
+                lines.push(field("Lineage", &lineage.join(" > ")));
+                let observed = self
+                    .observations
+                    .values()
+                    .filter(|obs| id_field(obs, "taxon") == Some(id))
+                    .count();
+                lines.push(field("Observations", &observed.to_string()));
+            }
+            _ => {
+                let ident = match self.identifications.get(&id) {
+                    Some(ident) => ident,
+                    _ => return Text::default(),
+                };
+                lines.push(heading(&self.identification_row(ident)));
+                for (label, key) in [("Created", "created_at"), ("Category", "category")] {
+                    lines.push(field(label, str_field(ident, key).unwrap_or_default()));
+                }
+                let current = ident.get("current").and_then(JsonValue::as_bool);
+                lines.push(field(
+                    "Current",
+                    if current == Some(false) { "no" } else { "yes" },
+                ));
+                if let Some(obs) = self
+                    .observations
+                    .iter()
+                    .find(|(_, obs)| ids(obs, "identifications").contains(&id))
+                {
+                    lines.push(field(
+                        "Observation",
+                        &format!("{} {}", obs.0, self.observation_title(obs.1)),
+                    ));
+                }
+                if let Some(body) = str_field(ident, "body") {
+                    lines.push(Line::default());
+                    lines.extend(body.lines().map(|line| Line::from(line.to_string())));
+                }
+            }
+        }
+
+        Text::from(lines)
+    }
+
+    fn observation_title(&self, obs: &Record) -> String {
+        match id_field(obs, "taxon").and_then(|id| self.taxa.get(&id)) {
+            Some(taxon) => taxon_name(taxon),
+            _ => str_field(obs, "species_guess")
+                .unwrap_or("Unknown")
+                .to_string(),
+        }
+    }
+
+    fn observation_row(&self, obs: &Record) -> String {
+        format!(
+            "{}  {}",
+            str_field(obs, "observed_on").unwrap_or("          "),
+            self.observation_title(obs)
+        )
+    }
+
+    fn identification_row(&self, ident: &Record) -> String {
+        let taxon = id_field(ident, "taxon")
+            .and_then(|id| self.taxa.get(&id))
+            .map(taxon_name)
+            .unwrap_or_default();
+        format!("{}: {}", self.login(ident), taxon)
+    }
+
+    fn login(&self, record: &Record) -> String {
+        id_field(record, "user")
+            .and_then(|id| self.users.get(&id))
+            .and_then(|user| str_field(user, "login"))
+            .unwrap_or("?")
+            .to_string()
+    }
+}
+
+fn heading(text: &str) -> Line<'static> {
+    Line::styled(text.to_string(), Style::new().add_modifier(Modifier::BOLD))
+}
+
+fn field(label: &str, value: &str) -> Line<'static> {
+    Line::from(format!("{:<13}{}", format!("{}:", label), value))
+}
+
+fn taxon_name(taxon: &Record) -> String {
+    let name = str_field(taxon, "name").unwrap_or("Unknown");
+    match str_field(taxon, "preferred_common_name") {
+        Some(common) => format!("{} ({})", common, name),
+        _ => name.to_string(),
+    }
+}
+
+fn str_field<'a>(record: &'a Record, key: &str) -> Option<&'a str> {
+    record.get(key).and_then(JsonValue::as_str)
+}
+
+fn id_field(record: &Record, key: &str) -> Option<u64> {
+    record.get(key).and_then(JsonValue::as_u64)
+}
+
+fn ids(record: &Record, key: &str) -> Vec<u64> {
+    match record.get(key) {
+        Some(JsonValue::Array(vals)) => vals.iter().filter_map(JsonValue::as_u64).collect(),
+        _ => vec![],
+    }
+}
@@ -0,0 +1,31 @@
+use reqwest::header::{HeaderMap, CACHE_CONTROL};
+
+// The directives that matter to a private cache that doesn't guess freshness.
+#[derive(Debug, Default)]
+pub(crate) struct CacheControl {
+    pub(crate) no_store: bool,
+    pub(crate) no_cache: bool,
+    pub(crate) max_age: Option<u64>,
+}
+
+impl CacheControl {
+    pub(crate) fn parse(headers: &HeaderMap) -> Self {
+        let mut cc = Self::default();
+        for directive in headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|val| val.to_str().ok())
+            .flat_map(|val| val.split(','))
+        {
+            let directive = directive.trim().to_ascii_lowercase();
+            match directive.split_once('=') {
+                Some(("max-age", secs)) => cc.max_age = secs.trim_matches('"').parse().ok(),
+                _ if directive == "no-store" => cc.no_store = true,
+                _ if directive == "no-cache" => cc.no_cache = true,
+                _ => {}
+            }
+        }
+
+        cc
+    }
+}
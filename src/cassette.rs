@@ -0,0 +1,157 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs::{read_to_string, File},
+    io::Write,
+    path::Path,
+    sync::Mutex,
+};
+
+use reqwest::{
+    header::{CONTENT_ENCODING, CONTENT_LENGTH, SET_COOKIE},
+    Method, Request, Response,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Error, middleware::Middleware};
+
+// One response of the API, as recorded. Bodies are kept decompressed, and the request only by its
+// method and URL: tokens and cookies stay out of cassettes, so that they can be attached to bug
+// reports as they are.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Interaction {
+    pub method: String,
+    pub url: String,
+    pub status: u16,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub body: String,
+}
+
+// Answers the syncs' requests from recorded interactions instead of the API, e.g. to reproduce a
+// bug with someone else's cassette, or in tests. Installed with Api::with_middleware; requests
+// not in the cassette fail, rather than reaching the API.
+//
+// The same request made more than once gets the recorded answers in order, then the last one
+// again; e.g. a second sync revalidating what the first one fetched.
+#[derive(Debug)]
+pub struct Cassette {
+    interactions: Mutex<HashMap<(String, String), VecDeque<Interaction>>>,
+}
+
+impl Cassette {
+    pub fn new<I: IntoIterator<Item = Interaction>>(interactions: I) -> Self {
+        let mut by_request: HashMap<_, VecDeque<_>> = HashMap::new();
+        for interaction in interactions {
+            by_request
+                .entry((interaction.method.clone(), interaction.url.clone()))
+                .or_default()
+                .push_back(interaction);
+        }
+
+        Self {
+            interactions: Mutex::new(by_request),
+        }
+    }
+
+    // As written by Api::with_recording, one interaction per line.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let interactions = read_to_string(path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<Interaction>, _>>()
+            .map_err(|err| Error::BadFile(path.to_path_buf(), err.to_string()))?;
+
+        Ok(Self::new(interactions))
+    }
+}
+
+impl Middleware for Cassette {
+    fn request(&self, req: &mut Request) -> Result<Option<Response>, Error> {
+        let key = (req.method().to_string(), req.url().to_string());
+        let mut interactions = self.interactions.lock().expect("cassette poisoned");
+        let interaction = match interactions.get_mut(&key) {
+            Some(queue) if queue.len() > 1 => queue.pop_front(),
+            Some(queue) => queue.front().cloned(),
+            _ => None,
+        }
+        .ok_or_else(|| Error::NotFound(format!("{} {} in the cassette", key.0, key.1)))?;
+
+        let mut res = http::Response::builder().status(interaction.status);
+        for (name, value) in &interaction.headers {
+            res = res.header(name, value);
+        }
+        let res = res
+            .body(interaction.body)
+            .map_err(|err| Error::Internal(format!("cassette response: {}", err)))?;
+
+        Ok(Some(Response::from(res)))
+    }
+}
+
+// Appends every response the API sends to a cassette, flushed as it goes, so that even a sync
+// that died halfway leaves one to replay.
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    pub(crate) fn create(path: &Path) -> Result<Self, Error> {
+        Ok(Self {
+            file: Mutex::new(File::create(path)?),
+        })
+    }
+
+    // The response again, its body read into the cassette; the inner error is the client's, like
+    // in Api::send.
+    pub(crate) async fn record(
+        &self,
+        method: &Method,
+        res: Response,
+    ) -> Result<Result<Response, reqwest::Error>, Error> {
+        let status = res.status();
+        let url = res.url().to_string();
+        let mut headers = res.headers().clone();
+        // Decompressed already, and no cookies in cassettes.
+        for name in [CONTENT_ENCODING, CONTENT_LENGTH, SET_COOKIE] {
+            headers.remove(name);
+        }
+        let body = match res.bytes().await {
+            Ok(body) => body,
+            Err(err) => return Ok(Err(err)),
+        };
+
+        let interaction = Interaction {
+            method: method.to_string(),
+            url,
+            status: status.as_u16(),
+            headers: headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+            body: String::from_utf8_lossy(&body).to_string(),
+        };
+        let mut line = serde_json::to_vec(&interaction)?;
+        line.push(b'\n');
+        {
+            let mut file = self.file.lock().expect("cassette poisoned");
+            file.write_all(&line)?;
+            file.flush()?;
+        }
+
+        let mut rebuilt = http::Response::builder().status(status);
+        if let Some(all) = rebuilt.headers_mut() {
+            *all = headers;
+        }
+        let rebuilt = rebuilt
+            .body(body)
+            .map_err(|err| Error::Internal(format!("recorded response: {}", err)))?;
+
+        Ok(Ok(Response::from(rebuilt)))
+    }
+}
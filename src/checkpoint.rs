@@ -0,0 +1,90 @@
+use std::{
+    fs::{create_dir_all, remove_file},
+    io::ErrorKind,
+    path::PathBuf,
+};
+
+use chrono::{DateTime, Utc};
+use reqwest::header::DATE;
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping as YamlMapping, Value as YamlValue};
+
+use crate::{
+    api::{lookup_cache_data, Api},
+    api_sync::Stage,
+    error::Error,
+};
+
+// Remaining work, persisted as each stage of a sync is done, and when the API quota runs out
+// halfway through one, or it's cancelled.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Checkpoint {
+    pub(crate) user_id: u64,
+    pub(crate) retry_at: DateTime<Utc>,
+    // Observations left to fetch, as far as the observations stage got.
+    pub(crate) remaining: Vec<u64>,
+    // Stages the next run can skip.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) done: Vec<Stage>,
+}
+
+impl Api {
+    pub(crate) fn load_checkpoint(&self, user_id: u64) -> Result<Option<Checkpoint>, Error> {
+        Ok(lookup_cache_data::<Checkpoint>(&self.checkpoint_path())?
+            .map(|(_, checkpoint)| checkpoint)
+            .filter(|checkpoint| checkpoint.user_id == user_id))
+    }
+
+    // When the sync that ran out of quota can go on, while that's still ahead, for any user: they
+    // share the quota.
+    pub(crate) fn retry_at(&self) -> Result<Option<DateTime<Utc>>, Error> {
+        Ok(lookup_cache_data::<Checkpoint>(&self.checkpoint_path())?
+            .map(|(_, checkpoint)| checkpoint.retry_at)
+            .filter(|retry_at| *retry_at > self.store.now()))
+    }
+
+    // The stages done so far, along with what the observations stage left, unless it's done.
+    pub(crate) fn save_stages(
+        &self,
+        user_id: u64,
+        done: &[Stage],
+        retry_at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let remaining = match done.contains(&Stage::Observations) {
+            true => vec![],
+            _ => self
+                .load_checkpoint(user_id)?
+                .map(|checkpoint| checkpoint.remaining)
+                .unwrap_or_default(),
+        };
+        self.save_checkpoint(&Checkpoint {
+            user_id,
+            retry_at,
+            remaining,
+            done: done.to_vec(),
+        })
+    }
+
+    pub(crate) fn save_checkpoint(&self, checkpoint: &Checkpoint) -> Result<(), Error> {
+        let mut header = YamlMapping::new();
+        header.insert(
+            YamlValue::String(DATE.to_string()),
+            YamlValue::String(self.store.now().to_rfc3339()),
+        );
+
+        create_dir_all(self.path(".sync"))?;
+        self.store
+            .write_cache(&self.checkpoint_path(), &header, checkpoint)
+    }
+
+    pub(crate) fn clear_checkpoint(&self) -> Result<(), Error> {
+        match remove_file(self.checkpoint_path()) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn checkpoint_path(&self) -> PathBuf {
+        self.path(".sync").join("checkpoint.yaml")
+    }
+}
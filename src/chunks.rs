@@ -0,0 +1,147 @@
+use std::{collections::BTreeMap, fs::create_dir_all, path::PathBuf};
+
+use chrono::{DateTime, Utc};
+use reqwest::header::{DATE, ETAG};
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping as YamlMapping, Value as YamlValue};
+
+use crate::{
+    api::{lookup_cache_data, Api},
+    error::Error,
+};
+
+// What the API said about a chunk of records fetched by ID, to ask if anything changed since.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Validator {
+    pub(crate) date: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) etag: Option<String>,
+}
+
+// Keyed by record ID, that of the last chunk each came in; chunks change as records are updated or
+// deleted, and the queue with them.
+pub(crate) type Validators = BTreeMap<u64, Validator>;
+
+impl Validator {
+    // From the header of the response, before the etag gets removed.
+    pub(crate) fn from_header(header: &YamlMapping) -> Option<Self> {
+        let get = |key: &str| header.get(YamlValue::String(key.to_string()))?.as_str();
+        Some(Self {
+            date: DateTime::parse_from_rfc3339(get(DATE.as_str())?)
+                .ok()?
+                .to_utc(),
+            etag: get(ETAG.as_str()).map(str::to_string),
+        })
+    }
+}
+
+impl Api {
+    pub(crate) fn load_validators(&self) -> Result<Validators, Error> {
+        Ok(lookup_cache_data::<Validators>(&self.validators_path())?
+            .map(|(_, validators)| validators)
+            .unwrap_or_default())
+    }
+
+    pub(crate) fn save_validators(&self, validators: &Validators) -> Result<(), Error> {
+        let mut header = YamlMapping::new();
+        header.insert(
+            YamlValue::String(DATE.to_string()),
+            YamlValue::String(self.store.now().to_rfc3339()),
+        );
+
+        create_dir_all(self.path(".sync"))?;
+        self.store
+            .write_cache(&self.validators_path(), &header, validators)
+    }
+
+    fn validators_path(&self) -> PathBuf {
+        self.path(".sync").join("chunks.yaml")
+    }
+}
+
+// Records to fetch by ID at once: as many as configured, as far as the endpoint allows.
+pub(crate) fn id_chunks(ids: &[u64], size: usize, max: usize) -> Vec<&[u64]> {
+    ids.chunks(size.clamp(1, max)).collect()
+}
+
+// The validator of a chunk of records, if all of them have one: the earliest date, and the etag
+// only if they all came in the same response as this very chunk, otherwise it wouldn't match and
+// the date wouldn't be looked at either.
+pub(crate) fn chunk_validator(validators: &Validators, ids: &[u64]) -> Option<Validator> {
+    let mut chunk = Vec::with_capacity(ids.len());
+    for id in ids {
+        chunk.push(validators.get(id)?);
+    }
+    let etag = chunk.first()?.etag.as_ref().filter(|etag| {
+        chunk
+            .iter()
+            .all(|validator| validator.etag.as_ref() == Some(etag))
+            && validators
+                .values()
+                .filter(|validator| validator.etag.as_ref() == Some(etag))
+                .count()
+                == ids.len()
+    });
+
+    Some(Validator {
+        date: chunk.iter().map(|validator| validator.date).min()?,
+        etag: etag.cloned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn validator(day: u32, etag: Option<&str>) -> Validator {
+        Validator {
+            date: Utc.with_ymd_and_hms(2024, 1, day, 0, 0, 0).unwrap(),
+            etag: etag.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn keeps_the_etag_of_the_same_chunk() {
+        let validators = Validators::from([
+            (1, validator(2, Some("a"))),
+            (2, validator(1, Some("a"))),
+            (3, validator(3, Some("b"))),
+        ]);
+
+        let chunk = chunk_validator(&validators, &[1, 2]).expect("validator");
+        assert_eq!(chunk.etag.as_deref(), Some("a"));
+        assert_eq!(chunk.date, validator(1, None).date);
+    }
+
+    #[test]
+    fn drops_the_etag_of_other_chunks() {
+        let validators = Validators::from([
+            (1, validator(1, Some("a"))),
+            (2, validator(1, Some("a"))),
+            (3, validator(2, Some("b"))),
+        ]);
+
+        // Part of the chunk the etag was for, or more than it.
+        assert_eq!(chunk_validator(&validators, &[1]).expect("part").etag, None);
+        let more = chunk_validator(&validators, &[1, 2, 3]).expect("more");
+        assert_eq!(more.etag, None);
+        assert_eq!(more.date, validator(1, None).date);
+    }
+
+    #[test]
+    fn needs_a_validator_for_every_record() {
+        let validators = Validators::from([(1, validator(1, Some("a")))]);
+        assert!(chunk_validator(&validators, &[1, 2]).is_none());
+        assert!(chunk_validator(&validators, &[]).is_none());
+    }
+
+    #[test]
+    fn chunks_by_the_size_up_to_the_max() {
+        let ids = [1, 2, 3, 4, 5];
+        assert_eq!(id_chunks(&ids, 2, 10), vec![&[1, 2][..], &[3, 4], &[5]]);
+        assert_eq!(id_chunks(&ids, 10, 3), vec![&[1, 2, 3][..], &[4, 5]]);
+        assert_eq!(id_chunks(&ids, 0, 10).len(), 5);
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+pub(crate) const DEFAULT_THRESHOLD: u32 = 3;
+pub(crate) const DEFAULT_COOL_DOWN: Duration = Duration::from_secs(5 * 60);
+
+// How often requests waiting for the probe check back.
+const PROBE_POLL: Duration = Duration::from_secs(1);
+
+// Stops all requests for a while after a number of consecutive failures, then lets a single
+// one through to see if the API is back.
+pub(crate) struct CircuitBreaker {
+    // Zero to never open.
+    threshold: u32,
+    cool_down: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    failures: u32,
+    open_until: Option<Instant>,
+    probing: bool,
+    // Counts the probes, so that each guard only ever ends its own.
+    probes: u64,
+}
+
+// Held while the request is out. A probe that ends without an outcome, e.g. one that was dropped,
+// cancelled or returned early, lets the next request probe instead.
+pub(crate) struct Entered<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: Option<u64>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(threshold: u32, cool_down: Duration) -> Self {
+        Self {
+            threshold,
+            cool_down,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    // Waits until the request may go out.
+    pub(crate) async fn enter(&self) -> Entered<'_> {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().expect("circuit breaker poisoned");
+                match state.open_until {
+                    None => {
+                        return Entered {
+                            breaker: self,
+                            probe: None,
+                        }
+                    }
+                    Some(until) if until > Instant::now() => until - Instant::now(),
+                    Some(_) if state.probing => PROBE_POLL,
+                    Some(_) => {
+                        info!("probing the API");
+                        state.probing = true;
+                        state.probes += 1;
+                        return Entered {
+                            breaker: self,
+                            probe: Some(state.probes),
+                        };
+                    }
+                }
+            };
+            sleep(wait).await;
+        }
+    }
+
+    pub(crate) fn success(&self) {
+        let mut state = self.state.lock().expect("circuit breaker poisoned");
+        if state.open_until.is_some() {
+            info!("API is back");
+        }
+        *state = State {
+            probes: state.probes,
+            ..State::default()
+        };
+    }
+
+    pub(crate) fn failure(&self) {
+        let mut state = self.state.lock().expect("circuit breaker poisoned");
+        state.failures += 1;
+        if self.threshold > 0 && (state.probing || state.failures >= self.threshold) {
+            warn!(
+                "{} failures in a row, pausing requests for {}s",
+                state.failures,
+                self.cool_down.as_secs()
+            );
+            state.open_until = Some(Instant::now() + self.cool_down);
+            state.probing = false;
+        }
+    }
+}
+
+impl Drop for Entered<'_> {
+    fn drop(&mut self) {
+        let Some(probe) = self.probe else {
+            return;
+        };
+        let mut state = self.breaker.state.lock().expect("circuit breaker poisoned");
+        if state.probing && state.probes == probe {
+            state.probing = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::timeout;
+
+    use super::*;
+
+    const COOL_DOWN: Duration = Duration::from_millis(10);
+
+    async fn half_open() -> CircuitBreaker {
+        let breaker = CircuitBreaker::new(1, COOL_DOWN);
+        breaker.failure();
+        sleep(COOL_DOWN).await;
+        breaker
+    }
+
+    #[tokio::test]
+    async fn dropped_probes_let_the_next_request_probe() {
+        let breaker = half_open().await;
+        let probe = breaker.enter().await;
+        assert!(probe.probe.is_some());
+        drop(probe);
+
+        let next = timeout(PROBE_POLL / 2, breaker.enter()).await;
+        assert!(next.expect("stuck probing").probe.is_some());
+    }
+
+    #[tokio::test]
+    async fn failed_probes_reopen() {
+        let breaker = half_open().await;
+        let probe = breaker.enter().await;
+        breaker.failure();
+        drop(probe);
+
+        assert!(timeout(COOL_DOWN / 2, breaker.enter()).await.is_err());
+        sleep(COOL_DOWN).await;
+        assert!(breaker.enter().await.probe.is_some());
+    }
+
+    #[tokio::test]
+    async fn successful_probes_close() {
+        let breaker = half_open().await;
+        let probe = breaker.enter().await;
+        breaker.success();
+        drop(probe);
+
+        assert!(breaker.enter().await.probe.is_none());
+    }
+}
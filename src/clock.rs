@@ -0,0 +1,29 @@
+use std::fmt::Debug;
+
+use chrono::{DateTime, Utc};
+
+// What time it is, for everything written to the data directory: cache headers, checkpoints, the
+// change log and the like. Set with ApiBuilder::clock or Archive::with_clock; the system clock
+// unless set.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+// Always the same time, e.g. for golden-file tests of a data directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FixedClock(pub DateTime<Utc>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
@@ -0,0 +1,80 @@
+use std::{
+    fs::{create_dir_all, File, OpenOptions},
+    io::{BufReader, BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{durable::sync_file, error::Error};
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EventKind {
+    NewObservation,
+    NewIdentification,
+    NewComment,
+    PhotoReplaced,
+    ResearchGrade,
+}
+
+// A change noticed while normalising, journaled for digests.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Event {
+    pub(crate) date: DateTime<Utc>,
+    pub(crate) kind: EventKind,
+    pub(crate) observation: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) id: Option<u64>,
+}
+
+pub(crate) fn append_events(data_dir: &Path, events: &[Event], durable: bool) -> Result<(), Error> {
+    if events.is_empty() {
+        return Ok(());
+    }
+
+    create_dir_all(data_dir.join(".sync"))?;
+    let path = journal_path(data_dir);
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let mut out = BufWriter::new(&file);
+    for event in events {
+        writeln!(out, "---")?;
+        serde_yaml::to_writer(&mut out, event)?;
+    }
+    out.flush()?;
+    drop(out);
+
+    sync_file(&file, &path, durable)
+}
+
+pub(crate) fn read_events(data_dir: &Path) -> Result<Vec<Event>, Error> {
+    let file = match File::open(journal_path(data_dir)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+
+    serde_yaml::Deserializer::from_reader(BufReader::new(file))
+        .map(|doc| Ok(Event::deserialize(doc)?))
+        .collect()
+}
+
+// Drops journaled events up to (and including) the given date.
+pub(crate) fn prune_events(
+    data_dir: &Path,
+    until: DateTime<Utc>,
+    durable: bool,
+) -> Result<(), Error> {
+    let events: Vec<_> = read_events(data_dir)?
+        .into_iter()
+        .filter(|event| event.date > until)
+        .collect();
+
+    File::create(journal_path(data_dir))?;
+    append_events(data_dir, &events, durable)
+}
+
+fn journal_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(".sync").join("events.yaml")
+}
@@ -0,0 +1,246 @@
+use std::{fmt::Write, fs::create_dir_all};
+
+use chrono::{DateTime, Utc};
+use reqwest::header::DATE;
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping as YamlMapping, Value as YamlValue};
+
+use crate::{
+    api::lookup_cache_data,
+    archive::{escape_xml, id_field, str_field, Archive, Record},
+    delta::{prune_events, read_events, Event, EventKind},
+    error::Error,
+};
+
+// Long lists get cut off, the archive has the rest.
+const MAX_SECTION_ITEMS: usize = 50;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DigestFormat {
+    Markdown,
+    Html,
+}
+
+impl DigestFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            DigestFormat::Markdown => "text/markdown",
+            DigestFormat::Html => "text/html",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct DigestState {
+    until: DateTime<Utc>,
+}
+
+struct Item {
+    title: String,
+    url: Option<String>,
+    detail: Option<String>,
+}
+
+impl Archive {
+    pub fn last_digest(&self) -> Result<Option<DateTime<Utc>>, Error> {
+        Ok(
+            lookup_cache_data::<DigestState>(&self.path(".sync").join("digest.yaml"))?
+                .map(|(_, state)| state.until),
+        )
+    }
+
+    // Renders everything journaled since the last digest, None if nothing happened.
+    pub fn digest(&self, format: DigestFormat) -> Result<Option<String>, Error> {
+        let since = self.last_digest()?;
+        let events: Vec<_> = read_events(&self.data_dir)?
+            .into_iter()
+            .filter(|event| since.is_none_or(|since| event.date > since))
+            .collect();
+
+        let mut sections = vec![];
+        for (kind, heading) in [
+            (EventKind::NewObservation, "New observations"),
+            (EventKind::ResearchGrade, "Promoted to research grade"),
+            (EventKind::NewIdentification, "New identifications"),
+            (EventKind::NewComment, "New comments"),
+            (EventKind::PhotoReplaced, "Replaced photos"),
+        ] {
+            let mut items = vec![];
+            for event in events.iter().filter(|event| event.kind == kind) {
+                if let Some(item) = self.digest_item(event)? {
+                    items.push(item);
+                }
+            }
+            if !items.is_empty() {
+                sections.push((heading, items));
+            }
+        }
+
+        if sections.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(match format {
+            DigestFormat::Markdown => render_markdown(&sections),
+            DigestFormat::Html => render_html(&sections),
+        }))
+    }
+
+    // Remembers that everything up to the given date was reported.
+    pub fn mark_digest(&self, until: DateTime<Utc>) -> Result<(), Error> {
+        let mut header = YamlMapping::new();
+        header.insert(
+            YamlValue::String(DATE.to_string()),
+            YamlValue::String(self.store.now().to_rfc3339()),
+        );
+
+        create_dir_all(self.path(".sync"))?;
+        self.store.write_cache(
+            &self.path(".sync").join("digest.yaml"),
+            &header,
+            &DigestState { until },
+        )?;
+        prune_events(&self.data_dir, until, self.store.is_durable())
+    }
+
+    fn digest_item(&self, event: &Event) -> Result<Option<Item>, Error> {
+        let obs = match self.record("observations", event.observation)? {
+            Some(obs) => obs,
+            _ => return Ok(None), // deleted since
+        };
+        let title = self.observation_title(&obs)?;
+        let url = str_field(&obs, "uri").map(str::to_string);
+
+        let table = match event.kind {
+            EventKind::NewIdentification => "identifications",
+            EventKind::NewComment => "comments",
+            EventKind::PhotoReplaced => {
+                let photo = match event.id {
+                    Some(id) => self.record("photos", id)?,
+                    _ => None,
+                };
+                let detail = photo
+                    .as_ref()
+                    .and_then(|photo| str_field(photo, "url"))
+                    .map(str::to_string);
+                return Ok(Some(Item { title, url, detail }));
+            }
+            _ => {
+                let detail = str_field(&obs, "observed_on").map(str::to_string);
+                return Ok(Some(Item { title, url, detail }));
+            }
+        };
+
+        let child = match event.id {
+            Some(id) => self.record(table, id)?,
+            _ => None,
+        };
+        let child = match child {
+            Some(child) => child,
+            _ => return Ok(None),
+        };
+
+        // Our own identifications and comments are old news.
+        let user = id_field(&child, "user");
+        if user.is_some() && user == id_field(&obs, "user") {
+            return Ok(None);
+        }
+
+        let login = self.user_login(user)?;
+        let detail = match event.kind {
+            EventKind::NewIdentification => {
+                let taxon = match id_field(&child, "taxon") {
+                    Some(id) => self.record("taxa", id)?,
+                    _ => None,
+                };
+                let name = taxon
+                    .as_ref()
+                    .and_then(|taxon| str_field(taxon, "name"))
+                    .unwrap_or("unknown taxon");
+                format!("{} suggested {}", login, name)
+            }
+            _ => format!(
+                "{}: {}",
+                login,
+                str_field(&child, "body").unwrap_or_default()
+            ),
+        };
+
+        Ok(Some(Item {
+            title,
+            url,
+            detail: Some(detail),
+        }))
+    }
+
+    fn user_login(&self, id: Option<u64>) -> Result<String, Error> {
+        let user: Option<Record> = match id {
+            Some(id) => self.record("users", id)?,
+            _ => None,
+        };
+        Ok(user
+            .as_ref()
+            .and_then(|user| str_field(user, "login"))
+            .unwrap_or("someone")
+            .to_string())
+    }
+}
+
+fn render_markdown(sections: &[(&str, Vec<Item>)]) -> String {
+    let mut out = String::from("# iNaturalist digest\n");
+    for (heading, items) in sections {
+        let _ = write!(out, "\n## {} ({})\n\n", heading, items.len());
+        for item in items.iter().take(MAX_SECTION_ITEMS) {
+            let _ = match &item.url {
+                Some(url) => write!(out, "- [{}]({})", item.title, url),
+                _ => write!(out, "- {}", item.title),
+            };
+            if let Some(detail) = &item.detail {
+                let _ = write!(out, " — {}", detail);
+            }
+            out.push('\n');
+        }
+        if items.len() > MAX_SECTION_ITEMS {
+            let _ = writeln!(out, "- …and {} more", items.len() - MAX_SECTION_ITEMS);
+        }
+    }
+
+    out
+}
+
+fn render_html(sections: &[(&str, Vec<Item>)]) -> String {
+    let mut out = String::from(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>iNaturalist digest</title></head>\n<body>\n<h1>iNaturalist digest</h1>\n",
+    );
+    for (heading, items) in sections {
+        let _ = writeln!(out, "<h2>{} ({})</h2>\n<ul>", heading, items.len());
+        for item in items.iter().take(MAX_SECTION_ITEMS) {
+            out.push_str("<li>");
+            let _ = match &item.url {
+                Some(url) => write!(
+                    out,
+                    "<a href=\"{}\">{}</a>",
+                    escape_xml(url),
+                    escape_xml(&item.title)
+                ),
+                _ => write!(out, "{}", escape_xml(&item.title)),
+            };
+            if let Some(detail) = &item.detail {
+                let _ = write!(out, " — {}", escape_xml(detail));
+            }
+            out.push_str("</li>\n");
+        }
+        if items.len() > MAX_SECTION_ITEMS {
+            let _ = writeln!(
+                out,
+                "<li>…and {} more</li>",
+                items.len() - MAX_SECTION_ITEMS
+            );
+        }
+        out.push_str("</ul>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+
+    out
+}
@@ -0,0 +1,91 @@
+use std::{
+    ffi::OsString,
+    fs::{self, metadata, read_dir, rename, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::error::Error;
+
+// Writes the whole file, unless it already has the same contents: unchanged records are left
+// alone, mtime and all. When durable, through a temporary file renamed over it, so that a power
+// loss leaves either the old version or the new one, never a truncated file.
+pub(crate) fn write_file(
+    path: &Path,
+    durable: bool,
+    write: impl FnOnce(&mut Vec<u8>) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut contents = vec![];
+    write(&mut contents)?;
+    if is_unchanged(path, &contents) {
+        return Ok(());
+    }
+    if !durable {
+        return Ok(fs::write(path, contents)?);
+    }
+
+    let tmp = tmp_path(path);
+    let mut file = File::create(&tmp)?;
+    file.write_all(&contents)?;
+    file.sync_all()?;
+    rename(&tmp, path)?;
+
+    sync_dir(path, durable)
+}
+
+// Whether the file holds exactly these contents; anything unreadable counts as changed. Sizes are
+// compared first, so that most changed files aren't even read.
+pub(crate) fn is_unchanged(path: &Path, contents: &[u8]) -> bool {
+    match metadata(path) {
+        Ok(md) if md.len() == contents.len() as u64 => {
+            fs::read(path).is_ok_and(|old| old == contents)
+        }
+        _ => false,
+    }
+}
+
+// After writing to the file in place, e.g. appending to it.
+pub(crate) fn sync_file(file: &File, path: &Path, durable: bool) -> Result<(), Error> {
+    if !durable {
+        return Ok(());
+    }
+    file.sync_all()?;
+
+    sync_dir(path, durable)
+}
+
+// The directory entry needs syncing too, for new or renamed files to survive.
+pub(crate) fn sync_dir(path: &Path, durable: bool) -> Result<(), Error> {
+    if !durable {
+        return Ok(());
+    }
+    let dir = match path.parent() {
+        Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+        Some(dir) => dir,
+        _ => return Ok(()),
+    };
+
+    Ok(File::open(dir)?.sync_all()?)
+}
+
+// Everything under the directory, durable or not: for the rare rewrites that lose data if cut
+// short, like converting the layout.
+pub(crate) fn sync_tree(dir: &Path) -> Result<(), Error> {
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let kind = entry.file_type()?;
+        if kind.is_dir() {
+            sync_tree(&entry.path())?;
+        } else if kind.is_file() {
+            File::open(entry.path())?.sync_all()?;
+        }
+    }
+
+    Ok(File::open(dir)?.sync_all()?)
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".tmp");
+    PathBuf::from(name)
+}
@@ -1,12 +1,15 @@
-use std::path::{Path, PathBuf};
+use std::{
+    path::{Path, PathBuf},
+    process::ExitStatus,
+};
 
-use chrono::OutOfRangeError;
+use chrono::{DateTime, OutOfRangeError, Utc};
 use core::num::ParseIntError;
 use reqwest::{
     header::{HeaderName, ToStrError},
-    Response, StatusCode,
+    Response, StatusCode, Url,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::{sync::AcquireError, task::JoinError};
 
@@ -16,6 +19,7 @@ struct ApiResponse {
 }
 
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
     #[error("bad status: {0}; {1}")]
     BadStatus(StatusCode, String),
@@ -35,15 +39,81 @@ pub enum Error {
     #[error("bad content type: {0}")]
     BadContentType(String),
 
+    #[error("request quota exhausted, retry at {0}")]
+    QuotaExhausted(DateTime<Utc>),
+
+    #[error("sync cancelled, the next one resumes where it stopped")]
+    Cancelled,
+
+    #[error("offline, not connecting to {0}")]
+    Offline(String),
+
+    #[error("not found: {0}")]
+    NotFound(String),
+
+    #[error("ambiguous: {0}")]
+    Ambiguous(String),
+
     #[error("response error: {0}")]
     ResponseError(String),
 
+    // A response without what its endpoint always sends, e.g. results; the URL unless read back
+    // from the cache.
+    #[error("unexpected response{}: {1}", from_url(.0))]
+    UnexpectedResponse(Option<Url>, String),
+
+    // A record without a field it should have, or with one of the wrong type: the table and ID
+    // as far as known, e.g. no ID for records missing theirs.
+    #[error("{}{field}: {problem}", record(.table, .id))]
+    BadRecord {
+        table: Option<String>,
+        id: Option<u64>,
+        field: String,
+        problem: String,
+    },
+
+    // Config, extraction rules or an import that can't be used as they are.
+    #[error("{}: {1}", .0.display())]
+    BadFile(PathBuf, String),
+
+    #[error("API token is not a valid header value")]
+    BadToken,
+
     #[error("path {0}: {1}")]
     CorruptCache(PathBuf, String),
 
+    #[error("data directory locked: {0}: {1}")]
+    Locked(PathBuf, String),
+
+    #[error("storage error: {0}")]
+    Storage(String),
+
+    #[error("conflicting write, changed by someone else meanwhile: {0}")]
+    Conflict(String),
+
+    #[error("git {0}")]
+    Git(String),
+
+    #[error("{0} failed: {1}")]
+    CommandFailed(String, ExitStatus),
+
+    #[error("keyring: {0}")]
+    Keyring(String),
+
+    #[error("script error: {0}")]
+    Script(String),
+
     #[error("internal error: {0}")]
     Internal(String),
 
+    // Data that needs an optional feature this build was made without, e.g. compressed tables.
+    #[error("built without the {0} feature")]
+    MissingFeature(&'static str),
+
+    #[cfg(any(feature = "export", feature = "import"))]
+    #[error(transparent)]
+    CsvError(#[from] csv::Error),
+
     #[error(transparent)]
     HttpDateError(#[from] httpdate::Error),
 
@@ -53,6 +123,22 @@ pub enum Error {
     #[error(transparent)]
     SerdeYamlError(#[from] serde_yaml::Error),
 
+    #[cfg(feature = "search")]
+    #[error(transparent)]
+    SearchError(#[from] tantivy::TantivyError),
+
+    #[cfg(feature = "search")]
+    #[error(transparent)]
+    SearchQueryError(#[from] tantivy::query::QueryParserError),
+
+    #[cfg(feature = "sql")]
+    #[error(transparent)]
+    SqlError(#[from] rusqlite::Error),
+
+    #[cfg(feature = "templates")]
+    #[error(transparent)]
+    TemplateError(#[from] tera::Error),
+
     #[error(transparent)]
     UrlError(#[from] url::ParseError),
 
@@ -67,6 +153,117 @@ pub enum Error {
 
     #[error(transparent)]
     JoinError(#[from] JoinError),
+
+    #[cfg(any(feature = "fixture", feature = "import"))]
+    #[error(transparent)]
+    ZipError(#[from] zip::result::ZipError),
+}
+
+// Broad categories, stable across new error variants.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ErrorKind {
+    Network,
+    Api,
+    // The API rejecting the token, or the lack of one.
+    Auth,
+    Quota,
+    Cache,
+    Io,
+    NotFound,
+    Input,
+    Internal,
+    Cancelled,
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::ReqwestError(_) | Error::Storage(_) | Error::Offline(_) => ErrorKind::Network,
+            Error::BadStatus(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN, _)
+            | Error::BadToken => ErrorKind::Auth,
+            Error::BadStatus(StatusCode::TOO_MANY_REQUESTS, _) | Error::QuotaExhausted(_) => {
+                ErrorKind::Quota
+            }
+            Error::BadStatus(_, _)
+            | Error::MissingHeader(_)
+            | Error::BadHeaderCoding(_, _)
+            | Error::BadIntFormat(_, _)
+            | Error::BadIntRange(_, _)
+            | Error::BadContentType(_)
+            | Error::ResponseError(_)
+            | Error::UnexpectedResponse(_, _)
+            | Error::BadRecord { .. }
+            | Error::HttpDateError(_)
+            | Error::SerdeJsonError(_) => ErrorKind::Api,
+            Error::Cancelled => ErrorKind::Cancelled,
+            Error::CorruptCache(_, _) | Error::SerdeYamlError(_) => ErrorKind::Cache,
+            Error::IoError(_)
+            | Error::Locked(_, _)
+            | Error::Conflict(_)
+            | Error::Git(_)
+            | Error::CommandFailed(_, _)
+            | Error::Keyring(_) => ErrorKind::Io,
+            Error::NotFound(_) => ErrorKind::NotFound,
+            Error::Ambiguous(_)
+            | Error::Script(_)
+            | Error::BadFile(_, _)
+            | Error::MissingFeature(_) => ErrorKind::Input,
+            #[cfg(any(feature = "export", feature = "import"))]
+            Error::CsvError(_) => ErrorKind::Io,
+            #[cfg(any(feature = "fixture", feature = "import"))]
+            Error::ZipError(_) => ErrorKind::Io,
+            #[cfg(feature = "search")]
+            Error::SearchError(_) => ErrorKind::Cache,
+            #[cfg(feature = "search")]
+            Error::SearchQueryError(_) => ErrorKind::Input,
+            #[cfg(feature = "sql")]
+            Error::SqlError(_) => ErrorKind::Input,
+            #[cfg(feature = "templates")]
+            Error::TemplateError(_) => ErrorKind::Input,
+            Error::Internal(_)
+            | Error::UrlError(_)
+            | Error::AcquireError(_)
+            | Error::JoinError(_) => ErrorKind::Internal,
+        }
+    }
+
+    // Whether the same call might succeed later as it is: connection trouble, the API or storage
+    // being unavailable or busy, the quota, another process holding the data directory, or a
+    // write that lost a race. Not for anything wrong with the data or the input.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::ReqwestError(err) => {
+                is_transient(err) || err.status().is_some_and(is_retryable_status)
+            }
+            Error::BadStatus(status, _) => is_retryable_status(*status),
+            Error::QuotaExhausted(_)
+            | Error::Cancelled
+            | Error::Locked(_, _)
+            | Error::Storage(_)
+            | Error::Conflict(_) => true,
+            _ => false,
+        }
+    }
+
+    // Fills in the table of a record error that doesn't know it yet.
+    pub(crate) fn in_table(self, name: &str) -> Self {
+        match self {
+            Error::BadRecord {
+                table: None,
+                id,
+                field,
+                problem,
+            } => Error::BadRecord {
+                table: Some(name.to_string()),
+                id,
+                field,
+                problem,
+            },
+            err => err,
+        }
+    }
 }
 
 pub fn internal(msg: &str) -> Error {
@@ -77,6 +274,19 @@ pub fn corrupt_cache(path: &Path, msg: &str) -> Error {
     Error::CorruptCache(path.to_path_buf(), msg.to_string())
 }
 
+pub fn unexpected_response(url: Option<&Url>, msg: &str) -> Error {
+    Error::UnexpectedResponse(url.cloned(), msg.to_string())
+}
+
+pub fn bad_record(id: Option<u64>, field: &str, problem: &str) -> Error {
+    Error::BadRecord {
+        table: None,
+        id,
+        field: field.to_string(),
+        problem: problem.to_string(),
+    }
+}
+
 pub async fn bad_status(res: Response) -> Error {
     Error::BadStatus(res.status(), extract_error(res).await)
 }
@@ -89,3 +299,32 @@ async fn extract_error(res: Response) -> String {
         Err(_) => data.to_string(),
     }
 }
+
+// Connection problems and timeouts, that might go away when tried again.
+pub(crate) fn is_transient(err: &reqwest::Error) -> bool {
+    // No such thing as a connection error from the browser's fetch.
+    #[cfg(not(target_arch = "wasm32"))]
+    if err.is_connect() {
+        return true;
+    }
+    err.is_timeout() || err.is_request() || err.is_body()
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+fn from_url(url: &Option<Url>) -> String {
+    url.as_ref()
+        .map(|url| format!(" from {}", url))
+        .unwrap_or_default()
+}
+
+fn record(table: &Option<String>, id: &Option<u64>) -> String {
+    match (table, id) {
+        (Some(table), Some(id)) => format!("{} {}: ", table, id),
+        (Some(table), _) => format!("{}: ", table),
+        (_, Some(id)) => format!("record {}: ", id),
+        _ => "".to_string(),
+    }
+}
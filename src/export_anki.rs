@@ -0,0 +1,137 @@
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, write},
+    path::Path,
+};
+
+use tracing::warn;
+use url::Url;
+
+use crate::{
+    api::Api,
+    archive::{escape_xml, id_field, ids, str_field, Archive},
+    error::Error,
+    filter::Filter,
+};
+
+impl Archive {
+    // Writes deck.csv and, given an Api to download them through, the card images to media/ under
+    // the given directory. Without one, only taxa with their image already in media/ get a card.
+    // The images need to be copied to Anki's collection.media folder before importing.
+    pub async fn export_anki(
+        &self,
+        dir: &Path,
+        rank: Option<&str>,
+        min_observations: usize,
+        api: Option<&Api>,
+        filter: &Filter,
+    ) -> Result<(), Error> {
+        let taxa = self.table("taxa")?;
+        let photos = self.table("photos")?;
+
+        let mut counts: BTreeMap<u64, usize> = BTreeMap::new();
+        let mut own_photos: BTreeMap<u64, u64> = BTreeMap::new();
+        for obs in self.observation_records(filter)?.values() {
+            if let Some(taxon) = id_field(obs, "taxon") {
+                *counts.entry(taxon).or_default() += 1;
+                if let Some(photo) = ids(obs, "photos").first() {
+                    own_photos.entry(taxon).or_insert(*photo);
+                }
+            }
+        }
+
+        let media = dir.join("media");
+        create_dir_all(&media)?;
+        let client = api.map(Api::download_client).transpose()?;
+        let mut csv = csv::Writer::from_path(dir.join("deck.csv"))?;
+        let mut missing = 0;
+        for (id, count) in counts {
+            let taxon = match taxa.get(&id) {
+                Some(taxon) => taxon,
+                _ => continue,
+            };
+            if count < min_observations || rank.is_some_and(|r| str_field(taxon, "rank") != Some(r))
+            {
+                continue;
+            }
+
+            // Prefer the taxon's own photo, it tends to be the clearest one.
+            let photo = id_field(taxon, "default_photo")
+                .or(own_photos.get(&id).copied())
+                .and_then(|id| photos.get(&id).map(|photo| (id, photo)));
+            let (photo_id, url) = match photo.and_then(|(id, p)| Some((id, str_field(p, "url")?))) {
+                Some(photo) => photo,
+                _ => continue,
+            };
+
+            let file = format!("inat_{}.{}", photo_id, extension(url));
+            // Cards go without photos that were never downloaded, they'd only show broken images.
+            if !media.join(&file).exists() {
+                let (Some(api), Some(client)) = (api, &client) else {
+                    missing += 1;
+                    continue;
+                };
+                match api
+                    .download(client, &url.replace("/square.", "/medium."))
+                    .await
+                {
+                    Ok(body) => write(media.join(&file), body)?,
+                    Err(err @ (Error::QuotaExhausted(_) | Error::Cancelled)) => return Err(err),
+                    Err(err) => {
+                        warn!("photo {}: {}", photo_id, err);
+                        continue;
+                    }
+                }
+            }
+
+            let name = str_field(taxon, "name").unwrap_or_default();
+            let back = match str_field(taxon, "preferred_common_name") {
+                Some(common) => format!("{}<br><i>{}</i>", escape_xml(common), escape_xml(name)),
+                _ => format!("<i>{}</i>", escape_xml(name)),
+            };
+            let tags: Vec<_> = [
+                str_field(taxon, "rank"),
+                str_field(taxon, "iconic_taxon_name"),
+            ]
+            .into_iter()
+            .flatten()
+            .collect();
+            csv.write_record([format!(r#"<img src="{}">"#, file), back, tags.join(" ")])?;
+        }
+        csv.flush()?;
+        if missing > 0 {
+            warn!(
+                "{} cards left out, their photos are not in {}",
+                missing,
+                media.display()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+// Of the photo's file name, e.g. jpeg or png; most are jpg.
+fn extension(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|url| {
+            let name = url.path_segments()?.next_back()?.to_string();
+            Some(name.rsplit_once('.')?.1.to_lowercase())
+        })
+        .filter(|ext| !ext.is_empty() && ext.chars().all(|c| c.is_ascii_alphanumeric()))
+        .unwrap_or_else(|| "jpg".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extensions_default_to_jpg() {
+        assert_eq!(extension("https://static/photos/1/square.png?123"), "png");
+        assert_eq!(extension("https://static/photos/1/square.JPEG"), "jpeg");
+        assert_eq!(extension("https://static/photos/1/square"), "jpg");
+        assert_eq!(extension("not a url"), "jpg");
+    }
+}
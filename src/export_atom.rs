@@ -0,0 +1,159 @@
+use std::io::Write;
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    archive::{escape_xml, id_field, ids, str_field, timestamp, Archive, Record},
+    error::Error,
+    filter::Filter,
+    resolve::url,
+};
+
+impl Archive {
+    pub fn export_atom<W: Write>(
+        &self,
+        out: &mut W,
+        limit: usize,
+        filter: &Filter,
+    ) -> Result<(), Error> {
+        let mut observations: Vec<_> = self.observation_records(filter)?.into_iter().collect();
+        observations.sort_by_key(|(id, obs)| (timestamp(obs, "created_at"), *id));
+        observations.reverse();
+        observations.truncate(limit);
+
+        let user = match observations
+            .first()
+            .and_then(|(_, obs)| id_field(obs, "user"))
+        {
+            Some(id) => self.record("users", id)?,
+            _ => None,
+        };
+        let login = user.as_ref().and_then(|user| str_field(user, "login"));
+        let updated = observations
+            .iter()
+            .filter_map(|(_, obs)| timestamp(obs, "updated_at"))
+            .max()
+            .unwrap_or_else(Utc::now);
+
+        writeln!(out, r#"<?xml version="1.0" encoding="utf-8"?>"#)?;
+        writeln!(out, r#"<feed xmlns="http://www.w3.org/2005/Atom">"#)?;
+        match login {
+            Some(login) => {
+                let url = format!("https://www.inaturalist.org/people/{}", login);
+                writeln!(out, "  <title>{}'s observations</title>", escape_xml(login))?;
+                writeln!(out, "  <id>{}</id>", escape_xml(&url))?;
+                writeln!(out, r#"  <link href="{}"/>"#, escape_xml(&url))?;
+                writeln!(out, "  <author><name>{}</name></author>", escape_xml(login))?;
+            }
+            _ => {
+                writeln!(out, "  <title>iNaturalist observations</title>")?;
+                writeln!(out, "  <id>urn:inat:observations</id>")?;
+                writeln!(out, "  <author><name>iNaturalist</name></author>")?;
+            }
+        }
+        writeln!(out, "  <updated>{}</updated>", updated.to_rfc3339())?;
+
+        for (id, obs) in &observations {
+            self.write_entry(out, *id, obs, updated)?;
+        }
+        writeln!(out, "</feed>")?;
+
+        Ok(())
+    }
+
+    fn write_entry<W: Write>(
+        &self,
+        out: &mut W,
+        id: u64,
+        obs: &Record,
+        fallback: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let url = url(id, obs);
+        let published = timestamp(obs, "created_at").unwrap_or(fallback);
+        let updated = timestamp(obs, "updated_at").unwrap_or(published);
+
+        let mut content = String::new();
+        if let Some(photo) = ids(obs, "photos").first() {
+            if let Some(url) = self
+                .record("photos", *photo)?
+                .as_ref()
+                .and_then(|photo| str_field(photo, "url"))
+            {
+                content.push_str(&format!(
+                    r#"<p><img src="{}"/></p>"#,
+                    escape_xml(&url.replace("/square.", "/medium."))
+                ));
+            }
+        }
+        let place = str_field(obs, "place_guess");
+        let date = str_field(obs, "observed_on");
+        if place.is_some() || date.is_some() {
+            let parts: Vec<_> = [date, place].into_iter().flatten().collect();
+            content.push_str(&format!("<p>{}</p>", escape_xml(&parts.join(", "))));
+        }
+        if let Some(description) = str_field(obs, "description") {
+            content.push_str(&format!("<p>{}</p>", escape_xml(description)));
+        }
+
+        writeln!(out, "  <entry>")?;
+        writeln!(
+            out,
+            "    <title>{}</title>",
+            escape_xml(&self.observation_title(obs)?)
+        )?;
+        writeln!(out, "    <id>{}</id>", escape_xml(&url))?;
+        writeln!(out, r#"    <link href="{}"/>"#, escape_xml(&url))?;
+        writeln!(out, "    <published>{}</published>", published.to_rfc3339())?;
+        writeln!(out, "    <updated>{}</updated>", updated.to_rfc3339())?;
+        writeln!(
+            out,
+            r#"    <content type="html">{}</content>"#,
+            escape_xml(&content)
+        )?;
+        writeln!(out, "  </entry>")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn lists_the_newest_observations_first() {
+        let dir = tempdir().expect("tempdir");
+        let archive = Archive::new(dir.path()).expect("archive");
+        archive.insert("users", json!([{ "id": 42, "login": "alice" }]));
+        archive.insert(
+            "photos",
+            json!([{ "id": 5, "url": "https://static/photos/5/square.jpg" }]),
+        );
+        archive.insert(
+            "observations",
+            json!([
+                { "id": 1, "user": 42, "created_at": "2024-05-01T10:00:00Z", "species_guess": "Oldest" },
+                { "id": 2, "user": 42, "created_at": "2024-05-03T10:00:00Z", "species_guess": "Fish & chips", "photos": [5] },
+                { "id": 3, "user": 42, "created_at": "2024-05-02T10:00:00Z", "updated_at": "2024-06-01T10:00:00Z" },
+            ]),
+        );
+
+        let mut out = Vec::new();
+        archive
+            .export_atom(&mut out, 2, &Filter::default())
+            .expect("export");
+        let feed = String::from_utf8(out).expect("utf-8");
+
+        assert!(feed.contains("<title>alice's observations</title>"));
+        assert!(feed.contains("<updated>2024-06-01T10:00:00+00:00</updated>"));
+        assert_eq!(feed.matches("<entry>").count(), 2);
+        let newest = feed.find("Fish &amp; chips").expect("newest");
+        let next = feed.find("<title>Unknown</title>").expect("next");
+        assert!(newest < next);
+        assert!(!feed.contains("Oldest"));
+        assert!(feed.contains("photos/5/medium.jpg"));
+    }
+}
@@ -0,0 +1,204 @@
+use std::{collections::BTreeMap, io::Write};
+
+use chrono::{NaiveDate, TimeDelta};
+
+use crate::{
+    archive::{coordinates, str_field, timestamp, Archive, Record},
+    error::Error,
+    filter::Filter,
+};
+
+// Content lines longer than this many octets have to be folded.
+const MAX_LINE: usize = 75;
+
+impl Archive {
+    // All-day events, one per observation or (per_day) one per field day.
+    pub fn export_ics<W: Write>(
+        &self,
+        out: &mut W,
+        per_day: bool,
+        filter: &Filter,
+    ) -> Result<(), Error> {
+        let stamp = self.store.now().format("%Y%m%dT%H%M%SZ").to_string();
+        let mut days: BTreeMap<NaiveDate, Vec<(u64, Record)>> = BTreeMap::new();
+        for (id, obs) in self.observation_records(filter)? {
+            if let Some(date) = observed_on(&obs) {
+                days.entry(date).or_default().push((id, obs));
+            }
+        }
+
+        write_line(out, "BEGIN:VCALENDAR")?;
+        write_line(out, "VERSION:2.0")?;
+        write_line(out, "PRODID:-//inat//observations//EN")?;
+        write_line(out, "CALSCALE:GREGORIAN")?;
+
+        for (date, observations) in &days {
+            if per_day {
+                let titles = observations
+                    .iter()
+                    .map(|(_, obs)| self.observation_title(obs))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let description: Vec<_> = observations
+                    .iter()
+                    .zip(&titles)
+                    .map(|((_, obs), title)| match str_field(obs, "uri") {
+                        Some(url) => format!("{} {}", title, url),
+                        _ => title.to_string(),
+                    })
+                    .collect();
+                let summary = match observations.len() {
+                    1 => titles[0].to_string(),
+                    n => format!("{} observations", n),
+                };
+
+                write_event_start(out, &format!("day-{}", date.format("%Y%m%d")), date)?;
+                write_line(out, &format!("DTSTAMP:{}", stamp))?;
+                write_line(out, &format!("SUMMARY:{}", escape_text(&summary)))?;
+                write_line(
+                    out,
+                    &format!("DESCRIPTION:{}", escape_text(&description.join("\n"))),
+                )?;
+                write_line(out, "END:VEVENT")?;
+                continue;
+            }
+
+            for (id, obs) in observations {
+                let updated = timestamp(obs, "updated_at")
+                    .map(|ts| ts.format("%Y%m%dT%H%M%SZ").to_string())
+                    .unwrap_or_else(|| stamp.to_string());
+
+                write_event_start(out, &format!("observation-{}", id), date)?;
+                write_line(out, &format!("DTSTAMP:{}", updated))?;
+                write_line(
+                    out,
+                    &format!("SUMMARY:{}", escape_text(&self.observation_title(obs)?)),
+                )?;
+                if let Some(place) = str_field(obs, "place_guess") {
+                    write_line(out, &format!("LOCATION:{}", escape_text(place)))?;
+                }
+                if let Some((lat, lng)) = coordinates(obs) {
+                    write_line(out, &format!("GEO:{};{}", lat, lng))?;
+                }
+                if let Some(url) = str_field(obs, "uri") {
+                    write_line(out, &format!("URL:{}", url))?;
+                }
+                if let Some(description) = str_field(obs, "description") {
+                    write_line(out, &format!("DESCRIPTION:{}", escape_text(description)))?;
+                }
+                write_line(out, "END:VEVENT")?;
+            }
+        }
+        write_line(out, "END:VCALENDAR")?;
+
+        Ok(())
+    }
+}
+
+fn observed_on(obs: &Record) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(str_field(obs, "observed_on")?, "%Y-%m-%d").ok()
+}
+
+fn write_event_start<W: Write>(out: &mut W, uid: &str, date: &NaiveDate) -> Result<(), Error> {
+    write_line(out, "BEGIN:VEVENT")?;
+    write_line(out, &format!("UID:{}@inaturalist.org", uid))?;
+    write_line(
+        out,
+        &format!("DTSTART;VALUE=DATE:{}", date.format("%Y%m%d")),
+    )?;
+    write_line(
+        out,
+        &format!(
+            "DTEND;VALUE=DATE:{}",
+            (*date + TimeDelta::days(1)).format("%Y%m%d")
+        ),
+    )
+}
+
+// Folds the line at octet boundaries without splitting characters, with CRLF endings.
+fn write_line<W: Write>(out: &mut W, line: &str) -> Result<(), Error> {
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf8() > MAX_LINE {
+            write!(out, "\r\n ")?;
+            len = 1;
+        }
+        write!(out, "{}", c)?;
+        len += c.len_utf8();
+    }
+    write!(out, "\r\n")?;
+
+    Ok(())
+}
+
+fn escape_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::clock::FixedClock;
+
+    fn export(archive: &Archive, per_day: bool) -> String {
+        let mut out = Vec::new();
+        archive
+            .export_ics(&mut out, per_day, &Filter::default())
+            .expect("export");
+        String::from_utf8(out).expect("utf-8")
+    }
+
+    fn archive(dir: &std::path::Path) -> Archive {
+        let archive = Archive::new(dir).expect("archive").with_clock(FixedClock(
+            Utc.with_ymd_and_hms(2024, 7, 1, 8, 0, 0).unwrap(),
+        ));
+        archive.insert(
+            "observations",
+            json!([
+                { "id": 1, "observed_on": "2024-05-01", "species_guess": "Raven", "place_guess": "Bern, CH" },
+                { "id": 2, "observed_on": "2024-05-01", "species_guess": "Crow" },
+                { "id": 3, "species_guess": "Undated" },
+            ]),
+        );
+        archive
+    }
+
+    #[test]
+    fn exports_one_event_per_observation() {
+        let dir = tempdir().expect("tempdir");
+        let ics = export(&archive(dir.path()), false);
+        assert_eq!(ics.matches("BEGIN:VEVENT").count(), 2);
+        assert!(ics.contains("UID:observation-1@inaturalist.org\r\n"));
+        assert!(ics.contains("DTSTART;VALUE=DATE:20240501\r\nDTEND;VALUE=DATE:20240502\r\n"));
+        assert!(ics.contains("LOCATION:Bern\\, CH\r\n"));
+        assert!(ics.contains("DTSTAMP:20240701T080000Z\r\n"));
+    }
+
+    #[test]
+    fn exports_one_event_per_day() {
+        let dir = tempdir().expect("tempdir");
+        let ics = export(&archive(dir.path()), true);
+        assert_eq!(ics.matches("BEGIN:VEVENT").count(), 1);
+        assert!(ics.contains("UID:day-20240501@inaturalist.org\r\n"));
+        assert!(ics.contains("SUMMARY:2 observations\r\n"));
+        assert!(ics.contains("DESCRIPTION:Raven\\nCrow\r\n"));
+    }
+
+    #[test]
+    fn folds_long_lines_between_characters() {
+        let line = format!("SUMMARY:{}", "é".repeat(80));
+        let mut out = Vec::new();
+        write_line(&mut out, &line).expect("write");
+
+        let folded = String::from_utf8(out).expect("utf-8");
+        assert!(folded.split("\r\n").all(|line| line.len() <= MAX_LINE));
+        assert_eq!(folded.replace("\r\n ", ""), format!("{}\r\n", line));
+    }
+}
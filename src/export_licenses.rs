@@ -0,0 +1,247 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::Write,
+};
+
+use crate::{
+    archive::{id_field, ids, str_field, Archive, Record},
+    error::Error,
+    filter::Filter,
+};
+
+// What the API reports for media without a license.
+const ALL_RIGHTS_RESERVED: &str = "all rights reserved";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AttributionFormat {
+    Csv,
+    Markdown,
+}
+
+struct Entry {
+    kind: &'static str,
+    id: u64,
+    attribution: String,
+    url: String,
+    used_by: Vec<String>,
+}
+
+impl Archive {
+    // Attribution manifest of photos and observations, grouped by license.
+    pub fn export_licenses<W: Write>(
+        &self,
+        out: &mut W,
+        format: AttributionFormat,
+        filter: &Filter,
+    ) -> Result<(), Error> {
+        let observations = self.observation_records(filter)?;
+        let users = self.table("users")?;
+
+        let mut used_by: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+        for (id, obs) in &observations {
+            for photo in ids(obs, "photos") {
+                used_by
+                    .entry(photo)
+                    .or_default()
+                    .push(format!("observation {}", id));
+            }
+        }
+        let taxa: HashSet<_> = observations
+            .values()
+            .filter_map(|obs| id_field(obs, "taxon"))
+            .collect();
+        for (id, taxon) in self.table("taxa")? {
+            if !filter.is_empty() && !taxa.contains(&id) {
+                continue;
+            }
+            if let Some(photo) = id_field(&taxon, "default_photo") {
+                used_by
+                    .entry(photo)
+                    .or_default()
+                    .push(format!("taxon {}", id));
+            }
+        }
+
+        let mut licenses: BTreeMap<String, Vec<Entry>> = BTreeMap::new();
+        for (id, photo) in self.table("photos")? {
+            // Filtered manifests only cover the photos in use by what's left.
+            if !filter.is_empty() && !used_by.contains_key(&id) {
+                continue;
+            }
+            licenses.entry(license(&photo)).or_default().push(Entry {
+                kind: "photo",
+                id,
+                attribution: str_field(&photo, "attribution")
+                    .unwrap_or_default()
+                    .to_string(),
+                url: str_field(&photo, "url").unwrap_or_default().to_string(),
+                used_by: used_by.remove(&id).unwrap_or_default(),
+            });
+        }
+        for (id, obs) in &observations {
+            let login = id_field(obs, "user")
+                .and_then(|id| users.get(&id))
+                .and_then(|user| str_field(user, "login"));
+            licenses.entry(license(obs)).or_default().push(Entry {
+                kind: "observation",
+                id: *id,
+                attribution: login
+                    .map(|login| format!("(c) {}", login))
+                    .unwrap_or_default(),
+                url: str_field(obs, "uri").unwrap_or_default().to_string(),
+                used_by: vec![],
+            });
+        }
+
+        // Flagged entries go first, those can't be republished without permission.
+        let mut groups: Vec<_> = licenses.into_iter().collect();
+        groups.sort_by_key(|(license, _)| license != ALL_RIGHTS_RESERVED);
+
+        match format {
+            AttributionFormat::Csv => write_csv(out, &groups),
+            AttributionFormat::Markdown => write_markdown(out, &groups),
+        }
+    }
+}
+
+fn license(record: &Record) -> String {
+    match str_field(record, "license_code") {
+        Some(code) if !code.is_empty() => code.to_lowercase(),
+        _ => ALL_RIGHTS_RESERVED.to_string(),
+    }
+}
+
+fn write_csv<W: Write>(out: &mut W, groups: &[(String, Vec<Entry>)]) -> Result<(), Error> {
+    let mut csv = csv::Writer::from_writer(out);
+    csv.write_record([
+        "license",
+        "all_rights_reserved",
+        "kind",
+        "id",
+        "attribution",
+        "url",
+        "used_by",
+    ])?;
+    for (license, entries) in groups {
+        for entry in entries {
+            csv.write_record([
+                license,
+                &(license == ALL_RIGHTS_RESERVED).to_string(),
+                entry.kind,
+                &entry.id.to_string(),
+                &entry.attribution,
+                &entry.url,
+                &entry.used_by.join("; "),
+            ])?;
+        }
+    }
+    csv.flush()?;
+
+    Ok(())
+}
+
+fn write_markdown<W: Write>(out: &mut W, groups: &[(String, Vec<Entry>)]) -> Result<(), Error> {
+    writeln!(out, "# License and attribution report")?;
+    for (license, entries) in groups {
+        writeln!(out)?;
+        if license == ALL_RIGHTS_RESERVED {
+            writeln!(out, "## ⚠ All rights reserved ({})", entries.len())?;
+            writeln!(out)?;
+            writeln!(out, "These need the owner's permission to republish.")?;
+        } else {
+            writeln!(out, "## {} ({})", license.to_uppercase(), entries.len())?;
+        }
+        writeln!(out)?;
+        for entry in entries {
+            write!(out, "- {} {}", entry.kind, entry.id)?;
+            if !entry.attribution.is_empty() {
+                write!(out, ": {}", entry.attribution)?;
+            }
+            if !entry.url.is_empty() {
+                write!(out, " <{}>", entry.url)?;
+            }
+            if !entry.used_by.is_empty() {
+                write!(out, " (used by {})", entry.used_by.join(", "))?;
+            }
+            writeln!(out)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn archive(dir: &std::path::Path) -> Archive {
+        let archive = Archive::new(dir).expect("archive");
+        archive.insert("users", json!([{ "id": 42, "login": "alice" }]));
+        archive.insert(
+            "photos",
+            json!([
+                { "id": 5, "license_code": "CC-BY", "attribution": "(c) alice, some rights reserved" },
+                { "id": 6, "attribution": "(c) alice, all rights reserved" },
+                { "id": 7, "license_code": "cc0" },
+            ]),
+        );
+        archive.insert("taxa", json!([{ "id": 3, "default_photo": 7 }]));
+        archive.insert(
+            "observations",
+            json!([
+                { "id": 1, "user": 42, "license_code": "cc-by-nc", "photos": [5], "quality_grade": "research" },
+                { "id": 2, "user": 42, "photos": [6], "quality_grade": "casual" },
+            ]),
+        );
+        archive
+    }
+
+    fn export(archive: &Archive, format: AttributionFormat, filter: &Filter) -> String {
+        let mut out = Vec::new();
+        archive
+            .export_licenses(&mut out, format, filter)
+            .expect("export");
+        String::from_utf8(out).expect("utf-8")
+    }
+
+    #[test]
+    fn lists_all_rights_reserved_first() {
+        let dir = tempdir().expect("tempdir");
+        let csv = export(
+            &archive(dir.path()),
+            AttributionFormat::Csv,
+            &Filter::default(),
+        );
+        let rows: Vec<_> = csv.lines().skip(1).collect();
+        assert_eq!(
+            rows,
+            [
+                "all rights reserved,true,photo,6,\"(c) alice, all rights reserved\",,observation 2",
+                "all rights reserved,true,observation,2,(c) alice,,",
+                "cc-by,false,photo,5,\"(c) alice, some rights reserved\",,observation 1",
+                "cc-by-nc,false,observation,1,(c) alice,,",
+                "cc0,false,photo,7,,,taxon 3",
+            ]
+        );
+    }
+
+    #[test]
+    fn filters_down_to_the_photos_in_use() {
+        let dir = tempdir().expect("tempdir");
+        let filter = Filter {
+            quality_grade: Some("research".to_string()),
+            ..Filter::default()
+        };
+        let markdown = export(&archive(dir.path()), AttributionFormat::Markdown, &filter);
+        assert!(!markdown.contains("All rights reserved"));
+        assert!(markdown.contains(
+            "## CC-BY (1)\n\n- photo 5: (c) alice, some rights reserved (used by observation 1)\n"
+        ));
+        assert!(markdown.contains("- observation 1: (c) alice\n"));
+        assert!(!markdown.contains("photo 7"));
+    }
+}
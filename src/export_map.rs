@@ -0,0 +1,97 @@
+use std::io::Write;
+
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+
+use crate::{
+    archive::{coordinates, ids, str_field, Archive},
+    error::Error,
+    filter::Filter,
+};
+
+const LEAFLET: &str = "https://unpkg.com/leaflet@1.9.4/dist";
+const MARKERCLUSTER: &str = "https://unpkg.com/leaflet.markercluster@1.5.3/dist";
+
+#[derive(Serialize)]
+struct Marker<'a> {
+    id: u64,
+    lat: f64,
+    lng: f64,
+    url: Option<&'a str>,
+    date: Option<&'a str>,
+    name: Option<&'a str>,
+    common_name: Option<&'a str>,
+    thumbnail: Option<&'a str>,
+}
+
+impl Archive {
+    pub fn export_map<W: Write>(&self, out: &mut W, filter: &Filter) -> Result<(), Error> {
+        let observations = self.observation_records(filter)?;
+        let taxa = self.table("taxa")?;
+        let photos = self.table("photos")?;
+
+        let markers: Vec<_> = observations
+            .iter()
+            .filter_map(|(id, obs)| {
+                let (lat, lng) = coordinates(obs)?;
+                let taxon = obs.get("taxon").and_then(JsonValue::as_u64);
+                let taxon = taxon.and_then(|id| taxa.get(&id));
+                let photo = ids(obs, "photos").first().and_then(|id| photos.get(id));
+                Some(Marker {
+                    id: *id,
+                    lat,
+                    lng,
+                    url: str_field(obs, "uri"),
+                    date: str_field(obs, "observed_on"),
+                    name: taxon
+                        .and_then(|t| str_field(t, "name"))
+                        .or(str_field(obs, "species_guess")),
+                    common_name: taxon.and_then(|t| str_field(t, "preferred_common_name")),
+                    thumbnail: photo.and_then(|p| str_field(p, "url")),
+                })
+            })
+            .collect();
+
+        // Keep the inline JSON from closing the script tag early.
+        let data = serde_json::to_string(&markers)?.replace("</", "<\\/");
+        write!(
+            out,
+            include_str!("export_map.html"),
+            leaflet = LEAFLET,
+            markercluster = MARKERCLUSTER,
+            data = data,
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn keeps_the_markers_inside_the_script() {
+        let dir = tempdir().expect("tempdir");
+        let archive = Archive::new(dir.path()).expect("archive");
+        archive.insert(
+            "observations",
+            json!([
+                { "id": 1, "species_guess": "</script><b>", "geojson": { "coordinates": [7.4, 46.9] } },
+                { "id": 2, "species_guess": "Nowhere" },
+            ]),
+        );
+
+        let mut out = Vec::new();
+        archive
+            .export_map(&mut out, &Filter::default())
+            .expect("export");
+        let html = String::from_utf8(out).expect("utf-8");
+        assert!(html.contains(r#""id":1,"lat":46.9,"lng":7.4"#));
+        assert!(html.contains(r#"<\/script><b>"#));
+        assert!(!html.contains("Nowhere"));
+    }
+}
@@ -0,0 +1,208 @@
+use std::io::Write;
+
+use chrono::{DateTime, NaiveDate};
+use serde_json::Value as JsonValue;
+use tracing::warn;
+
+use crate::{
+    archive::{coordinates, id_field, ids, str_field, Archive, Record},
+    error::Error,
+    filter::Filter,
+};
+
+impl Archive {
+    // One row per observation with a value for the given observation field (ID or name).
+    pub fn export_ofv<W: Write>(
+        &self,
+        out: &mut W,
+        field: &str,
+        filter: &Filter,
+    ) -> Result<(), Error> {
+        let fields = self.table("observation_fields")?;
+        let (field_id, field) = fields
+            .iter()
+            .find(|(id, f)| {
+                id.to_string() == field
+                    || str_field(f, "name").is_some_and(|name| name.eq_ignore_ascii_case(field))
+            })
+            .ok_or(Error::NotFound(format!("observation field: {}", field)))?;
+        let name = str_field(field, "name").unwrap_or("value");
+        let datatype = str_field(field, "datatype").unwrap_or("text");
+
+        let ofvs = self.table("observation_field_values")?;
+        let taxa = self.table("taxa")?;
+
+        let mut csv = csv::Writer::from_writer(out);
+        let mut header = vec![
+            "observation_id",
+            "observed_on",
+            "taxon_id",
+            "taxon_name",
+            "latitude",
+            "longitude",
+            name,
+        ];
+        if datatype == "taxon" {
+            header.push("value_taxon_name");
+        }
+        csv.write_record(&header)?;
+
+        for (id, obs) in self.observation_records(filter)? {
+            let ofv = ids(&obs, "ofvs")
+                .into_iter()
+                .filter_map(|id| ofvs.get(&id))
+                .find(|ofv| {
+                    id_field(ofv, "observation_field").or(id_field(ofv, "field_id"))
+                        == Some(*field_id)
+                });
+            let ofv = match ofv {
+                Some(ofv) => ofv,
+                _ => continue,
+            };
+
+            let taxon = id_field(&obs, "taxon");
+            let (lat, lng) = match coordinates(&obs) {
+                Some((lat, lng)) => (lat.to_string(), lng.to_string()),
+                _ => (String::new(), String::new()),
+            };
+            let value = typed_value(ofv, datatype).unwrap_or_else(|| {
+                warn!("observation {}: bad {} value for {}", id, datatype, name);
+                String::new()
+            });
+
+            let mut row = vec![
+                id.to_string(),
+                str_field(&obs, "observed_on")
+                    .unwrap_or_default()
+                    .to_string(),
+                taxon.map(|id| id.to_string()).unwrap_or_default(),
+                taxon
+                    .and_then(|id| taxa.get(&id))
+                    .and_then(|t| str_field(t, "name"))
+                    .unwrap_or_default()
+                    .to_string(),
+                lat,
+                lng,
+                value.clone(),
+            ];
+            if datatype == "taxon" {
+                row.push(
+                    value
+                        .parse()
+                        .ok()
+                        .and_then(|id: u64| taxa.get(&id))
+                        .and_then(|t| str_field(t, "name"))
+                        .unwrap_or_default()
+                        .to_string(),
+                );
+            }
+            csv.write_record(&row)?;
+        }
+        csv.flush()?;
+
+        Ok(())
+    }
+}
+
+fn typed_value(ofv: &Record, datatype: &str) -> Option<String> {
+    let value = match ofv.get("value")? {
+        JsonValue::String(val) => val.trim().to_string(),
+        JsonValue::Null => return Some(String::new()),
+        val => val.to_string(),
+    };
+
+    Some(match datatype {
+        "numeric" => value.parse::<f64>().ok()?.to_string(),
+        "date" => NaiveDate::parse_from_str(value.get(..10)?, "%Y-%m-%d")
+            .ok()?
+            .to_string(),
+        "datetime" => DateTime::parse_from_rfc3339(&value).ok()?.to_rfc3339(),
+        "boolean" => match value.to_lowercase().as_str() {
+            "yes" | "true" => "true".to_string(),
+            "no" | "false" => "false".to_string(),
+            _ => return None,
+        },
+        // Taxon values hold the ID, the name goes in its own column.
+        "taxon" => match id_field(ofv, "taxon") {
+            Some(id) => id.to_string(),
+            _ => value.parse::<u64>().ok()?.to_string(),
+        },
+        _ => value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn ofv(value: JsonValue) -> Record {
+        json!({ "value": value })
+            .as_object()
+            .expect("object")
+            .clone()
+    }
+
+    #[test]
+    fn normalises_values_by_datatype() {
+        assert_eq!(
+            typed_value(&ofv(json!(" 3.50 ")), "numeric").as_deref(),
+            Some("3.5")
+        );
+        assert_eq!(typed_value(&ofv(json!("many")), "numeric"), None);
+        assert_eq!(
+            typed_value(&ofv(json!("2024-05-01T10:00:00Z")), "date").as_deref(),
+            Some("2024-05-01")
+        );
+        assert_eq!(
+            typed_value(&ofv(json!("Yes")), "boolean").as_deref(),
+            Some("true")
+        );
+        assert_eq!(typed_value(&ofv(json!("maybe")), "boolean"), None);
+        assert_eq!(typed_value(&ofv(json!(12)), "taxon").as_deref(), Some("12"));
+        assert_eq!(
+            typed_value(&ofv(JsonValue::Null), "numeric").as_deref(),
+            Some("")
+        );
+    }
+
+    #[test]
+    fn exports_the_observations_with_the_field() {
+        let dir = tempdir().expect("tempdir");
+        let archive = Archive::new(dir.path()).expect("archive");
+        archive.insert(
+            "observation_fields",
+            json!([{ "id": 9, "name": "Host plant", "datatype": "taxon" }]),
+        );
+        archive.insert(
+            "observation_field_values",
+            json!([{ "id": 90, "observation_field": 9, "value": "3", "taxon": 3 }]),
+        );
+        archive.insert(
+            "taxa",
+            json!([{ "id": 2, "name": "Papilio machaon" }, { "id": 3, "name": "Daucus carota" }]),
+        );
+        archive.insert(
+            "observations",
+            json!([
+                { "id": 1, "observed_on": "2024-05-01", "taxon": 2, "ofvs": [90] },
+                { "id": 2, "observed_on": "2024-05-02" },
+            ]),
+        );
+
+        let mut out = Vec::new();
+        archive
+            .export_ofv(&mut out, "host PLANT", &Filter::default())
+            .expect("export");
+        assert_eq!(
+            String::from_utf8(out).expect("utf-8"),
+            "observation_id,observed_on,taxon_id,taxon_name,latitude,longitude,Host plant,value_taxon_name\n\
+             1,2024-05-01,2,Papilio machaon,,,3,Daucus carota\n"
+        );
+
+        let err = archive.export_ofv(&mut Vec::new(), "Missing", &Filter::default());
+        assert!(matches!(err, Err(Error::NotFound(_))));
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{create_dir_all, read_to_string, write},
+    path::{Path, PathBuf},
+};
+
+use serde_json::Value as JsonValue;
+use tera::{Context, Tera};
+
+use crate::{
+    archive::{Archive, Record},
+    error::Error,
+    filter::Filter,
+};
+
+const TEMPLATE: &str = "export";
+
+impl Archive {
+    // Renders the template once per record into dir/{id}.{ext}.
+    pub fn export_template_records(
+        &self,
+        template: &Path,
+        table: &str,
+        dir: &Path,
+        filter: &Filter,
+    ) -> Result<(), Error> {
+        let tera = self.tera(template)?;
+        let ext = record_extension(template);
+
+        create_dir_all(dir)?;
+        for (id, record) in self.filtered(table, filter)? {
+            let mut context = Context::new();
+            context.insert("table", table);
+            context.insert("id", &id);
+            context.insert("record", &record);
+            write(
+                dir.join(format!("{}.{}", id, ext)),
+                tera.render(TEMPLATE, &context)?,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // Renders the template once, with all records of the table in context.
+    pub fn export_template_table(
+        &self,
+        template: &Path,
+        table: &str,
+        output: &Path,
+        filter: &Filter,
+    ) -> Result<(), Error> {
+        let tera = self.tera(template)?;
+        let records: Vec<_> = self.filtered(table, filter)?.into_values().collect();
+
+        let mut context = Context::new();
+        context.insert("table", table);
+        context.insert("records", &records);
+        write(output, tera.render(TEMPLATE, &context)?)?;
+
+        Ok(())
+    }
+
+    // Filters only apply to observations, other tables are rendered whole.
+    fn filtered(&self, table: &str, filter: &Filter) -> Result<BTreeMap<u64, Record>, Error> {
+        match table {
+            "observations" => self.observation_records(filter),
+            _ => self.table(table),
+        }
+    }
+
+    // Templates can pull in referenced records, e.g. lookup(table="taxa", id=record.taxon).
+    fn tera(&self, template: &Path) -> Result<Tera, Error> {
+        let mut tera = Tera::default();
+        tera.add_raw_template(TEMPLATE, &read_to_string(template)?)?;
+
+        let store = self.store.clone();
+        tera.register_function("lookup", move |args: &HashMap<String, JsonValue>| {
+            let table = args
+                .get("table")
+                .and_then(JsonValue::as_str)
+                .ok_or("lookup: missing table")?;
+            let id = match args.get("id") {
+                Some(JsonValue::Null) | None => return Ok(JsonValue::Null),
+                Some(id) => id.as_u64().ok_or("lookup: id is not u64")?,
+            };
+            match store.get(table, id) {
+                Ok(Some((_, record))) => Ok(JsonValue::Object(record)),
+                Ok(None) => Ok(JsonValue::Null),
+                Err(err) => Err(err.to_string().into()),
+            }
+        });
+
+        Ok(tera)
+    }
+}
+
+// observation.md.tera renders to {id}.md
+fn record_extension(template: &Path) -> String {
+    let name = PathBuf::from(template.file_stem().unwrap_or_default());
+    match (template.extension(), name.extension()) {
+        (Some(ext), Some(inner)) if ext == "tera" => inner.to_string_lossy().to_string(),
+        _ => "txt".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn takes_the_extension_from_the_template_name() {
+        assert_eq!(record_extension(Path::new("observation.md.tera")), "md");
+        assert_eq!(record_extension(Path::new("dir/page.html.tera")), "html");
+        assert_eq!(record_extension(Path::new("observation.tera")), "txt");
+        assert_eq!(record_extension(Path::new("observation.md")), "txt");
+    }
+
+    #[test]
+    fn renders_records_with_their_lookups() {
+        let dir = tempdir().expect("tempdir");
+        let archive = Archive::new(dir.path().join("data")).expect("archive");
+        archive.insert("taxa", json!([{ "id": 2, "name": "Corvus corax" }]));
+        archive.insert(
+            "observations",
+            json!([{ "id": 1, "taxon": 2 }, { "id": 3, "taxon": null }]),
+        );
+        let template = dir.path().join("observation.md.tera");
+        write(
+            &template,
+            r#"{{ id }}: {% set taxon = lookup(table="taxa", id=record.taxon) %}{{ taxon.name | default(value="?") }}"#,
+        )
+        .expect("write");
+
+        let out = dir.path().join("out");
+        archive
+            .export_template_records(&template, "observations", &out, &Filter::default())
+            .expect("export");
+        assert_eq!(
+            read_to_string(out.join("1.md")).expect("read"),
+            "1: Corvus corax"
+        );
+        assert_eq!(read_to_string(out.join("3.md")).expect("read"), "3: ?");
+    }
+}
@@ -0,0 +1,85 @@
+use std::{collections::HashMap, mem::take};
+
+use serde_json::Value as JsonValue;
+
+use crate::{
+    api::ID,
+    archive::Record,
+    error::Error,
+    normalise::{extract_object_by, extract_objects_by, AllTables},
+};
+
+// An extraction pass of one's own, e.g. for a table the crate doesn't know about, or fields
+// derived from others. Installed with Api::with_extractor or Archive::with_extractor, it runs for
+// each batch the normaliser writes, after the built-in passes and before anything is written.
+// Layout conversions, verification and the like only know the built-in tables.
+pub trait TableExtractor: Send + Sync {
+    // The tables of its own the pass fills, written along with the built-in ones.
+    fn tables(&self) -> &[&'static str] {
+        &[]
+    }
+
+    fn extract(&self, batch: &mut Batch) -> Result<(), Error>;
+}
+
+// The records of a batch, by table, the extractors' own tables included.
+pub struct Batch<'a> {
+    pub(crate) tables: &'a mut AllTables,
+}
+
+impl Batch<'_> {
+    // The built-in ones, then the extractors' own.
+    pub fn tables(&self) -> Vec<&'static str> {
+        self.tables.names()
+    }
+
+    // None for tables that are neither built in nor any extractor's own.
+    pub fn table(&mut self, table: &str) -> Option<&mut HashMap<u64, Record>> {
+        self.tables.table_mut(table)
+    }
+
+    // Moves the records nested under the key (a single one, or an array of them) into another
+    // table, leaving their IDs in their place, like the built-in passes do.
+    pub fn extract(&mut self, from: &str, key: &str, into: &str) -> Result<(), Error> {
+        self.move_records(from, key, into, ID, None)
+    }
+
+    // Like extract, with records identified by the given field, and either always a single one
+    // or always an array of them if many is given.
+    pub(crate) fn move_records(
+        &mut self,
+        from: &str,
+        key: &str,
+        into: &str,
+        id: &str,
+        many: Option<bool>,
+    ) -> Result<(), Error> {
+        if self.table(into).is_none() {
+            return Err(Error::NotFound(format!("table {}", into)));
+        }
+        let mut records = match self.table(from) {
+            Some(records) => take(records),
+            _ => return Err(Error::NotFound(format!("table {}", from))),
+        };
+
+        let mut extracted = vec![];
+        let res = records.values_mut().try_for_each(|record| {
+            let many = many.unwrap_or(matches!(record.get(key), Some(JsonValue::Array(_))));
+            match many {
+                true => extracted.extend(extract_objects_by(record, key, id)?),
+                _ => extracted.extend(extract_object_by(record, key, id)?),
+            }
+            Ok(())
+        });
+        let res = res.map_err(|err: Error| err.in_table(from));
+        // Put back either way, the records extracted so far only hold IDs now.
+        if let Some(table) = self.table(from) {
+            *table = records;
+        }
+        if let Some(table) = self.table(into) {
+            table.extend(extracted);
+        }
+
+        res
+    }
+}
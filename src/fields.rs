@@ -0,0 +1,319 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Formatter, Result as FmtResult},
+};
+
+// Which fields of the records to ask API v2 for, in its fields= syntax: e.g.
+// Fields::new(["id", "name"]).with("taxon", Fields::new(["id"])) is (id:!t,name:!t,taxon:(id:!t)).
+// Nested records get only the fields given for them; v1 sends them all, whatever is asked for.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Fields {
+    // None for plain fields, so that asking for a record's ID alone stays short.
+    fields: BTreeMap<String, Option<Fields>>,
+}
+
+impl Fields {
+    pub fn new<I: IntoIterator<Item = S>, S: Into<String>>(names: I) -> Self {
+        names
+            .into_iter()
+            .fold(Self::default(), |fields, name| fields.field(name))
+    }
+
+    // Keeps the nested fields already asked for, if any.
+    pub fn field<S: Into<String>>(mut self, name: S) -> Self {
+        self.fields.entry(name.into()).or_default();
+        self
+    }
+
+    // Merged with the nested fields already asked for, if any.
+    pub fn with<S: Into<String>>(mut self, name: S, nested: Fields) -> Self {
+        let entry = self.fields.entry(name.into()).or_default();
+        *entry = Some(entry.take().unwrap_or_default().merge(nested));
+        self
+    }
+
+    pub fn merge(self, other: Fields) -> Self {
+        other
+            .fields
+            .into_iter()
+            .fold(self, |fields, (name, nested)| match nested {
+                Some(nested) => fields.with(name, nested),
+                _ => fields.field(name),
+            })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+impl Display for Fields {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "(")?;
+        for (i, (name, nested)) in self.fields.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            match nested {
+                Some(nested) => write!(f, "{}:{}", name, nested)?,
+                _ => write!(f, "{}:!t", name)?,
+            }
+        }
+        write!(f, ")")
+    }
+}
+
+// What the syncs ask API v2 for: the fields of the models, those the exports and reports read, and
+// the records the normaliser moves into their own tables, with the same fields wherever they're
+// nested. Keep in sync with models.rs and the REFERENCES of the normaliser.
+
+// Of listings, which only need the IDs.
+pub(crate) fn ids() -> Fields {
+    Fields::new(["id"])
+}
+
+// Of the records of an endpoint fetched by ID, like /observations/{id,id,...}.
+pub(crate) fn by_path(path: &str) -> Option<fn() -> Fields> {
+    match path {
+        "/observations" => Some(observations),
+        "/taxa" => Some(taxa),
+        "/users" => Some(users),
+        _ => None,
+    }
+}
+
+pub(crate) fn species_counts() -> Fields {
+    Fields::new(["count"]).with("taxon", ids())
+}
+
+fn observations() -> Fields {
+    Fields::new([
+        "created_at",
+        "description",
+        "geoprivacy",
+        "id",
+        "license_code",
+        "location",
+        "obscured",
+        "observed_on",
+        "place_guess",
+        "place_ids",
+        "positional_accuracy",
+        "preferences",
+        "quality_grade",
+        "species_guess",
+        "taxon_geoprivacy",
+        "time_observed_at",
+        "updated_at",
+        "uri",
+        "uuid",
+    ])
+    .with("annotations", annotations())
+    .with("application", Fields::new(["icon", "id", "name", "url"]))
+    .with("comments", comments())
+    .with("community_taxon", taxon())
+    .with(
+        "faves",
+        Fields::new(["created_at", "id"]).with("user", users()),
+    )
+    .with("flags", flags())
+    .with("geojson", Fields::new(["coordinates", "type"]))
+    .with("identifications", identifications())
+    .with("non_owner_ids", identifications())
+    .with(
+        "observation_photos",
+        Fields::new(["id", "position", "uuid"]).with("photo", photo()),
+    )
+    .with(
+        "observation_sounds",
+        Fields::new(["id", "uuid"]).with("sound", sound()),
+    )
+    .with("ofvs", observation_field_values())
+    .with("outlinks", Fields::new(["source", "url"]))
+    .with("photos", photo())
+    .with("project_observations", project_observations())
+    .with("quality_metrics", quality_metrics())
+    .with("sounds", sound())
+    .with("taxon", taxon())
+    .with("user", users())
+    .with("votes", votes())
+}
+
+// Of taxa fetched on their own, which also have their ancestors, photos, statuses and lists per
+// place, unlike nested ones; the taxon photos tell the syncs which taxa are enriched already.
+fn taxa() -> Fields {
+    taxon()
+        .with("ancestors", taxon())
+        .with(
+            "conservation_statuses",
+            Fields::new([
+                "authority",
+                "geoprivacy",
+                "iucn",
+                "id",
+                "status",
+                "status_name",
+            ])
+            .with("place", place()),
+        )
+        .with(
+            "listed_taxa",
+            Fields::new(["establishment_means", "id", "occurrence_status_level"])
+                .with("list", Fields::new(["id", "title"]))
+                .with("place", place()),
+        )
+        .with(
+            "taxon_photos",
+            Fields::new(["taxon_id"]).with("photo", photo()),
+        )
+}
+
+pub(crate) fn sites() -> Fields {
+    Fields::new([
+        "icon_url",
+        "id",
+        "locale",
+        "name",
+        "place_id",
+        "site_name_short",
+        "url",
+    ])
+}
+
+pub(crate) fn users() -> Fields {
+    Fields::new([
+        "created_at",
+        "icon",
+        "id",
+        "login",
+        "name",
+        "observations_count",
+        "preferences",
+        "site_id",
+    ])
+}
+
+fn taxon() -> Fields {
+    Fields::new([
+        "ancestor_ids",
+        "iconic_taxon_name",
+        "id",
+        "is_active",
+        "name",
+        "observations_count",
+        "parent_id",
+        "preferred_common_name",
+        "rank",
+        "rank_level",
+        "wikipedia_url",
+    ])
+    .with(
+        "conservation_status",
+        Fields::new(["authority", "iucn", "id", "place_id", "status"]),
+    )
+    .with("default_photo", photo())
+}
+
+fn place() -> Fields {
+    Fields::new(["display_name", "id", "name"])
+}
+
+fn photo() -> Fields {
+    Fields::new(["attribution", "id", "license_code", "url"])
+        .with("flags", flags())
+        .with("original_dimensions", Fields::new(["height", "width"]))
+}
+
+fn sound() -> Fields {
+    Fields::new([
+        "attribution",
+        "file_content_type",
+        "file_url",
+        "id",
+        "license_code",
+    ])
+    .with("flags", flags())
+}
+
+fn flags() -> Fields {
+    Fields::new(["created_at", "flag", "id", "resolved", "user_id"])
+}
+
+fn identifications() -> Fields {
+    Fields::new([
+        "body",
+        "category",
+        "created_at",
+        "current",
+        "disagreement",
+        "id",
+        "uuid",
+    ])
+    .with("flags", flags())
+    .with("previous_observation_taxon", taxon())
+    .with("taxon", taxon())
+    .with("taxon_change", Fields::new(["id", "type"]))
+    .with("user", users())
+    .with("votes", votes())
+}
+
+fn comments() -> Fields {
+    Fields::new(["body", "created_at", "id", "uuid"])
+        .with("flags", flags())
+        .with("user", users())
+        .with("votes", votes())
+}
+
+fn annotations() -> Fields {
+    let term = Fields::new(["id", "label", "multivalued"]).with(
+        "labels",
+        Fields::new(["definition", "id", "label", "locale"]),
+    );
+    Fields::new([
+        "controlled_attribute_id",
+        "controlled_value_id",
+        "user_id",
+        "uuid",
+        "vote_score",
+    ])
+    .with(
+        "controlled_attribute",
+        term.clone().with("values", term.clone()),
+    )
+    .with("controlled_value", term)
+    .with("user", users())
+    .with("votes", votes())
+}
+
+fn observation_field_values() -> Fields {
+    Fields::new(["datatype", "field_id", "id", "name", "uuid", "value"])
+        .with(
+            "observation_field",
+            Fields::new(["allowed_values", "datatype", "description", "id", "name"]),
+        )
+        .with("taxon", taxon())
+        .with("user", users())
+}
+
+fn project_observations() -> Fields {
+    Fields::new(["id", "uuid"])
+        .with("project", Fields::new(["icon", "id", "slug", "title"]))
+        .with("project_user", Fields::new(["id", "role"]))
+        .with("user", users())
+}
+
+fn quality_metrics() -> Fields {
+    Fields::new(["agree", "created_at", "id", "metric"]).with("user", users())
+}
+
+fn votes() -> Fields {
+    Fields::new([
+        "created_at",
+        "id",
+        "votable_id",
+        "votable_type",
+        "vote_flag",
+        "vote_scope",
+    ])
+    .with("user", users())
+}
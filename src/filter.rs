@@ -0,0 +1,348 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use chrono::NaiveDate;
+
+use crate::{
+    archive::{id_field, ids, str_field, Archive, Record},
+    error::Error,
+    models::{from_record, Observation},
+};
+
+// Which observations to include; the default matches everything.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct Filter {
+    pub quality_grade: Option<String>,
+    // Taxon ID or (common) name, matching the taxon and everything below it.
+    pub taxon: Option<String>,
+    // Observed on or after this day.
+    pub after: Option<NaiveDate>,
+    // Observed on or before this day.
+    pub before: Option<NaiveDate>,
+    // Place ID, or part of the place guess.
+    pub place: Option<String>,
+    // Project ID, slug or title.
+    pub project: Option<String>,
+    pub with_photos: bool,
+}
+
+// A filter with names resolved against the archive, ready for matching.
+pub(crate) struct Matcher<'a> {
+    filter: &'a Filter,
+    taxa: Option<HashSet<u64>>,
+    // Matching project IDs, and which project each project observation is in.
+    projects: Option<(HashSet<u64>, HashMap<u64, u64>)>,
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        self.quality_grade.is_none()
+            && self.taxon.is_none()
+            && self.after.is_none()
+            && self.before.is_none()
+            && self.place.is_none()
+            && self.project.is_none()
+            && !self.with_photos
+    }
+
+    pub(crate) fn matcher(&self, archive: &Archive) -> Result<Matcher<'_>, Error> {
+        let taxa = match &self.taxon {
+            Some(taxon) => Some(subtree(&archive.table("taxa")?, taxon)),
+            _ => None,
+        };
+
+        let projects = match &self.project {
+            Some(project) => Some((
+                projects(&archive.table("projects")?, project),
+                archive
+                    .table("project_observations")?
+                    .into_iter()
+                    .filter_map(|(id, po)| Some((id, id_field(&po, "project")?)))
+                    .collect(),
+            )),
+            _ => None,
+        };
+
+        Ok(Matcher {
+            filter: self,
+            taxa,
+            projects,
+        })
+    }
+}
+
+impl Matcher<'_> {
+    pub(crate) fn matches(&self, obs: &Record) -> bool {
+        let filter = self.filter;
+        if let Some(grade) = &filter.quality_grade {
+            if str_field(obs, "quality_grade") != Some(grade) {
+                return false;
+            }
+        }
+        if let Some(taxa) = &self.taxa {
+            if !id_field(obs, "taxon").is_some_and(|id| taxa.contains(&id)) {
+                return false;
+            }
+        }
+        if filter.after.is_some() || filter.before.is_some() {
+            let date = str_field(obs, "observed_on")
+                .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok());
+            match date {
+                Some(date)
+                    if filter.after.is_none_or(|after| date >= after)
+                        && filter.before.is_none_or(|before| date <= before) => {}
+                _ => return false,
+            }
+        }
+        if let Some(place) = &filter.place {
+            let found = match place.parse::<u64>() {
+                Ok(id) => ids(obs, "place_ids").contains(&id),
+                _ => str_field(obs, "place_guess")
+                    .is_some_and(|guess| guess.to_lowercase().contains(&place.to_lowercase())),
+            };
+            if !found {
+                return false;
+            }
+        }
+        if let Some((projects, memberships)) = &self.projects {
+            let found = ids(obs, "project_ids")
+                .into_iter()
+                .chain(
+                    ids(obs, "project_observations")
+                        .iter()
+                        .filter_map(|id| memberships.get(id).copied()),
+                )
+                .any(|id| projects.contains(&id));
+            if !found {
+                return false;
+            }
+        }
+        if filter.with_photos && ids(obs, "photos").is_empty() {
+            return false;
+        }
+
+        true
+    }
+}
+
+impl Archive {
+    pub fn observations(&self, filter: &Filter) -> Result<BTreeMap<u64, Observation>, Error> {
+        self.observation_records(filter)?
+            .into_iter()
+            .map(|(id, obs)| from_record(obs).map(|obs| (id, obs)))
+            .collect()
+    }
+
+    // Like observations, untyped.
+    pub(crate) fn observation_records(
+        &self,
+        filter: &Filter,
+    ) -> Result<BTreeMap<u64, Record>, Error> {
+        let mut observations = self.table("observations")?;
+        if !filter.is_empty() {
+            let matcher = filter.matcher(self)?;
+            observations.retain(|_, obs| matcher.matches(obs));
+        }
+
+        Ok(observations)
+    }
+}
+
+fn projects(projects: &BTreeMap<u64, Record>, project: &str) -> HashSet<u64> {
+    if let Ok(id) = project.parse::<u64>() {
+        return HashSet::from([id]);
+    }
+
+    let name = project.to_lowercase();
+    projects
+        .iter()
+        .filter(|(_, project)| {
+            ["slug", "title"]
+                .iter()
+                .any(|key| str_field(project, key).is_some_and(|n| n.to_lowercase() == name))
+        })
+        .map(|(id, _)| *id)
+        .collect()
+}
+
+// The taxa matching the ID or name, plus all their descendants.
+fn subtree(taxa: &BTreeMap<u64, Record>, taxon: &str) -> HashSet<u64> {
+    let roots: HashSet<u64> = match taxon.parse::<u64>() {
+        Ok(id) => HashSet::from([id]),
+        _ => {
+            let name = taxon.to_lowercase();
+            taxa.iter()
+                .filter(|(_, taxon)| {
+                    ["name", "preferred_common_name"]
+                        .iter()
+                        .any(|key| str_field(taxon, key).is_some_and(|n| n.to_lowercase() == name))
+                })
+                .map(|(id, _)| *id)
+                .collect()
+        }
+    };
+
+    let mut matched = roots.clone();
+    for (id, taxon) in taxa {
+        if ids(taxon, "ancestor_ids")
+            .iter()
+            .any(|ancestor| roots.contains(ancestor))
+        {
+            matched.insert(*id);
+        }
+    }
+
+    matched
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn archive(dir: &std::path::Path) -> Archive {
+        let archive = Archive::new(dir).expect("archive");
+        archive.insert(
+            "taxa",
+            json!([
+                { "id": 1, "name": "Aves", "preferred_common_name": "Birds", "ancestor_ids": [1] },
+                { "id": 2, "name": "Corvus corax", "ancestor_ids": [1, 2] },
+                { "id": 3, "name": "Plantae", "ancestor_ids": [3] },
+            ]),
+        );
+        archive.insert(
+            "projects",
+            json!([{ "id": 10, "slug": "city-birds", "title": "City Birds" }]),
+        );
+        archive.insert(
+            "project_observations",
+            json!([{ "id": 100, "project": 10 }]),
+        );
+        archive.insert(
+            "observations",
+            json!([
+                {
+                    "id": 1001,
+                    "quality_grade": "research",
+                    "taxon": 2,
+                    "observed_on": "2024-05-01",
+                    "place_guess": "Zürich, Switzerland",
+                    "place_ids": [7],
+                    "project_observations": [100],
+                    "photos": [5],
+                },
+                {
+                    "id": 1002,
+                    "quality_grade": "needs_id",
+                    "taxon": 3,
+                    "observed_on": "2024-06-01",
+                    "place_guess": "Bern",
+                    "project_ids": [10],
+                },
+                { "id": 1003, "quality_grade": "casual" },
+            ]),
+        );
+        archive
+    }
+
+    fn matching(archive: &Archive, filter: Filter) -> Vec<u64> {
+        archive
+            .observation_records(&filter)
+            .expect("observations")
+            .into_keys()
+            .collect()
+    }
+
+    #[test]
+    fn matches_everything_by_default() {
+        let dir = tempdir().expect("tempdir");
+        let archive = archive(dir.path());
+        assert!(Filter::default().is_empty());
+        assert_eq!(matching(&archive, Filter::default()), [1001, 1002, 1003]);
+    }
+
+    #[test]
+    fn matches_taxa_by_name_and_below() {
+        let dir = tempdir().expect("tempdir");
+        let archive = archive(dir.path());
+        for taxon in ["birds", "Aves", "1"] {
+            let filter = Filter {
+                taxon: Some(taxon.to_string()),
+                ..Filter::default()
+            };
+            assert_eq!(matching(&archive, filter), [1001], "{}", taxon);
+        }
+    }
+
+    #[test]
+    fn matches_dates_inclusively() {
+        let dir = tempdir().expect("tempdir");
+        let archive = archive(dir.path());
+        let day = |day| NaiveDate::from_ymd_opt(2024, 5, day);
+        let filter = Filter {
+            after: day(1),
+            before: day(31),
+            ..Filter::default()
+        };
+        assert_eq!(matching(&archive, filter), [1001]);
+        let filter = Filter {
+            after: day(2),
+            ..Filter::default()
+        };
+        assert_eq!(matching(&archive, filter), [1002]);
+    }
+
+    #[test]
+    fn matches_places_by_id_or_guess() {
+        let dir = tempdir().expect("tempdir");
+        let archive = archive(dir.path());
+        for (place, expected) in [
+            ("7", vec![1001]),
+            ("zürich", vec![1001]),
+            ("bern", vec![1002]),
+        ] {
+            let filter = Filter {
+                place: Some(place.to_string()),
+                ..Filter::default()
+            };
+            assert_eq!(matching(&archive, filter), expected, "{}", place);
+        }
+    }
+
+    #[test]
+    fn matches_projects_directly_and_through_memberships() {
+        let dir = tempdir().expect("tempdir");
+        let archive = archive(dir.path());
+        for project in ["city-birds", "city birds", "10"] {
+            let filter = Filter {
+                project: Some(project.to_string()),
+                ..Filter::default()
+            };
+            assert_eq!(matching(&archive, filter), [1001, 1002], "{}", project);
+        }
+    }
+
+    #[test]
+    fn matches_every_condition() {
+        let dir = tempdir().expect("tempdir");
+        let archive = archive(dir.path());
+        let filter = Filter {
+            quality_grade: Some("needs_id".to_string()),
+            ..Filter::default()
+        };
+        assert_eq!(matching(&archive, filter), [1002]);
+        let filter = Filter {
+            with_photos: true,
+            ..Filter::default()
+        };
+        assert_eq!(matching(&archive, filter), [1001]);
+        let filter = Filter {
+            quality_grade: Some("needs_id".to_string()),
+            with_photos: true,
+            ..Filter::default()
+        };
+        assert!(matching(&archive, filter).is_empty());
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{create_dir_all, read_dir, read_link},
+    io::ErrorKind,
+};
+
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::{
+    archive::{id_field, ids, Archive, Record},
+    error::Error,
+    normalise::{REFERENCES, TABLES},
+};
+
+type Tables = HashMap<&'static str, BTreeMap<u64, Record>>;
+
+#[derive(Debug, Default, Serialize)]
+#[non_exhaustive]
+pub struct GcReport {
+    // Unreferenced record IDs per table, removed unless it was a dry run.
+    pub removed: BTreeMap<String, Vec<u64>>,
+}
+
+impl Archive {
+    // Removes records no longer reachable, see reachable.
+    pub fn gc(&self, dry_run: bool) -> Result<GcReport, Error> {
+        let tables = self.all_tables()?;
+        let mut reachable = self.reachable(&tables)?;
+
+        let mut report = GcReport::default();
+        for table in TABLES {
+            let keep = reachable.remove(table).unwrap_or_default();
+            let orphans: Vec<u64> = tables[table]
+                .keys()
+                .filter(|id| !keep.contains(id))
+                .copied()
+                .collect();
+            if orphans.is_empty() {
+                continue;
+            }
+            if !dry_run {
+                self.store.remove_records(table, &orphans)?;
+            }
+            report.removed.insert(table.to_string(), orphans);
+        }
+        self.store.compact()?;
+
+        Ok(report)
+    }
+
+    // Moves observations deleted on iNat to deleted/{table}/{id}.yaml, along with the records only
+    // they referred to, e.g. their identifications; they'd be orphans otherwise. Gc removes the
+    // orphans that were there already.
+    pub(crate) fn move_deleted(&self, observations: &[u64]) -> Result<(), Error> {
+        let mut tables = self.all_tables()?;
+        let mut queue: Vec<(&str, u64)> = observations
+            .iter()
+            .map(|id| ("observations", *id))
+            .collect();
+        let mut referred: HashMap<&str, HashSet<u64>> = HashMap::new();
+        while let Some((table, id)) = queue.pop() {
+            if !referred.entry(table).or_default().insert(id) {
+                continue;
+            }
+            if let Some(record) = tables[table].get(&id) {
+                queue.extend(references(table, record));
+            }
+        }
+
+        if let Some(remaining) = tables.get_mut("observations") {
+            let observations: HashSet<u64> = observations.iter().copied().collect();
+            remaining.retain(|id, _| !observations.contains(id));
+        }
+        let reachable = self.reachable(&tables)?;
+        for table in TABLES {
+            let moved: Vec<u64> = referred
+                .remove(table)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|id| !reachable.get(table).is_some_and(|keep| keep.contains(id)))
+                .sorted()
+                .collect();
+            if moved.is_empty() {
+                continue;
+            }
+            let dir = self.path("deleted").join(table);
+            create_dir_all(&dir)?;
+            for id in &moved {
+                if let Some((header, record)) = self.store.get(table, *id)? {
+                    self.store
+                        .write_file(&dir.join(format!("{}.yaml", id)), &header, &record)?;
+                }
+            }
+            self.store.remove_records(table, &moved)?;
+        }
+        self.store.compact()
+    }
+
+    fn all_tables(&self) -> Result<Tables, Error> {
+        let mut tables = Tables::new();
+        for table in TABLES {
+            tables.insert(table, self.table(table)?);
+        }
+
+        Ok(tables)
+    }
+
+    // Everything reachable from any observation or synced user; sites are all kept, the syncs list
+    // them in full.
+    fn reachable(&self, tables: &Tables) -> Result<HashMap<&'static str, HashSet<u64>>, Error> {
+        let mut queue: Vec<(&str, u64)> = tables["observations"]
+            .keys()
+            .map(|id| ("observations", *id))
+            .collect();
+        queue.extend(self.synced_users()?.into_iter().map(|id| ("users", id)));
+        queue.extend(tables["sites"].keys().map(|id| ("sites", *id)));
+
+        let mut reachable: HashMap<&str, HashSet<u64>> = HashMap::new();
+        while let Some((table, id)) = queue.pop() {
+            if !reachable.entry(table).or_default().insert(id) {
+                continue;
+            }
+            if let Some(record) = tables[table].get(&id) {
+                queue.extend(references(table, record));
+            }
+        }
+
+        Ok(reachable)
+    }
+
+    // Users with a login symlink or an observation listing are sync state, not just references.
+    fn synced_users(&self) -> Result<HashSet<u64>, Error> {
+        let dir = self.path("users");
+        let files = match read_dir(&dir) {
+            Ok(files) => files,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(HashSet::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut users = HashSet::new();
+        for file in files {
+            let path = file?.path();
+            let name = match read_link(&path) {
+                Ok(target) => target,
+                _ => path,
+            };
+            if let Some(id) = name
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.split('.').next())
+                .and_then(|id| id.parse().ok())
+            {
+                users.insert(id);
+            }
+        }
+
+        Ok(users)
+    }
+}
+
+// Everything the record points at, including the references not listed in REFERENCES.
+fn references<'a>(table: &'a str, record: &'a Record) -> Vec<(&'static str, u64)> {
+    let mut targets = vec![];
+    for reference in REFERENCES.iter().filter(|r| r.table == table) {
+        match reference.many {
+            true => targets.extend(
+                ids(record, reference.key)
+                    .into_iter()
+                    .map(|id| (reference.target, id)),
+            ),
+            _ => targets.extend(id_field(record, reference.key).map(|id| (reference.target, id))),
+        }
+    }
+
+    match table {
+        // Abbreviated taxa only list the IDs of their ancestors.
+        "taxa" => targets.extend(
+            ids(record, "ancestor_ids")
+                .into_iter()
+                .map(|id| ("taxa", id)),
+        ),
+        // Annotations older versions kept in the observations.
+        "observations" => {
+            for annotation in record
+                .get("annotations")
+                .and_then(|annotations| annotations.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(|annotation| annotation.as_object())
+            {
+                for key in ["controlled_attribute", "controlled_value"] {
+                    targets.extend(id_field(annotation, key).map(|id| ("controlled_terms", id)));
+                }
+                targets.extend(id_field(annotation, "user").map(|id| ("users", id)));
+                targets.extend(ids(annotation, "votes").into_iter().map(|id| ("votes", id)));
+            }
+        }
+        _ => {}
+    }
+
+    targets
+}
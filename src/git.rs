@@ -0,0 +1,97 @@
+use std::{
+    fs::write,
+    path::Path,
+    process::{Command, Output},
+};
+
+use tracing::{debug, info};
+
+use crate::{api::Api, error::Error, summary::SyncSummary};
+
+// Sync state and caches change on every run, only the tables are worth keeping history of.
+const GITIGNORE: &str = "/.http-cache/\n/.lock\n/.raw/\n/.sync/\n";
+
+// Used when git doesn't know who the user is.
+const NAME: &str = "inat";
+const EMAIL: &str = "inat@localhost";
+
+impl Api {
+    // Commits whatever changed in the data directory, with the sync's summary as the message. The data directory becomes a repository of its own if it's not in one already.
+    pub(crate) fn commit_sync(&self, username: &str, summary: &SyncSummary) -> Result<(), Error> {
+        // Unchanged records are rewritten with a new date, which is not worth a commit by itself.
+        let total = summary.changes.total();
+        if total.new + total.updated + total.deleted == 0 {
+            debug!("no records changed, not committing");
+            return Ok(());
+        }
+
+        let dir = self.path("");
+        if git(&dir, &["rev-parse", "--is-inside-work-tree"]).is_err() {
+            info!("creating a git repository in {}", dir.display());
+            git(&dir, &["init", "--quiet"])?;
+        }
+        // Also when in a larger repository, which shouldn't get the caches either.
+        if !dir.join(".gitignore").exists() {
+            write(dir.join(".gitignore"), GITIGNORE)?;
+        }
+
+        git(&dir, &["add", "--all", "--", "."])?;
+        if git(&dir, &["diff", "--cached", "--quiet", "--", "."]).is_ok() {
+            debug!("nothing to commit");
+            return Ok(());
+        }
+
+        let identity = [
+            format!("user.name={}", NAME),
+            format!("user.email={}", EMAIL),
+        ];
+        let mut args = vec![];
+        if git(&dir, &["config", "user.email"]).is_err() {
+            args.extend(["-c", &identity[0], "-c", &identity[1]]);
+        }
+        let message = message(username, summary);
+        args.extend(["commit", "--quiet", "--message", &message, "--", "."]);
+        git(&dir, &args)?;
+
+        Ok(())
+    }
+}
+
+// E.g. "Sync alice: 2 new, 1 updated, 0 deleted records", followed by the tables that changed.
+fn message(username: &str, summary: &SyncSummary) -> String {
+    let total = summary.changes.total();
+    let mut message = format!(
+        "Sync {}: {} new, {} updated, {} deleted records\n",
+        username, total.new, total.updated, total.deleted
+    );
+    let mut tables = summary
+        .changes
+        .tables
+        .iter()
+        .filter(|(_, changes)| changes.new + changes.updated + changes.deleted > 0)
+        .peekable();
+    if tables.peek().is_some() {
+        message.push('\n');
+    }
+    for (table, changes) in tables {
+        message.push_str(&format!(
+            "{}: {} new, {} updated, {} deleted\n",
+            table, changes.new, changes.updated, changes.deleted
+        ));
+    }
+
+    message
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<Output, Error> {
+    let output = Command::new("git").arg("-C").arg(dir).args(args).output()?;
+    match output.status.success() {
+        true => Ok(output),
+        _ => Err(Error::Git(format!(
+            "{}: {}; {}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))),
+    }
+}
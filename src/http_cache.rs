@@ -0,0 +1,250 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fs::{create_dir_all, read, read_dir, remove_file, write, File},
+    io::{BufReader, ErrorKind},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use reqwest::{
+    header::{DATE, ETAG},
+    Url,
+};
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping as YamlMapping, Value as YamlValue};
+use sha2::{Digest, Sha256};
+
+use crate::{cache_control::CacheControl, error::Error};
+
+// Responses not stored or revalidated for this long are dropped, e.g. those of ID chunks that
+// have since been split differently.
+const MAX_IDLE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+// And the least recently stored ones beyond this size.
+const MAX_SIZE: u64 = 256 << 20;
+
+// Responses to GET requests, keyed by URL, like a private HTTP cache (RFC 9111). Only what the
+// API allows to be stored; stale responses are revalidated with their ETag or Date.
+pub(crate) struct HttpCache {
+    dir: PathBuf,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Entry {
+    url: String,
+    // As returned along with the body, with the Date adjusted for the Age.
+    pub(crate) header: YamlMapping,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_age: Option<u64>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    no_cache: bool,
+    #[serde(skip)]
+    pub(crate) body: Bytes,
+}
+
+impl Entry {
+    pub(crate) fn date(&self) -> Option<DateTime<Utc>> {
+        let date = self.header.get(YamlValue::String(DATE.to_string()))?;
+        Some(DateTime::parse_from_rfc3339(date.as_str()?).ok()?.to_utc())
+    }
+
+    pub(crate) fn etag(&self) -> Option<&str> {
+        self.header
+            .get(YamlValue::String(ETAG.to_string()))?
+            .as_str()
+    }
+
+    // Can be used without asking the API.
+    pub(crate) fn is_fresh(&self, now: DateTime<Utc>) -> bool {
+        match (self.no_cache, self.max_age, self.date()) {
+            (false, Some(max_age), Some(date)) => (now - date).num_seconds() < max_age as i64,
+            _ => false,
+        }
+    }
+}
+
+impl HttpCache {
+    pub(crate) fn new(data_dir: &Path) -> Self {
+        Self {
+            dir: data_dir.join(".http-cache"),
+        }
+    }
+
+    // The token the request was sent with is part of the key, since the account can change the
+    // response, e.g. with private coordinates.
+    pub(crate) fn get(&self, url: &Url, token: Option<&str>) -> Result<Option<Entry>, Error> {
+        let (meta, body) = self.paths(url, token);
+        let mut entry: Entry = match File::open(&meta) {
+            Ok(file) => serde_yaml::from_reader(BufReader::new(file))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        // Hash collisions are unlikely, but cheap to rule out.
+        if entry.url != url.as_str() {
+            return Ok(None);
+        }
+        entry.body = match read(&body) {
+            Ok(body) => body.into(),
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Some(entry))
+    }
+
+    pub(crate) fn put(
+        &self,
+        url: &Url,
+        token: Option<&str>,
+        header: &YamlMapping,
+        cc: &CacheControl,
+        body: &Bytes,
+    ) -> Result<(), Error> {
+        if cc.no_store {
+            return Ok(());
+        }
+        let (meta, body_path) = self.paths(url, token);
+        create_dir_all(&self.dir)?;
+        // The body goes first, so that the entry is never there without it.
+        write(&body_path, body)?;
+        self.write_entry(&meta, url, header, cc)
+    }
+
+    // After a 304, the cached response is as good as new.
+    pub(crate) fn refresh(
+        &self,
+        url: &Url,
+        token: Option<&str>,
+        mut entry: Entry,
+        header: Option<YamlMapping>,
+        cc: &CacheControl,
+    ) -> Result<Entry, Error> {
+        if let Some(header) = header {
+            if let Some(date) = header.get(YamlValue::String(DATE.to_string())) {
+                entry
+                    .header
+                    .insert(YamlValue::String(DATE.to_string()), date.clone());
+            }
+        }
+        entry.max_age = cc.max_age;
+        entry.no_cache = cc.no_cache;
+        if !cc.no_store {
+            let (meta, _) = self.paths(url, token);
+            self.write_entry(&meta, url, &entry.header, cc)?;
+        }
+
+        Ok(entry)
+    }
+
+    // Drops idle responses, then the oldest ones until the rest fit; returns how many.
+    pub(crate) fn evict(&self) -> Result<usize, Error> {
+        let dir = match read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        // By name: the last time either file was written, and their size.
+        let mut entries: HashMap<String, (SystemTime, u64)> = HashMap::new();
+        for file in dir {
+            let path = file?.path();
+            let (Some(name), Some("json" | "yaml")) = (
+                path.file_stem().and_then(|name| name.to_str()),
+                path.extension().and_then(|ext| ext.to_str()),
+            ) else {
+                continue;
+            };
+            let md = path.metadata()?;
+            let entry = entries
+                .entry(name.to_string())
+                .or_insert((SystemTime::UNIX_EPOCH, 0));
+            entry.0 = entry.0.max(md.modified()?);
+            entry.1 += md.len();
+        }
+
+        let now = SystemTime::now();
+        let mut entries: Vec<_> = entries.into_iter().collect();
+        entries.sort_by_key(|(name, (modified, _))| (Reverse(*modified), name.clone()));
+        let mut size = 0;
+        let mut evicted = 0;
+        for (name, (modified, len)) in entries {
+            size += len;
+            let idle = now.duration_since(modified).unwrap_or_default();
+            if idle > MAX_IDLE || size > MAX_SIZE {
+                for ext in ["yaml", "json"] {
+                    match remove_file(self.dir.join(format!("{}.{}", name, ext))) {
+                        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                        _ => {}
+                    }
+                }
+                evicted += 1;
+            }
+        }
+
+        Ok(evicted)
+    }
+
+    fn write_entry(
+        &self,
+        path: &Path,
+        url: &Url,
+        header: &YamlMapping,
+        cc: &CacheControl,
+    ) -> Result<(), Error> {
+        let entry = Entry {
+            url: url.to_string(),
+            header: header.clone(),
+            max_age: cc.max_age,
+            no_cache: cc.no_cache,
+            body: Bytes::new(),
+        };
+        Ok(write(path, serde_yaml::to_string(&entry)?)?)
+    }
+
+    fn paths(&self, url: &Url, token: Option<&str>) -> (PathBuf, PathBuf) {
+        let mut hash = Sha256::new();
+        match token {
+            Some(token) => {
+                hash.update([1]);
+                hash.update(Sha256::digest(token));
+            }
+            _ => hash.update([0]),
+        }
+        hash.update(url.as_str());
+        let name = format!("{:x}", hash.finalize());
+        (
+            self.dir.join(format!("{}.yaml", name)),
+            self.dir.join(format!("{}.json", name)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn keys_responses_by_token() {
+        let dir = tempdir().expect("tempdir");
+        let cache = HttpCache::new(dir.path());
+        let url = Url::parse("https://api.inaturalist.org/v1/users/me").expect("url");
+        let body = Bytes::from_static(b"{}");
+        cache
+            .put(
+                &url,
+                Some("alice"),
+                &YamlMapping::new(),
+                &CacheControl::default(),
+                &body,
+            )
+            .expect("put");
+
+        assert!(cache.get(&url, Some("alice")).expect("get").is_some());
+        assert!(cache.get(&url, Some("bob")).expect("get").is_none());
+        assert!(cache.get(&url, None).expect("get").is_none());
+    }
+}
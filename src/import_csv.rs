@@ -0,0 +1,239 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::create_dir_all,
+    path::Path,
+};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use serde_yaml::{Mapping as YamlMapping, Value as YamlValue};
+
+use crate::{
+    api::{lookup_cache_ids, ID},
+    archive::{Archive, Record},
+    error::Error,
+};
+
+// Columns copied verbatim, by their name in the export and in the normalised record.
+const TEXT_FIELDS: [(&str, &str); 8] = [
+    ("url", "uri"),
+    ("observed_on", "observed_on"),
+    ("quality_grade", "quality_grade"),
+    ("license", "license_code"),
+    ("description", "description"),
+    ("place_guess", "place_guess"),
+    ("species_guess", "species_guess"),
+    ("time_zone", "observed_time_zone"),
+];
+
+const TIME_FIELDS: [&str; 3] = ["time_observed_at", "created_at", "updated_at"];
+
+#[derive(Debug, Default, Serialize)]
+pub struct CsvReport {
+    pub observations: usize,
+    // Stub records written for observations not yet in the cache.
+    pub seeded: usize,
+}
+
+impl Archive {
+    // Seeds the observation ID lists and stub records from iNat's CSV export;
+    // the next sync fetches the full records without having to list them first.
+    pub fn import_csv(&self, path: &Path) -> Result<CsvReport, Error> {
+        let mut csv = csv::Reader::from_path(path)?;
+        let columns = csv.headers()?.clone();
+        let column = |name: &str| columns.iter().position(|col| col == name);
+        let missing = |name| Error::BadFile(path.to_path_buf(), format!("no {} column", name));
+        let id = column(ID).ok_or_else(|| missing(ID))?;
+        let user_id = column("user_id").ok_or_else(|| missing("user_id"))?;
+
+        let mut report = CsvReport::default();
+        let mut users: BTreeMap<u64, BTreeSet<u64>> = BTreeMap::new();
+        let mut records = vec![];
+        let mut exported_at: Option<DateTime<Utc>> = None;
+        for row in csv.records() {
+            let row = row?;
+            let get = |col: usize| row.get(col).filter(|v| !v.is_empty());
+            let (id, user_id) = match (
+                get(id).and_then(|id| id.parse::<u64>().ok()),
+                get(user_id).and_then(|id| id.parse::<u64>().ok()),
+            ) {
+                (Some(id), Some(user_id)) => (id, user_id),
+                _ => continue,
+            };
+            report.observations += 1;
+            users.entry(user_id).or_default().insert(id);
+
+            let mut obs = Record::new();
+            obs.insert(ID.to_string(), json!(id));
+            obs.insert("user".to_string(), json!(user_id));
+            for (from, to) in TEXT_FIELDS {
+                if let Some(val) = column(from).and_then(get) {
+                    obs.insert(to.to_string(), json!(val));
+                }
+            }
+            for key in TIME_FIELDS {
+                if let Some(ts) = column(key).and_then(get).and_then(parse_time) {
+                    obs.insert(key.to_string(), json!(ts.to_rfc3339()));
+                    if key == "created_at" {
+                        exported_at = exported_at.max(Some(ts));
+                    }
+                }
+            }
+            if let Some(taxon) = column("taxon_id").and_then(get) {
+                if let Ok(taxon) = taxon.parse::<u64>() {
+                    obs.insert("taxon".to_string(), json!(taxon));
+                }
+            }
+            if let (Some(lat), Some(lng)) = (
+                column("latitude").and_then(get),
+                column("longitude").and_then(get),
+            ) {
+                obs.insert("location".to_string(), json!(format!("{},{}", lat, lng)));
+            }
+            records.push((id, obs));
+        }
+
+        // The export has no date of its own; the newest observation in it is a safe lower bound.
+        let mut header = YamlMapping::new();
+        header.insert(
+            YamlValue::String("date".to_string()),
+            YamlValue::String(exported_at.unwrap_or_default().to_rfc3339()),
+        );
+
+        let dir = self.path("users");
+        create_dir_all(&dir)?;
+        // An existing list is more current than the export, and listing resumes after its
+        // last ID, so seeding it with IDs from a partial export could leave gaps.
+        for (user_id, ids) in users {
+            let path = dir.join(format!("{}.observations.yaml", user_id));
+            if lookup_cache_ids(&path)?.is_none() {
+                self.store.write_cache(&path, &header, &ids)?;
+            }
+        }
+
+        let mut seeds = vec![];
+        for (id, obs) in &records {
+            if !self.store.contains("observations", *id)? {
+                seeds.push((*id, obs));
+            }
+        }
+        report.seeded = seeds.len();
+        self.store.put("observations", &header, seeds)?;
+        self.store.compact()?;
+
+        Ok(report)
+    }
+}
+
+// The export uses "2024-05-01 12:34:56 UTC", but be lenient in case that changes.
+fn parse_time(val: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(val)
+        .map(|ts| ts.to_utc())
+        .or_else(|_| {
+            NaiveDateTime::parse_from_str(val, "%Y-%m-%d %H:%M:%S UTC").map(|ts| ts.and_utc())
+        })
+        .or_else(|_| DateTime::parse_from_str(val, "%Y-%m-%d %H:%M:%S %z").map(|ts| ts.to_utc()))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read_to_string, write};
+
+    use chrono::TimeZone;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    const EXPORT: &str = "\
+id,user_id,observed_on,created_at,taxon_id,latitude,longitude,license
+1,42,2024-05-01,2024-05-01 10:00:00 UTC,2,46.9,7.4,CC-BY
+2,42,2024-05-02,2024-05-03 10:00:00 UTC,,,,
+3,43,,2024-05-02T10:00:00+02:00,,,,
+,44,,,,,,
+";
+
+    #[test]
+    fn seeds_the_lists_and_the_missing_observations() {
+        let dir = tempdir().expect("tempdir");
+        let archive = Archive::new(dir.path().join("data")).expect("archive");
+        archive.insert(
+            "observations",
+            json!([{ "id": 2, "description": "Cached" }]),
+        );
+        let csv = dir.path().join("export.csv");
+        write(&csv, EXPORT).expect("write");
+
+        let report = archive.import_csv(&csv).expect("import");
+        assert_eq!((report.observations, report.seeded), (3, 2));
+
+        let obs = archive
+            .record("observations", 1)
+            .expect("get")
+            .expect("seeded");
+        assert_eq!(obs["user"], json!(42));
+        assert_eq!(obs["taxon"], json!(2));
+        assert_eq!(obs["location"], json!("46.9,7.4"));
+        assert_eq!(obs["license_code"], json!("CC-BY"));
+        assert_eq!(obs["created_at"], json!("2024-05-01T10:00:00+00:00"));
+        let cached = archive
+            .record("observations", 2)
+            .expect("get")
+            .expect("kept");
+        assert_eq!(cached["description"], json!("Cached"));
+
+        let list = |user| {
+            read_to_string(
+                archive
+                    .path("users")
+                    .join(format!("{}.observations.yaml", user)),
+            )
+        };
+        assert!(list(42)
+            .expect("list")
+            .contains("date: 2024-05-03T10:00:00+00:00"));
+        assert!(list(43).is_ok());
+        assert!(list(44).is_err());
+    }
+
+    #[test]
+    fn keeps_existing_lists() {
+        let dir = tempdir().expect("tempdir");
+        let archive = Archive::new(dir.path().join("data")).expect("archive");
+        let csv = dir.path().join("export.csv");
+        write(&csv, EXPORT).expect("write");
+        archive.import_csv(&csv).expect("import");
+
+        let path = archive.path("users").join("42.observations.yaml");
+        let before = read_to_string(&path).expect("read");
+        write(&csv, "id,user_id\n9,42\n").expect("write");
+        archive.import_csv(&csv).expect("import");
+        assert_eq!(read_to_string(&path).expect("read"), before);
+    }
+
+    #[test]
+    fn needs_the_id_columns() {
+        let dir = tempdir().expect("tempdir");
+        let archive = Archive::new(dir.path().join("data")).expect("archive");
+        let csv = dir.path().join("export.csv");
+        write(&csv, "id,observed_on\n1,2024-05-01\n").expect("write");
+        assert!(matches!(
+            archive.import_csv(&csv),
+            Err(Error::BadFile(_, _))
+        ));
+    }
+
+    #[test]
+    fn parses_the_export_times() {
+        let ts = Utc.with_ymd_and_hms(2024, 5, 1, 10, 0, 0).unwrap();
+        for val in [
+            "2024-05-01 10:00:00 UTC",
+            "2024-05-01T12:00:00+02:00",
+            "2024-05-01 12:00:00 +0200",
+        ] {
+            assert_eq!(parse_time(val), Some(ts), "{}", val);
+        }
+        assert_eq!(parse_time("yesterday"), None);
+    }
+}
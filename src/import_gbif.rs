@@ -0,0 +1,236 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::Read,
+    path::Path,
+};
+
+use serde::Serialize;
+use serde_json::json;
+use zip::ZipArchive;
+
+use crate::{archive::Archive, error::Error};
+
+const DEFAULT_CORE: &str = "occurrence.txt";
+
+// The iNaturalist research-grade observations dataset.
+const INAT_DATASET: &str = "50c9509d-22c7-4a22-a47d-8c48425ef4a7";
+
+#[derive(Debug, Default, Serialize)]
+pub struct GbifReport {
+    pub linked: usize,
+    // Cached observations missing from the download, e.g. not research grade or unlicensed.
+    pub only_inat: Vec<u64>,
+    // GBIF occurrences without a cached observation, by GBIF ID.
+    pub only_gbif: Vec<String>,
+}
+
+struct Occurrence {
+    gbif_id: String,
+    taxon_key: Option<u64>,
+    dataset_key: Option<String>,
+}
+
+impl Archive {
+    // Links GBIF occurrences from a Darwin Core Archive download into the cached observations.
+    pub fn import_gbif(&self, path: &Path) -> Result<GbifReport, Error> {
+        let mut zip = ZipArchive::new(File::open(path)?)?;
+        let core = match zip.by_name("meta.xml") {
+            Ok(mut meta) => {
+                let mut xml = String::new();
+                meta.read_to_string(&mut xml)?;
+                core_location(&xml).unwrap_or(DEFAULT_CORE.to_string())
+            }
+            _ => DEFAULT_CORE.to_string(),
+        };
+
+        let mut occurrences = BTreeMap::new();
+        let mut report = GbifReport::default();
+        let mut tsv = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .quoting(false)
+            .flexible(true)
+            .from_reader(zip.by_name(&core)?);
+        let columns = tsv.headers()?.clone();
+        let column = |name: &str| columns.iter().position(|col| col == name);
+        let gbif_id = column("gbifID").ok_or_else(|| {
+            Error::BadFile(path.to_path_buf(), format!("{}: no gbifID column", core))
+        })?;
+        let (catalog, occurrence_id) = (column("catalogNumber"), column("occurrenceID"));
+        let (taxon_key, dataset_key) = (column("taxonKey"), column("datasetKey"));
+
+        for row in tsv.records() {
+            let row = row?;
+            let get =
+                |col: Option<usize>| col.and_then(|col| row.get(col)).filter(|v| !v.is_empty());
+            let occurrence = Occurrence {
+                gbif_id: get(Some(gbif_id)).unwrap_or_default().to_string(),
+                taxon_key: get(taxon_key).and_then(|key| key.parse().ok()),
+                dataset_key: get(dataset_key).map(str::to_string),
+            };
+            // Catalog numbers are only meaningful within the iNat dataset.
+            let from_inat = occurrence
+                .dataset_key
+                .as_deref()
+                .is_none_or(|key| key == INAT_DATASET);
+            let id = get(occurrence_id).and_then(observation_id).or_else(|| {
+                get(catalog)
+                    .filter(|_| from_inat)
+                    .and_then(|id| id.parse().ok())
+            });
+            match id {
+                Some(id) => {
+                    occurrences.insert(id, occurrence);
+                }
+                _ => report.only_gbif.push(occurrence.gbif_id),
+            }
+        }
+
+        let mut seen = BTreeSet::new();
+        for id in self.table("observations")?.into_keys() {
+            let occurrence = match occurrences.get(&id) {
+                Some(occurrence) => occurrence,
+                _ => {
+                    report.only_inat.push(id);
+                    continue;
+                }
+            };
+            seen.insert(id);
+
+            if let Some((header, mut obs)) = self.store.get("observations", id)? {
+                let link = json!({
+                    "gbif_id": occurrence.gbif_id,
+                    "taxon_key": occurrence.taxon_key,
+                    "dataset_key": occurrence.dataset_key,
+                });
+                if obs.get("gbif") != Some(&link) {
+                    obs.insert("gbif".to_string(), link);
+                    self.store.put("observations", &header, [(id, &obs)])?;
+                }
+                report.linked += 1;
+            }
+        }
+
+        self.store.compact()?;
+        report.only_gbif.extend(
+            occurrences
+                .into_iter()
+                .filter(|(id, _)| !seen.contains(id))
+                .map(|(_, occurrence)| occurrence.gbif_id),
+        );
+
+        Ok(report)
+    }
+}
+
+// Good enough for GBIF's meta.xml: the first location after the core element.
+fn core_location(xml: &str) -> Option<String> {
+    let core = &xml[xml.find("<core")?..];
+    let start = core.find("<location>")? + "<location>".len();
+    let end = core[start..].find("</location>")?;
+    Some(core[start..start + end].trim().to_string())
+}
+
+// iNat occurrence IDs look like https://www.inaturalist.org/observations/123.
+fn observation_id(occurrence_id: &str) -> Option<u64> {
+    let (host, id) = occurrence_id.rsplit_once("/observations/")?;
+    if !host.contains("inaturalist") {
+        return None;
+    }
+    id.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use serde_json::json;
+    use tempfile::tempdir;
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::*;
+
+    const META: &str = r#"<archive><core rowType="Occurrence"><files><location>occurrences.tsv</location></files></core></archive>"#;
+
+    fn download(path: &Path, files: &[(&str, &str)]) {
+        let mut zip = ZipWriter::new(File::create(path).expect("create"));
+        for (name, contents) in files {
+            zip.start_file(*name, SimpleFileOptions::default())
+                .expect("start");
+            zip.write_all(contents.as_bytes()).expect("write");
+        }
+        zip.finish().expect("finish");
+    }
+
+    #[test]
+    fn links_occurrences_to_observations() {
+        let dir = tempdir().expect("tempdir");
+        let archive = Archive::new(dir.path().join("data")).expect("archive");
+        archive.insert(
+            "observations",
+            json!([{ "id": 1 }, { "id": 2 }, { "id": 3 }]),
+        );
+        let path = dir.path().join("download.zip");
+        download(
+            &path,
+            &[
+                ("meta.xml", META),
+                (
+                    "occurrences.tsv",
+                    &[
+                        "gbifID\toccurrenceID\tcatalogNumber\ttaxonKey\tdatasetKey",
+                        &format!(
+                            "10\thttps://www.inaturalist.org/observations/1\t\t99\t{}",
+                            INAT_DATASET
+                        ),
+                        &format!("20\t\t2\t\t{}", INAT_DATASET),
+                        // Catalog numbers of other datasets don't count.
+                        "30\t\t3\t\tother",
+                        "40\thttps://example.org/observations/4\t\t\t",
+                    ]
+                    .join("\n"),
+                ),
+            ],
+        );
+
+        let report = archive.import_gbif(&path).expect("import");
+        assert_eq!(report.linked, 2);
+        assert_eq!(report.only_inat, [3]);
+        assert_eq!(report.only_gbif, ["30", "40"]);
+        let obs = archive
+            .record("observations", 1)
+            .expect("get")
+            .expect("obs");
+        assert_eq!(
+            obs["gbif"],
+            json!({ "gbif_id": "10", "taxon_key": 99, "dataset_key": INAT_DATASET })
+        );
+    }
+
+    #[test]
+    fn defaults_to_the_occurrence_file() {
+        let dir = tempdir().expect("tempdir");
+        let archive = Archive::new(dir.path().join("data")).expect("archive");
+        let path = dir.path().join("download.zip");
+        download(&path, &[(DEFAULT_CORE, "catalogNumber\n1\n")]);
+        assert!(matches!(
+            archive.import_gbif(&path),
+            Err(Error::BadFile(_, _))
+        ));
+    }
+
+    #[test]
+    fn reads_inat_observation_ids() {
+        assert_eq!(
+            observation_id("https://www.inaturalist.org/observations/123"),
+            Some(123)
+        );
+        assert_eq!(
+            observation_id("https://inaturalist.ca/observations/7"),
+            Some(7)
+        );
+        assert_eq!(observation_id("https://example.org/observations/7"), None);
+        assert_eq!(observation_id("urn:catalog:1"), None);
+        assert_eq!(core_location(META).as_deref(), Some("occurrences.tsv"));
+    }
+}
@@ -0,0 +1,144 @@
+use std::{collections::HashMap, future::Future, sync::Mutex};
+
+use tokio::sync::watch::{channel, Receiver, Sender};
+
+// None while in flight, then the result, or None again if it failed.
+type Outcome<T> = Option<Option<T>>;
+
+// Requests in flight, so that the same one made again meanwhile waits for it instead.
+pub(crate) struct InFlight<T> {
+    requests: Mutex<HashMap<String, Receiver<Outcome<T>>>>,
+}
+
+enum Role<T> {
+    Leader(Sender<Outcome<T>>),
+    Follower(Receiver<Outcome<T>>),
+}
+
+// Forgets the request once done, or if the leader is dropped halfway.
+struct Landed<'a, T> {
+    in_flight: &'a InFlight<T>,
+    key: String,
+}
+
+impl<T: Clone> InFlight<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            requests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Runs the request, unless one with the same key is already in flight. Errors are not shared:
+    // if that one fails, this one is tried separately.
+    pub(crate) async fn run<E>(
+        &self,
+        key: String,
+        req: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        let role = {
+            let mut requests = self.requests.lock().expect("in-flight requests poisoned");
+            match requests.get(&key) {
+                Some(rx) => Role::Follower(rx.clone()),
+                _ => {
+                    let (tx, rx) = channel(None);
+                    requests.insert(key.clone(), rx);
+                    Role::Leader(tx)
+                }
+            }
+        };
+
+        match role {
+            Role::Follower(mut rx) => {
+                let shared = match rx.wait_for(Option::is_some).await {
+                    Ok(outcome) => outcome.clone().flatten(),
+                    // The leader was dropped.
+                    Err(_) => None,
+                };
+                match shared {
+                    Some(val) => Ok(val),
+                    _ => req.await,
+                }
+            }
+            Role::Leader(tx) => {
+                let _landed = Landed {
+                    in_flight: self,
+                    key,
+                };
+                let res = req.await;
+                tx.send_replace(Some(res.as_ref().ok().cloned()));
+                res
+            }
+        }
+    }
+}
+
+impl<T> Drop for Landed<'_, T> {
+    fn drop(&mut self) {
+        if let Ok(mut requests) = self.in_flight.requests.lock() {
+            requests.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use futures::future::join;
+    use tokio::time::sleep;
+
+    use super::*;
+
+    // Counts how often it's actually made.
+    async fn request(made: &AtomicUsize, res: Result<u32, ()>) -> Result<u32, ()> {
+        made.fetch_add(1, Ordering::SeqCst);
+        sleep(Duration::from_millis(10)).await;
+        res
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_same_request_in_flight() {
+        let in_flight = InFlight::new();
+        let made = AtomicUsize::new(0);
+        let (first, second) = join(
+            in_flight.run("a".to_string(), request(&made, Ok(1))),
+            in_flight.run("a".to_string(), request(&made, Ok(2))),
+        )
+        .await;
+
+        assert_eq!((first, second), (Ok(1), Ok(1)));
+        assert_eq!(made.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn tries_again_after_a_failure() {
+        let in_flight = InFlight::new();
+        let made = AtomicUsize::new(0);
+        let (first, second) = join(
+            in_flight.run("a".to_string(), request(&made, Err(()))),
+            in_flight.run("a".to_string(), request(&made, Ok(2))),
+        )
+        .await;
+
+        assert_eq!((first, second), (Err(()), Ok(2)));
+        assert_eq!(made.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn forgets_requests_once_landed() {
+        let in_flight = InFlight::new();
+        let made = AtomicUsize::new(0);
+        in_flight
+            .run("a".to_string(), request(&made, Ok(1)))
+            .await
+            .expect("run");
+        let again = in_flight.run("a".to_string(), request(&made, Ok(2))).await;
+
+        assert_eq!(again, Ok(2));
+        assert_eq!(made.load(Ordering::SeqCst), 2);
+        assert!(in_flight.requests.lock().expect("lock").is_empty());
+    }
+}
@@ -1,8 +1,151 @@
 mod api;
+mod api_auth;
+mod api_cache;
+mod api_doctor;
+#[cfg(feature = "fixture")]
+mod api_fixture;
 mod api_observations;
+mod api_sites;
+mod api_species_counts;
+mod api_sync;
+mod api_taxa;
 mod api_users;
+mod archive;
+mod cache_control;
+#[cfg(not(target_arch = "wasm32"))]
+mod cassette;
+mod checkpoint;
+mod chunks;
+mod circuit_breaker;
+mod clock;
+mod delta;
+mod digest;
+mod durable;
 mod error;
+#[cfg(feature = "export")]
+mod export_anki;
+#[cfg(feature = "export")]
+mod export_atom;
+#[cfg(feature = "export")]
+mod export_ics;
+#[cfg(feature = "export")]
+mod export_licenses;
+#[cfg(feature = "export")]
+mod export_map;
+#[cfg(feature = "export")]
+mod export_ofv;
+#[cfg(feature = "templates")]
+mod export_template;
+mod extractor;
+mod fields;
+mod filter;
+mod gc;
+mod git;
+#[cfg(feature = "http-cache")]
+mod http_cache;
+#[cfg(feature = "import")]
+mod import_csv;
+#[cfg(feature = "import")]
+mod import_gbif;
+mod in_flight;
+mod lifelist;
+#[cfg(feature = "locking")]
+mod lock;
+mod middleware;
+mod models;
 mod normalise;
+mod progress;
+mod query;
+mod quota;
+mod rate_limit;
+#[cfg(feature = "raw-archive")]
+mod raw_archive;
+mod renormalise;
+mod resolve;
+mod rules;
+mod schema;
+#[cfg(feature = "scripting")]
+mod script;
+#[cfg(feature = "search")]
+mod search;
+#[cfg(feature = "sql")]
+mod sql;
+mod stats;
+mod status;
+#[cfg(feature = "replica")]
+mod storage;
+#[cfg(feature = "memory")]
+mod storage_memory;
+#[cfg(feature = "s3")]
+mod storage_s3;
+#[cfg(feature = "webdav")]
+mod storage_webdav;
+mod store;
+mod summary;
+mod verify;
 
-pub use api::Api;
-pub use error::Error;
+// Everything below is the public API; modules stay private so they can be reshuffled freely.
+pub use api::{Api, ApiBuilder, ApiVersion, CachePolicy, HttpVersion};
+pub use api_doctor::{Check, CheckStatus, DoctorReport};
+pub use api_sync::{Selection, SyncOptions};
+pub use archive::Archive;
+#[cfg(not(target_arch = "wasm32"))]
+pub use cassette::{Cassette, Interaction};
+pub use clock::{Clock, FixedClock, SystemClock};
+pub use digest::DigestFormat;
+pub use error::{Error, ErrorKind};
+#[cfg(feature = "export")]
+pub use export_licenses::AttributionFormat;
+pub use extractor::{Batch, TableExtractor};
+pub use fields::Fields;
+pub use filter::Filter;
+pub use gc::GcReport;
+#[cfg(feature = "import")]
+pub use import_csv::CsvReport;
+#[cfg(feature = "import")]
+pub use import_gbif::GbifReport;
+pub use lifelist::{LifeList, LifeListDiff, LifeListEntry};
+#[cfg(feature = "locking")]
+pub use lock::DataLock;
+pub use middleware::Middleware;
+pub use models::{
+    Comment, Dimensions, Identification, Model, Observation, Photo, Ref, Taxon, User,
+};
+pub use normalise::{Normaliser, Tables};
+pub use progress::SyncProgress;
+pub use query::QueryFormat;
+pub use rules::{ExtractionRule, ExtractionRules};
+#[cfg(feature = "scripting")]
+pub use script::Script;
+pub use stats::{Stats, TaxonCount};
+pub use status::{Status, TableStatus};
+#[cfg(feature = "replica")]
+pub use storage::{Precondition, Storage};
+#[cfg(feature = "memory")]
+pub use storage_memory::MemoryStorage;
+#[cfg(feature = "s3")]
+pub use storage_s3::S3Storage;
+#[cfg(feature = "webdav")]
+pub use storage_webdav::WebDavStorage;
+pub use store::{Changes, Layout, TableChanges};
+pub use summary::SyncSummary;
+pub use verify::{Problem, ProblemKind, VerifyReport};
+
+pub mod prelude {
+    #[cfg(feature = "export")]
+    pub use crate::AttributionFormat;
+    #[cfg(feature = "locking")]
+    pub use crate::DataLock;
+    #[cfg(feature = "replica")]
+    pub use crate::Storage;
+    pub use crate::{
+        Api, ApiBuilder, ApiVersion, Archive, CachePolicy, Changes, Check, CheckStatus, Comment,
+        DigestFormat, Dimensions, DoctorReport, Error, ErrorKind, Filter, GcReport, Identification,
+        Layout, LifeList, LifeListDiff, LifeListEntry, Model, Normaliser, Observation, Photo,
+        Problem, ProblemKind, QueryFormat, Ref, Selection, Stats, Status, SyncOptions,
+        SyncProgress, SyncSummary, TableChanges, TableExtractor, TableStatus, Tables, Taxon,
+        TaxonCount, User, VerifyReport,
+    };
+    #[cfg(feature = "import")]
+    pub use crate::{CsvReport, GbifReport};
+}
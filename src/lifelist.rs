@@ -0,0 +1,174 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    io::Write,
+};
+
+use serde::Serialize;
+
+use crate::{
+    archive::{id_field, ids, str_field, taxon_name, Archive, Record},
+    error::Error,
+    query::write_rows,
+};
+
+#[derive(Debug, Default, Serialize)]
+#[non_exhaustive]
+pub struct LifeList {
+    // Leaf taxa, i.e. observed taxa without anything more specific observed below them.
+    pub taxa: Vec<LifeListEntry>,
+    // Distinct taxa per rank, counting the ancestors of all observed taxa.
+    pub ranks: BTreeMap<String, usize>,
+}
+
+#[derive(Debug, Serialize)]
+#[non_exhaustive]
+pub struct LifeListEntry {
+    pub taxon: u64,
+    pub name: String,
+    pub rank: Option<String>,
+    pub observations: usize,
+    pub first_observation: u64,
+    pub first_observed_on: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[non_exhaustive]
+pub struct LifeListDiff {
+    // Leaf taxa only found in the cache, e.g. observations since deleted on the server.
+    pub only_local: Vec<u64>,
+    // Leaf taxa the server counts but the cache doesn't have.
+    pub only_server: Vec<u64>,
+}
+
+impl Archive {
+    // The user's own identification stands in for observations without a taxon.
+    pub fn lifelist(&self) -> Result<LifeList, Error> {
+        let taxa = self.table("taxa")?;
+        let identifications = self.table("identifications")?;
+
+        let mut observed: BTreeMap<u64, Vec<(Option<String>, u64)>> = BTreeMap::new();
+        for (id, obs) in self.table("observations")? {
+            let taxon = id_field(&obs, "taxon").or_else(|| own_taxon(&obs, &identifications));
+            if let Some(taxon) = taxon {
+                let date = str_field(&obs, "observed_on").map(str::to_string);
+                observed.entry(taxon).or_default().push((date, id));
+            }
+        }
+
+        let mut lineage: BTreeSet<u64> = BTreeSet::new();
+        let mut inner: HashSet<u64> = HashSet::new();
+        for taxon in observed.keys() {
+            lineage.insert(*taxon);
+            if let Some(record) = taxa.get(taxon) {
+                for ancestor in ids(record, "ancestor_ids") {
+                    lineage.insert(ancestor);
+                    if ancestor != *taxon {
+                        inner.insert(ancestor);
+                    }
+                }
+            }
+        }
+
+        let mut list = LifeList::default();
+        for id in lineage {
+            if let Some(rank) = taxa.get(&id).and_then(|taxon| str_field(taxon, "rank")) {
+                *list.ranks.entry(rank.to_string()).or_default() += 1;
+            }
+        }
+        for (taxon, mut observations) in observed {
+            if inner.contains(&taxon) {
+                continue;
+            }
+            // Undated observations go last.
+            observations.sort_by_key(|(date, id)| (date.is_none(), date.clone(), *id));
+            let (first_observed_on, first_observation) = observations[0].clone();
+            let record = taxa.get(&taxon);
+            list.taxa.push(LifeListEntry {
+                taxon,
+                name: record.map(taxon_name).unwrap_or_else(|| taxon.to_string()),
+                rank: record
+                    .and_then(|taxon| str_field(taxon, "rank"))
+                    .map(str::to_string),
+                observations: observations.len(),
+                first_observation,
+                first_observed_on,
+            });
+        }
+        list.taxa.sort_by_key(|entry| {
+            (
+                entry.first_observed_on.is_none(),
+                entry.first_observed_on.clone(),
+                entry.first_observation,
+            )
+        });
+
+        Ok(list)
+    }
+}
+
+impl LifeList {
+    // Compares the leaf taxa against the server's species counts, by taxon ID.
+    pub fn compare(&self, server: &BTreeMap<u64, u64>) -> LifeListDiff {
+        let local: BTreeSet<_> = self.taxa.iter().map(|entry| entry.taxon).collect();
+        LifeListDiff {
+            only_local: local
+                .iter()
+                .filter(|id| !server.contains_key(id))
+                .copied()
+                .collect(),
+            only_server: server
+                .keys()
+                .filter(|id| !local.contains(id))
+                .copied()
+                .collect(),
+        }
+    }
+
+    pub fn write_table<W: Write>(&self, out: &mut W) -> Result<(), Error> {
+        let mut rows = vec![["first observed", "observation", "taxon", "rank", "count"]
+            .map(str::to_string)
+            .to_vec()];
+        for entry in &self.taxa {
+            rows.push(vec![
+                entry.first_observed_on.clone().unwrap_or_default(),
+                entry.first_observation.to_string(),
+                entry.name.to_string(),
+                entry.rank.clone().unwrap_or_default(),
+                entry.observations.to_string(),
+            ]);
+        }
+        write_rows(out, &rows)?;
+
+        writeln!(out)?;
+        let ranks: Vec<_> = self
+            .ranks
+            .iter()
+            .map(|(rank, count)| vec![rank.to_string(), count.to_string()])
+            .collect();
+        write_rows(out, &ranks)
+    }
+}
+
+impl LifeListDiff {
+    pub fn write_table<W: Write>(&self, out: &mut W) -> Result<(), Error> {
+        let mut rows = vec![["only in", "taxon"].map(str::to_string).to_vec()];
+        for (side, taxa) in [("cache", &self.only_local), ("server", &self.only_server)] {
+            for taxon in taxa {
+                rows.push(vec![side.to_string(), taxon.to_string()]);
+            }
+        }
+
+        write_rows(out, &rows)
+    }
+}
+
+fn own_taxon(obs: &Record, identifications: &BTreeMap<u64, Record>) -> Option<u64> {
+    let user = id_field(obs, "user")?;
+    ids(obs, "identifications")
+        .iter()
+        .filter_map(|id| identifications.get(id))
+        .filter(|ident| id_field(ident, "user") == Some(user))
+        .filter(|ident| ident.get("current").and_then(|c| c.as_bool()) != Some(false))
+        .filter_map(|ident| id_field(ident, "taxon"))
+        .next_back()
+}
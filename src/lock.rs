@@ -0,0 +1,93 @@
+use std::{
+    fs::{create_dir_all, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use fs2::FileExt;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use crate::error::Error;
+
+const LOCK_POLL: Duration = Duration::from_secs(1);
+
+// Advisory lock on the data directory, held until dropped, so that two processes don't write the
+// same files and symlinks at once.
+//
+// The lock itself is an flock, which the kernel releases along with the process: a lock file left
+// behind by a crashed process is stale, and taken over as such. The file only says who held it.
+pub struct DataLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl DataLock {
+    // Waits up to timeout for another process to let go of the lock; zero to not wait at all.
+    pub async fn acquire<P: AsRef<Path>>(data_dir: P, timeout: Duration) -> Result<Self, Error> {
+        let data_dir = data_dir.as_ref();
+        create_dir_all(data_dir)?;
+        let path = data_dir.join(".lock");
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        let start = Instant::now();
+        let mut waiting = false;
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(()) => break,
+                Err(err) if err.kind() == fs2::lock_contended_error().kind() => {
+                    let holder =
+                        holder(&mut file).unwrap_or_else(|| "held by another process".to_string());
+                    if start.elapsed() >= timeout {
+                        return Err(Error::Locked(path, holder));
+                    }
+                    if !waiting {
+                        info!("{}: {}; waiting", path.display(), holder);
+                        waiting = true;
+                    }
+                    sleep(LOCK_POLL.min(timeout.saturating_sub(start.elapsed()))).await;
+                }
+                // E.g. some network filesystems; better unlocked than not working at all.
+                Err(err) => {
+                    warn!("{}: {}; continuing without a lock", path.display(), err);
+                    break;
+                }
+            }
+        }
+
+        if let Some(stale) = holder(&mut file) {
+            info!("{}: taking over stale lock, {}", path.display(), stale);
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "held by pid {} since {}", process::id(), Utc::now())?;
+
+        Ok(Self { file, path })
+    }
+}
+
+impl Drop for DataLock {
+    fn drop(&mut self) {
+        // Emptied, so that the next one doesn't take it for stale.
+        if let Err(err) = self.file.set_len(0) {
+            warn!("{}: {}", self.path.display(), err);
+        }
+        let _ = self.file.unlock();
+    }
+}
+
+// Whoever wrote the lock file last, unless it's empty.
+fn holder(file: &mut File) -> Option<String> {
+    let mut holder = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut holder).ok()?;
+    Some(holder.trim().to_string()).filter(|holder| !holder.is_empty())
+}
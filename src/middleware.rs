@@ -0,0 +1,18 @@
+use reqwest::{Request, Response};
+
+use crate::error::Error;
+
+// Sees every request the syncs make, on every attempt, retries included: e.g. to add headers, log
+// them, or answer them from a fixture in tests. Installed with Api::with_middleware; requests go
+// through them in the order installed, responses in reverse.
+pub trait Middleware: Send + Sync {
+    // Some to answer the request instead of the API, and the middleware installed after this one.
+    fn request(&self, _req: &mut Request) -> Result<Option<Response>, Error> {
+        Ok(None)
+    }
+
+    // Before the response is looked at, whether it came from the API or a middleware.
+    fn response(&self, _res: &Response) -> Result<(), Error> {
+        Ok(())
+    }
+}
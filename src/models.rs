@@ -0,0 +1,264 @@
+use chrono::{DateTime, FixedOffset};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map as JsonMap, Value as JsonValue};
+
+use crate::{
+    archive::Record,
+    error::Error,
+    schema::{field, Field, Fields},
+};
+
+// Records of a cache table, typed. Only the commonly used fields are spelled out, everything else
+// is kept as it came in the extra fields, so that nothing gets lost going back and forth.
+pub trait Model: DeserializeOwned + Serialize {
+    const TABLE: &'static str;
+
+    fn id(&self) -> u64;
+}
+
+// API responses nest the records other tables hold, the cache refers to them by ID instead.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Ref<T> {
+    Id(u64),
+    Inline(Box<T>),
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct Observation {
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uri: Option<String>,
+    // E.g. "research", "needs_id" or "casual".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality_grade: Option<String>,
+    // The date as entered, which is not always a valid one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_on: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_observed_at: Option<DateTime<FixedOffset>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<FixedOffset>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime<FixedOffset>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub species_guess: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub place_guess: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    // "lat,lng", obscured unless it's one's own observation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license_code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<Ref<User>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub taxon: Option<Ref<Taxon>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub community_taxon: Option<Ref<Taxon>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub identifications: Vec<Ref<Identification>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub comments: Vec<Ref<Comment>>,
+    // The first one is the cover.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub photos: Vec<Ref<Photo>>,
+    #[serde(flatten)]
+    pub extra: JsonMap<String, JsonValue>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct Taxon {
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rank: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rank_level: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferred_common_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iconic_taxon_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<u64>,
+    // From the root down, including the taxon itself.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ancestor_ids: Vec<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ancestors: Vec<Ref<Taxon>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub is_active: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_photo: Option<Ref<Photo>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wikipedia_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observations_count: Option<u64>,
+    #[serde(flatten)]
+    pub extra: JsonMap<String, JsonValue>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct User {
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub login: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<FixedOffset>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observations_count: Option<u64>,
+    #[serde(flatten)]
+    pub extra: JsonMap<String, JsonValue>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct Identification {
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<Ref<User>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub taxon: Option<Ref<Taxon>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_observation_taxon: Option<Ref<Taxon>>,
+    // Withdrawn ones are not current.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current: Option<bool>,
+    // E.g. "improving", "supporting" or "maverick".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disagreement: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<FixedOffset>>,
+    #[serde(flatten)]
+    pub extra: JsonMap<String, JsonValue>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct Comment {
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<Ref<User>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<FixedOffset>>,
+    #[serde(flatten)]
+    pub extra: JsonMap<String, JsonValue>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct Photo {
+    pub id: u64,
+    // The square thumbnail; other sizes are the same URL with e.g. "medium" or "original".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    // None for all rights reserved.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license_code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attribution: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_dimensions: Option<Dimensions>,
+    #[serde(flatten)]
+    pub extra: JsonMap<String, JsonValue>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct Dimensions {
+    pub width: u64,
+    pub height: u64,
+}
+
+impl<T: Model> Ref<T> {
+    pub fn id(&self) -> u64 {
+        match self {
+            Ref::Id(id) => *id,
+            Ref::Inline(record) => record.id(),
+        }
+    }
+
+    // None if only the ID is known; look it up in the archive then.
+    pub fn record(&self) -> Option<&T> {
+        match self {
+            Ref::Inline(record) => Some(record),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! model {
+    ($($model:ident => $table:literal { $($field:ident),* }),*) => {
+        $(
+            impl Model for $model {
+                const TABLE: &'static str = $table;
+
+                fn id(&self) -> u64 {
+                    self.id
+                }
+            }
+
+            impl Fields for $model {
+                fn fields() -> Vec<Field> {
+                    // Destructured without "..", so that a field missing from the list won't build.
+                    let _ = |$model { $($field: _,)* extra: _ }: $model| ();
+                    vec![$(field(stringify!($field), |record: &$model| &record.$field)),*]
+                }
+            }
+        )*
+
+        // The fields of the model of a table, if there is one.
+        pub(crate) fn fields(table: &str) -> Option<Vec<Field>> {
+            match table {
+                $($table => Some($model::fields()),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+model!(
+    Comment => "comments" { id, uuid, user, body, created_at },
+    Identification => "identifications" {
+        id, uuid, user, taxon, previous_observation_taxon, current, category, disagreement, body,
+        created_at
+    },
+    Observation => "observations" {
+        id, uuid, uri, quality_grade, observed_on, time_observed_at, created_at, updated_at,
+        species_guess, place_guess, description, location, license_code, user, taxon,
+        community_taxon, identifications, comments, photos
+    },
+    Photo => "photos" { id, url, license_code, attribution, original_dimensions },
+    Taxon => "taxa" {
+        id, name, rank, rank_level, preferred_common_name, iconic_taxon_name, parent_id,
+        ancestor_ids, ancestors, is_active, default_photo, wikipedia_url, observations_count
+    },
+    User => "users" { id, login, name, icon, created_at, observations_count }
+);
+
+// Records come and go as untyped JSON objects, whether from the API or the cache.
+pub(crate) fn from_record<T: Model>(record: Record) -> Result<T, Error> {
+    Ok(serde_json::from_value(JsonValue::Object(record))?)
+}
@@ -1,30 +1,215 @@
-use std::{collections::HashMap, fs::create_dir_all, path::PathBuf};
-
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    hash::{DefaultHasher, Hasher},
+    io::{Result as IoResult, Write},
+    mem::take,
+    sync::Arc,
+};
+
+#[cfg(feature = "lru")]
+use std::num::NonZeroUsize;
+
+use itertools::Itertools;
+#[cfg(feature = "lru")]
+use lru::LruCache;
 use serde_json::{Map as JsonMap, Value as JsonValue};
 use serde_yaml::Mapping as YamlMapping;
 
-use crate::api::{extract_id, write_cache};
-use crate::error::{internal, Error};
+use crate::api::{expect_results, extract_id, parse_response, ID};
+use crate::api_sync::Selection;
+use crate::archive::ids;
+use crate::delta::{append_events, Event, EventKind};
+use crate::error::{bad_record, Error};
+use crate::extractor::{Batch, TableExtractor};
+use crate::store::Store;
+
+type Entry = (u64, JsonMap<String, JsonValue>);
+
+// ID lists whose order means something, e.g. the first photo is the cover; the rest get sorted.
+const ORDERED: [&str; 6] = [
+    "ancestors",
+    "observation_photos",
+    "observation_sounds",
+    "photos",
+    "sounds",
+    "taxon_photos",
+];
+
+// Observations (or taxa) extracted and written at once, so that memory use stays flat however many
+// there are to normalise. Except in the file layout: its store keeps whole tables in memory anyway.
+const BATCH: usize = 50;
+
+// Records many observations share, which later batches would write again unchanged.
+const SHARED: [&str; 10] = [
+    "applications",
+    "controlled_term_labels",
+    "controlled_terms",
+    "lists",
+    "observation_fields",
+    "places",
+    "preferences",
+    "projects",
+    "taxa",
+    "users",
+];
+
+// Shared records to remember as written, by table and ID.
+const WRITTEN: usize = 1024;
+
+// Hashes of those records: the most recently written ones, or without the lru feature, the ones
+// written since the last time it filled up.
+#[cfg(feature = "lru")]
+type Written = LruCache<(&'static str, u64), u64>;
+#[cfg(not(feature = "lru"))]
+type Written = HashMap<(&'static str, u64), u64>;
+
+// Tables merged with the records written before, and the fields those have to agree on.
+const STORED_MERGED: [(&str, &[&str]); 3] =
+    [("photos", &["url"]), ("taxa", &["rank"]), ("users", &[])];
+
+// Observation fields added locally, e.g. by imports.
+const LOCAL_FIELDS: [&str; 1] = ["gbif"];
+
+// Keys replaced by IDs (or lists of IDs) into other tables; keep in sync with extract_*.
+pub(crate) struct Reference {
+    pub(crate) table: &'static str,
+    pub(crate) key: &'static str,
+    pub(crate) target: &'static str,
+    pub(crate) many: bool,
+}
+
+macro_rules! references {
+    ($($table:ident . $key:ident -> $target:ident $([$many:tt])?),* $(,)?) => {
+        pub(crate) const REFERENCES: &[Reference] = &[
+            $(
+                Reference {
+                    table: stringify!($table),
+                    key: stringify!($key),
+                    target: stringify!($target),
+                    many: references!(@many $($many)?),
+                },
+            )*
+        ];
+    };
+    (@many) => { false };
+    (@many $many:tt) => { true };
+}
+
+references!(
+    annotations.controlled_attribute -> controlled_terms,
+    annotations.controlled_value -> controlled_terms,
+    annotations.user -> users,
+    annotations.votes -> votes[*],
+    comments.flags -> flags[*],
+    comments.user -> users,
+    comments.votes -> votes[*],
+    conservation_statuses.place -> places,
+    controlled_terms.labels -> controlled_term_labels[*],
+    controlled_terms.values -> controlled_terms[*],
+    faves.user -> users,
+    identifications.flags -> flags[*],
+    identifications.previous_observation_taxon -> taxa,
+    identifications.taxon -> taxa,
+    identifications.taxon_change -> taxon_changes,
+    identifications.user -> users,
+    identifications.votes -> votes[*],
+    listed_taxa.list -> lists,
+    listed_taxa.place -> places,
+    observation_field_values.observation_field -> observation_fields,
+    observation_field_values.taxon -> taxa,
+    observation_field_values.user -> users,
+    observation_photos.photo -> photos,
+    observation_sounds.sound -> sounds,
+    observations.annotations -> annotations[*],
+    observations.application -> applications,
+    observations.comments -> comments[*],
+    observations.community_taxon -> taxa,
+    observations.faves -> faves[*],
+    observations.flags -> flags[*],
+    observations.identifications -> identifications[*],
+    observations.non_owner_ids -> identifications[*],
+    observations.observation_photos -> observation_photos[*],
+    observations.observation_sounds -> observation_sounds[*],
+    observations.ofvs -> observation_field_values[*],
+    observations.outlinks -> outlinks[*],
+    observations.photos -> photos[*],
+    observations.preferences -> preferences,
+    observations.project_observations -> project_observations[*],
+    observations.quality_metrics -> quality_metrics[*],
+    observations.sounds -> sounds[*],
+    observations.taxon -> taxa,
+    observations.user -> users,
+    observations.votes -> votes[*],
+    photos.flags -> flags[*],
+    project_observation_fields.observation_field -> observation_fields,
+    project_observations.project -> projects,
+    project_observations.project_user -> project_users,
+    project_observations.user -> users,
+    projects.admins -> project_admins[*],
+    projects.flags -> flags[*],
+    projects.project_observation_fields -> project_observation_fields[*],
+    projects.project_observation_rules -> project_observation_rules[*],
+    quality_metrics.user -> users,
+    sounds.flags -> flags[*],
+    taxa.ancestors -> taxa[*],
+    taxa.conservation_status -> conservation_statuses,
+    taxa.conservation_statuses -> conservation_statuses[*],
+    taxa.default_photo -> photos,
+    taxa.listed_taxa -> listed_taxa[*],
+    taxa.outlinks -> outlinks[*],
+    taxa.taxon_photos -> taxon_photos[*],
+    taxon_photos.photo -> photos,
+    users.preferences -> preferences,
+    votes.user -> users,
+);
+
+// Normalises records of one kind into their tables, without an archive: e.g. observations fetched
+// some other way, or from an export. Records other tables hold are moved there, with only their
+// IDs left behind, the same way syncs do.
+#[derive(Clone, Default)]
+pub struct Normaliser {
+    extractors: Vec<Arc<dyn TableExtractor>>,
+}
+
+// Records by table and ID; tables left empty are missing.
+pub type Tables = BTreeMap<String, BTreeMap<u64, JsonMap<String, JsonValue>>>;
 
-pub(crate) struct Normaliser {
+// Like Normaliser, writing to the store as it goes, batch by batch. One writer lasts a whole sync,
+// so that shared records written by earlier chunks are remembered.
+pub(crate) struct Writer {
     header: YamlMapping,
-    data_dir: PathBuf,
+    store: Arc<Store>,
+    // The current batch.
     cache: AllTables,
+    // Only these tables get written, everything else is still extracted.
+    selection: Option<Selection>,
+    // Hashes of the shared records written by earlier batches.
+    written: RefCell<Written>,
+    // Run after the built-in passes.
+    extractors: Vec<Arc<dyn TableExtractor>>,
 }
 
 macro_rules! all_tables {
     ($($field:ident),*) => {
-        struct AllTables {
+        pub(crate) const TABLES: &[&str] = &[$(stringify!($field)),*];
+
+        pub(crate) struct AllTables {
             $(
                 $field:  HashMap<u64, JsonMap<String, JsonValue>>,
             )*
+            // The extractors' own tables.
+            custom: BTreeMap<&'static str, HashMap<u64, JsonMap<String, JsonValue>>>,
         }
 
-        impl Normaliser {
+        impl Writer {
             fn write_all(&self) -> Result<(), Error> {
                 $(
                     self.write_cache(&self.cache.$field, stringify!($field))?;
                 )*
+                for (table, records) in &self.cache.custom {
+                    self.write_cache(records, table)?;
+                }
 
                 Ok(())
             }
@@ -36,13 +221,47 @@ macro_rules! all_tables {
                     $(
                         $field: HashMap::new(),
                     )*
+                    custom: BTreeMap::new(),
+                }
+            }
+
+            pub(crate) fn table_mut(
+                &mut self,
+                table: &str,
+            ) -> Option<&mut HashMap<u64, JsonMap<String, JsonValue>>> {
+                match table {
+                    $(
+                        stringify!($field) => Some(&mut self.$field),
+                    )*
+                    _ => self.custom.get_mut(table),
+                }
+            }
+
+            pub(crate) fn names(&self) -> Vec<&'static str> {
+                TABLES.iter().copied().chain(self.custom.keys().copied()).collect()
+            }
+
+            fn into_tables(self) -> Tables {
+                let mut tables = Tables::new();
+                $(
+                    if !self.$field.is_empty() {
+                        tables.insert(stringify!($field).to_string(), self.$field.into_iter().collect());
+                    }
+                )*
+                for (table, records) in self.custom {
+                    if !records.is_empty() {
+                        tables.insert(table.to_string(), records.into_iter().collect());
+                    }
                 }
+
+                tables
             }
         }
     };
 }
 
 all_tables!(
+    annotations,
     applications,
     comments,
     conservation_statuses,
@@ -51,12 +270,17 @@ all_tables!(
     faves,
     flags,
     identifications,
+    listed_taxa,
+    lists,
     observation_field_values,
     observation_fields,
     observation_photos,
     observation_sounds,
     observations,
+    outlinks,
     photos,
+    places,
+    preferences,
     project_admins,
     project_observations,
     project_observation_fields,
@@ -64,9 +288,11 @@ all_tables!(
     project_users,
     projects,
     quality_metrics,
+    sites,
     sounds,
     taxa,
     taxon_changes,
+    taxon_photos,
     users,
     votes
 );
@@ -74,9 +300,25 @@ all_tables!(
 macro_rules! extract_flags {
     ($self:ident, $($from:ident),*) => {
         $(
-            for item in $self.cache.$from.values_mut() {
-                for (id, obj) in extract_objects(item, "flags")? {
-                    $self.cache.flags.insert(id, obj);
+            for item in $self.$from.values_mut() {
+                for (id, obj) in extract_objects(stringify!($from), item, "flags")? {
+                    $self.flags.insert(id, obj);
+                }
+            }
+        )*
+    };
+}
+
+// Votes keep what they were cast on, as votable_type and votable_id like in the iNat database. Not
+// listed in REFERENCES: hydrating records through them would go round in circles.
+macro_rules! extract_votes {
+    ($self:ident, $($from:ident: $type:literal),*) => {
+        $(
+            for (&parent, item) in $self.$from.iter_mut() {
+                for (id, mut obj) in extract_objects(stringify!($from), item, "votes")? {
+                    obj.entry("votable_type").or_insert($type.into());
+                    obj.entry("votable_id").or_insert(parent.into());
+                    $self.votes.insert(id, obj);
                 }
             }
         )*
@@ -86,9 +328,9 @@ macro_rules! extract_flags {
 macro_rules! extract_users {
     ($self:ident, $($from:ident),*) => {
         $(
-            for item in $self.cache.$from.values_mut() {
-                if let Some((id, obj)) = extract_object(item, "user")? {
-                    $self.cache.users.insert(id, obj);
+            for item in $self.$from.values_mut() {
+                if let Some((id, obj)) = extract_object(stringify!($from), item, "user")? {
+                    merge(&mut $self.users, id, obj, &[]);
                 }
             }
         )*
@@ -96,21 +338,275 @@ macro_rules! extract_users {
 }
 
 impl Normaliser {
-    pub(crate) fn new(
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Like Api::with_extractor.
+    pub fn with_extractor<E: TableExtractor + 'static>(mut self, extractor: E) -> Self {
+        self.extractors.push(Arc::new(extractor));
+        self
+    }
+
+    // As the API returns them, e.g. the results of /observations.
+    pub fn observations<I>(&self, observations: I) -> Result<Tables, Error>
+    where
+        I: IntoIterator<Item = JsonMap<String, JsonValue>>,
+    {
+        let mut tables = AllTables::new();
+        tables.observations = by_id(observations).map_err(|err| err.in_table("observations"))?;
+        tables.extract(&self.extractors)?;
+
+        Ok(tables.into_tables())
+    }
+
+    // A whole /observations response, e.g. saved from an earlier API call.
+    pub fn observations_response(&self, body: &[u8]) -> Result<Tables, Error> {
+        self.observations(expect_results(parse_response(body)?)?)
+    }
+
+    // As the API returns them, e.g. the results of /taxa.
+    pub fn taxa<I>(&self, taxa: I) -> Result<Tables, Error>
+    where
+        I: IntoIterator<Item = JsonMap<String, JsonValue>>,
+    {
+        let mut tables = AllTables::new();
+        tables.taxa = by_id(taxa).map_err(|err| err.in_table("taxa"))?;
+        tables.extract(&self.extractors)?;
+
+        Ok(tables.into_tables())
+    }
+}
+
+fn by_id<I>(records: I) -> Result<HashMap<u64, JsonMap<String, JsonValue>>, Error>
+where
+    I: IntoIterator<Item = JsonMap<String, JsonValue>>,
+{
+    records
+        .into_iter()
+        .map(|record| extract_id(&record).map(|id| (id, record)))
+        .collect()
+}
+
+impl Writer {
+    pub(crate) fn new(store: Arc<Store>) -> Self {
+        Self {
+            header: YamlMapping::new(),
+            store,
+            cache: AllTables::new(),
+            selection: None,
+            written: RefCell::new(new_written()),
+            extractors: Vec::new(),
+        }
+    }
+
+    pub(crate) fn select(mut self, selection: Selection) -> Self {
+        self.selection = Some(selection);
+        self
+    }
+
+    pub(crate) fn extract_with(mut self, extractors: Vec<Arc<dyn TableExtractor>>) -> Self {
+        self.extractors = extractors;
+        self
+    }
+
+    pub(crate) fn observations(
+        &mut self,
         header: YamlMapping,
         observations: HashMap<u64, JsonMap<String, JsonValue>>,
-        data_dir: &PathBuf,
-    ) -> Self {
-        let mut cache = AllTables::new();
-        cache.observations = observations;
-        Self {
-            header,
-            data_dir: data_dir.to_path_buf(),
-            cache,
+    ) -> Result<(), Error> {
+        self.header = header;
+        for batch in &observations
+            .into_iter()
+            .sorted_by_key(|(id, _)| *id)
+            .chunks(BATCH)
+        {
+            self.cache.observations = batch.collect();
+            self.write_batch()?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn taxa(
+        &mut self,
+        header: YamlMapping,
+        taxa: HashMap<u64, JsonMap<String, JsonValue>>,
+    ) -> Result<(), Error> {
+        self.header = header;
+        for batch in &taxa.into_iter().sorted_by_key(|(id, _)| *id).chunks(BATCH) {
+            self.cache.taxa = batch.collect();
+            self.write_batch()?;
         }
+
+        Ok(())
     }
 
-    pub(crate) fn write(&mut self) -> Result<(), Error> {
+    // Only a few dozen of them, written at once.
+    pub(crate) fn sites(
+        &mut self,
+        header: YamlMapping,
+        sites: HashMap<u64, JsonMap<String, JsonValue>>,
+    ) -> Result<(), Error> {
+        self.header = header;
+        self.cache.sites = sites;
+
+        self.write_batch()
+    }
+
+    fn write_batch(&mut self) -> Result<(), Error> {
+        self.cache.extract(&self.extractors)?;
+
+        // NEEDS: everything extracted, but nothing written yet
+        // Events compare against the cache, so only skipped tables would be news every time.
+        if self.is_selected("observations") {
+            self.record_events()?;
+        }
+        self.keep_local_fields()?;
+        self.keep_stored_fields()?;
+        self.write_all()?;
+        self.cache = AllTables::new();
+
+        Ok(())
+    }
+
+    // Imports add their own fields to observations, which the API knows nothing about.
+    fn keep_local_fields(&mut self) -> Result<(), Error> {
+        for (id, obs) in self.cache.observations.iter_mut() {
+            if let Some((_, old)) = self.store.get("observations", *id)? {
+                for key in LOCAL_FIELDS {
+                    if let Some(val) = old.get(key) {
+                        obs.insert(key.to_string(), val.clone());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Most photos, taxa and users come nested, with a few fields only; don't let them replace the
+    // fuller records written before, e.g. the taxa the taxa stage fetched, or the profile of the
+    // user synced. Replaced photos, with a new URL, do. Runs before written records are skipped:
+    // merged, they might differ again.
+    fn keep_stored_fields(&mut self) -> Result<(), Error> {
+        for (table, agree) in STORED_MERGED {
+            let records = match self.cache.table_mut(table) {
+                Some(records) => records,
+                _ => continue,
+            };
+            for (id, record) in records.iter_mut() {
+                if let Some((_, old)) = self.store.get(table, *id)? {
+                    *record = merged(old, take(record), agree);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn record_events(&self) -> Result<(), Error> {
+        let now = self.store.now();
+        let mut events = vec![];
+        let event = |kind, observation, id| Event {
+            date: now,
+            kind,
+            observation,
+            id,
+        };
+
+        for (id, obs) in self.cache.observations.iter().sorted_by_key(|(id, _)| **id) {
+            let research = obs.get("quality_grade").and_then(JsonValue::as_str) == Some("research");
+            match self.store.get("observations", *id)? {
+                Some((_, old)) => {
+                    if research
+                        && old.get("quality_grade").and_then(JsonValue::as_str) != Some("research")
+                    {
+                        events.push(event(EventKind::ResearchGrade, *id, None));
+                    }
+                }
+                _ => events.push(event(EventKind::NewObservation, *id, None)),
+            }
+
+            for (table, key, kind) in [
+                (
+                    "identifications",
+                    "identifications",
+                    EventKind::NewIdentification,
+                ),
+                ("comments", "comments", EventKind::NewComment),
+            ] {
+                if !self.is_selected(table) {
+                    continue;
+                }
+                for child in ids(obs, key) {
+                    if !self.store.contains(table, child)? {
+                        events.push(event(kind, *id, Some(child)));
+                    }
+                }
+            }
+
+            // Photos keep their IDs when uploaded again, but not their URLs.
+            if !self.is_selected("photos") {
+                continue;
+            }
+            let url = |photo: &JsonMap<String, JsonValue>| {
+                photo
+                    .get("url")
+                    .and_then(JsonValue::as_str)
+                    .map(str::to_string)
+            };
+            for photo in ids(obs, "photos") {
+                let new = self.cache.photos.get(&photo).and_then(url);
+                let old = self
+                    .store
+                    .get("photos", photo)?
+                    .and_then(|(_, old)| url(&old));
+                if new.is_some() && old.is_some() && new != old {
+                    events.push(event(EventKind::PhotoReplaced, *id, Some(photo)));
+                }
+            }
+        }
+
+        append_events(self.store.data_dir(), &events, self.store.is_durable())
+    }
+
+    fn is_selected(&self, table: &str) -> bool {
+        self.selection
+            .as_ref()
+            .is_none_or(|selection| selection.includes(table))
+    }
+
+    fn write_cache(
+        &self,
+        extracted: &HashMap<u64, JsonMap<String, JsonValue>>,
+        table: &'static str,
+    ) -> Result<(), Error> {
+        if !self.is_selected(table) {
+            return Ok(());
+        }
+        let shared = SHARED.contains(&table);
+        let mut written = self.written.borrow_mut();
+        self.store.put(
+            table,
+            &self.header,
+            extracted
+                .iter()
+                .sorted_by_key(|(id, _)| **id)
+                .filter(|(id, data)| {
+                    if !shared {
+                        return true;
+                    }
+                    !remember(&mut written, (table, **id), hash(data))
+                })
+                .map(|(id, data)| (*id, data)),
+        )
+    }
+}
+
+impl AllTables {
+    // All the extraction passes, the built-in ones first.
+    fn extract(&mut self, extractors: &[Arc<dyn TableExtractor>]) -> Result<(), Error> {
         // NEEDS: observations
         self.extract_annotations()?;
         self.extract_applications()?;
@@ -122,11 +618,13 @@ impl Normaliser {
         self.extract_observation_sounds()?;
         self.extract_project_observations()?;
         self.extract_quality_metrics()?;
-        self.extract_votes()?;
 
         // NEEDS: annotations
         self.extract_labels()?;
 
+        // NEEDS: annotations, comments, identifications
+        self.extract_votes()?;
+
         // NEEDS: identifications, observation_field_values
         self.extract_taxa()?;
 
@@ -147,51 +645,73 @@ impl Normaliser {
 
         // NEEDS: taxa
         self.extract_conservation_status()?;
+        self.extract_listed_taxa()?;
+        self.extract_outlinks()?;
+        self.extract_taxon_photos()?;
 
-        // NEEDS: observation_photos, taxa
-        self.extract_photos()?;
+        // NEEDS: listed_taxa
+        self.extract_lists()?;
 
-        // NEEDS: comments, identifications, observations, photos, projects
-        self.extract_flags()?;
+        // NEEDS: conservation_statuses, listed_taxa
+        self.extract_places()?;
+
+        // NEEDS: observation_photos, taxa, taxon_photos
+        self.extract_photos()?;
 
         // NEEDS: observation_sounds
         self.extract_sounds()?;
 
+        // NEEDS: comments, identifications, observations, photos, projects, sounds
+        self.extract_flags()?;
+
         // NEEDS: many other fields, should be the last
         self.extract_users()?;
 
-        self.write_all()
+        // NEEDS: observations, users
+        self.extract_preferences()?;
+
+        // NEEDS: everything built in extracted
+        self.run_extractors(extractors)?;
+
+        Ok(())
+    }
+
+    fn run_extractors(&mut self, extractors: &[Arc<dyn TableExtractor>]) -> Result<(), Error> {
+        for extractor in extractors {
+            for table in extractor.tables() {
+                self.custom.entry(table).or_default();
+            }
+            extractor.extract(&mut Batch { tables: self })?;
+        }
+
+        Ok(())
     }
 
+    // Annotations only have UUIDs, hashed into IDs.
     fn extract_annotations(&mut self) -> Result<(), Error> {
-        for obs in self.cache.observations.values_mut() {
-            if let Some(annotations) = obs.get_mut("annotations") {
-                for annotation in annotations
-                    .as_array_mut()
-                    .ok_or(internal("annotations: not an array"))?
-                    .iter_mut()
-                    .map(|val| {
-                        val.as_object_mut()
-                            .ok_or(internal("annotations item: not an object"))
-                    })
-                    .collect::<Result<Vec<_>, _>>()?
-                {
-                    for key in ["controlled_attribute", "controlled_value"] {
-                        if let Some((id, mut obj)) = extract_object(annotation, key)? {
-                            for (id, obj) in extract_objects(&mut obj, "values")? {
-                                self.cache.controlled_terms.insert(id, obj);
-                            }
-
-                            self.cache.controlled_terms.insert(id, obj);
-                        }
-                    }
+        for obs in self.observations.values_mut() {
+            let uuid = |annotation: &JsonMap<String, JsonValue>| {
+                Some(hashed_id(annotation.get("uuid")?.as_str()?))
+            };
+            for (id, obj) in extract_hashed(
+                "observations",
+                obs,
+                "annotations",
+                uuid,
+                "item without a UUID",
+            )? {
+                self.annotations.insert(id, obj);
+            }
+        }
 
-                    for (id, obj) in extract_objects(annotation, "votes")? {
-                        self.cache.votes.insert(id, obj);
-                    }
-                    if let Some((id, obj)) = extract_object(annotation, "user")? {
-                        self.cache.users.insert(id, obj);
+        for annotation in self.annotations.values_mut() {
+            for key in ["controlled_attribute", "controlled_value"] {
+                if let Some((id, mut obj)) = extract_object("annotations", annotation, key)? {
+                    for (id, obj) in extract_objects("controlled_terms", &mut obj, "values")? {
+                        self.controlled_terms.insert(id, obj);
                     }
+
+                    self.controlled_terms.insert(id, obj);
                 }
             }
         }
@@ -200,9 +720,9 @@ impl Normaliser {
     }
 
     fn extract_applications(&mut self) -> Result<(), Error> {
-        for obs in self.cache.observations.values_mut() {
-            if let Some((id, obj)) = extract_object(obs, "application")? {
-                self.cache.applications.insert(id, obj);
+        for obs in self.observations.values_mut() {
+            if let Some((id, obj)) = extract_object("observations", obs, "application")? {
+                self.applications.insert(id, obj);
             }
         }
 
@@ -210,9 +730,9 @@ impl Normaliser {
     }
 
     fn extract_comments(&mut self) -> Result<(), Error> {
-        for obs in self.cache.observations.values_mut() {
-            for (id, obj) in extract_objects(obs, "comments")? {
-                self.cache.comments.insert(id, obj);
+        for obs in self.observations.values_mut() {
+            for (id, obj) in extract_objects("observations", obs, "comments")? {
+                self.comments.insert(id, obj);
             }
         }
 
@@ -220,46 +740,46 @@ impl Normaliser {
     }
 
     fn extract_taxa(&mut self) -> Result<(), Error> {
-        for mut obs in self.cache.observations.values_mut() {
+        for obs in self.observations.values_mut() {
             for key in ["taxon", "community_taxon"] {
-                if let Some((id, obj)) = extract_object(&mut obs, key)? {
-                    self.cache.taxa.insert(id, obj);
+                if let Some((id, obj)) = extract_object("observations", obs, key)? {
+                    merge(&mut self.taxa, id, obj, &["rank"]);
                 }
             }
         }
 
-        for mut ident in self.cache.identifications.values_mut() {
+        for ident in self.identifications.values_mut() {
             for key in ["taxon", "previous_observation_taxon"] {
-                if let Some((id, obj)) = extract_object(&mut ident, key)? {
-                    self.cache.taxa.insert(id, obj);
+                if let Some((id, obj)) = extract_object("identifications", ident, key)? {
+                    merge(&mut self.taxa, id, obj, &["rank"]);
                 }
             }
         }
 
-        for mut ofv in self.cache.observation_field_values.values_mut() {
-            if let Some((id, obj)) = extract_object(&mut ofv, "taxon")? {
-                self.cache.taxa.insert(id, obj);
+        for ofv in self.observation_field_values.values_mut() {
+            if let Some((id, obj)) = extract_object("observation_field_values", ofv, "taxon")? {
+                merge(&mut self.taxa, id, obj, &["rank"]);
             }
         }
 
         // Ancestors are self-references, create a copy first.
-        // TODO: make sure not to overwrite with less detailed values.
         let mut ancestors = HashMap::new();
-        for taxon in self.cache.taxa.values_mut() {
-            for (id, obj) in extract_objects(taxon, "ancestors")? {
-                ancestors.insert(id, obj);
+        for taxon in self.taxa.values_mut() {
+            for (id, obj) in extract_objects("taxa", taxon, "ancestors")? {
+                merge(&mut ancestors, id, obj, &["rank"]);
             }
         }
-
-        self.cache.taxa.extend(ancestors);
+        for (id, obj) in ancestors {
+            merge(&mut self.taxa, id, obj, &["rank"]);
+        }
 
         Ok(())
     }
 
     fn extract_taxon_changes(&mut self) -> Result<(), Error> {
-        for mut ident in self.cache.identifications.values_mut() {
-            if let Some((id, obj)) = extract_object(&mut ident, "taxon_change")? {
-                self.cache.taxon_changes.insert(id, obj);
+        for ident in self.identifications.values_mut() {
+            if let Some((id, obj)) = extract_object("identifications", ident, "taxon_change")? {
+                self.taxon_changes.insert(id, obj);
             }
         }
 
@@ -269,6 +789,7 @@ impl Normaliser {
     fn extract_users(&mut self) -> Result<(), Error> {
         extract_users!(
             self,
+            annotations,
             comments,
             faves,
             identifications,
@@ -283,9 +804,9 @@ impl Normaliser {
     }
 
     fn extract_labels(&mut self) -> Result<(), Error> {
-        for term in self.cache.controlled_terms.values_mut() {
-            for (id, obj) in extract_objects(term, "labels")? {
-                self.cache.controlled_term_labels.insert(id, obj);
+        for term in self.controlled_terms.values_mut() {
+            for (id, obj) in extract_objects("controlled_terms", term, "labels")? {
+                self.controlled_term_labels.insert(id, obj);
             }
         }
 
@@ -293,9 +814,13 @@ impl Normaliser {
     }
 
     fn extract_conservation_status(&mut self) -> Result<(), Error> {
-        for taxon in self.cache.taxa.values_mut() {
-            if let Some((id, obj)) = extract_object(taxon, "conservation_status")? {
-                self.cache.conservation_statuses.insert(id, obj);
+        for taxon in self.taxa.values_mut() {
+            if let Some((id, obj)) = extract_object("taxa", taxon, "conservation_status")? {
+                self.conservation_statuses.insert(id, obj);
+            }
+            // Those of each place, not only the one for the taxon overall.
+            for (id, obj) in extract_objects("taxa", taxon, "conservation_statuses")? {
+                self.conservation_statuses.insert(id, obj);
             }
         }
 
@@ -303,9 +828,9 @@ impl Normaliser {
     }
 
     fn extract_faves(&mut self) -> Result<(), Error> {
-        for obs in self.cache.observations.values_mut() {
-            for (id, obj) in extract_objects(obs, "faves")? {
-                self.cache.faves.insert(id, obj);
+        for obs in self.observations.values_mut() {
+            for (id, obj) in extract_objects("observations", obs, "faves")? {
+                self.faves.insert(id, obj);
             }
         }
 
@@ -319,17 +844,18 @@ impl Normaliser {
             identifications,
             observations,
             photos,
-            projects
+            projects,
+            sounds
         );
 
         Ok(())
     }
 
     fn extract_identifications(&mut self) -> Result<(), Error> {
-        for obs in self.cache.observations.values_mut() {
+        for obs in self.observations.values_mut() {
             for key in ["identifications", "non_owner_ids"] {
-                for (id, obj) in extract_objects(obs, key)? {
-                    self.cache.identifications.insert(id, obj);
+                for (id, obj) in extract_objects("observations", obs, key)? {
+                    self.identifications.insert(id, obj);
                 }
             }
         }
@@ -338,31 +864,88 @@ impl Normaliser {
     }
 
     fn extract_observation_photos(&mut self) -> Result<(), Error> {
-        for obs in self.cache.observations.values_mut() {
-            for (id, obj) in extract_objects(obs, "observation_photos")? {
-                self.cache.observation_photos.insert(id, obj);
+        for obs in self.observations.values_mut() {
+            for (id, obj) in extract_objects("observations", obs, "observation_photos")? {
+                self.observation_photos.insert(id, obj);
             }
         }
 
         Ok(())
     }
 
+    // The same photos come with more fields in some places than in others.
     fn extract_photos(&mut self) -> Result<(), Error> {
-        for obs in self.cache.observations.values_mut() {
-            for (id, obj) in extract_objects(obs, "photos")? {
-                self.cache.photos.insert(id, obj);
+        for obs in self.observations.values_mut() {
+            for (id, obj) in extract_objects("observations", obs, "photos")? {
+                merge(&mut self.photos, id, obj, &[]);
+            }
+        }
+
+        for obs_photo in self.observation_photos.values_mut() {
+            if let Some((id, obj)) = extract_object("observation_photos", obs_photo, "photo")? {
+                merge(&mut self.photos, id, obj, &[]);
+            }
+        }
+
+        for taxon in self.taxa.values_mut() {
+            if let Some((id, obj)) = extract_object("taxa", taxon, "default_photo")? {
+                merge(&mut self.photos, id, obj, &[]);
             }
         }
 
-        for obs_photo in self.cache.observation_photos.values_mut() {
-            if let Some((id, obj)) = extract_object(obs_photo, "photo")? {
-                self.cache.photos.insert(id, obj);
+        for taxon_photo in self.taxon_photos.values_mut() {
+            if let Some((id, obj)) = extract_object("taxon_photos", taxon_photo, "photo")? {
+                merge(&mut self.photos, id, obj, &[]);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Links to the same records elsewhere, e.g. on GBIF; those only have their source and URL,
+    // hashed together into their IDs.
+    fn extract_outlinks(&mut self) -> Result<(), Error> {
+        let link = |outlink: &JsonMap<String, JsonValue>| {
+            let source = outlink.get("source").and_then(JsonValue::as_str);
+            let url = outlink.get("url")?.as_str()?;
+            Some(hashed_id(&format!(
+                "{} {}",
+                source.unwrap_or_default(),
+                url
+            )))
+        };
+        for (table, records) in [
+            ("observations", &mut self.observations),
+            ("taxa", &mut self.taxa),
+        ] {
+            for record in records.values_mut() {
+                for (id, obj) in
+                    extract_hashed(table, record, "outlinks", link, "item without a URL")?
+                {
+                    self.outlinks.insert(id, obj);
+                }
             }
         }
 
-        for taxon in self.cache.taxa.values_mut() {
-            if let Some((id, obj)) = extract_object(taxon, "default_photo")? {
-                self.cache.photos.insert(id, obj);
+        Ok(())
+    }
+
+    // Taxon photos only have the IDs of their taxon and photo, hashed together into theirs.
+    fn extract_taxon_photos(&mut self) -> Result<(), Error> {
+        for (&taxon_id, taxon) in self.taxa.iter_mut() {
+            let photo_id = |taxon_photo: &JsonMap<String, JsonValue>| {
+                let photo_id = taxon_photo.get("photo")?.get(ID)?.as_u64()?;
+                Some(hashed_id(&format!("{}/{}", taxon_id, photo_id)))
+            };
+            for (id, mut obj) in extract_hashed(
+                "taxa",
+                taxon,
+                "taxon_photos",
+                photo_id,
+                "item without a photo ID",
+            )? {
+                obj.entry("taxon_id").or_insert(taxon_id.into());
+                self.taxon_photos.insert(id, obj);
             }
         }
 
@@ -370,9 +953,9 @@ impl Normaliser {
     }
 
     fn extract_observation_sounds(&mut self) -> Result<(), Error> {
-        for obs in self.cache.observations.values_mut() {
-            for (id, obj) in extract_objects(obs, "observation_sounds")? {
-                self.cache.observation_sounds.insert(id, obj);
+        for obs in self.observations.values_mut() {
+            for (id, obj) in extract_objects("observations", obs, "observation_sounds")? {
+                self.observation_sounds.insert(id, obj);
             }
         }
 
@@ -380,15 +963,15 @@ impl Normaliser {
     }
 
     fn extract_sounds(&mut self) -> Result<(), Error> {
-        for obs in self.cache.observations.values_mut() {
-            for (id, obj) in extract_objects(obs, "sounds")? {
-                self.cache.sounds.insert(id, obj);
+        for obs in self.observations.values_mut() {
+            for (id, obj) in extract_objects("observations", obs, "sounds")? {
+                self.sounds.insert(id, obj);
             }
         }
 
-        for obs_sound in self.cache.observation_sounds.values_mut() {
-            if let Some((id, obj)) = extract_object(obs_sound, "sound")? {
-                self.cache.sounds.insert(id, obj);
+        for obs_sound in self.observation_sounds.values_mut() {
+            if let Some((id, obj)) = extract_object("observation_sounds", obs_sound, "sound")? {
+                self.sounds.insert(id, obj);
             }
         }
 
@@ -396,9 +979,9 @@ impl Normaliser {
     }
 
     fn extract_project_admins(&mut self) -> Result<(), Error> {
-        for proj in self.cache.projects.values_mut() {
-            for (id, obj) in extract_objects(proj, "admins")? {
-                self.cache.project_admins.insert(id, obj);
+        for proj in self.projects.values_mut() {
+            for (id, obj) in extract_objects("projects", proj, "admins")? {
+                self.project_admins.insert(id, obj);
             }
         }
 
@@ -406,9 +989,9 @@ impl Normaliser {
     }
 
     fn extract_project_observation_fields(&mut self) -> Result<(), Error> {
-        for proj in self.cache.projects.values_mut() {
-            for (id, obj) in extract_objects(proj, "project_observation_fields")? {
-                self.cache.project_observation_fields.insert(id, obj);
+        for proj in self.projects.values_mut() {
+            for (id, obj) in extract_objects("projects", proj, "project_observation_fields")? {
+                self.project_observation_fields.insert(id, obj);
             }
         }
 
@@ -416,9 +999,39 @@ impl Normaliser {
     }
 
     fn extract_project_observation_rules(&mut self) -> Result<(), Error> {
-        for proj in self.cache.projects.values_mut() {
-            for (id, obj) in extract_objects(proj, "project_observation_rules")? {
-                self.cache.project_observation_rules.insert(id, obj);
+        for proj in self.projects.values_mut() {
+            for (id, obj) in extract_objects("projects", proj, "project_observation_rules")? {
+                self.project_observation_rules.insert(id, obj);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn extract_places(&mut self) -> Result<(), Error> {
+        for status in self.conservation_statuses.values_mut() {
+            if let Some((id, obj)) = extract_object("conservation_statuses", status, "place")? {
+                self.places.insert(id, obj);
+            }
+        }
+        for listed_taxon in self.listed_taxa.values_mut() {
+            if let Some((id, obj)) = extract_object("listed_taxa", listed_taxon, "place")? {
+                self.places.insert(id, obj);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn extract_preferences(&mut self) -> Result<(), Error> {
+        for (&id, obs) in self.observations.iter_mut() {
+            if let Some((id, obj)) = extract_preferences("observations", "Observation", id, obs)? {
+                self.preferences.insert(id, obj);
+            }
+        }
+        for (&id, user) in self.users.iter_mut() {
+            if let Some((id, obj)) = extract_preferences("users", "User", id, user)? {
+                self.preferences.insert(id, obj);
             }
         }
 
@@ -426,9 +1039,9 @@ impl Normaliser {
     }
 
     fn extract_project_observations(&mut self) -> Result<(), Error> {
-        for obs in self.cache.observations.values_mut() {
-            for (id, obj) in extract_objects(obs, "project_observations")? {
-                self.cache.project_observations.insert(id, obj);
+        for obs in self.observations.values_mut() {
+            for (id, obj) in extract_objects("observations", obs, "project_observations")? {
+                self.project_observations.insert(id, obj);
             }
         }
 
@@ -436,9 +1049,9 @@ impl Normaliser {
     }
 
     fn extract_project_users(&mut self) -> Result<(), Error> {
-        for proj in self.cache.project_observations.values_mut() {
-            if let Some((id, obj)) = extract_object(proj, "project_user")? {
-                self.cache.project_users.insert(id, obj);
+        for proj in self.project_observations.values_mut() {
+            if let Some((id, obj)) = extract_object("project_observations", proj, "project_user")? {
+                self.project_users.insert(id, obj);
             }
         }
 
@@ -446,9 +1059,32 @@ impl Normaliser {
     }
 
     fn extract_projects(&mut self) -> Result<(), Error> {
-        for project_obs in self.cache.project_observations.values_mut() {
-            if let Some((id, obj)) = extract_object(project_obs, "project")? {
-                self.cache.projects.insert(id, obj);
+        for project_obs in self.project_observations.values_mut() {
+            if let Some((id, obj)) = extract_object("project_observations", project_obs, "project")?
+            {
+                self.projects.insert(id, obj);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Which lists the taxa are on, e.g. places' checklists, and whether they're native there.
+    fn extract_listed_taxa(&mut self) -> Result<(), Error> {
+        for (&taxon_id, taxon) in self.taxa.iter_mut() {
+            for (id, mut obj) in extract_objects("taxa", taxon, "listed_taxa")? {
+                obj.entry("taxon_id").or_insert(taxon_id.into());
+                self.listed_taxa.insert(id, obj);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn extract_lists(&mut self) -> Result<(), Error> {
+        for listed_taxon in self.listed_taxa.values_mut() {
+            if let Some((id, obj)) = extract_object("listed_taxa", listed_taxon, "list")? {
+                self.lists.insert(id, obj);
             }
         }
 
@@ -456,9 +1092,9 @@ impl Normaliser {
     }
 
     fn extract_observation_field_values(&mut self) -> Result<(), Error> {
-        for obs in self.cache.observations.values_mut() {
-            for (id, obj) in extract_objects(obs, "ofvs")? {
-                self.cache.observation_field_values.insert(id, obj);
+        for obs in self.observations.values_mut() {
+            for (id, obj) in extract_objects("observations", obs, "ofvs")? {
+                self.observation_field_values.insert(id, obj);
             }
         }
 
@@ -466,15 +1102,19 @@ impl Normaliser {
     }
 
     fn extract_observation_fields(&mut self) -> Result<(), Error> {
-        for ofv in self.cache.observation_field_values.values_mut() {
-            if let Some((id, obj)) = extract_object(ofv, "observation_field")? {
-                self.cache.observation_fields.insert(id, obj);
+        for ofv in self.observation_field_values.values_mut() {
+            if let Some((id, obj)) =
+                extract_object("observation_field_values", ofv, "observation_field")?
+            {
+                self.observation_fields.insert(id, obj);
             }
         }
 
-        for pof in self.cache.project_observation_fields.values_mut() {
-            if let Some((id, obj)) = extract_object(pof, "observation_field")? {
-                self.cache.observation_fields.insert(id, obj);
+        for pof in self.project_observation_fields.values_mut() {
+            if let Some((id, obj)) =
+                extract_object("project_observation_fields", pof, "observation_field")?
+            {
+                self.observation_fields.insert(id, obj);
             }
         }
 
@@ -482,9 +1122,9 @@ impl Normaliser {
     }
 
     fn extract_quality_metrics(&mut self) -> Result<(), Error> {
-        for mut obs in self.cache.observations.values_mut() {
-            for (id, obj) in extract_objects(&mut obs, "quality_metrics")? {
-                self.cache.quality_metrics.insert(id, obj);
+        for obs in self.observations.values_mut() {
+            for (id, obj) in extract_objects("observations", obs, "quality_metrics")? {
+                self.quality_metrics.insert(id, obj);
             }
         }
 
@@ -492,76 +1132,329 @@ impl Normaliser {
     }
 
     fn extract_votes(&mut self) -> Result<(), Error> {
-        for mut obs in self.cache.observations.values_mut() {
-            for (id, obj) in extract_objects(&mut obs, "votes")? {
-                self.cache.votes.insert(id, obj);
-            }
-        }
+        extract_votes!(
+            self,
+            annotations: "Annotation",
+            comments: "Comment",
+            identifications: "Identification",
+            observations: "Observation"
+        );
 
         Ok(())
     }
+}
 
-    fn write_cache(
-        &self,
-        extracted: &HashMap<u64, JsonMap<String, JsonValue>>,
-        subdir: &str,
-    ) -> Result<(), Error> {
-        let dir = self.data_dir.join(subdir);
+#[cfg(feature = "lru")]
+fn new_written() -> Written {
+    LruCache::new(NonZeroUsize::new(WRITTEN).expect("WRITTEN is zero"))
+}
 
-        create_dir_all(&dir)?;
-        for (id, data) in extracted {
-            write_cache(&dir.join(format!("{}.yaml", id)), &self.header, &data)?;
-        }
+#[cfg(not(feature = "lru"))]
+fn new_written() -> Written {
+    HashMap::with_capacity(WRITTEN)
+}
+
+// Whether the record was written with the same hash before; remembered as written either way.
+#[cfg(feature = "lru")]
+fn remember(written: &mut Written, key: (&'static str, u64), hash: u64) -> bool {
+    written.put(key, hash) == Some(hash)
+}
+
+#[cfg(not(feature = "lru"))]
+fn remember(written: &mut Written, key: (&'static str, u64), hash: u64) -> bool {
+    if written.len() >= WRITTEN && !written.contains_key(&key) {
+        written.clear();
+    }
+    written.insert(key, hash) == Some(hash)
+}
+
+fn hash(data: &JsonMap<String, JsonValue>) -> u64 {
+    let mut hasher = HashWriter(DefaultHasher::new());
+    // Writing to a hasher doesn't fail.
+    let _ = serde_json::to_writer(&mut hasher, data);
+    hasher.0.finish()
+}
+
+// Hashes whatever gets written to it, so that records are hashed without serialising them into
+// a string first.
+struct HashWriter(DefaultHasher);
 
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.0.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
         Ok(())
     }
 }
 
+// Extracted objects are moved out of their parents, which keep only their IDs: observations are
+// large, and cloning them for every key made up most of the time spent normalising.
 fn extract_object(
+    table: &str,
     data: &mut JsonMap<String, JsonValue>,
     key: &str,
-) -> Result<Option<(u64, JsonMap<String, JsonValue>)>, Error> {
-    Ok(match data.get(key) {
-        Some(val) => {
-            if val.is_null() {
-                None
-            } else {
-                let obj = val
-                    .as_object()
-                    .ok_or(internal(&format!("{}: not an object", key)))?
-                    .clone();
-                let id = extract_id(&obj)?;
-                data.insert(key.to_string(), id.into());
-                data.remove(&format!("{}_id", key));
-                Some((id, obj))
-            }
-        }
-        _ => None,
-    })
+) -> Result<Option<Entry>, Error> {
+    extract_object_by(data, key, ID).map_err(|err| err.in_table(table))
 }
 
 fn extract_objects(
+    table: &str,
     data: &mut JsonMap<String, JsonValue>,
     key: &str,
-) -> Result<Vec<(u64, JsonMap<String, JsonValue>)>, Error> {
-    Ok(match data.get(key) {
-        Some(val) => {
-            let arr: Vec<_> = val
-                .as_array()
-                .ok_or(internal(&format!("{}: not an array", key)))?
-                .clone()
-                .into_iter()
-                .map(|item| {
-                    item.as_object()
-                        .ok_or(internal(&format!("{} item: not an object", key)))
-                        .and_then(|obj| extract_id(&obj).and_then(|id| Ok((id, obj.clone()))))
-                })
-                .collect::<Result<_, _>>()?;
-            let ids: Vec<_> = arr.iter().map(|(id, _)| id).copied().collect();
-            data.insert(key.to_string(), ids.into());
-            data.remove(&format!("{}_ids", key));
-            arr
-        }
-        _ => vec![],
+) -> Result<Vec<Entry>, Error> {
+    extract_objects_by(data, key, ID).map_err(|err| err.in_table(table))
+}
+
+// Like extract_object, for records identified by another field.
+pub(crate) fn extract_object_by(
+    data: &mut JsonMap<String, JsonValue>,
+    key: &str,
+    id: &str,
+) -> Result<Option<Entry>, Error> {
+    let obj = match data.get_mut(key) {
+        Some(JsonValue::Object(obj)) => take(obj),
+        Some(JsonValue::Null) | None => return Ok(None),
+        Some(_) => return Err(bad_record(data_id(data), key, "not an object")),
+    };
+    let id = record_id(data, key, &obj, id)?;
+    data.insert(key.to_string(), id.into());
+    data.remove(&format!("{}_id", key));
+
+    Ok(Some((id, obj)))
+}
+
+pub(crate) fn extract_objects_by(
+    data: &mut JsonMap<String, JsonValue>,
+    key: &str,
+    id: &str,
+) -> Result<Vec<Entry>, Error> {
+    let arr = match data.get_mut(key) {
+        Some(JsonValue::Array(arr)) => take(arr),
+        Some(_) => return Err(bad_record(data_id(data), key, "not an array")),
+        None => return Ok(vec![]),
+    };
+    let arr: Vec<_> = arr
+        .into_iter()
+        .map(|item| match item {
+            JsonValue::Object(obj) => record_id(data, key, &obj, id).map(|id| (id, obj)),
+            _ => Err(bad_record(data_id(data), key, "item not an object")),
+        })
+        .collect::<Result<_, _>>()?;
+    let mut ids: Vec<_> = arr.iter().map(|(id, _)| id).copied().collect();
+    if !ORDERED.contains(&key) {
+        ids.sort_unstable();
+    }
+    data.insert(key.to_string(), ids.into());
+    data.remove(&format!("{}_ids", key));
+
+    Ok(arr)
+}
+
+// The ID of a record nested under the key, errors pointing at the record it's nested in.
+fn record_id(
+    data: &JsonMap<String, JsonValue>,
+    key: &str,
+    obj: &JsonMap<String, JsonValue>,
+    field: &str,
+) -> Result<u64, Error> {
+    obj.get(field).and_then(JsonValue::as_u64).ok_or_else(|| {
+        bad_record(
+            data_id(data),
+            &format!("{}.{}", key, field),
+            "missing, or not u64",
+        )
     })
 }
+
+// Like extract_objects, for records without IDs of their own: theirs are made up from what they
+// have, and added to them.
+fn extract_hashed(
+    table: &str,
+    data: &mut JsonMap<String, JsonValue>,
+    key: &str,
+    id: impl Fn(&JsonMap<String, JsonValue>) -> Option<u64>,
+    problem: &str,
+) -> Result<Vec<Entry>, Error> {
+    let error = |data: &JsonMap<String, JsonValue>, problem| {
+        bad_record(data_id(data), key, problem).in_table(table)
+    };
+    let arr = match data.get_mut(key) {
+        Some(JsonValue::Array(arr)) => take(arr),
+        Some(_) => return Err(error(data, "not an array")),
+        None => return Ok(vec![]),
+    };
+    let mut entries = vec![];
+    for item in arr {
+        let mut obj = match item {
+            JsonValue::Object(obj) => obj,
+            _ => return Err(error(data, "item not an object")),
+        };
+        let id = id(&obj).ok_or_else(|| error(data, problem))?;
+        obj.insert(ID.to_string(), id.into());
+        entries.push((id, obj));
+    }
+    let mut ids: Vec<_> = entries.iter().map(|(id, _)| id).copied().collect();
+    if !ORDERED.contains(&key) {
+        ids.sort_unstable();
+    }
+    data.insert(key.to_string(), ids.into());
+
+    Ok(entries)
+}
+
+// Records nested in many places come with more fields in some than in others, e.g. taxa fetched
+// on their own have their photos and statuses, those of identifications no ancestors. They're
+// merged field by field: those the later one has set (not null or empty) win, the rest are kept
+// from the earlier one. If they differ in any of the fields that have to agree, e.g. the ranks of
+// taxa after a taxon change, the later one wins outright: fields of either rank don't go together.
+fn merge(
+    records: &mut HashMap<u64, JsonMap<String, JsonValue>>,
+    id: u64,
+    obj: JsonMap<String, JsonValue>,
+    agree: &[&str],
+) {
+    match records.get_mut(&id) {
+        Some(old) => *old = merged(take(old), obj, agree),
+        _ => {
+            records.insert(id, obj);
+        }
+    }
+}
+
+fn merged(
+    mut old: JsonMap<String, JsonValue>,
+    new: JsonMap<String, JsonValue>,
+    agree: &[&str],
+) -> JsonMap<String, JsonValue> {
+    if agree.iter().any(|key| old.get(*key) != new.get(*key)) {
+        return new;
+    }
+    for (key, val) in new {
+        if !is_blank(&val) || !old.contains_key(&key) {
+            old.insert(key, val);
+        }
+    }
+
+    old
+}
+
+fn is_blank(val: &JsonValue) -> bool {
+    match val {
+        JsonValue::Null => true,
+        JsonValue::Array(arr) => arr.is_empty(),
+        JsonValue::Object(obj) => obj.is_empty(),
+        _ => false,
+    }
+}
+
+// The preferences of a record, e.g. whether the community taxon is preferred, as their own record
+// with the owner_type and owner_id of the iNat database; the ID is made up from those. Also for
+// the user synced, which isn't normalised along with the rest.
+pub(crate) fn extract_preferences(
+    table: &str,
+    owner_type: &str,
+    owner_id: u64,
+    data: &mut JsonMap<String, JsonValue>,
+) -> Result<Option<Entry>, Error> {
+    let mut obj = match data.get_mut("preferences") {
+        Some(JsonValue::Object(obj)) => take(obj),
+        Some(JsonValue::Null) | None => return Ok(None),
+        Some(_) => {
+            return Err(bad_record(data_id(data), "preferences", "not an object").in_table(table))
+        }
+    };
+    let id = hashed_id(&format!("{}/{}", table, owner_id));
+    obj.insert(ID.to_string(), id.into());
+    obj.insert("owner_id".to_string(), owner_id.into());
+    obj.insert("owner_type".to_string(), owner_type.into());
+    data.insert("preferences".to_string(), id.into());
+
+    Ok(Some((id, obj)))
+}
+
+// IDs of records without one of their own: those are u64 in every other table. FNV-1a, since the
+// IDs have to stay the same across Rust versions; kept below i64::MAX for whatever reads them as
+// signed.
+fn hashed_id(key: &str) -> u64 {
+    let hash = key.bytes().fold(0xcbf29ce484222325, |hash: u64, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+
+    (hash >> 1).max(1)
+}
+
+fn data_id(data: &JsonMap<String, JsonValue>) -> Option<u64> {
+    data.get(ID).and_then(JsonValue::as_u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::store::Layout;
+
+    fn record(val: JsonValue) -> JsonMap<String, JsonValue> {
+        val.as_object().expect("object").clone()
+    }
+
+    #[test]
+    fn newer_thin_records_replace_stale_fields() {
+        let old = record(json!({
+            "id": 1,
+            "quality_grade": "needs_id",
+            "taxon": 11,
+            "photos": [101],
+            "description": "A duck",
+        }));
+        let new = record(json!({
+            "id": 1,
+            "quality_grade": "research",
+            "taxon": 12,
+            "photos": [],
+            "description": null,
+        }));
+
+        assert_eq!(
+            merged(old, new, &[]),
+            record(json!({
+                "id": 1,
+                "quality_grade": "research",
+                "taxon": 12,
+                "photos": [101],
+                "description": "A duck",
+            }))
+        );
+    }
+
+    #[test]
+    fn records_that_disagree_are_replaced() {
+        let old = record(json!({ "id": 11, "rank": "species", "taxon_photos": [1] }));
+        let new = record(json!({ "id": 11, "rank": "genus" }));
+
+        assert_eq!(merged(old, new.clone(), &["rank"]), new);
+    }
+
+    #[test]
+    fn writers_remember_shared_records_across_writes() {
+        let dir = tempdir().expect("tempdir");
+        let store = Arc::new(Store::with_layout(dir.path(), Layout::Directory));
+        let mut writer = Writer::new(store.clone());
+        for id in [1, 2] {
+            let obs = record(json!({ "id": id, "user": { "id": 7, "login": "alice" } }));
+            writer
+                .observations(YamlMapping::new(), HashMap::from([(id, obs)]))
+                .expect("write");
+        }
+
+        let changes = store.changes();
+        assert_eq!(changes.tables["observations"].new, 2);
+        // Written once, and skipped the second time rather than written again unchanged.
+        assert_eq!(changes.tables["users"].new, 1);
+        assert_eq!(changes.tables["users"].unchanged, 0);
+    }
+}
@@ -0,0 +1,22 @@
+use url::Url;
+
+use crate::store::Changes;
+
+// Told what a sync is up to as it goes, e.g. to show progress in a UI. Installed with
+// Api::with_progress; called from the sync's tasks, possibly concurrently.
+pub trait SyncProgress: Send + Sync {
+    // The stages of a sync each fill a few tables, some of them concurrently.
+    fn tables_started(&self, _tables: &[&str]) {}
+
+    fn tables_finished(&self, _tables: &[&str]) {}
+
+    // Answered by the API, or the HTTP cache.
+    fn page_fetched(&self, _url: &Url) {}
+
+    // Answered by the HTTP cache without asking the API, or with the API confirming that nothing
+    // changed.
+    fn cache_hit(&self, _url: &Url) {}
+
+    // The records written so far in this sync, after each batch.
+    fn written(&self, _changes: &Changes) {}
+}
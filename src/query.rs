@@ -0,0 +1,77 @@
+use std::io::Write;
+
+use crate::{
+    archive::{str_field, Archive},
+    error::Error,
+    filter::Filter,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum QueryFormat {
+    Table,
+    Json,
+}
+
+const COLUMNS: [&str; 5] = ["id", "observed_on", "taxon", "quality_grade", "place_guess"];
+
+impl Archive {
+    // Matching observations, oldest first; at most limit of them if given.
+    pub fn query<W: Write>(
+        &self,
+        out: &mut W,
+        filter: &Filter,
+        format: QueryFormat,
+        limit: Option<usize>,
+    ) -> Result<(), Error> {
+        let observations = self.observation_records(filter)?;
+        let observations = observations.into_iter().take(limit.unwrap_or(usize::MAX));
+
+        if format == QueryFormat::Json {
+            let records: Vec<_> = observations.map(|(_, obs)| obs).collect();
+            serde_json::to_writer_pretty(&mut *out, &records)?;
+            writeln!(out)?;
+            return Ok(());
+        }
+
+        let mut rows = vec![COLUMNS.map(str::to_string).to_vec()];
+        for (id, obs) in observations {
+            rows.push(vec![
+                id.to_string(),
+                str_field(&obs, "observed_on")
+                    .unwrap_or_default()
+                    .to_string(),
+                self.observation_title(&obs)?,
+                str_field(&obs, "quality_grade")
+                    .unwrap_or_default()
+                    .to_string(),
+                str_field(&obs, "place_guess")
+                    .unwrap_or_default()
+                    .to_string(),
+            ]);
+        }
+
+        write_rows(out, &rows)
+    }
+}
+
+// Plain text table, columns padded to the widest cell.
+pub(crate) fn write_rows<W: Write>(out: &mut W, rows: &[Vec<String>]) -> Result<(), Error> {
+    let mut widths = vec![];
+    for row in rows {
+        widths.resize(widths.len().max(row.len()), 0);
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in rows {
+        let cells: Vec<_> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        writeln!(out, "{}", cells.join("  ").trim_end())?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,221 @@
+use std::{
+    fs::create_dir_all,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use chrono::{Days, NaiveDate};
+use reqwest::header::DATE;
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping as YamlMapping, Value as YamlValue};
+use tracing::warn;
+
+use crate::{
+    api::{lookup_cache_data, write_cache},
+    error::Error,
+    store::{Settings, Store},
+};
+
+// See https://www.inaturalist.org/pages/api+recommended+practices.
+pub(crate) const DEFAULT_DAILY_QUOTA: u32 = 10_000;
+
+// Share of the quota after which to warn.
+const WARN_AT: f64 = 0.9;
+
+// Requests counted but not written yet, at most; those are lost if the process dies.
+const FLUSH_EVERY: u32 = 100;
+
+#[derive(Debug, Deserialize, Serialize)]
+struct RequestCount {
+    day: NaiveDate,
+    requests: u32,
+}
+
+// Requests per UTC day, counted in the data directory so that they add up across runs. The count
+// is read once, kept in memory and written every so often, at the end of syncs and when dropped.
+pub(crate) struct DailyQuota {
+    // Zero for no limit.
+    limit: u32,
+    state: Mutex<Option<State>>,
+    warned: AtomicBool,
+}
+
+struct State {
+    path: PathBuf,
+    count: RequestCount,
+    unflushed: u32,
+    // The store's, for flushing when dropped.
+    settings: Settings,
+}
+
+impl DailyQuota {
+    pub(crate) fn new(limit: u32) -> Self {
+        Self {
+            limit,
+            state: Mutex::new(None),
+            warned: AtomicBool::new(false),
+        }
+    }
+
+    // Counts one more request, unless that would go over the limit.
+    pub(crate) fn count(&self, store: &Store) -> Result<(), Error> {
+        let mut state = self.state.lock().expect("quota poisoned");
+        let today = store.now().date_naive();
+        let state = match &mut *state {
+            Some(state) => state,
+            state => state.insert(State::load(store, today)?),
+        };
+        if state.count.day != today {
+            state.count = RequestCount {
+                day: today,
+                requests: 0,
+            };
+        }
+        let requests = state.count.requests;
+
+        if self.limit > 0 {
+            if requests >= self.limit {
+                let tomorrow = today
+                    .checked_add_days(Days::new(1))
+                    .unwrap_or(today)
+                    .and_hms_opt(0, 0, 0)
+                    .unwrap_or_default()
+                    .and_utc();
+                return Err(Error::QuotaExhausted(tomorrow));
+            }
+            if requests as f64 >= self.limit as f64 * WARN_AT
+                && !self.warned.swap(true, Ordering::Relaxed)
+            {
+                warn!("{} of {} daily API requests used up", requests, self.limit);
+            }
+        }
+
+        state.count.requests += 1;
+        state.unflushed += 1;
+        match state.unflushed >= FLUSH_EVERY {
+            true => state.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    // Writes the requests counted since the last time.
+    pub(crate) fn flush(&self) -> Result<(), Error> {
+        match &mut *self.state.lock().expect("quota poisoned") {
+            Some(state) if state.unflushed > 0 => state.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for DailyQuota {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            warn!("request count: {}", err);
+        }
+    }
+}
+
+impl State {
+    fn load(store: &Store, today: NaiveDate) -> Result<Self, Error> {
+        let path = store.data_dir().join(".sync").join("requests.yaml");
+        let count = match lookup_cache_data::<RequestCount>(&path)? {
+            Some((_, count)) => count,
+            _ => RequestCount {
+                day: today,
+                requests: 0,
+            },
+        };
+
+        Ok(Self {
+            path,
+            count,
+            unflushed: 0,
+            settings: store.settings().clone(),
+        })
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        let mut header = YamlMapping::new();
+        header.insert(
+            YamlValue::String(DATE.to_string()),
+            YamlValue::String(self.settings.clock.now().to_rfc3339()),
+        );
+        if let Some(dir) = self.path.parent() {
+            create_dir_all(dir)?;
+        }
+        write_cache(&self.path, &header, &self.count, self.settings.durable)?;
+        self.unflushed = 0;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc};
+
+    use chrono::{TimeZone, Utc};
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{clock::FixedClock, store::Layout};
+
+    fn store_on(dir: &Path, day: u32) -> Store {
+        Store::with_layout(dir, Layout::default()).with_settings(Settings {
+            clock: Arc::new(FixedClock(
+                Utc.with_ymd_and_hms(2024, 1, day, 12, 0, 0).unwrap(),
+            )),
+            ..Settings::default()
+        })
+    }
+
+    fn written(store: &Store) -> Option<u32> {
+        let path = store.data_dir().join(".sync").join("requests.yaml");
+        lookup_cache_data::<RequestCount>(&path)
+            .expect("read")
+            .map(|(_, count)| count.requests)
+    }
+
+    #[test]
+    fn writes_the_count_every_so_often_and_when_dropped() {
+        let dir = tempdir().expect("tempdir");
+        let store = store_on(dir.path(), 1);
+        let quota = DailyQuota::new(0);
+        for _ in 1..FLUSH_EVERY {
+            quota.count(&store).expect("count");
+        }
+        assert_eq!(written(&store), None);
+        quota.count(&store).expect("count");
+        assert_eq!(written(&store), Some(FLUSH_EVERY));
+
+        quota.count(&store).expect("count");
+        drop(quota);
+        assert_eq!(written(&store), Some(FLUSH_EVERY + 1));
+
+        // Read back by the next run.
+        let quota = DailyQuota::new(0);
+        quota.count(&store).expect("count");
+        quota.flush().expect("flush");
+        assert_eq!(written(&store), Some(FLUSH_EVERY + 2));
+    }
+
+    #[test]
+    fn stops_at_the_limit_until_the_next_day() {
+        let dir = tempdir().expect("tempdir");
+        let store = store_on(dir.path(), 1);
+        let quota = DailyQuota::new(2);
+        quota.count(&store).expect("count");
+        quota.count(&store).expect("count");
+        match quota.count(&store) {
+            Err(Error::QuotaExhausted(retry)) => {
+                assert_eq!(retry, Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap())
+            }
+            res => panic!("not exhausted: {:?}", res),
+        }
+
+        quota.count(&store_on(dir.path(), 2)).expect("count");
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::time::sleep;
+
+// See https://www.inaturalist.org/pages/api+recommended+practices.
+pub(crate) const DEFAULT_REQUESTS_PER_MINUTE: u32 = 60;
+
+// Requests that may go out back to back after a quiet period.
+const BURST: f64 = 5.0;
+
+// Token bucket shared by all requests of an Api, concurrent ones included.
+pub(crate) struct RateLimiter {
+    per_sec: f64,
+    capacity: f64,
+    // Tokens left, negative once requests are queued up waiting for them.
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(per_minute: u32) -> Self {
+        let per_sec = per_minute.max(1) as f64 / 60.0;
+        let capacity = BURST.min(per_minute.max(1) as f64);
+        Self {
+            per_sec,
+            capacity,
+            bucket: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    pub(crate) async fn acquire(&self) {
+        let wait = {
+            let mut bucket = self.bucket.lock().expect("rate limiter poisoned");
+            let (tokens, last) = &mut *bucket;
+            let now = Instant::now();
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.per_sec)
+                .min(self.capacity);
+            *last = now;
+            *tokens -= 1.0;
+            match *tokens < 0.0 {
+                true => Duration::from_secs_f64(-*tokens / self.per_sec),
+                _ => Duration::ZERO,
+            }
+        };
+
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lets_a_burst_through_then_paces() {
+        // Ten a second, so that the request after the burst waits a tenth of one.
+        let limiter = RateLimiter::new(600);
+        let start = Instant::now();
+        for _ in 0..BURST as usize {
+            limiter.acquire().await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        limiter.acquire().await;
+        assert!(start.elapsed() >= Duration::from_millis(80));
+    }
+
+    #[test]
+    fn allows_at_least_one_request_per_minute() {
+        let limiter = RateLimiter::new(0);
+        assert_eq!(limiter.capacity, 1.0);
+        assert_eq!(limiter.per_sec, 1.0 / 60.0);
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, read_dir, write, File},
+    io::{BufReader, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use reqwest::{header::ETAG, Url};
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping as YamlMapping, Value as YamlValue};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::{
+    api::{expect_results, extract_id, parse_response},
+    archive::Archive,
+    error::Error,
+    normalise::Writer,
+};
+
+// API responses exactly as received, compressed, so that whatever a buggy normaliser dropped can
+// be recovered by replaying them.
+pub(crate) struct RawArchive {
+    dir: PathBuf,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct RawResponse {
+    url: String,
+    header: YamlMapping,
+}
+
+impl RawArchive {
+    pub(crate) fn new(data_dir: &Path) -> Self {
+        Self {
+            dir: raw_dir(data_dir),
+        }
+    }
+
+    // Named by when they arrived, so that they sort in the order to replay them.
+    pub(crate) fn put(
+        &self,
+        url: &Url,
+        header: &YamlMapping,
+        body: &[u8],
+        now: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let dir = self.dir.join(now.format("%Y-%m-%d").to_string());
+        create_dir_all(&dir)?;
+        let name = format!(
+            "{}-{:.8x}",
+            now.format("%H%M%S%.6f"),
+            Sha256::digest(url.as_str())
+        );
+
+        write(
+            dir.join(format!("{}.json.zst", name)),
+            zstd::encode_all(body, 0)?,
+        )?;
+        Ok(write(
+            dir.join(format!("{}.yaml", name)),
+            serde_yaml::to_string(&RawResponse {
+                url: url.to_string(),
+                header: header.clone(),
+            })?,
+        )?)
+    }
+}
+
+impl Archive {
+    // Normalises the observations and taxa in the raw archive again, oldest first, so that the
+    // latest of each ends up in the cache.
+    pub fn replay_raw(&self) -> Result<usize, Error> {
+        let mut writer = Writer::new(self.store.clone()).extract_with(self.extractors.clone());
+        let mut count = 0;
+        for path in raw_responses(&raw_dir(&self.data_dir))? {
+            match self.replay_response(&path, &mut writer) {
+                Ok(n) => count += n,
+                Err(err) => warn!("{}: {}; skipping", path.display(), err),
+            }
+        }
+        self.store.compact()?;
+
+        Ok(count)
+    }
+
+    fn replay_response(&self, path: &Path, writer: &mut Writer) -> Result<usize, Error> {
+        let res: RawResponse = serde_yaml::from_reader(BufReader::new(File::open(path)?))?;
+        let url: Url = res.url.parse()?;
+        let segments: Vec<&str> = url
+            .path_segments()
+            .map(Iterator::collect)
+            .unwrap_or_default();
+        let endpoint = match segments[..] {
+            [.., endpoint, ids] if ids.split(',').all(|id| id.parse::<u64>().is_ok()) => endpoint,
+            // Listings and the like have nothing to normalise.
+            _ => return Ok(0),
+        };
+        if !matches!(endpoint, "observations" | "taxa") {
+            return Ok(0);
+        }
+
+        let body = zstd::decode_all(File::open(path.with_extension("json.zst"))?)?;
+        let records = expect_results(parse_response(&body)?)?
+            .into_iter()
+            .map(|obj| extract_id(&obj).map(|id| (id, obj)))
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        let count = records.len();
+
+        // Like when fetched; the etag doesn't match single items.
+        let mut header = res.header;
+        header.remove(YamlValue::String(ETAG.to_string()));
+        match endpoint {
+            "taxa" => writer.taxa(header, records)?,
+            _ => writer.observations(header, records)?,
+        }
+
+        Ok(count)
+    }
+}
+
+fn raw_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join(".raw")
+}
+
+// The response headers, sorted by day and time.
+fn raw_responses(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let days = match read_dir(dir) {
+        Ok(days) => days,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut paths = vec![];
+    for day in days {
+        let day = day?.path();
+        if !day.is_dir() {
+            continue;
+        }
+        for entry in read_dir(&day)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "yaml") {
+                paths.push(path);
+            }
+        }
+    }
+    paths.sort();
+
+    Ok(paths)
+}
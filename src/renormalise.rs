@@ -0,0 +1,175 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{metadata, read},
+    path::Path,
+};
+
+use chrono::{DateTime, Utc};
+use reqwest::header::DATE;
+use serde_json::Value as JsonValue;
+use serde_yaml::{Mapping as YamlMapping, Value as YamlValue};
+use tracing::warn;
+
+use crate::{
+    api::{expect_results, extract_id, parse_response},
+    archive::{Archive, Record},
+    error::{bad_record, Error},
+    normalise::{Reference, Writer, REFERENCES, TABLES},
+};
+
+type Tables = HashMap<&'static str, BTreeMap<u64, Record>>;
+
+impl Archive {
+    // Re-runs the normaliser over the cached observations, with everything they reference put
+    // back in place, as if they were fetched again.
+    pub fn normalise(&self) -> Result<usize, Error> {
+        let mut tables = Tables::new();
+        for table in TABLES {
+            tables.insert(table, self.table(table)?);
+        }
+
+        // Observations fetched together share their header, normalise them together too.
+        let mut groups: BTreeMap<String, (YamlMapping, HashMap<u64, Record>)> = BTreeMap::new();
+        for id in tables["observations"].keys() {
+            let header = match self.store.get("observations", *id)? {
+                Some((header, _)) => header,
+                _ => continue,
+            };
+            match hydrate(&tables, "observations", *id, None) {
+                Ok(JsonValue::Object(obs)) => {
+                    groups
+                        .entry(serde_yaml::to_string(&header)?)
+                        .or_insert_with(|| (header, HashMap::new()))
+                        .1
+                        .insert(*id, obs);
+                }
+                Ok(_) => {}
+                Err(err) => warn!("observation {}: {}; skipping", id, err),
+            }
+        }
+
+        let mut writer = Writer::new(self.store.clone()).extract_with(self.extractors.clone());
+        let mut count = 0;
+        for (header, observations) in groups.into_values() {
+            count += observations.len();
+            writer.observations(header, observations)?;
+        }
+        self.store.compact()?;
+
+        Ok(count)
+    }
+
+    // Normalises /observations API responses saved to disk, dated by their modification time.
+    pub fn normalise_responses<P: AsRef<Path>>(&self, paths: &[P]) -> Result<usize, Error> {
+        let mut writer = Writer::new(self.store.clone()).extract_with(self.extractors.clone());
+        let mut count = 0;
+        for path in paths {
+            let path = path.as_ref();
+            let date: DateTime<Utc> = metadata(path)?.modified()?.into();
+            let mut header = YamlMapping::new();
+            header.insert(
+                YamlValue::String(DATE.to_string()),
+                YamlValue::String(date.to_rfc3339()),
+            );
+
+            let observations = expect_results(parse_response(&read(path)?)?)?
+                .into_iter()
+                .map(|obs| extract_id(&obs).map(|id| (id, obs)))
+                .collect::<Result<HashMap<_, _>, _>>()?;
+            count += observations.len();
+            writer.observations(header, observations)?;
+        }
+        self.store.compact()?;
+
+        Ok(count)
+    }
+}
+
+// The record with its references replaced by the records themselves, the way the API nests them.
+// Records reached through a self-reference (e.g. ancestors) keep theirs as IDs, like the API does.
+fn hydrate(
+    tables: &Tables,
+    table: &str,
+    id: u64,
+    via: Option<&Reference>,
+) -> Result<JsonValue, Error> {
+    let mut record = tables[table]
+        .get(&id)
+        .ok_or_else(|| Error::NotFound(format!("{} {}", table, id)))?
+        .clone();
+
+    for reference in REFERENCES.iter().filter(|r| r.table == table) {
+        if via.is_some_and(|via| via.table == via.target && via.key == reference.key) {
+            continue;
+        }
+        let val = match record.get(reference.key) {
+            Some(val) if !val.is_null() => val.clone(),
+            _ => continue,
+        };
+        let error = |problem| bad_record(Some(id), reference.key, problem).in_table(table);
+        let hydrated = match reference.many {
+            true => JsonValue::Array(
+                val.as_array()
+                    .ok_or_else(|| error("not an array"))?
+                    .iter()
+                    .map(|item| match item {
+                        // Kept in place by older versions, e.g. annotations.
+                        JsonValue::Object(_) => Ok(item.clone()),
+                        _ => item
+                            .as_u64()
+                            .ok_or_else(|| error("item not an ID"))
+                            .and_then(|id| hydrate(tables, reference.target, id, Some(reference))),
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+            // Likewise, e.g. preferences.
+            _ if val.is_object() => val,
+            _ => hydrate(
+                tables,
+                reference.target,
+                val.as_u64().ok_or_else(|| error("not an ID"))?,
+                Some(reference),
+            )?,
+        };
+        record.insert(reference.key.to_string(), hydrated);
+    }
+
+    if table == "observations" {
+        hydrate_annotations(tables, &mut record)?;
+    }
+
+    Ok(JsonValue::Object(record))
+}
+
+// Annotations older versions kept in the observations, with only the references in them replaced
+// by IDs; those in their own table are hydrated along with the rest.
+fn hydrate_annotations(tables: &Tables, obs: &mut Record) -> Result<(), Error> {
+    let annotations = match obs.get_mut("annotations").and_then(JsonValue::as_array_mut) {
+        Some(annotations) => annotations,
+        _ => return Ok(()),
+    };
+
+    for annotation in annotations.iter_mut().filter_map(JsonValue::as_object_mut) {
+        for (key, target) in [
+            ("controlled_attribute", "controlled_terms"),
+            ("controlled_value", "controlled_terms"),
+            ("user", "users"),
+        ] {
+            if let Some(id) = annotation.get(key).and_then(JsonValue::as_u64) {
+                annotation.insert(key.to_string(), hydrate(tables, target, id, None)?);
+            }
+        }
+        if let Some(votes) = annotation.get("votes").and_then(JsonValue::as_array) {
+            let votes = votes
+                .iter()
+                .map(|vote| match vote.as_u64() {
+                    Some(id) => hydrate(tables, "votes", id, None),
+                    _ => Ok(vote.clone()),
+                })
+                .collect::<Result<_, _>>()?;
+            annotation.insert("votes".to_string(), JsonValue::Array(votes));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use crate::{
+    archive::{str_field, Archive, Record},
+    error::Error,
+};
+
+// Listed in the error when a query matches more than one observation.
+const CANDIDATES: usize = 10;
+
+impl Archive {
+    // The observation with this ID, or the only one matching all words of the query: dates (or
+    // date prefixes like 2023-05) against the day observed, anything else against taxon names.
+    pub fn resolve_observation(&self, query: &str) -> Result<u64, Error> {
+        if let Ok(id) = query.trim().parse() {
+            return Ok(id);
+        }
+
+        let (dates, words): (Vec<_>, Vec<_>) = query
+            .split_whitespace()
+            .map(str::to_lowercase)
+            .partition(|word| is_date(word));
+        let mut matches = vec![];
+        for (id, obs) in self.table("observations")? {
+            let title = self.observation_title(&obs)?;
+            let names = format!(
+                "{} {}",
+                title,
+                str_field(&obs, "species_guess").unwrap_or_default()
+            )
+            .to_lowercase();
+            let date = str_field(&obs, "observed_on").unwrap_or_default();
+            if dates.iter().all(|prefix| date.starts_with(prefix.as_str()))
+                && words.iter().all(|word| names.contains(word.as_str()))
+            {
+                matches.push((id, format!("{} {} {}", id, date, title)));
+            }
+        }
+
+        match &matches[..] {
+            [] => Err(Error::NotFound(format!("observation matching {:?}", query))),
+            [(id, _)] => Ok(*id),
+            _ => {
+                let candidates: Vec<_> = matches
+                    .iter()
+                    .take(CANDIDATES)
+                    .map(|(_, row)| row.as_str())
+                    .collect();
+                Err(Error::Ambiguous(format!(
+                    "{} observations match {:?}: {}",
+                    matches.len(),
+                    query,
+                    candidates.join("; ")
+                )))
+            }
+        }
+    }
+
+    // Link to the observation on the iNaturalist website.
+    pub fn observation_url(&self, id: u64) -> Result<String, Error> {
+        Ok(match self.record("observations", id)? {
+            Some(obs) => url(id, &obs),
+            _ => format!("https://www.inaturalist.org/observations/{}", id),
+        })
+    }
+}
+
+pub(crate) fn url(id: u64, obs: &Record) -> String {
+    str_field(obs, "uri")
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("https://www.inaturalist.org/observations/{}", id))
+}
+
+fn is_date(word: &str) -> bool {
+    word.len() >= 4
+        && word.starts_with(|c: char| c.is_ascii_digit())
+        && word.chars().all(|c| c.is_ascii_digit() || c == '-')
+}
@@ -0,0 +1,108 @@
+use std::{fs::read_to_string, path::Path};
+
+use serde::Deserialize;
+
+use crate::{
+    api::ID,
+    error::Error,
+    extractor::{Batch, TableExtractor},
+    normalise::TABLES,
+};
+
+// Extraction rules read from a YAML file, for nested records the built-in passes don't know
+// about (yet), without waiting for a release; e.g. the outlinks of observations into a table of
+// their own:
+//
+//   - from: observations
+//     key: outlinks
+//     into: outlinks
+//     many: true
+//     id: source_id
+//
+// Rules run in order, so that later ones can extract from the tables earlier ones filled.
+#[derive(Clone, Debug)]
+pub struct ExtractionRules {
+    rules: Vec<ExtractionRule>,
+    // Those of the rules' tables that are not built in.
+    tables: Vec<&'static str>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[non_exhaustive]
+pub struct ExtractionRule {
+    // The table whose records have the key.
+    pub from: String,
+    pub key: String,
+    // The table to move the records to.
+    pub into: String,
+    // An array of records, rather than a single one.
+    #[serde(default)]
+    pub many: bool,
+    // The field the records are identified by.
+    #[serde(default = "default_id")]
+    pub id: String,
+}
+
+impl ExtractionRules {
+    pub fn new(rules: Vec<ExtractionRule>) -> Self {
+        let mut tables: Vec<&'static str> = vec![];
+        for rule in &rules {
+            let table = rule.into.as_str();
+            if !TABLES.contains(&table) && !tables.contains(&table) {
+                // Tables are named for as long as the extractor runs, i.e. the whole process.
+                tables.push(Box::leak(table.to_string().into_boxed_str()));
+            }
+        }
+
+        Self { rules, tables }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let rules = serde_yaml::from_str(&read_to_string(path)?)
+            .map_err(|err| Error::BadFile(path.to_path_buf(), err.to_string()))?;
+
+        Ok(Self::new(rules))
+    }
+}
+
+impl ExtractionRule {
+    pub fn new(from: &str, key: &str, into: &str) -> Self {
+        Self {
+            from: from.to_string(),
+            key: key.to_string(),
+            into: into.to_string(),
+            many: false,
+            id: default_id(),
+        }
+    }
+
+    pub fn many(mut self, many: bool) -> Self {
+        self.many = many;
+        self
+    }
+
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = id.to_string();
+        self
+    }
+}
+
+impl TableExtractor for ExtractionRules {
+    fn tables(&self) -> &[&'static str] {
+        &self.tables
+    }
+
+    fn extract(&self, batch: &mut Batch) -> Result<(), Error> {
+        for rule in &self.rules {
+            batch.move_records(&rule.from, &rule.key, &rule.into, &rule.id, Some(rule.many))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn default_id() -> String {
+    ID.to_string()
+}
@@ -0,0 +1,153 @@
+use chrono::{DateTime, FixedOffset};
+use serde_json::{json, Map as JsonMap, Value as JsonValue};
+
+use crate::{
+    api::ID,
+    archive::Archive,
+    error::Error,
+    models::{self, Dimensions, Model, Ref},
+    normalise::{REFERENCES, TABLES},
+};
+
+const DRAFT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+impl Archive {
+    pub fn tables() -> &'static [&'static str] {
+        TABLES
+    }
+
+    // JSON Schema of the records (the second YAML document) of a table.
+    // Fields come from the typed models and references from the normaliser. Other keys are kept
+    // as the API sent them, so these are allowed too.
+    pub fn schema(table: &str) -> Result<JsonValue, Error> {
+        if !TABLES.contains(&table) {
+            return Err(Error::NotFound(format!("table {}", table)));
+        }
+
+        let mut properties = JsonMap::new();
+        let mut required = vec![ID];
+        for field in models::fields(table).unwrap_or_default() {
+            properties.insert(field.name.to_string(), field.schema);
+            if !field.optional && field.name != ID {
+                required.push(field.name);
+            }
+        }
+        properties.insert(ID.to_string(), json!({ "type": "integer", "minimum": 1 }));
+        for reference in REFERENCES.iter().filter(|r| r.table == table) {
+            let id = reference_schema(reference.target);
+            properties
+                .entry(reference.key)
+                .or_insert_with(|| match reference.many {
+                    true => json!({ "type": "array", "items": id }),
+                    false => nullable(id),
+                });
+        }
+
+        Ok(json!({
+            "$schema": DRAFT,
+            "$id": format!("{}.schema.json", table),
+            "title": table,
+            "description": format!("Body of {}/<id>.yaml, after the cache header.", table),
+            "type": "object",
+            "required": required,
+            "properties": properties,
+        }))
+    }
+}
+
+pub(crate) struct Field {
+    pub(crate) name: &'static str,
+    pub(crate) schema: JsonValue,
+    // Left out of the records when missing or empty.
+    pub(crate) optional: bool,
+}
+
+// The fields of a model, for its schema; listed by the model! macro.
+pub(crate) trait Fields {
+    fn fields() -> Vec<Field>;
+}
+
+// JSON Schema of a field's type, as it is cached: records other tables hold by their ID.
+pub(crate) trait Describe {
+    const OPTIONAL: bool = false;
+
+    fn schema() -> JsonValue;
+}
+
+// The type comes from the accessor, so the schema can't drift from the model.
+pub(crate) fn field<M, T: Describe>(name: &'static str, _: fn(&M) -> &T) -> Field {
+    Field {
+        name,
+        schema: T::schema(),
+        optional: T::OPTIONAL,
+    }
+}
+
+fn reference_schema(target: &str) -> JsonValue {
+    json!({
+        "type": "integer",
+        "minimum": 1,
+        "description": format!("ID of a record in {}", target),
+        "x-references": target,
+    })
+}
+
+fn nullable(mut schema: JsonValue) -> JsonValue {
+    match schema.get("type").cloned() {
+        Some(JsonValue::String(kind)) => {
+            schema["type"] = json!([kind, "null"]);
+            schema
+        }
+        _ => json!({ "anyOf": [schema, { "type": "null" }] }),
+    }
+}
+
+macro_rules! describe {
+    ($($type:ty => $schema:tt),*) => {
+        $(
+            impl Describe for $type {
+                fn schema() -> JsonValue {
+                    json!($schema)
+                }
+            }
+        )*
+    };
+}
+
+describe!(
+    bool => { "type": "boolean" },
+    f64 => { "type": "number" },
+    u64 => { "type": "integer", "minimum": 0 },
+    String => { "type": "string" },
+    DateTime<FixedOffset> => { "type": "string", "format": "date-time" },
+    Dimensions => {
+        "type": "object",
+        "required": ["width", "height"],
+        "properties": {
+            "width": { "type": "integer", "minimum": 0 },
+            "height": { "type": "integer", "minimum": 0 },
+        },
+    }
+);
+
+impl<T: Describe> Describe for Option<T> {
+    const OPTIONAL: bool = true;
+
+    fn schema() -> JsonValue {
+        nullable(T::schema())
+    }
+}
+
+impl<T: Describe> Describe for Vec<T> {
+    const OPTIONAL: bool = true;
+
+    fn schema() -> JsonValue {
+        json!({ "type": "array", "items": T::schema() })
+    }
+}
+
+impl<T: Model> Describe for Ref<T> {
+    fn schema() -> JsonValue {
+        reference_schema(T::TABLE)
+    }
+}
@@ -0,0 +1,79 @@
+use std::{fmt::Display, fs::read_to_string, mem::take, path::Path};
+
+use rhai::{
+    serde::{from_dynamic, to_dynamic},
+    Dynamic, Engine, ParseError, Scope, AST,
+};
+
+use crate::{
+    archive::Record,
+    error::Error,
+    extractor::{Batch, TableExtractor},
+};
+
+// A Rhai script run on every record before it's written, e.g. to drop noisy fields, rename keys
+// or compute derived ones. It sees the record as `record`, to change in place, along with its
+// `table` and `id`:
+//
+//   if table == "observations" {
+//       record.remove("preferences");
+//       record.photo_count = record.photos?.len() ?? 0;
+//   }
+//
+// Records referred to by others are already their own by then, with only their IDs left behind.
+#[derive(Debug)]
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+}
+
+impl Script {
+    pub fn new(source: &str) -> Result<Self, Error> {
+        Self::compile(source).map_err(|err| Error::Script(err.to_string()))
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        Self::compile(&read_to_string(path)?)
+            .map_err(|err| Error::Script(format!("{}: {}", path.display(), err)))
+    }
+
+    fn compile(source: &str) -> Result<Self, ParseError> {
+        let engine = Engine::new();
+        let ast = engine.compile(source)?;
+
+        Ok(Self { engine, ast })
+    }
+
+    fn run(&self, table: &str, id: u64, record: Record) -> Result<Record, Error> {
+        let error = |err: &dyn Display| Error::Script(format!("{} {}: {}", table, id, err));
+
+        let mut scope = Scope::new();
+        scope.push_constant("table", table.to_string());
+        scope.push_constant("id", id as i64);
+        scope.push("record", to_dynamic(record).map_err(|err| error(&err))?);
+        self.engine
+            .run_ast_with_scope(&mut scope, &self.ast)
+            .map_err(|err| error(&err))?;
+
+        let record = scope.get_value::<Dynamic>("record").unwrap_or_default();
+        if !record.is_map() {
+            return Err(error(&"record is not a map anymore"));
+        }
+        from_dynamic(&record).map_err(|err| error(&err))
+    }
+}
+
+impl TableExtractor for Script {
+    fn extract(&self, batch: &mut Batch) -> Result<(), Error> {
+        for table in batch.tables() {
+            if let Some(records) = batch.table(table) {
+                for (id, record) in records.iter_mut() {
+                    *record = self.run(table, *id, take(record))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
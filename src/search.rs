@@ -0,0 +1,162 @@
+use std::{
+    fs::{create_dir_all, remove_dir_all},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+};
+
+use tantivy::{
+    collector::TopDocs,
+    query::QueryParser,
+    schema::{
+        Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, Value, INDEXED, STORED,
+    },
+    tokenizer::{AsciiFoldingFilter, Language, LowerCaser, SimpleTokenizer, Stemmer, TextAnalyzer},
+    Index, IndexWriter, TantivyDocument,
+};
+
+use crate::{
+    archive::{id_field, ids, str_field, Archive},
+    error::Error,
+};
+
+const TOKENIZER: &str = "folded";
+
+// NOTE: Tantivy wants at least 15MB per indexing thread.
+const WRITER_MEMORY: usize = 50_000_000;
+
+struct Fields {
+    id: Field,
+    description: Field,
+    comments: Field,
+    place: Field,
+    taxon: Field,
+}
+
+impl Archive {
+    // Rebuilds the full-text index of all observations from scratch.
+    pub fn index(&self) -> Result<usize, Error> {
+        let path = self.index_path();
+        match remove_dir_all(&path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        create_dir_all(&path)?;
+
+        let index = Index::create_in_dir(&path, schema())?;
+        let fields = fields(&index.schema())?;
+        register_tokenizer(&index);
+
+        let comments = self.table("comments")?;
+        let taxa = self.table("taxa")?;
+        let mut writer: IndexWriter = index.writer(WRITER_MEMORY)?;
+        let observations = self.table("observations")?;
+        for (id, obs) in &observations {
+            let mut doc = TantivyDocument::new();
+            doc.add_u64(fields.id, *id);
+            if let Some(description) = str_field(obs, "description") {
+                doc.add_text(fields.description, description);
+            }
+            for comment in ids(obs, "comments")
+                .iter()
+                .filter_map(|id| comments.get(id))
+                .filter_map(|comment| str_field(comment, "body"))
+            {
+                doc.add_text(fields.comments, comment);
+            }
+            if let Some(place) = str_field(obs, "place_guess") {
+                doc.add_text(fields.place, place);
+            }
+            if let Some(guess) = str_field(obs, "species_guess") {
+                doc.add_text(fields.taxon, guess);
+            }
+            if let Some(taxon) = id_field(obs, "taxon").and_then(|id| taxa.get(&id)) {
+                for key in ["name", "preferred_common_name"] {
+                    if let Some(name) = str_field(taxon, key) {
+                        doc.add_text(fields.taxon, name);
+                    }
+                }
+            }
+            writer.add_document(doc)?;
+        }
+        writer.commit()?;
+
+        Ok(observations.len())
+    }
+
+    // Queries use Tantivy's syntax, e.g. "taxon:mallard AND place:budapest".
+    pub fn search<W: Write>(&self, out: &mut W, query: &str, limit: usize) -> Result<(), Error> {
+        let index = Index::open_in_dir(self.index_path())?;
+        register_tokenizer(&index);
+        let fields = fields(&index.schema())?;
+
+        let searcher = index.reader()?.searcher();
+        let parser = QueryParser::for_index(
+            &index,
+            vec![
+                fields.description,
+                fields.comments,
+                fields.place,
+                fields.taxon,
+            ],
+        );
+        for (score, address) in
+            searcher.search(&parser.parse_query(query)?, &TopDocs::with_limit(limit))?
+        {
+            let doc: TantivyDocument = searcher.doc(address)?;
+            let id = match doc.get_first(fields.id).and_then(|id| id.as_u64()) {
+                Some(id) => id,
+                _ => continue,
+            };
+            let title = match self.record("observations", id)? {
+                Some(obs) => self.observation_title(&obs)?,
+                // Deleted since the index was built.
+                _ => continue,
+            };
+            writeln!(out, "{:.2}  {}  {}", score, id, title)?;
+        }
+
+        Ok(())
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.path(".index")
+    }
+}
+
+fn schema() -> Schema {
+    let text = TextOptions::default().set_indexing_options(
+        TextFieldIndexing::default()
+            .set_tokenizer(TOKENIZER)
+            .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+    );
+
+    let mut schema = Schema::builder();
+    schema.add_u64_field("id", INDEXED | STORED);
+    schema.add_text_field("description", text.clone());
+    schema.add_text_field("comments", text.clone());
+    schema.add_text_field("place", text.clone());
+    schema.add_text_field("taxon", text);
+    schema.build()
+}
+
+fn fields(schema: &Schema) -> Result<Fields, Error> {
+    Ok(Fields {
+        id: schema.get_field("id")?,
+        description: schema.get_field("description")?,
+        comments: schema.get_field("comments")?,
+        place: schema.get_field("place")?,
+        taxon: schema.get_field("taxon")?,
+    })
+}
+
+// Case and diacritics insensitive, with English stemming.
+fn register_tokenizer(index: &Index) {
+    index.tokenizers().register(
+        TOKENIZER,
+        TextAnalyzer::builder(SimpleTokenizer::default())
+            .filter(LowerCaser)
+            .filter(AsciiFoldingFilter)
+            .filter(Stemmer::new(Language::English))
+            .build(),
+    );
+}
@@ -0,0 +1,148 @@
+use std::{collections::BTreeSet, io::Write, iter::once};
+
+use itertools::Itertools;
+use rusqlite::{params_from_iter, types::Value as SqlValue, Connection};
+use serde_json::{Map as JsonMap, Value as JsonValue};
+
+use crate::{
+    api::ID,
+    archive::Archive,
+    error::Error,
+    normalise::TABLES,
+    query::{write_rows, QueryFormat},
+};
+
+impl Archive {
+    // Runs the query against an in-memory SQLite database loaded with the tables it mentions.
+    // Columns are the top level keys of the records; lists and objects are stored as JSON.
+    pub fn sql<W: Write>(
+        &self,
+        out: &mut W,
+        query: &str,
+        format: QueryFormat,
+    ) -> Result<(), Error> {
+        let db = Connection::open_in_memory()?;
+        let words: BTreeSet<_> = query
+            .split(|c: char| !c.is_alphanumeric() && c != '_')
+            .map(str::to_lowercase)
+            .collect();
+        for table in TABLES.iter().filter(|table| words.contains(**table)) {
+            self.load_table(&db, table)?;
+        }
+
+        let mut stmt = db.prepare(query)?;
+        let columns: Vec<_> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+        let mut rows = stmt.query([])?;
+        let mut results = vec![];
+        while let Some(row) = rows.next()? {
+            results.push(
+                (0..columns.len())
+                    .map(|i| row.get::<_, SqlValue>(i))
+                    .collect::<Result<Vec<_>, _>>()?,
+            );
+        }
+
+        match format {
+            QueryFormat::Json => {
+                let records: Vec<JsonMap<String, JsonValue>> = results
+                    .into_iter()
+                    .map(|row| {
+                        columns
+                            .iter()
+                            .cloned()
+                            .zip(row.into_iter().map(to_json))
+                            .collect()
+                    })
+                    .collect();
+                serde_json::to_writer_pretty(&mut *out, &records)?;
+                writeln!(out)?;
+                Ok(())
+            }
+            QueryFormat::Table => {
+                let mut rows = vec![columns];
+                rows.extend(
+                    results
+                        .into_iter()
+                        .map(|row| row.into_iter().map(to_text).collect()),
+                );
+                write_rows(out, &rows)
+            }
+        }
+    }
+
+    fn load_table(&self, db: &Connection, table: &str) -> Result<(), Error> {
+        let records = self.table(table)?;
+        let mut columns = BTreeSet::new();
+        for record in records.values() {
+            columns.extend(record.keys().filter(|key| *key != ID).cloned());
+        }
+        let columns: Vec<_> = columns.into_iter().collect();
+
+        db.execute_batch(&format!(
+            "CREATE TABLE {} ({} INTEGER PRIMARY KEY{});",
+            quote(table),
+            ID,
+            columns
+                .iter()
+                .map(|col| format!(", {}", quote(col)))
+                .join(""),
+        ))?;
+
+        let tx = db.unchecked_transaction()?;
+        let mut insert = tx.prepare(&format!(
+            "INSERT INTO {} VALUES (?{})",
+            quote(table),
+            ", ?".repeat(columns.len()),
+        ))?;
+        for (id, record) in &records {
+            let values = once(SqlValue::Integer(*id as i64)).chain(
+                columns
+                    .iter()
+                    .map(|col| from_json(record.get(col).unwrap_or(&JsonValue::Null))),
+            );
+            insert.execute(params_from_iter(values))?;
+        }
+        drop(insert);
+        tx.commit()?;
+
+        Ok(())
+    }
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn from_json(val: &JsonValue) -> SqlValue {
+    match val {
+        JsonValue::Null => SqlValue::Null,
+        JsonValue::Bool(b) => SqlValue::Integer(*b as i64),
+        JsonValue::Number(n) => match n.as_i64() {
+            Some(i) => SqlValue::Integer(i),
+            _ => SqlValue::Real(n.as_f64().unwrap_or_default()),
+        },
+        JsonValue::String(s) => SqlValue::Text(s.to_string()),
+        // Still queryable with json_extract() and friends.
+        val => SqlValue::Text(val.to_string()),
+    }
+}
+
+fn to_json(val: SqlValue) -> JsonValue {
+    match val {
+        SqlValue::Null => JsonValue::Null,
+        SqlValue::Integer(i) => i.into(),
+        SqlValue::Real(f) => f.into(),
+        SqlValue::Text(s) => s.into(),
+        SqlValue::Blob(b) => String::from_utf8_lossy(&b).into(),
+    }
+}
+
+fn to_text(val: SqlValue) -> String {
+    match val {
+        SqlValue::Null => String::new(),
+        SqlValue::Integer(i) => i.to_string(),
+        SqlValue::Real(f) => f.to_string(),
+        SqlValue::Text(s) => s,
+        SqlValue::Blob(b) => String::from_utf8_lossy(&b).to_string(),
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    io::Write,
+};
+
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+
+use crate::{
+    archive::{id_field, ids, str_field, taxon_name, Archive, Record},
+    error::Error,
+    normalise::TABLES,
+    query::write_rows,
+};
+
+const SPECIES: &str = "species";
+
+// NOTE: Taxa below species (subspecies, varieties, etc.) have a lower rank level.
+const SPECIES_RANK_LEVEL: f64 = 10.0;
+
+#[derive(Debug, Default, Serialize)]
+#[non_exhaustive]
+pub struct Stats {
+    // Records per table.
+    pub tables: BTreeMap<String, usize>,
+    pub observations: usize,
+    // Distinct species observed, with infraspecific taxa counted as their species.
+    pub species: usize,
+    pub per_year: BTreeMap<String, usize>,
+    pub per_month: BTreeMap<String, usize>,
+    pub quality_grades: BTreeMap<String, usize>,
+    pub top_taxa: Vec<TaxonCount>,
+}
+
+#[derive(Debug, Serialize)]
+#[non_exhaustive]
+pub struct TaxonCount {
+    pub id: u64,
+    pub name: String,
+    pub observations: usize,
+}
+
+impl Archive {
+    pub fn stats(&self, top: usize) -> Result<Stats, Error> {
+        let mut stats = Stats::default();
+        for table in TABLES {
+            stats
+                .tables
+                .insert(table.to_string(), self.table(table)?.len());
+        }
+
+        let observations = self.table("observations")?;
+        let taxa = self.table("taxa")?;
+        let mut species = BTreeSet::new();
+        let mut counts: BTreeMap<u64, usize> = BTreeMap::new();
+        stats.observations = observations.len();
+        for obs in observations.values() {
+            if let Some(date) = str_field(obs, "observed_on") {
+                if let (Some(year), Some(month)) = (date.get(..4), date.get(..7)) {
+                    *stats.per_year.entry(year.to_string()).or_default() += 1;
+                    *stats.per_month.entry(month.to_string()).or_default() += 1;
+                }
+            }
+            let grade = str_field(obs, "quality_grade").unwrap_or("unknown");
+            *stats.quality_grades.entry(grade.to_string()).or_default() += 1;
+
+            if let Some(id) = id_field(obs, "taxon") {
+                *counts.entry(id).or_default() += 1;
+                if let Some(id) = taxa.get(&id).and_then(|taxon| species_of(&taxa, id, taxon)) {
+                    species.insert(id);
+                }
+            }
+        }
+        stats.species = species.len();
+
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by_key(|(id, count)| (Reverse(*count), *id));
+        stats.top_taxa = counts
+            .into_iter()
+            .take(top)
+            .map(|(id, observations)| TaxonCount {
+                id,
+                name: taxa
+                    .get(&id)
+                    .map(taxon_name)
+                    .unwrap_or_else(|| id.to_string()),
+                observations,
+            })
+            .collect();
+
+        Ok(stats)
+    }
+}
+
+impl Stats {
+    pub fn write_table<W: Write>(&self, out: &mut W) -> Result<(), Error> {
+        let mut rows = vec![];
+        let mut section = |title: &str, entries: Vec<(String, usize)>| {
+            rows.push(vec![title.to_string()]);
+            rows.extend(
+                entries
+                    .into_iter()
+                    .map(|(key, count)| vec![format!("  {}", key), count.to_string()]),
+            );
+        };
+
+        section(
+            "Observations",
+            vec![
+                ("total".to_string(), self.observations),
+                ("species".to_string(), self.species),
+            ],
+        );
+        section("Quality grades", clone_entries(&self.quality_grades));
+        section("Per year", clone_entries(&self.per_year));
+        section("Per month", clone_entries(&self.per_month));
+        section(
+            "Top taxa",
+            self.top_taxa
+                .iter()
+                .map(|taxon| (taxon.name.to_string(), taxon.observations))
+                .collect(),
+        );
+        section("Tables", clone_entries(&self.tables));
+
+        write_rows(out, &rows)
+    }
+}
+
+fn clone_entries(map: &BTreeMap<String, usize>) -> Vec<(String, usize)> {
+    map.iter()
+        .map(|(key, count)| (key.to_string(), *count))
+        .collect()
+}
+
+// The species a taxon belongs to, None above species level.
+fn species_of(taxa: &BTreeMap<u64, Record>, id: u64, taxon: &Record) -> Option<u64> {
+    if str_field(taxon, "rank") == Some(SPECIES) {
+        return Some(id);
+    }
+    if taxon.get("rank_level").and_then(JsonValue::as_f64)? >= SPECIES_RANK_LEVEL {
+        return None;
+    }
+
+    ids(taxon, "ancestor_ids")
+        .into_iter()
+        .find(|id| taxa.get(id).and_then(|t| str_field(t, "rank")) == Some(SPECIES))
+}
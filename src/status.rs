@@ -0,0 +1,77 @@
+use std::{io::Write, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{archive::Archive, error::Error, normalise::TABLES, query::write_rows};
+
+#[derive(Debug, Default, Serialize)]
+#[non_exhaustive]
+pub struct Status {
+    pub tables: Vec<TableStatus>,
+}
+
+#[derive(Debug, Serialize)]
+#[non_exhaustive]
+pub struct TableStatus {
+    pub table: String,
+    pub records: usize,
+    // Sync dates of the least and most recently synced records, from their cache headers.
+    pub oldest: Option<DateTime<Utc>>,
+    pub newest: Option<DateTime<Utc>>,
+    // Records synced longer than the max age ago.
+    pub stale: usize,
+}
+
+impl Archive {
+    pub fn status(&self, max_age: Duration) -> Result<Status, Error> {
+        let cutoff = self.store.now() - max_age;
+        let mut status = Status::default();
+        for table in TABLES {
+            let entries = self.store.entries(table)?;
+            let dates: Vec<_> = entries
+                .values()
+                .filter_map(|(header, _)| header.get("date")?.as_str())
+                .filter_map(|date| DateTime::parse_from_rfc3339(date).ok())
+                .map(|date| date.with_timezone(&Utc))
+                .collect();
+            status.tables.push(TableStatus {
+                table: table.to_string(),
+                records: entries.len(),
+                oldest: dates.iter().min().copied(),
+                newest: dates.iter().max().copied(),
+                // Records without a date can't be fresh either.
+                stale: entries.len() - dates.iter().filter(|date| **date >= cutoff).count(),
+            });
+        }
+
+        Ok(status)
+    }
+}
+
+impl Status {
+    pub fn is_stale(&self) -> bool {
+        self.tables.iter().any(|table| table.stale > 0)
+    }
+
+    pub fn write_table<W: Write>(&self, out: &mut W) -> Result<(), Error> {
+        let date = |date: Option<DateTime<Utc>>| {
+            date.map(|date| date.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default()
+        };
+        let mut rows = vec![["table", "records", "oldest", "newest", "stale"]
+            .map(str::to_string)
+            .to_vec()];
+        for table in self.tables.iter().filter(|table| table.records > 0) {
+            rows.push(vec![
+                table.table.to_string(),
+                table.records.to_string(),
+                date(table.oldest),
+                date(table.newest),
+                table.stale.to_string(),
+            ]);
+        }
+
+        write_rows(out, &rows)
+    }
+}
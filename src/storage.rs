@@ -0,0 +1,323 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    fs::{self, create_dir_all, read_dir, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use sha2::{Digest, Sha256};
+use tracing::{debug, info};
+
+use crate::{
+    durable::{self, sync_file},
+    error::Error,
+    normalise::TABLES,
+};
+
+// Where the archive lives besides the data directory, e.g. an S3 bucket. Keys are paths relative
+// to the data directory, with forward slashes.
+//
+// It's a replica, not a replacement: the data directory always holds a full copy, restored from
+// the storage where missing. The calls block; syncs make them off the async runtime, but building
+// an Api or an Archive with a storage restores right away, so do that through spawn_blocking.
+pub trait Storage: Debug + Send + Sync {
+    // The object and its etag, None if there is no such object.
+    fn get(&self, key: &str) -> Result<Option<(Vec<u8>, String)>, Error>;
+
+    // Fails with Error::Conflict unless the precondition holds; returns the new etag.
+    fn put(&self, key: &str, data: &[u8], precondition: Precondition) -> Result<String, Error>;
+
+    // Succeeds for missing objects too.
+    fn delete(&self, key: &str) -> Result<(), Error>;
+
+    // All keys, with their etags.
+    fn list(&self) -> Result<Vec<(String, String)>, Error>;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Precondition {
+    None,
+    // Only if there is no such object yet.
+    Absent,
+    // Only if the object still has this etag, i.e. nobody else wrote it since.
+    Matches(String),
+}
+
+// The data directory kept in sync with the storage: whatever the store writes there is written
+// through, and whatever is missing locally is restored when opened. The index remembers what
+// the storage holds, so that unchanged files aren't uploaded again and writes can be conditional
+// without asking the storage first.
+#[derive(Debug)]
+pub(crate) struct Replica {
+    storage: Arc<dyn Storage>,
+    data_dir: PathBuf,
+    index: Mutex<BTreeMap<String, IndexEntry>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct IndexEntry {
+    etag: String,
+    sha256: String,
+}
+
+impl Replica {
+    pub(crate) fn open(data_dir: &Path, storage: Arc<dyn Storage>) -> Result<Self, Error> {
+        Ok(Self {
+            storage,
+            data_dir: data_dir.to_path_buf(),
+            index: Mutex::new(read_index(&index_path(data_dir))?),
+        })
+    }
+
+    // Downloads the objects that are missing locally, or changed since last seen; forgets those
+    // deleted meanwhile.
+    pub(crate) fn restore(&self, durable: bool) -> Result<(), Error> {
+        let remote = self.storage.list()?;
+        let mut index = self.index.lock().expect("replica poisoned");
+        let mut restored = 0;
+        for (key, etag) in &remote {
+            let path = self.path(key);
+            if index.get(key).is_some_and(|entry| &entry.etag == etag) && path.exists() {
+                continue;
+            }
+            let (data, etag) = match self.storage.get(key)? {
+                Some(object) => object,
+                // Deleted since listed.
+                _ => continue,
+            };
+            if let Some(dir) = path.parent() {
+                create_dir_all(dir)?;
+            }
+            durable::write_file(&path, durable, |out| {
+                out.extend_from_slice(&data);
+                Ok(())
+            })?;
+            index.insert(
+                key.clone(),
+                IndexEntry {
+                    etag,
+                    sha256: sha256(&data),
+                },
+            );
+            restored += 1;
+        }
+        let keys: BTreeMap<_, _> = remote.into_iter().collect();
+        index.retain(|key, _| keys.contains_key(key));
+        if restored > 0 {
+            info!("restored {} files from storage", restored);
+        }
+
+        write_index(&index_path(&self.data_dir), &index, durable)
+    }
+
+    // Uploads the file as it is now, or deletes the object if there is no file.
+    pub(crate) fn push(&self, path: &Path, durable: bool) -> Result<(), Error> {
+        let key = match self.key(path) {
+            Some(key) => key,
+            _ => return Ok(()),
+        };
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => return self.delete(key, durable),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut index = self.index.lock().expect("replica poisoned");
+        let sha256 = sha256(&data);
+        let precondition = match index.get(&key) {
+            Some(entry) if entry.sha256 == sha256 => return Ok(()),
+            Some(entry) => Precondition::Matches(entry.etag.clone()),
+            _ => Precondition::Absent,
+        };
+        debug!("uploading {}", key);
+        let entry = IndexEntry {
+            etag: self.storage.put(&key, &data, precondition)?,
+            sha256,
+        };
+        append_index(&index_path(&self.data_dir), &key, Some(&entry), durable)?;
+        index.insert(key, entry);
+
+        Ok(())
+    }
+
+    // Pushes all the files the store owns, and deletes the objects of those gone, e.g. after
+    // converting to another layout.
+    pub(crate) fn reconcile(&self, durable: bool) -> Result<(), Error> {
+        let mut paths = vec![layout_path(&self.data_dir)];
+        for table in TABLES {
+            let file = self.data_dir.join(format!("{}.yaml", table));
+            paths.push(file.with_extension("yaml.zst"));
+            paths.push(file);
+            files(&self.data_dir.join(table), &mut paths)?;
+        }
+        for path in &paths {
+            self.push(path, durable)?;
+        }
+
+        let owned: Vec<String> = paths.iter().filter_map(|path| self.key(path)).collect();
+        let gone: Vec<String> = self
+            .index
+            .lock()
+            .expect("replica poisoned")
+            .keys()
+            .filter(|key| is_owned(key) && !owned.contains(key))
+            .cloned()
+            .collect();
+        for key in gone {
+            self.delete(key, durable)?;
+        }
+
+        Ok(())
+    }
+
+    fn delete(&self, key: String, durable: bool) -> Result<(), Error> {
+        let mut index = self.index.lock().expect("replica poisoned");
+        if index.remove(&key).is_none() {
+            return Ok(());
+        }
+        debug!("deleting {}", key);
+        self.storage.delete(&key)?;
+
+        append_index(&index_path(&self.data_dir), &key, None, durable)
+    }
+
+    fn key(&self, path: &Path) -> Option<String> {
+        let rel = path.strip_prefix(&self.data_dir).ok()?;
+        let parts: Option<Vec<&str>> = rel.components().map(|c| c.as_os_str().to_str()).collect();
+        Some(parts?.join("/"))
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        key.split('/')
+            .fold(self.data_dir.clone(), |path, part| path.join(part))
+    }
+}
+
+// One line per change, "{key}\t{etag}\t{sha256}", or just the key once deleted; compacted when
+// restoring.
+fn read_index(path: &Path) -> Result<BTreeMap<String, IndexEntry>, Error> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut index = BTreeMap::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        match line.split('\t').collect::<Vec<_>>()[..] {
+            [key, etag, sha256] => index.insert(
+                key.to_string(),
+                IndexEntry {
+                    etag: etag.to_string(),
+                    sha256: sha256.to_string(),
+                },
+            ),
+            [key] => index.remove(key),
+            _ => None,
+        };
+    }
+
+    Ok(index)
+}
+
+fn write_index(
+    path: &Path,
+    index: &BTreeMap<String, IndexEntry>,
+    durable: bool,
+) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir)?;
+    }
+    durable::write_file(path, durable, |out| {
+        for (key, entry) in index {
+            writeln!(out, "{}\t{}\t{}", key, entry.etag, entry.sha256)?;
+        }
+        Ok(())
+    })
+}
+
+fn append_index(
+    path: &Path,
+    key: &str,
+    entry: Option<&IndexEntry>,
+    durable: bool,
+) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir)?;
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut out = BufWriter::new(&file);
+    match entry {
+        Some(entry) => writeln!(out, "{}\t{}\t{}", key, entry.etag, entry.sha256)?,
+        _ => writeln!(out, "{}", key)?,
+    }
+    out.flush()?;
+    drop(out);
+
+    sync_file(&file, path, durable)
+}
+
+// Regular files only: the login symlinks in users are left out.
+fn files(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<(), Error> {
+    let entries = match read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let kind = entry.file_type()?;
+        if kind.is_dir() {
+            files(&entry.path(), paths)?;
+        } else if kind.is_file() {
+            paths.push(entry.path());
+        }
+    }
+
+    Ok(())
+}
+
+fn is_owned(key: &str) -> bool {
+    let table = key
+        .split_once('/')
+        .map(|(dir, _)| dir)
+        .unwrap_or_else(|| key.trim_end_matches(".zst").trim_end_matches(".yaml"));
+    key == ".sync/layout.yaml" || TABLES.contains(&table)
+}
+
+#[cfg(any(feature = "s3", feature = "webdav"))]
+pub(crate) fn etag(res: &ureq::Response, object: &str) -> Result<String, Error> {
+    res.header("ETag")
+        .map(str::to_string)
+        .ok_or(Error::Storage(format!("{}: no etag", object)))
+}
+
+#[cfg(any(feature = "s3", feature = "webdav"))]
+pub(crate) fn storage_error(method: &str, object: &str, err: ureq::Error) -> Error {
+    match err {
+        ureq::Error::Status(status, res) => Error::Storage(format!(
+            "{} {}: {}; {}",
+            method,
+            object,
+            status,
+            res.into_string().unwrap_or_default().trim()
+        )),
+        err => Error::Storage(format!("{} {}: {}", method, object, err)),
+    }
+}
+
+fn sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn layout_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(".sync").join("layout.yaml")
+}
+
+fn index_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(".sync").join("storage.index")
+}
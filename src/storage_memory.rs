@@ -0,0 +1,131 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use crate::{
+    error::Error,
+    storage::{Precondition, Storage},
+};
+
+// Objects kept in memory, e.g. in tests, or where there is no bucket to keep them in. Etags count
+// the writes, so that every write gets a new one.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    objects: Mutex<BTreeMap<String, (Vec<u8>, String)>>,
+    writes: Mutex<u64>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // All objects, e.g. to hand them over to whatever keeps them for longer.
+    pub fn objects(&self) -> BTreeMap<String, Vec<u8>> {
+        self.objects
+            .lock()
+            .expect("storage poisoned")
+            .iter()
+            .map(|(key, (data, _))| (key.clone(), data.clone()))
+            .collect()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &str) -> Result<Option<(Vec<u8>, String)>, Error> {
+        Ok(self
+            .objects
+            .lock()
+            .expect("storage poisoned")
+            .get(key)
+            .cloned())
+    }
+
+    fn put(&self, key: &str, data: &[u8], precondition: Precondition) -> Result<String, Error> {
+        let mut objects = self.objects.lock().expect("storage poisoned");
+        let current = objects.get(key).map(|(_, etag)| etag);
+        let holds = match &precondition {
+            Precondition::None => true,
+            Precondition::Absent => current.is_none(),
+            Precondition::Matches(etag) => current == Some(etag),
+        };
+        if !holds {
+            return Err(Error::Conflict(key.to_string()));
+        }
+
+        let mut writes = self.writes.lock().expect("storage poisoned");
+        *writes += 1;
+        let etag = format!("\"{}\"", writes);
+        objects.insert(key.to_string(), (data.to_vec(), etag.clone()));
+
+        Ok(etag)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Error> {
+        self.objects.lock().expect("storage poisoned").remove(key);
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<(String, String)>, Error> {
+        Ok(self
+            .objects
+            .lock()
+            .expect("storage poisoned")
+            .iter()
+            .map(|(key, (_, etag))| (key.clone(), etag.clone()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+    use serde_yaml::Mapping as YamlMapping;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::store::{Settings, Store};
+
+    #[test]
+    fn refuses_writes_over_changes() {
+        let storage = MemoryStorage::new();
+        let etag = storage.put("a", b"1", Precondition::Absent).expect("put");
+        assert!(matches!(
+            storage.put("a", b"2", Precondition::Absent),
+            Err(Error::Conflict(_))
+        ));
+
+        let newer = storage
+            .put("a", b"2", Precondition::Matches(etag.clone()))
+            .expect("put");
+        assert_ne!(newer, etag);
+        assert!(matches!(
+            storage.put("a", b"3", Precondition::Matches(etag)),
+            Err(Error::Conflict(_))
+        ));
+        assert_eq!(storage.objects()["a"], b"2");
+    }
+
+    #[test]
+    fn restores_another_data_directory() {
+        let storage = Arc::new(MemoryStorage::new());
+        let record = json!({ "id": 1, "name": "one" })
+            .as_object()
+            .expect("object")
+            .clone();
+        let first = tempdir().expect("tempdir");
+        Store::open_with_storage(first.path(), storage.clone(), Settings::default())
+            .expect("open")
+            .put("observations", &YamlMapping::new(), [(1, &record)])
+            .expect("put");
+
+        let second = tempdir().expect("tempdir");
+        let store =
+            Store::open_with_storage(second.path(), storage, Settings::default()).expect("restore");
+        let (_, restored) = store
+            .get("observations", 1)
+            .expect("get")
+            .expect("restored");
+        assert_eq!(restored, record);
+    }
+}
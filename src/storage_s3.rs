@@ -0,0 +1,189 @@
+use std::{env, io::Read, time::Duration};
+
+use percent_encoding::percent_decode_str;
+use rusty_s3::{actions::ListObjectsV2, Bucket, Credentials, S3Action, UrlStyle};
+use ureq::{Agent, AgentBuilder};
+
+use crate::{
+    error::Error,
+    storage::{etag, storage_error, Precondition, Storage},
+};
+
+// Signed URLs are used right away, this is just for slow uploads.
+const SIGNATURE_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+// An S3 bucket, or anything speaking the same API, e.g. MinIO. Writes are conditional, which
+// needs If-Match and If-None-Match support on PutObject: AWS has it, and so does MinIO.
+#[derive(Debug)]
+pub struct S3Storage {
+    bucket: Bucket,
+    credentials: Credentials,
+    // Empty, or ending with a slash.
+    prefix: String,
+    agent: Agent,
+}
+
+impl S3Storage {
+    // The bucket at the endpoint, e.g. https://s3.eu-central-1.amazonaws.com or MinIO's
+    // http://localhost:9000, addressed path-style, with the keys under the prefix, if any.
+    pub fn new(
+        endpoint: &str,
+        region: &str,
+        bucket: &str,
+        prefix: &str,
+        access_key: &str,
+        secret_key: &str,
+    ) -> Result<Self, Error> {
+        Self::with_credentials(
+            endpoint,
+            region,
+            bucket,
+            prefix,
+            Credentials::new(access_key, secret_key),
+        )
+    }
+
+    fn with_credentials(
+        endpoint: &str,
+        region: &str,
+        bucket: &str,
+        prefix: &str,
+        credentials: Credentials,
+    ) -> Result<Self, Error> {
+        let bucket = Bucket::new(
+            endpoint.parse()?,
+            UrlStyle::Path,
+            bucket.to_string(),
+            region.to_string(),
+        )
+        .map_err(|err| Error::Storage(format!("bucket {}: {}", bucket, err)))?;
+        let prefix = match prefix.trim_matches('/') {
+            "" => String::new(),
+            prefix => format!("{}/", prefix),
+        };
+
+        Ok(Self {
+            bucket,
+            credentials,
+            prefix,
+            agent: AgentBuilder::new().timeout(TIMEOUT).build(),
+        })
+    }
+
+    // Like new, with the credentials from AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and
+    // AWS_SESSION_TOKEN, if set.
+    pub fn from_env(
+        endpoint: &str,
+        region: &str,
+        bucket: &str,
+        prefix: &str,
+    ) -> Result<Self, Error> {
+        let credentials = Credentials::from_env().ok_or(Error::Storage(
+            "AWS_ACCESS_KEY_ID or AWS_SECRET_ACCESS_KEY not set".to_string(),
+        ))?;
+        // Not picked up by Credentials::from_env.
+        let credentials = match env::var("AWS_SESSION_TOKEN") {
+            Ok(token) => Credentials::new_with_token(
+                credentials.key().to_string(),
+                credentials.secret().to_string(),
+                token,
+            ),
+            _ => credentials,
+        };
+
+        Self::with_credentials(endpoint, region, bucket, prefix, credentials)
+    }
+
+    fn object(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+impl Storage for S3Storage {
+    fn get(&self, key: &str) -> Result<Option<(Vec<u8>, String)>, Error> {
+        let object = self.object(key);
+        let url = self
+            .bucket
+            .get_object(Some(&self.credentials), &object)
+            .sign(SIGNATURE_EXPIRY);
+        let res = match self.agent.get(url.as_str()).call() {
+            Ok(res) => res,
+            Err(ureq::Error::Status(404, _)) => return Ok(None),
+            Err(err) => return Err(storage_error("GET", &object, err)),
+        };
+        let etag = etag(&res, &object)?;
+        let mut data = vec![];
+        res.into_reader().read_to_end(&mut data)?;
+
+        Ok(Some((data, etag)))
+    }
+
+    fn put(&self, key: &str, data: &[u8], precondition: Precondition) -> Result<String, Error> {
+        let object = self.object(key);
+        let url = self
+            .bucket
+            .put_object(Some(&self.credentials), &object)
+            .sign(SIGNATURE_EXPIRY);
+        let req = self.agent.put(url.as_str());
+        let req = match &precondition {
+            Precondition::None => req,
+            Precondition::Absent => req.set("If-None-Match", "*"),
+            Precondition::Matches(etag) => req.set("If-Match", etag),
+        };
+        match req.send_bytes(data) {
+            Ok(res) => etag(&res, &object),
+            // 409 when another conditional write to the same key is in progress.
+            Err(ureq::Error::Status(409 | 412, _)) => Err(Error::Conflict(object)),
+            Err(err) => Err(storage_error("PUT", &object, err)),
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Error> {
+        let object = self.object(key);
+        let url = self
+            .bucket
+            .delete_object(Some(&self.credentials), &object)
+            .sign(SIGNATURE_EXPIRY);
+        match self.agent.delete(url.as_str()).call() {
+            Ok(_) | Err(ureq::Error::Status(404, _)) => Ok(()),
+            Err(err) => Err(storage_error("DELETE", &object, err)),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<(String, String)>, Error> {
+        let mut keys = vec![];
+        let mut token = None;
+        loop {
+            let mut action = self.bucket.list_objects_v2(Some(&self.credentials));
+            if !self.prefix.is_empty() {
+                action.with_prefix(self.prefix.as_str());
+            }
+            if let Some(token) = &token {
+                action.with_continuation_token(String::clone(token));
+            }
+            let url = action.sign(SIGNATURE_EXPIRY);
+            let body = self
+                .agent
+                .get(url.as_str())
+                .call()
+                .map_err(|err| storage_error("GET", &self.prefix, err))?
+                .into_string()?;
+            let res = ListObjectsV2::parse_response(&body)
+                .map_err(|err| Error::Storage(format!("listing {}: {}", self.prefix, err)))?;
+
+            for object in res.contents {
+                // Listed URL-encoded.
+                let key = percent_decode_str(&object.key).decode_utf8_lossy();
+                if let Some(key) = key.strip_prefix(&self.prefix) {
+                    keys.push((key.to_string(), object.etag));
+                }
+            }
+            match res.next_continuation_token {
+                Some(next) => token = Some(next),
+                _ => return Ok(keys),
+            }
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use std::{io::Read, mem::take, time::Duration};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use percent_encoding::percent_decode_str;
+use quick_xml::{events::Event, Reader};
+use ureq::{Agent, AgentBuilder, Request, Response};
+use url::Url;
+
+use crate::{
+    error::Error,
+    storage::{etag, storage_error, Precondition, Storage},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+// Only what listing needs.
+const PROPFIND: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:getetag/><d:resourcetype/></d:prop></d:propfind>"#;
+
+// A WebDAV folder, e.g. on Nextcloud:
+// https://cloud.example.com/remote.php/dav/files/{user}/inat, with an app password. Writes are
+// conditional, which needs If-Match and If-None-Match support on PUT: Nextcloud has it, and so
+// do Apache's mod_dav and nginx's dav_ext.
+#[derive(Debug)]
+pub struct WebDavStorage {
+    // Ending with a slash.
+    root: Url,
+    authorization: Option<String>,
+    agent: Agent,
+}
+
+#[derive(Debug, Default)]
+struct Entry {
+    href: String,
+    etag: Option<String>,
+    collection: bool,
+}
+
+impl WebDavStorage {
+    pub fn new(url: &str) -> Result<Self, Error> {
+        let mut root: Url = url.parse()?;
+        if !root.path().ends_with('/') {
+            root.set_path(&format!("{}/", root.path()));
+        }
+
+        Ok(Self {
+            root,
+            authorization: None,
+            agent: AgentBuilder::new().timeout(TIMEOUT).build(),
+        })
+    }
+
+    // Basic auth; kept out of the URL so that it doesn't end up in errors.
+    pub fn with_login(mut self, username: &str, password: &str) -> Self {
+        let login = STANDARD.encode(format!("{}:{}", username, password));
+        self.authorization = Some(format!("Basic {}", login));
+        self
+    }
+
+    fn url(&self, key: &str) -> Url {
+        let mut url = self.root.clone();
+        if let Ok(mut path) = url.path_segments_mut() {
+            path.pop_if_empty().extend(key.split('/'));
+        }
+        url
+    }
+
+    fn request(&self, method: &str, url: &Url) -> Request {
+        let req = self.agent.request_url(method, url);
+        match &self.authorization {
+            Some(authorization) => req.set("Authorization", authorization),
+            _ => req,
+        }
+    }
+
+    // Creates the folders above the key, the root included, unless they exist already.
+    fn create_parents(&self, key: &str) -> Result<(), Error> {
+        let mut dir = self.root.clone();
+        let mut parts: Vec<&str> = key.split('/').collect();
+        parts.pop();
+        for part in [None].into_iter().chain(parts.into_iter().map(Some)) {
+            if let (Some(part), Ok(mut path)) = (part, dir.path_segments_mut()) {
+                path.pop_if_empty().push(part).push("");
+            }
+            match self.request("MKCOL", &dir).call() {
+                // 405 if it exists already.
+                Ok(_) | Err(ureq::Error::Status(405, _)) => {}
+                Err(err) => return Err(storage_error("MKCOL", dir.path(), err)),
+            }
+        }
+
+        Ok(())
+    }
+
+    // One folder's entries, the folder itself included.
+    fn propfind(&self, url: &Url) -> Result<Vec<Entry>, Error> {
+        let body = match self
+            .request("PROPFIND", url)
+            .set("Depth", "1")
+            .set("Content-Type", "application/xml; charset=utf-8")
+            .send_string(PROPFIND)
+        {
+            Ok(res) => res.into_string()?,
+            Err(ureq::Error::Status(404, _)) => return Ok(vec![]),
+            Err(err) => return Err(storage_error("PROPFIND", url.path(), err)),
+        };
+
+        parse_multistatus(&body).map_err(|err| Error::Storage(format!("{}: {}", url.path(), err)))
+    }
+
+    // None when the folder doesn't exist yet.
+    fn put_object(
+        &self,
+        url: &Url,
+        data: &[u8],
+        precondition: &Precondition,
+    ) -> Result<Option<Response>, Error> {
+        let req = self.request("PUT", url);
+        let req = match precondition {
+            Precondition::None => req,
+            Precondition::Absent => req.set("If-None-Match", "*"),
+            Precondition::Matches(etag) => req.set("If-Match", etag),
+        };
+        match req.send_bytes(data) {
+            Ok(res) => Ok(Some(res)),
+            Err(ureq::Error::Status(409, _)) => Ok(None),
+            Err(ureq::Error::Status(412, _)) => Err(Error::Conflict(url.path().to_string())),
+            Err(err) => Err(storage_error("PUT", url.path(), err)),
+        }
+    }
+
+    // Some servers don't send the etag along when writing.
+    fn etag(&self, key: &str) -> Result<String, Error> {
+        let url = self.url(key);
+        self.propfind(&url)?
+            .into_iter()
+            .find_map(|entry| entry.etag)
+            .ok_or(Error::Storage(format!("{}: no etag", url.path())))
+    }
+}
+
+impl Storage for WebDavStorage {
+    fn get(&self, key: &str) -> Result<Option<(Vec<u8>, String)>, Error> {
+        let url = self.url(key);
+        let res = match self.request("GET", &url).call() {
+            Ok(res) => res,
+            Err(ureq::Error::Status(404, _)) => return Ok(None),
+            Err(err) => return Err(storage_error("GET", url.path(), err)),
+        };
+        let etag = etag(&res, url.path())?;
+        let mut data = vec![];
+        res.into_reader().read_to_end(&mut data)?;
+
+        Ok(Some((data, etag)))
+    }
+
+    fn put(&self, key: &str, data: &[u8], precondition: Precondition) -> Result<String, Error> {
+        let url = self.url(key);
+        let res = match self.put_object(&url, data, &precondition)? {
+            Some(res) => res,
+            _ => {
+                self.create_parents(key)?;
+                self.put_object(&url, data, &precondition)?
+                    .ok_or(Error::Storage(format!("{}: no such folder", url.path())))?
+            }
+        };
+        match res.header("ETag") {
+            Some(_) => etag(&res, url.path()),
+            _ => self.etag(key),
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Error> {
+        let url = self.url(key);
+        match self.request("DELETE", &url).call() {
+            Ok(_) | Err(ureq::Error::Status(404, _)) => Ok(()),
+            Err(err) => Err(storage_error("DELETE", url.path(), err)),
+        }
+    }
+
+    // Folder by folder: Nextcloud doesn't allow listing all of them at once.
+    fn list(&self) -> Result<Vec<(String, String)>, Error> {
+        let root = percent_decode_str(self.root.path())
+            .decode_utf8_lossy()
+            .to_string();
+        let mut keys = vec![];
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in self.propfind(&dir)? {
+                // Listed URL-encoded, either as a path or a full URL.
+                let href = dir.join(&entry.href)?;
+                if href.path() == dir.path() {
+                    continue;
+                }
+                let path = percent_decode_str(href.path()).decode_utf8_lossy();
+                let key = match path.strip_prefix(&root) {
+                    Some(key) => key.trim_end_matches('/'),
+                    _ => continue,
+                };
+                match (entry.collection, entry.etag) {
+                    (true, _) => dirs.push(href),
+                    (_, Some(etag)) => keys.push((key.to_string(), etag)),
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(keys)
+    }
+}
+
+fn parse_multistatus(body: &str) -> Result<Vec<Entry>, quick_xml::Error> {
+    let mut reader = Reader::from_str(body);
+    reader.trim_text(true);
+
+    let mut entries = vec![];
+    let mut entry = Entry::default();
+    // The element whose text is being read.
+    let mut text: Option<Vec<u8>> = None;
+    loop {
+        match reader.read_event()? {
+            Event::Start(el) => match el.local_name().as_ref() {
+                b"response" => entry = Entry::default(),
+                b"collection" => entry.collection = true,
+                name @ (b"href" | b"getetag") => text = Some(name.to_vec()),
+                _ => {}
+            },
+            Event::Empty(el) if el.local_name().as_ref() == b"collection" => {
+                entry.collection = true;
+            }
+            Event::Text(val) => match text.as_deref() {
+                Some(b"href") => entry.href.push_str(&val.unescape()?),
+                Some(b"getetag") => entry.etag = Some(val.unescape()?.to_string()),
+                _ => {}
+            },
+            Event::End(el) => match el.local_name().as_ref() {
+                b"response" => entries.push(take(&mut entry)),
+                _ => text = None,
+            },
+            Event::Eof => return Ok(entries),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,1017 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::{
+        copy, create_dir_all, read_dir, read_link, remove_dir_all, remove_file, rename,
+        symlink_metadata, File, OpenOptions,
+    },
+    io::{BufWriter, ErrorKind, Write},
+    mem::take,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+#[cfg(unix)]
+pub(crate) use std::os::unix::fs::symlink;
+
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use reqwest::header::DATE;
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping as YamlMapping, Value as YamlValue};
+
+#[cfg(feature = "replica")]
+use crate::storage::{Replica, Storage};
+use crate::{
+    api::{
+        canonical, compressed_path, extract_id, lookup_cache_data, lookup_cache_raw, open_cache,
+        write_cache,
+    },
+    archive::Record,
+    clock::{Clock, SystemClock},
+    durable::{self, is_unchanged, sync_dir, sync_file},
+    error::{corrupt_cache, Error},
+};
+
+type Entries = BTreeMap<u64, (YamlMapping, Record)>;
+
+// How tables are laid out in the data directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Layout {
+    // One {table}/{id}.yaml file per record.
+    #[default]
+    Directory,
+    // One {table}.yaml file per table, alternating header and record documents.
+    // Writes are appended, later documents win until the file gets compacted.
+    // Unlike the others, it keeps each table it reads in memory, whole, for as long as the store is
+    // open: meant for archives of a few thousand observations, the others scale further.
+    File,
+    // Like Directory, but in {table}/{aa}/{bb}/{id}.yaml subdirectories by the leading digits of
+    // the ID, padded to four, to keep the directories small. Users stay in users/{id}.yaml, along
+    // with the sync state in there.
+    Sharded,
+}
+
+// Records written or removed since the changes were last taken, by table.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct Changes {
+    pub tables: BTreeMap<String, TableChanges>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct TableChanges {
+    pub new: usize,
+    // Rewritten with different contents; records fetched again unchanged are not counted.
+    pub updated: usize,
+    pub deleted: usize,
+    // Written again as they were.
+    pub unchanged: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct LayoutState {
+    layout: Layout,
+    // The zstd level tables are written at, if compressed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compression: Option<i32>,
+}
+
+// How the data directory gets written, by the store and everything writing next to it; shared by
+// the Api and the Archive using the same store.
+#[derive(Clone, Debug)]
+pub(crate) struct Settings {
+    pub(crate) clock: Arc<dyn Clock>,
+    // See ApiBuilder::deterministic.
+    pub(crate) deterministic: bool,
+    // See ApiBuilder::durable.
+    pub(crate) durable: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct Store {
+    data_dir: PathBuf,
+    layout: Layout,
+    // Written as {path}.zst if set; either kind is read.
+    compression: Option<i32>,
+    // File layout only: whole tables are loaded on first use, and kept around since the
+    // alternative is re-reading them for every chunk of a sync.
+    tables: Mutex<HashMap<String, Entries>>,
+    dirty: Mutex<HashSet<String>>,
+    changes: Mutex<Changes>,
+    // Where whatever is written gets uploaded to, if anywhere.
+    #[cfg(feature = "replica")]
+    replica: Option<Arc<Replica>>,
+    settings: Settings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            clock: Arc::new(SystemClock),
+            deterministic: false,
+            durable: false,
+        }
+    }
+}
+
+impl Store {
+    pub(crate) fn open(data_dir: &Path) -> Result<Self, Error> {
+        Ok(
+            match lookup_cache_data::<LayoutState>(&layout_path(data_dir))? {
+                Some((_, state)) => {
+                    Self::with_layout(data_dir, state.layout).with_compression(state.compression)
+                }
+                _ => Self::with_layout(data_dir, Layout::default()),
+            },
+        )
+    }
+
+    pub(crate) fn with_layout(data_dir: &Path, layout: Layout) -> Self {
+        Self {
+            data_dir: data_dir.to_path_buf(),
+            layout,
+            compression: None,
+            tables: Mutex::new(HashMap::new()),
+            dirty: Mutex::new(HashSet::new()),
+            changes: Mutex::new(Changes::default()),
+            #[cfg(feature = "replica")]
+            replica: None,
+            settings: Settings::default(),
+        }
+    }
+
+    // Like open, after restoring whatever is missing locally from the storage.
+    #[cfg(feature = "replica")]
+    pub(crate) fn open_with_storage(
+        data_dir: &Path,
+        storage: Arc<dyn Storage>,
+        settings: Settings,
+    ) -> Result<Self, Error> {
+        let replica = Replica::open(data_dir, storage)?;
+        replica.restore(settings.durable)?;
+
+        let mut store = Self::open(data_dir)?.with_settings(settings);
+        store.replica = Some(Arc::new(replica));
+
+        Ok(store)
+    }
+
+    // The same data directory and storage, written with other settings; nothing is read again.
+    pub(crate) fn reopen(&self, settings: Settings) -> Self {
+        Self::with_layout(&self.data_dir, self.layout)
+            .with_compression(self.compression)
+            .with_replica_of(self)
+            .with_settings(settings)
+    }
+
+    // Uploading to the same storage as the other store, if any.
+    #[cfg(feature = "replica")]
+    fn with_replica_of(mut self, other: &Store) -> Self {
+        self.replica = other.replica.clone();
+        self
+    }
+
+    #[cfg(not(feature = "replica"))]
+    fn with_replica_of(self, _: &Store) -> Self {
+        self
+    }
+
+    pub(crate) fn with_settings(mut self, settings: Settings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    pub(crate) fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub(crate) fn now(&self) -> DateTime<Utc> {
+        self.settings.clock.now()
+    }
+
+    pub(crate) fn is_deterministic(&self) -> bool {
+        self.settings.deterministic
+    }
+
+    pub(crate) fn is_durable(&self) -> bool {
+        self.settings.durable
+    }
+
+    pub(crate) fn with_compression(mut self, compression: Option<i32>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub(crate) fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    pub(crate) fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub(crate) fn compression(&self) -> Option<i32> {
+        self.compression
+    }
+
+    // Of the files written, for naming links to them.
+    pub(crate) fn extension(&self) -> &'static str {
+        match self.compression {
+            Some(_) => "yaml.zst",
+            _ => "yaml",
+        }
+    }
+
+    // Writes a cache file the way the tables are written, removing the other kind if left over.
+    pub(crate) fn write_file<H: Serialize>(
+        &self,
+        path: &Path,
+        header: &H,
+        record: &Record,
+    ) -> Result<(), Error> {
+        let (target, stale) = match self.compression {
+            Some(_) => (compressed_path(path), path.to_path_buf()),
+            _ => (path.to_path_buf(), compressed_path(path)),
+        };
+        durable::write_file(&target, self.is_durable(), |out| {
+            write_documents(out, self.compression, |out| {
+                serde_yaml::to_writer(&mut *out, &canonical(header)?)?;
+                writeln!(out, "---")?;
+                write_record(out, record)
+            })
+        })?;
+        remove_if_exists(&stale)?;
+
+        self.mirror(&target)?;
+        self.mirror(&stale)
+    }
+
+    // For the sync state kept next to the tables, never compressed.
+    pub(crate) fn write_cache<H: Serialize, D: Serialize>(
+        &self,
+        path: &Path,
+        header: &H,
+        data: &D,
+    ) -> Result<(), Error> {
+        write_cache(path, header, data, self.is_durable())
+    }
+
+    // Uploads the file as it is now to the storage, if any; or deletes it there if it's gone.
+    pub(crate) fn mirror(&self, path: &Path) -> Result<(), Error> {
+        #[cfg(feature = "replica")]
+        if let Some(replica) = &self.replica {
+            replica.push(path, self.is_durable())?;
+        }
+        #[cfg(not(feature = "replica"))]
+        let _ = path;
+
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, table: &str, id: u64) -> Result<Option<(YamlMapping, Record)>, Error> {
+        match self.layout {
+            Layout::Directory | Layout::Sharded => {
+                for path in self.record_paths(table, id) {
+                    if let Some(entry) = lookup_cache_raw(&path)? {
+                        return Ok(Some(entry));
+                    }
+                }
+                Ok(None)
+            }
+            Layout::File => self.with_table(table, |entries| entries.get(&id).cloned()),
+        }
+    }
+
+    pub(crate) fn contains(&self, table: &str, id: u64) -> Result<bool, Error> {
+        match self.layout {
+            Layout::Directory | Layout::Sharded => Ok(self
+                .record_paths(table, id)
+                .iter()
+                .any(|path| path.exists() || compressed_path(path).exists())),
+            Layout::File => self.with_table(table, |entries| entries.contains_key(&id)),
+        }
+    }
+
+    pub(crate) fn all(&self, table: &str) -> Result<BTreeMap<u64, Record>, Error> {
+        Ok(self
+            .entries(table)?
+            .into_iter()
+            .map(|(id, (_, record))| (id, record))
+            .collect())
+    }
+
+    pub(crate) fn put<'a, I>(
+        &self,
+        table: &str,
+        header: &YamlMapping,
+        records: I,
+    ) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (u64, &'a Record)>,
+    {
+        match self.layout {
+            Layout::Directory | Layout::Sharded => {
+                create_dir_all(self.data_dir.join(table))?;
+                for (id, record) in records {
+                    let path = self.record_path(table, id);
+                    // Unreadable files are overwritten anyway, count them as changed.
+                    let old = match path.exists() || compressed_path(&path).exists() {
+                        true => Some(lookup_cache_raw::<Record>(&path).ok().flatten()),
+                        _ => None,
+                    };
+                    if let Some(dir) = path.parent() {
+                        create_dir_all(dir)?;
+                    }
+                    self.write_file(&path, header, record)?;
+                    self.count(
+                        table,
+                        old.as_ref().map(|old| old.as_ref().map(|(_, r)| r)),
+                        record,
+                    );
+                }
+            }
+            Layout::File => {
+                let records: Vec<_> = records.into_iter().collect();
+                if records.is_empty() {
+                    return Ok(());
+                }
+                self.with_table(table, |_| ())?;
+
+                let path = match self.compression {
+                    Some(_) => compressed_path(&self.table_path(table)),
+                    _ => self.table_path(table),
+                };
+                let file = OpenOptions::new().create(true).append(true).open(&path)?;
+                let mut tables = self.tables.lock().expect("store poisoned");
+                let entries = tables.entry(table.to_string()).or_default();
+                write_documents(BufWriter::new(&file), self.compression, |out| {
+                    for (id, record) in records {
+                        write_entry(out, header, record)?;
+                        let old = entries.insert(id, (header.clone(), record.clone()));
+                        self.count(table, old.as_ref().map(|(_, old)| Some(old)), record);
+                    }
+                    Ok(())
+                })?;
+                sync_file(&file, &path, self.is_durable())?;
+                self.dirty
+                    .lock()
+                    .expect("store poisoned")
+                    .insert(table.to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn remove_records(&self, table: &str, ids: &[u64]) -> Result<(), Error> {
+        match self.layout {
+            Layout::Directory | Layout::Sharded => {
+                for id in ids {
+                    let mut removed = false;
+                    for path in self
+                        .record_paths(table, *id)
+                        .into_iter()
+                        .flat_map(|path| [compressed_path(&path), path])
+                    {
+                        match remove_file(&path) {
+                            Ok(()) => removed = true,
+                            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                            _ => {}
+                        }
+                        self.mirror(&path)?;
+                    }
+                    if removed {
+                        self.count_deleted(table);
+                    }
+                }
+            }
+            Layout::File => {
+                self.with_table(table, |_| ())?;
+                let mut tables = self.tables.lock().expect("store poisoned");
+                let entries = tables.entry(table.to_string()).or_default();
+                for id in ids {
+                    if entries.remove(id).is_some() {
+                        self.count_deleted(table);
+                    }
+                }
+                self.dirty
+                    .lock()
+                    .expect("store poisoned")
+                    .insert(table.to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn changes(&self) -> Changes {
+        self.changes.lock().expect("store poisoned").clone()
+    }
+
+    pub(crate) fn take_changes(&self) -> Changes {
+        take(&mut self.changes.lock().expect("store poisoned"))
+    }
+
+    // Old is None for new records, Some(None) for existing ones that could not be read.
+    fn count(&self, table: &str, old: Option<Option<&Record>>, new: &Record) {
+        let mut changes = self.changes.lock().expect("store poisoned");
+        let changes = changes.tables.entry(table.to_string()).or_default();
+        match old {
+            None => changes.new += 1,
+            Some(old) if old != Some(new) => changes.updated += 1,
+            _ => changes.unchanged += 1,
+        }
+    }
+
+    fn count_deleted(&self, table: &str) {
+        let mut changes = self.changes.lock().expect("store poisoned");
+        changes.tables.entry(table.to_string()).or_default().deleted += 1;
+    }
+
+    // Rewrites appended-to table files with only the latest version of each record.
+    pub(crate) fn compact(&self) -> Result<(), Error> {
+        let dirty: Vec<_> = self.dirty.lock().expect("store poisoned").drain().collect();
+        let tables = self.tables.lock().expect("store poisoned");
+        for table in dirty {
+            if let Some(entries) = tables.get(&table) {
+                let path = self.table_path(&table);
+                write_table(&path, entries, self.compression, self.is_durable())?;
+                self.mirror(&compressed_path(&path))?;
+                self.mirror(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Moves every table found on disk over to the other layout, or compression. The new files are
+    // written to a staging directory and synced, then swapped in by renaming; the old ones are
+    // removed only once the layout they are in is no longer recorded.
+    pub(crate) fn convert(&self, layout: Layout, compression: Option<i32>) -> Result<Self, Error> {
+        let target = Self::with_layout(&self.data_dir, layout)
+            .with_compression(compression)
+            .with_replica_of(self)
+            .with_settings(self.settings.clone());
+        if layout == self.layout && compression == self.compression {
+            return Ok(target);
+        }
+
+        // Records removed from table files are only gone from the disk once compacted.
+        self.compact()?;
+        let staging = self.data_dir.join(".sync").join("layout.new");
+        let old = self.data_dir.join(".sync").join("layout.old");
+        // Left over from an interrupted conversion: put back whatever was moved away and never
+        // replaced, and start over.
+        unswap(&self.data_dir, &old)?;
+        remove_dir_if_exists(&staging)?;
+        create_dir_all(&staging)?;
+
+        let staged = Self::with_layout(&staging, layout)
+            .with_compression(compression)
+            .with_settings(self.settings.clone());
+        let users = self.data_dir.join("users");
+        let linked = linked_users(&users)?;
+        let mut tables = vec![];
+        for table in tables_on_disk(&self.data_dir)? {
+            // Read whichever way they are laid out, so that half-converted tables read whole.
+            let mut entries = read_records(&self.data_dir.join(&table))?;
+            entries.extend(read_table(&self.table_path(&table))?);
+            if entries.is_empty() {
+                continue;
+            }
+            match layout {
+                Layout::Directory | Layout::Sharded => {
+                    for (id, (header, record)) in &entries {
+                        staged.put(&table, header, [(*id, record)])?;
+                    }
+                }
+                Layout::File => {
+                    write_table(
+                        &staged.table_path(&table),
+                        &entries,
+                        compression,
+                        self.is_durable(),
+                    )?;
+                    // The logged in users are looked up by file, whatever the layout.
+                    if table == "users" {
+                        create_dir_all(staging.join(&table))?;
+                        for (id, (header, record)) in
+                            entries.iter().filter(|(id, _)| linked.contains(id))
+                        {
+                            staged.write_file(&staged.record_path(&table, *id), header, record)?;
+                        }
+                    }
+                }
+            }
+            tables.push(table);
+        }
+        copy_user_state(&users, &staging.join("users"))?;
+        relink_users(&staging.join("users"))?;
+        durable::sync_tree(&staging)?;
+
+        create_dir_all(&old)?;
+        for table in &tables {
+            for name in [
+                table.clone(),
+                format!("{}.yaml", table),
+                format!("{}.yaml.zst", table),
+            ] {
+                rename_if_exists(&self.data_dir.join(&name), &old.join(&name))?;
+                rename_if_exists(&staging.join(&name), &self.data_dir.join(&name))?;
+            }
+        }
+        File::open(&self.data_dir)?.sync_all()?;
+
+        let mut header = YamlMapping::new();
+        header.insert(
+            YamlValue::String(DATE.to_string()),
+            YamlValue::String(self.now().to_rfc3339()),
+        );
+        self.write_cache(
+            &layout_path(&self.data_dir),
+            &header,
+            &LayoutState {
+                layout,
+                compression,
+            },
+        )?;
+        durable::sync_tree(&self.data_dir.join(".sync"))?;
+        remove_dir_if_exists(&old)?;
+        remove_dir_if_exists(&staging)?;
+        #[cfg(feature = "replica")]
+        if let Some(replica) = &self.replica {
+            replica.reconcile(self.is_durable())?;
+        }
+
+        Ok(target)
+    }
+
+    pub(crate) fn entries(&self, table: &str) -> Result<Entries, Error> {
+        match self.layout {
+            Layout::Directory | Layout::Sharded => read_records(&self.data_dir.join(table)),
+            Layout::File => self.with_table(table, Entries::clone),
+        }
+    }
+
+    fn with_table<T>(&self, table: &str, f: impl FnOnce(&Entries) -> T) -> Result<T, Error> {
+        let mut tables = self.tables.lock().expect("store poisoned");
+        if !tables.contains_key(table) {
+            tables.insert(table.to_string(), read_table(&self.table_path(table))?);
+        }
+
+        Ok(f(&tables[table]))
+    }
+
+    fn record_path(&self, table: &str, id: u64) -> PathBuf {
+        let dir = self.data_dir.join(table);
+        let name = format!("{}.yaml", id);
+        match self.layout {
+            Layout::Sharded if table != "users" => dir.join(shard(id)).join(name),
+            _ => dir.join(name),
+        }
+    }
+
+    // Where the record is written, relative to the data directory, e.g. for reports.
+    pub(crate) fn relative_path(&self, table: &str, id: u64) -> PathBuf {
+        let path = match self.layout {
+            Layout::File => self.table_path(table),
+            _ => self.record_path(table, id),
+        };
+        let path = match self.compression {
+            Some(_) => compressed_path(&path),
+            _ => path,
+        };
+        match path.strip_prefix(&self.data_dir) {
+            Ok(relative) => relative.to_path_buf(),
+            _ => path,
+        }
+    }
+
+    // Where the record is written first, then where the other directory layout has it, so that
+    // half-converted caches still read.
+    fn record_paths(&self, table: &str, id: u64) -> Vec<PathBuf> {
+        let path = self.record_path(table, id);
+        let flat = self.data_dir.join(table).join(format!("{}.yaml", id));
+        let sharded = self
+            .data_dir
+            .join(table)
+            .join(shard(id))
+            .join(format!("{}.yaml", id));
+        [path, flat, sharded].into_iter().unique().collect()
+    }
+
+    fn table_path(&self, table: &str) -> PathBuf {
+        self.data_dir.join(format!("{}.yaml", table))
+    }
+}
+
+impl Changes {
+    pub fn total(&self) -> TableChanges {
+        let mut total = TableChanges::default();
+        for changes in self.tables.values() {
+            total.new += changes.new;
+            total.updated += changes.updated;
+            total.deleted += changes.deleted;
+            total.unchanged += changes.unchanged;
+        }
+
+        total
+    }
+}
+
+// By the leading digits, padded to four: 123456 goes in 12/34, 7 in 00/07.
+fn shard(id: u64) -> PathBuf {
+    let digits = format!("{:04}", id);
+    Path::new(&digits[..2]).join(&digits[2..4])
+}
+
+fn layout_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(".sync").join("layout.yaml")
+}
+
+fn read_records(dir: &Path) -> Result<Entries, Error> {
+    let mut entries = Entries::new();
+    let files = match read_dir(dir) {
+        Ok(files) => files,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(entries),
+        Err(err) => return Err(err.into()),
+    };
+
+    for file in files {
+        let path = file?.path();
+        // Shards, named by digits.
+        if path.is_dir() && file_name_is_digits(&path) {
+            entries.extend(read_records(&path)?);
+            continue;
+        }
+        if let Some(id) = record_id(&path) {
+            // Looked up by the plain name, which finds the compressed one too.
+            if let Some(entry) = lookup_cache_raw(&path.with_file_name(format!("{}.yaml", id)))? {
+                entries.insert(id, entry);
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+fn read_table(path: &Path) -> Result<Entries, Error> {
+    let mut entries = Entries::new();
+    let file = match open_cache(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(entries),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut docs = serde_yaml::Deserializer::from_reader(file);
+    while let Some(header) = docs.next() {
+        let header = YamlMapping::deserialize(header)?;
+        let record = Record::deserialize(
+            docs.next()
+                .ok_or(corrupt_cache(path, "header without a record"))?,
+        )?;
+        let id = extract_id(&record).map_err(|_| corrupt_cache(path, "record without an id"))?;
+        entries.insert(id, (header, record));
+    }
+
+    Ok(entries)
+}
+
+// Writes to a temporary file first, so that readers never see half a table.
+fn write_table(
+    path: &Path,
+    entries: &Entries,
+    compression: Option<i32>,
+    durable: bool,
+) -> Result<(), Error> {
+    let mut contents = vec![];
+    write_documents(&mut contents, compression, |out| {
+        for (header, record) in entries.values() {
+            write_entry(out, header, record)?;
+        }
+        Ok(())
+    })?;
+    let (target, stale) = match compression {
+        Some(_) => (compressed_path(path), path.to_path_buf()),
+        _ => (path.to_path_buf(), compressed_path(path)),
+    };
+    if !is_unchanged(&target, &contents) {
+        let tmp = path.with_extension("yaml.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&contents)?;
+        sync_file(&file, &tmp, durable)?;
+        rename(tmp, &target)?;
+        sync_dir(&target, durable)?;
+    }
+
+    remove_if_exists(&stale)
+}
+
+// Compressed ones as a single zstd frame; appended frames read back as one.
+fn write_documents<W: Write>(
+    mut out: W,
+    compression: Option<i32>,
+    write: impl FnOnce(&mut dyn Write) -> Result<(), Error>,
+) -> Result<(), Error> {
+    match compression {
+        #[cfg(feature = "compression")]
+        Some(level) => {
+            let mut out = zstd::Encoder::new(out, level)?;
+            write(&mut out)?;
+            out.finish()?.flush()?;
+        }
+        #[cfg(not(feature = "compression"))]
+        Some(_) => return Err(Error::MissingFeature("compression")),
+        _ => {
+            write(&mut out)?;
+            out.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_entry<W: Write + ?Sized>(
+    out: &mut W,
+    header: &YamlMapping,
+    record: &Record,
+) -> Result<(), Error> {
+    writeln!(out, "---")?;
+    serde_yaml::to_writer(&mut *out, &canonical(header)?)?;
+    writeln!(out, "---")?;
+
+    write_record(out, record)
+}
+
+// JSON objects keep their keys sorted already, so records are written as they are, without going
+// through canonical and serialising them twice.
+fn write_record<W: Write + ?Sized>(out: &mut W, record: &Record) -> Result<(), Error> {
+    Ok(serde_yaml::to_writer(out, record)?)
+}
+
+// Every table in the data directory, the extractors' own too: directories, or files in the file
+// layout. Hidden ones hold the sync state.
+fn tables_on_disk(data_dir: &Path) -> Result<BTreeSet<String>, Error> {
+    let mut tables = BTreeSet::new();
+    for file in read_dir(data_dir)? {
+        let file = file?;
+        let path = file.path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if !name.starts_with('.') => name,
+            _ => continue,
+        };
+        if file.file_type()?.is_dir() {
+            tables.insert(name.to_string());
+        } else if let Some(table) = file_stem(&path) {
+            tables.insert(table.to_string());
+        }
+    }
+
+    Ok(tables)
+}
+
+// The IDs of the users with a login symlink.
+fn linked_users(dir: &Path) -> Result<HashSet<u64>, Error> {
+    let mut linked = HashSet::new();
+    let files = match read_dir(dir) {
+        Ok(files) => files,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(linked),
+        Err(err) => return Err(err.into()),
+    };
+    for file in files {
+        if let Some(id) = read_link(file?.path()).ok().as_deref().and_then(record_id) {
+            linked.insert(id);
+        }
+    }
+
+    Ok(linked)
+}
+
+// The users directory holds sync state too: the login symlinks and the listings go along with the
+// records.
+fn copy_user_state(from: &Path, to: &Path) -> Result<(), Error> {
+    let files = match read_dir(from) {
+        Ok(files) => files,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    create_dir_all(to)?;
+    for file in files {
+        let file = file?;
+        let path = file.path();
+        let kind = file.file_type()?;
+        if kind.is_symlink() {
+            symlink(read_link(&path)?, to.join(file.file_name()))?;
+        } else if kind.is_file() && record_id(&path).is_none() {
+            copy(&path, to.join(file.file_name()))?;
+        }
+    }
+
+    Ok(())
+}
+
+// Points the login symlinks at the user files as they are now named, after a conversion.
+fn relink_users(dir: &Path) -> Result<(), Error> {
+    let files = match read_dir(dir) {
+        Ok(files) => files,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    for file in files {
+        let link = file?.path();
+        let target = match read_link(&link) {
+            Ok(target) if !link.exists() => target,
+            _ => continue,
+        };
+        let (login, id) = match (file_stem(&link), file_stem(&target)) {
+            (Some(login), Some(id)) => (login.to_string(), id.to_string()),
+            _ => continue,
+        };
+        for extension in ["yaml", "yaml.zst"] {
+            let target = format!("{}.{}", id, extension);
+            if dir.join(&target).exists() {
+                remove_file(&link)?;
+                symlink(target, dir.join(format!("{}.{}", login, extension)))?;
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> Result<(), Error> {
+    match rename(from, to) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+fn remove_dir_if_exists(path: &Path) -> Result<(), Error> {
+    match remove_dir_all(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+// Moves back whatever an interrupted conversion moved out of the way and did not replace yet, and
+// drops the rest, which was replaced already.
+fn unswap(data_dir: &Path, old: &Path) -> Result<(), Error> {
+    let files = match read_dir(old) {
+        Ok(files) => files,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    for file in files {
+        let name = file?.file_name();
+        if symlink_metadata(data_dir.join(&name)).is_err() {
+            rename(old.join(&name), data_dir.join(&name))?;
+        }
+    }
+
+    remove_dir_if_exists(old)
+}
+
+// Without them, e.g. on wasm32, users can only be looked up by ID.
+#[cfg(not(unix))]
+pub(crate) fn symlink<P: AsRef<Path>, Q: AsRef<Path>>(_: P, _: Q) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        ErrorKind::Unsupported,
+        "no symlinks here",
+    ))
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), Error> {
+    match remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+fn file_name_is_digits(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn record_id(path: &Path) -> Option<u64> {
+    file_stem(path)?.parse().ok()
+}
+
+// The name without the .yaml or .yaml.zst extension.
+fn file_stem(path: &Path) -> Option<&str> {
+    let name = path.file_name()?.to_str()?;
+    name.strip_suffix(".yaml.zst")
+        .or_else(|| name.strip_suffix(".yaml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::clock::FixedClock;
+
+    fn record(id: u64) -> Record {
+        json!({ "id": id, "name": format!("record {}", id) })
+            .as_object()
+            .expect("object")
+            .clone()
+    }
+
+    fn fill(store: &Store) {
+        let header = YamlMapping::new();
+        for (table, id) in [
+            ("observations", 1),
+            ("observations", 12345),
+            ("extracted", 7),
+        ] {
+            store.put(table, &header, [(id, &record(id))]).expect("put");
+        }
+        store
+            .put("users", &header, [(42, &record(42))])
+            .expect("put");
+        symlink("42.yaml", store.data_dir().join("users/alice.yaml")).expect("symlink");
+        std::fs::write(store.data_dir().join("users/42.observations.yaml"), "[]").expect("write");
+    }
+
+    #[test]
+    fn shards_by_leading_digits() {
+        assert_eq!(shard(7), Path::new("00/07"));
+        assert_eq!(shard(42), Path::new("00/42"));
+        assert_eq!(shard(1234), Path::new("12/34"));
+        assert_eq!(shard(12345), Path::new("12/34"));
+        assert_eq!(shard(123456), Path::new("12/34"));
+    }
+
+    #[test]
+    fn converts_every_table_on_disk() {
+        let dir = tempdir().expect("tempdir");
+        let store = Store::with_layout(dir.path(), Layout::Directory);
+        fill(&store);
+
+        // Compressed tables need the feature to be written, let alone read back.
+        let level = cfg!(feature = "compression").then_some(3);
+        for (layout, compression) in [
+            (Layout::File, level),
+            (Layout::Sharded, None),
+            (Layout::Directory, None),
+        ] {
+            Store::open(dir.path())
+                .expect("open")
+                .convert(layout, compression)
+                .expect("convert");
+            let store = Store::open(dir.path()).expect("reopen");
+            assert_eq!(store.layout(), layout);
+            assert_eq!(store.compression(), compression);
+            for (table, ids) in [("observations", vec![1, 12345]), ("extracted", vec![7])] {
+                let all = store.all(table).expect("all");
+                assert_eq!(all.into_keys().collect::<Vec<_>>(), ids, "{:?}", layout);
+            }
+            let users = dir.path().join("users");
+            assert!(users.join(format!("alice.{}", store.extension())).exists());
+            assert!(users.join("42.observations.yaml").exists());
+            assert!(!dir.path().join(".sync/layout.new").exists());
+            assert!(!dir.path().join(".sync/layout.old").exists());
+        }
+        assert!(dir.path().join("observations/1.yaml").exists());
+        assert!(!dir.path().join("observations/00").exists());
+    }
+
+    #[test]
+    fn recovers_interrupted_conversions() {
+        let dir = tempdir().expect("tempdir");
+        let store = Store::with_layout(dir.path(), Layout::Directory);
+        fill(&store);
+        // Cut short between moving the old table away and moving the new one in.
+        let old = dir.path().join(".sync/layout.old");
+        create_dir_all(&old).expect("mkdir");
+        rename(dir.path().join("observations"), old.join("observations")).expect("rename");
+
+        let store = store.convert(Layout::Sharded, None).expect("convert");
+        assert_eq!(store.all("observations").expect("all").len(), 2);
+        assert!(dir.path().join("observations/12/34/12345.yaml").exists());
+    }
+
+    #[test]
+    fn settings_stay_with_the_store() {
+        let dir = tempdir().expect("tempdir");
+        let clock = FixedClock(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap());
+        let store = Store::with_layout(dir.path(), Layout::Directory);
+        let pinned = store.reopen(Settings {
+            clock: Arc::new(clock),
+            deterministic: true,
+            durable: true,
+        });
+
+        assert_eq!(pinned.now(), clock.0);
+        assert!(pinned.is_deterministic() && pinned.is_durable());
+        assert_ne!(store.now(), clock.0);
+        assert!(!store.is_deterministic() && !store.is_durable());
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    fs::create_dir_all,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Serializer};
+
+use crate::{api::Api, durable, error::Error, store::Changes};
+
+// What a sync did, from the requests it made to the records it wrote.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[non_exhaustive]
+pub struct SyncSummary {
+    pub started: DateTime<Utc>,
+    // In seconds, when serialised.
+    #[serde(serialize_with = "seconds")]
+    pub duration: Duration,
+    // Sent to the API, retries included.
+    pub requests: u64,
+    // Response bodies received, decompressed.
+    pub bytes: u64,
+    // Responses the HTTP cache answered while fresh, or the API confirmed as unchanged.
+    pub cache_hits: u64,
+    #[serde(flatten)]
+    pub changes: Changes,
+}
+
+// Counted across concurrent requests, and taken at the end of each sync.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    requests: AtomicU64,
+    bytes: AtomicU64,
+    cache_hits: AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn received(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Requests, bytes and cache hits since last taken.
+    pub(crate) fn take(&self) -> (u64, u64, u64) {
+        (
+            self.requests.swap(0, Ordering::Relaxed),
+            self.bytes.swap(0, Ordering::Relaxed),
+            self.cache_hits.swap(0, Ordering::Relaxed),
+        )
+    }
+}
+
+impl Api {
+    pub(crate) fn save_summary(&self, summary: &SyncSummary) -> Result<(), Error> {
+        create_dir_all(self.path(".sync"))?;
+        durable::write_file(&self.summary_path(), self.store.is_durable(), |out| {
+            Ok(serde_yaml::to_writer(out, summary)?)
+        })
+    }
+
+    fn summary_path(&self) -> PathBuf {
+        self.path(".sync").join("last_run.yaml")
+    }
+}
+
+fn seconds<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
@@ -0,0 +1,334 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{read_dir, read_to_string, File},
+    io::{ErrorKind, Read},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value as YamlValue;
+
+use crate::{
+    api::{decompress, ID},
+    archive::{id_field, ids, Archive, Record},
+    error::Error,
+    normalise::{REFERENCES, TABLES},
+};
+
+const LISTING_SUFFIX: &str = ".observations.yaml";
+
+#[derive(Debug, Default, Serialize)]
+#[non_exhaustive]
+pub struct VerifyReport {
+    pub files: usize,
+    pub records: usize,
+    pub problems: Vec<Problem>,
+    // References to records not cached, by the table of the records they're in.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub dangling: BTreeMap<String, usize>,
+}
+
+#[derive(Debug, Serialize)]
+#[non_exhaustive]
+pub struct Problem {
+    // Relative to the data directory.
+    pub path: PathBuf,
+    pub kind: ProblemKind,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ProblemKind {
+    // Not valid YAML.
+    Parse,
+    // Valid YAML, but not the header and record documents expected.
+    Structure,
+    // Missing ID, or one not matching the file name.
+    Id,
+    // A reference to a record that is not cached.
+    Reference,
+}
+
+#[derive(Default)]
+struct Verifier {
+    report: VerifyReport,
+    ids: HashMap<&'static str, HashSet<u64>>,
+    records: Vec<(&'static str, PathBuf, Record)>,
+}
+
+impl Archive {
+    // Checks every cached file in both layouts, so that half-converted caches are covered too.
+    pub fn verify(&self) -> Result<VerifyReport, Error> {
+        let mut verifier = Verifier::default();
+        for table in TABLES {
+            verifier.table_dir(&self.data_dir, table, Path::new(table))?;
+            for name in [format!("{}.yaml", table), format!("{}.yaml.zst", table)] {
+                let path = PathBuf::from(name);
+                if self.data_dir.join(&path).exists() {
+                    verifier.table_file(&self.data_dir, table, path);
+                }
+            }
+        }
+        verifier.sync_state(&self.data_dir)?;
+        verifier.references();
+
+        Ok(verifier.report)
+    }
+
+    // Only that the references resolve, e.g. after normalising: the records are read like
+    // everywhere else, the files they're in aren't checked.
+    pub fn verify_refs(&self) -> Result<VerifyReport, Error> {
+        let mut verifier = Verifier::default();
+        for table in TABLES {
+            for (id, record) in self.table(table)? {
+                verifier.report.records += 1;
+                verifier.ids.entry(table).or_default().insert(id);
+                verifier
+                    .records
+                    .push((table, self.store.relative_path(table, id), record));
+            }
+        }
+        verifier.references();
+
+        Ok(verifier.report)
+    }
+}
+
+impl Verifier {
+    fn table_dir(&mut self, data_dir: &Path, table: &'static str, dir: &Path) -> Result<(), Error> {
+        let files = match read_dir(data_dir.join(dir)) {
+            Ok(files) => files,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut names: Vec<_> = files
+            .map(|file| Ok(file?.file_name()))
+            .collect::<Result<_, Error>>()?;
+        names.sort();
+        for name in names {
+            let path = dir.join(&name);
+            let name = name.to_string_lossy();
+            let full = data_dir.join(&path);
+            // Shards of the sharded layout.
+            if full.is_dir() && !full.is_symlink() {
+                self.table_dir(data_dir, table, &path)?;
+                continue;
+            }
+            if full.is_symlink() {
+                // The users directory links logins to user IDs.
+                if !full.exists() {
+                    self.problem(path, ProblemKind::Structure, "dangling symlink");
+                }
+                continue;
+            }
+
+            if table == "users" && name.ends_with(LISTING_SUFFIX) {
+                self.listing(data_dir, path);
+                continue;
+            }
+            let stem = name
+                .strip_suffix(".yaml.zst")
+                .or_else(|| name.strip_suffix(".yaml"));
+            let id = match stem.map(str::parse::<u64>) {
+                Some(Ok(id)) => id,
+                Some(_) => {
+                    self.problem(path, ProblemKind::Id, "file name is not an ID");
+                    continue;
+                }
+                _ => {
+                    self.problem(path, ProblemKind::Structure, "not a YAML file");
+                    continue;
+                }
+            };
+
+            let docs = match self.documents(data_dir, &path) {
+                Some(docs) => docs,
+                _ => continue,
+            };
+            if docs.len() != 2 {
+                let message = format!("expected 2 documents, found {}", docs.len());
+                self.problem(path, ProblemKind::Structure, &message);
+                continue;
+            }
+            if let Some(record) = self.record(table, &path, &docs[0], &docs[1]) {
+                if id_field(&record, ID) != Some(id) {
+                    self.problem(path.clone(), ProblemKind::Id, "ID does not match file name");
+                }
+                self.records.push((table, path, record));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn table_file(&mut self, data_dir: &Path, table: &'static str, path: PathBuf) {
+        let docs = match self.documents(data_dir, &path) {
+            Some(docs) => docs,
+            _ => return,
+        };
+        if docs.len() % 2 != 0 {
+            self.problem(
+                path.clone(),
+                ProblemKind::Structure,
+                "header without a record",
+            );
+        }
+        for pair in docs.chunks_exact(2) {
+            if let Some(record) = self.record(table, &path, &pair[0], &pair[1]) {
+                self.records.push((table, path.clone(), record));
+            }
+        }
+    }
+
+    // The users' observation ID listings, with a header like any other cache file.
+    fn listing(&mut self, data_dir: &Path, path: PathBuf) {
+        let docs = match self.documents(data_dir, &path) {
+            Some(docs) => docs,
+            _ => return,
+        };
+        let valid = docs.len() == 2
+            && docs[0].is_mapping()
+            && docs[1]
+                .as_sequence()
+                .is_some_and(|ids| ids.iter().all(|id| id.as_u64().is_some()));
+        if !valid {
+            self.problem(
+                path,
+                ProblemKind::Structure,
+                "expected a header and an ID list",
+            );
+        }
+    }
+
+    // Sync state only needs to parse, its structure is checked when it gets used.
+    fn sync_state(&mut self, data_dir: &Path) -> Result<(), Error> {
+        let files = match read_dir(data_dir.join(".sync")) {
+            Ok(files) => files,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut names: Vec<_> = files
+            .map(|file| Ok(file?.file_name()))
+            .collect::<Result<_, Error>>()?;
+        names.sort();
+        for name in names {
+            if name.to_string_lossy().ends_with(".yaml") {
+                self.documents(data_dir, &Path::new(".sync").join(name));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn references(&mut self) {
+        let mut problems = vec![];
+        for (table, path, record) in &self.records {
+            let id = id_field(record, ID).unwrap_or_default();
+            for reference in REFERENCES.iter().filter(|r| r.table == *table) {
+                let targets = match reference.many {
+                    true => ids(record, reference.key),
+                    _ => id_field(record, reference.key).into_iter().collect(),
+                };
+                let known = self.ids.get(reference.target);
+                for target in targets {
+                    if !known.is_some_and(|ids| ids.contains(&target)) {
+                        *self.report.dangling.entry(table.to_string()).or_default() += 1;
+                        problems.push(Problem {
+                            path: path.clone(),
+                            kind: ProblemKind::Reference,
+                            message: format!(
+                                "{} {}: {} -> {} {} not found",
+                                table, id, reference.key, reference.target, target
+                            ),
+                        });
+                    }
+                }
+            }
+        }
+        self.report.problems.extend(problems);
+    }
+
+    fn documents(&mut self, data_dir: &Path, path: &Path) -> Option<Vec<YamlValue>> {
+        self.report.files += 1;
+        let read = match path.extension().is_some_and(|ext| ext == "zst") {
+            true => File::open(data_dir.join(path))
+                .and_then(decompress)
+                .and_then(|mut read| {
+                    let mut text = String::new();
+                    read.read_to_string(&mut text).map(|_| text)
+                }),
+            _ => read_to_string(data_dir.join(path)),
+        };
+        let text = match read {
+            Ok(text) => text,
+            Err(err) => {
+                self.problem(path.to_path_buf(), ProblemKind::Parse, &err.to_string());
+                return None;
+            }
+        };
+
+        let mut docs = vec![];
+        for doc in serde_yaml::Deserializer::from_str(&text) {
+            match YamlValue::deserialize(doc) {
+                Ok(doc) => docs.push(doc),
+                Err(err) => {
+                    self.problem(path.to_path_buf(), ProblemKind::Parse, &err.to_string());
+                    return None;
+                }
+            }
+        }
+
+        Some(docs)
+    }
+
+    fn record(
+        &mut self,
+        table: &'static str,
+        path: &Path,
+        header: &YamlValue,
+        record: &YamlValue,
+    ) -> Option<Record> {
+        if !header.is_mapping() {
+            self.problem(
+                path.to_path_buf(),
+                ProblemKind::Structure,
+                "header is not a mapping",
+            );
+            return None;
+        }
+        let record: Record = match serde_yaml::from_value(record.clone()) {
+            Ok(record) => record,
+            _ => {
+                self.problem(
+                    path.to_path_buf(),
+                    ProblemKind::Structure,
+                    "record is not a mapping",
+                );
+                return None;
+            }
+        };
+        match id_field(&record, ID) {
+            Some(id) => {
+                self.report.records += 1;
+                self.ids.entry(table).or_default().insert(id);
+                Some(record)
+            }
+            _ => {
+                self.problem(path.to_path_buf(), ProblemKind::Id, "record without an ID");
+                None
+            }
+        }
+    }
+
+    fn problem(&mut self, path: PathBuf, kind: ProblemKind, message: &str) {
+        self.report.problems.push(Problem {
+            path,
+            kind,
+            message: message.to_string(),
+        });
+    }
+}
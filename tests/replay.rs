@@ -0,0 +1,304 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use chrono::{TimeZone, Utc};
+use inat::{
+    Api, ApiBuilder, Archive, Cassette, Error, ErrorKind, FixedClock, Interaction, Middleware,
+    Model, Observation, Selection, SyncOptions,
+};
+use tempfile::tempdir;
+
+// A sync of alice's four observations, recorded with --record.
+const CASSETTE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/sync.jsonl");
+
+fn api(dir: &Path) -> Api {
+    replay(Api::builder().data_dir(dir))
+}
+
+fn replay(builder: ApiBuilder) -> Api {
+    builder
+        .build()
+        .expect("api")
+        .with_middleware(Cassette::from_file(CASSETTE).expect("cassette"))
+}
+
+// When the cassette was recorded, more or less.
+fn clock() -> FixedClock {
+    FixedClock(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap())
+}
+
+fn interactions() -> Vec<Interaction> {
+    fs::read_to_string(CASSETTE)
+        .expect("cassette")
+        .lines()
+        .map(|line| serde_json::from_str::<Interaction>(line).expect("interaction"))
+        .collect()
+}
+
+// The cassette's interactions, but those whose URL contains the given part.
+fn interactions_without(part: &str) -> Vec<Interaction> {
+    interactions()
+        .into_iter()
+        .filter(|interaction| !interaction.url.contains(part))
+        .collect()
+}
+
+fn opts() -> SyncOptions {
+    SyncOptions::new(Selection::new(vec![], vec![]).expect("selection"))
+}
+
+// Every file under the directory, by its path relative to it.
+fn files(root: &Path, dir: &Path, files: &mut BTreeMap<String, Vec<u8>>) {
+    for entry in fs::read_dir(dir).expect("read dir") {
+        let path = entry.expect("dir entry").path();
+        match path.is_dir() {
+            true => self::files(root, &path, files),
+            _ => {
+                let name = path.strip_prefix(root).expect("relative path");
+                files.insert(name.display().to_string(), fs::read(&path).expect("read"));
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn sync_from_cassette() {
+    let dir = tempdir().expect("tempdir");
+    let api = api(dir.path());
+
+    let summary = api.sync("alice", &opts()).await.expect("sync");
+    assert_eq!(summary.requests, 5);
+    let new = |table: &str| summary.changes.tables.get(table).map(|changes| changes.new);
+    assert_eq!(new("observations"), Some(4));
+    assert_eq!(new("identifications"), Some(8));
+    assert_eq!(new("taxa"), Some(5));
+    assert_eq!(new("users"), Some(1));
+    assert_eq!(new("sites"), Some(2));
+
+    let obs: Observation = api.load(1).expect("load").expect("observation 1");
+    assert_eq!(obs.id(), 1);
+}
+
+#[tokio::test]
+async fn resyncs_keep_taxon_detail() {
+    let dir = tempdir().expect("tempdir");
+    api(dir.path()).sync("alice", &opts()).await.expect("sync");
+
+    // Observations come with their taxa nested, without taxon photos; those fetched before stay.
+    let api = Api::builder()
+        .data_dir(dir.path())
+        .build()
+        .expect("api")
+        .with_middleware(Cassette::new(interactions_without("/taxa/")));
+    api.sync("alice", &opts().full(true)).await.expect("resync");
+
+    let taxa = api.archive().table("taxa").expect("taxa");
+    assert!(taxa[&11].contains_key("taxon_photos"));
+}
+
+#[tokio::test]
+async fn resyncs_keep_photo_detail() {
+    let dir = tempdir().expect("tempdir");
+    api(dir.path()).sync("alice", &opts()).await.expect("sync");
+
+    // The same observations, but with less detail about their photos.
+    let mut interactions = interactions_without("/taxa/");
+    for interaction in &mut interactions {
+        if interaction.url.contains("/observations/") {
+            interaction.body = interaction
+                .body
+                .replace(r#", "attribution": "(c) alice""#, "");
+        }
+    }
+    let api = Api::builder()
+        .data_dir(dir.path())
+        .build()
+        .expect("api")
+        .with_middleware(Cassette::new(interactions));
+    api.sync("alice", &opts().full(true)).await.expect("resync");
+
+    let photos = api.archive().table("photos").expect("photos");
+    assert_eq!(photos[&101]["attribution"], "(c) alice");
+}
+
+#[tokio::test]
+async fn resyncs_move_deleted_observations() {
+    let dir = tempdir().expect("tempdir");
+    replay(Api::builder().data_dir(dir.path()).clock(clock()))
+        .sync("alice", &opts())
+        .await
+        .expect("sync");
+
+    // Observation 4 is gone: nothing new is listed after it, but iNat lists one fewer.
+    let (listings, mut interactions): (Vec<_>, Vec<_>) = interactions()
+        .into_iter()
+        .partition(|interaction| interaction.url.contains("/observations?"));
+    let listing = &listings[0];
+    for (url, total, per_page, ids) in [
+        (format!("{}&id_above=4", listing.url), 3, 200, "[]"),
+        (
+            listing.url.replace("per_page=200", "per_page=1"),
+            3,
+            1,
+            r#"[{"id": 1}]"#,
+        ),
+        (
+            listing.url.clone(),
+            3,
+            200,
+            r#"[{"id": 1}, {"id": 2}, {"id": 3}]"#,
+        ),
+        (
+            format!(
+                "{}&updated_since=2024-01-01T00%3A00%3A00%2B00%3A00",
+                listing.url
+            ),
+            0,
+            200,
+            "[]",
+        ),
+    ] {
+        interactions.push(Interaction {
+            url,
+            body: format!(
+                r#"{{"total_results": {}, "page": 1, "per_page": {}, "results": {}}}"#,
+                total, per_page, ids
+            ),
+            ..listing.clone()
+        });
+    }
+    let api = Api::builder()
+        .data_dir(dir.path())
+        .clock(clock())
+        .build()
+        .expect("api")
+        .with_middleware(Cassette::new(interactions));
+    api.sync("alice", &opts()).await.expect("resync");
+
+    assert!(!api
+        .archive()
+        .table("observations")
+        .expect("observations")
+        .contains_key(&4));
+    assert!(dir.path().join("deleted/observations/4.yaml").exists());
+}
+
+#[cfg(feature = "export")]
+#[tokio::test]
+async fn anki_decks_leave_out_missing_photos() {
+    let dir = tempdir().expect("tempdir");
+    let api = api(dir.path());
+    api.sync("alice", &opts()).await.expect("sync");
+
+    let out = tempdir().expect("tempdir");
+    let cards = || {
+        fs::read_to_string(out.path().join("deck.csv"))
+            .expect("deck")
+            .lines()
+            .count()
+    };
+    let archive = api.archive();
+    let filter = inat::Filter::default();
+    archive
+        .export_anki(out.path(), None, 1, None, &filter)
+        .await
+        .expect("export");
+    assert_eq!(cards(), 0);
+
+    let media = out.path().join("media");
+    for photo in archive.table("photos").expect("photos").keys() {
+        fs::write(media.join(format!("inat_{}.jpg", photo)), b"").expect("photo");
+    }
+    archive
+        .export_anki(out.path(), None, 1, None, &filter)
+        .await
+        .expect("export");
+    assert!(cards() > 0);
+}
+
+// Runs out of quota on the taxa.
+struct NoTaxa;
+
+impl Middleware for NoTaxa {
+    fn request(&self, req: &mut reqwest::Request) -> Result<Option<reqwest::Response>, Error> {
+        match req.url().path().starts_with("/v1/taxa/") {
+            true => Err(Error::QuotaExhausted(
+                Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap(),
+            )),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[tokio::test]
+async fn resyncs_skip_stages_done_before_the_quota_ran_out() {
+    let dir = tempdir().expect("tempdir");
+    let api = Api::builder()
+        .data_dir(dir.path())
+        .build()
+        .expect("api")
+        .with_middleware(NoTaxa)
+        .with_middleware(Cassette::from_file(CASSETTE).expect("cassette"));
+    let err = api.sync("alice", &opts()).await.expect_err("quota");
+    assert_eq!(err.kind(), ErrorKind::Quota);
+
+    // Only the taxa are left: observations aren't listed or fetched again.
+    let api = Api::builder()
+        .data_dir(dir.path())
+        .build()
+        .expect("api")
+        .with_middleware(Cassette::new(interactions_without("/observations")));
+    api.sync("alice", &opts()).await.expect("resume");
+    assert!(dir.path().join("taxa").exists());
+    assert!(!dir.path().join(".sync/checkpoint.yaml").exists());
+}
+
+#[tokio::test]
+async fn requests_not_in_cassette_fail() {
+    let dir = tempdir().expect("tempdir");
+
+    let err = api(dir.path())
+        .sync("bob", &opts())
+        .await
+        .expect_err("bob is not in the cassette");
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    assert!(!err.is_retryable());
+}
+
+#[test]
+fn schemas_follow_the_models() {
+    let schema = Archive::schema("taxa").expect("schema");
+    assert_eq!(schema["properties"]["rank"]["type"][0], "string");
+    assert_eq!(
+        schema["properties"]["default_photo"]["x-references"],
+        "photos"
+    );
+    assert_eq!(schema["required"], serde_json::json!(["id"]));
+}
+
+#[tokio::test]
+async fn deterministic_syncs_match() {
+    let mut trees = vec![];
+    for _ in 0..2 {
+        let dir = tempdir().expect("tempdir");
+        let builder = Api::builder()
+            .data_dir(dir.path())
+            .clock(clock())
+            .deterministic(true);
+        replay(builder)
+            .sync("alice", &opts().save_summary(true))
+            .await
+            .expect("sync");
+        let mut tree = BTreeMap::new();
+        files(dir.path(), dir.path(), &mut tree);
+        trees.push(tree);
+    }
+
+    assert!(!trees[0].is_empty());
+    assert_eq!(
+        trees[0].keys().collect::<Vec<_>>(),
+        trees[1].keys().collect::<Vec<_>>()
+    );
+    for (name, content) in &trees[0] {
+        assert!(trees[1][name] == *content, "{} differs", name);
+    }
+}